@@ -0,0 +1,82 @@
+//! Rewrite counting comparisons into the functions that say what they mean:
+//! `x.count() > 0` and `x.count() != 0` become `x.exists()`, and
+//! `x.count() = 0` becomes `x.empty()`.
+//!
+//! ```bash
+//! cargo run --example rewrite -- "Patient.name.where(given.count() > 0).count() = 0"
+//! # Patient.name.where(given.exists()).empty()
+//! ```
+
+use fhirlighter::parser::grammar::{BinaryOperator, ExprPool, ExprRef, Expression};
+use fhirlighter::parser::unparser::unparse;
+use fhirlighter::parser::visit::{Folder, fold};
+use fhirlighter::{Error, Span, parse};
+use std::env;
+use std::process;
+
+struct CountComparisons;
+
+impl Folder for CountComparisons {
+    fn fold_binary_operation(
+        &mut self,
+        pool: &mut ExprPool,
+        span: Span,
+        operator: BinaryOperator,
+        lhs: ExprRef,
+        rhs: ExprRef,
+    ) -> Result<ExprRef, Error> {
+        let function = match operator {
+            BinaryOperator::GreaterThan | BinaryOperator::NotEquals => Some("exists"),
+            BinaryOperator::Equals => Some("empty"),
+            _ => None,
+        };
+        let rewrite = counted(pool, lhs)
+            .filter(|_| matches!(pool.get(rhs), Expression::Integer(0)))
+            .zip(function);
+        let Some((object, function)) = rewrite else {
+            let operation = Expression::BinaryOperation { operator, lhs, rhs };
+            return pool.add_with_span(operation, span);
+        };
+        let function = pool.add_with_span(Expression::Identifier(function.to_string()), span)?;
+        let call = Expression::FunctionCall {
+            object: Some(object),
+            function,
+            arguments: Vec::new(),
+        };
+        pool.add_with_span(call, span)
+    }
+}
+
+// The collection counted by `x.count()`
+fn counted(pool: &ExprPool, expr_ref: ExprRef) -> Option<ExprRef> {
+    match pool.get(expr_ref) {
+        Expression::FunctionCall {
+            object: Some(object),
+            function,
+            arguments,
+        } if arguments.is_empty()
+            && matches!(pool.get(*function), Expression::Identifier(name) if name == "count") =>
+        {
+            Some(*object)
+        }
+        _ => None,
+    }
+}
+
+fn main() {
+    let Some(expression) = env::args().nth(1) else {
+        eprintln!("Usage: cargo run --example rewrite -- <path>");
+        process::exit(1);
+    };
+    let ast = parse(&expression).unwrap_or_else(|error| {
+        eprintln!("{}", error.render(&expression));
+        process::exit(1);
+    });
+    match fold(&ast, &mut CountComparisons) {
+        Ok(rewritten) => println!("{}", unparse(&rewritten)),
+        Err(error) => {
+            eprintln!("{error}");
+            process::exit(1);
+        }
+    }
+}
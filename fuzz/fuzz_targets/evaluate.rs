@@ -0,0 +1,17 @@
+#![no_main]
+
+use fhirlighter::evaluator::context::EvaluationContext;
+use libfuzzer_sys::fuzz_target;
+use serde_json::Value;
+use std::sync::LazyLock;
+
+static PATIENT: LazyLock<Value> = LazyLock::new(|| {
+    serde_json::from_str(include_str!("../../tests/examples/patient-example.json"))
+        .expect("Failed to parse example")
+});
+
+// Evaluating any text against a resource either succeeds or returns an error
+fuzz_target!(|expression: &str| {
+    let context = EvaluationContext::new(&PATIENT);
+    let _ = fhirlighter::evaluate_with_diagnostics(expression, &context);
+});
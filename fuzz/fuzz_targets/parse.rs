@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// Tokenizing and parsing any text either succeeds or returns an error
+fuzz_target!(|expression: &str| {
+    let _ = fhirlighter::parse(expression);
+});
@@ -0,0 +1,335 @@
+use super::error::{Error, ErrorCode};
+use super::node::{Collection, Node};
+use super::resolver::ReferenceResolver;
+use super::utils::{Parents, nearest_resource, root_resource};
+use serde_json::{Map, Number, Value};
+use std::borrow::Cow;
+use std::cell::OnceCell;
+use std::collections::HashMap;
+use time::{Date, OffsetDateTime};
+
+/// The resource an expression is evaluated against, together with the variables
+/// and services evaluation may call upon.
+///
+/// Besides the variables added with [`EvaluationContext::with_variable`], the
+/// following environment variables are defined:
+///
+/// | Variable | Value |
+/// |----------|-------|
+/// | `%context` | The node evaluation starts from |
+/// | `%resource` | The resource containing `%context` |
+/// | `%rootResource` | The container of `%resource` when it is a contained resource, otherwise `%resource` |
+/// | `%ucum` | `http://unitsofmeasure.org` |
+/// | `%sct` | `http://snomed.info/sct` |
+/// | `%loinc` | `http://loinc.org` |
+/// | ``%`vs-[name]` `` | `http://hl7.org/fhir/ValueSet/[name]` |
+/// | ``%`ext-[name]` `` | `http://hl7.org/fhir/StructureDefinition/[name]` |
+///
+/// # Examples
+///
+/// ```rust
+/// use fhirlighter::evaluator::context::EvaluationContext;
+/// use fhirlighter::evaluator::resolver::ResourceStore;
+/// use serde_json::json;
+///
+/// let mut store = ResourceStore::new();
+/// store.insert(json!({"resourceType": "Patient", "id": "p1", "gender": "female"}))?;
+///
+/// let observation = json!({
+///     "resourceType": "Observation",
+///     "subject": {"reference": "Patient/p1"}
+/// });
+/// let context = EvaluationContext::new(&observation)
+///     .with_resolver(&store)
+///     .with_variable("gender", "female");
+///
+/// let result = fhirlighter::evaluate_with_context(
+///     "Observation.subject.resolve().gender = %gender",
+///     &context,
+/// )?;
+/// assert_eq!(result, json!(true));
+/// # Ok::<(), fhirlighter::Error>(())
+/// ```
+pub struct EvaluationContext<'a> {
+    pub(crate) resource: &'a Value,
+    pub(crate) focus: &'a Value,
+    pub(crate) resolver: Option<&'a dyn ReferenceResolver>,
+    pub(crate) unmatched_type: UnmatchedType,
+    variables: HashMap<String, Value>,
+    parents: OnceCell<Parents>,
+}
+
+impl<'a> EvaluationContext<'a> {
+    #[must_use]
+    pub fn new(resource: &'a Value) -> Self {
+        Self {
+            resource,
+            focus: resource,
+            resolver: None,
+            unmatched_type: UnmatchedType::Empty,
+            variables: HashMap::new(),
+            parents: OnceCell::new(),
+        }
+    }
+
+    /// Start evaluation at `node`, an element within the resource, which then
+    /// becomes `%context`. `%resource` and `%rootResource` are the resources
+    /// enclosing it, so invariants can be evaluated on elements of Bundle entries
+    /// and contained resources.
+    #[must_use]
+    pub const fn with_focus(mut self, node: &'a Value) -> Self {
+        self.focus = node;
+        self
+    }
+
+    /// Resolve references that are neither contained nor in the enclosing Bundle
+    /// through `resolver`.
+    #[must_use]
+    pub const fn with_resolver(mut self, resolver: &'a dyn ReferenceResolver) -> Self {
+        self.resolver = Some(resolver);
+        self
+    }
+
+    /// Choose what a leading type name such as `Encounter` in `Encounter.status`
+    /// does when it doesn't match the resource it is evaluated against.
+    #[must_use]
+    pub const fn with_unmatched_type(mut self, unmatched_type: UnmatchedType) -> Self {
+        self.unmatched_type = unmatched_type;
+        self
+    }
+
+    /// Define `%name`. An array is a collection of its items and `null` is empty.
+    /// User variables take precedence over the environment variables above.
+    #[must_use]
+    pub fn with_variable(mut self, name: &str, value: impl Into<Value>) -> Self {
+        self.variables.insert(name.to_string(), value.into());
+        self
+    }
+
+    /// The value of `%name`.
+    pub(crate) fn variable(&self, name: &str) -> Result<Collection<'a>, Error> {
+        if let Some(value) = self.variables.get(name) {
+            return Ok(match value {
+                Value::Null => Vec::new(),
+                Value::Array(items) => items.iter().cloned().map(Node::owned).collect(),
+                other => vec![Node::owned(other.clone())],
+            });
+        }
+        let value = match name {
+            "context" => self.focus,
+            "resource" => nearest_resource(&self.focus_path()).unwrap_or(self.resource),
+            "rootResource" => root_resource(&self.focus_path()).unwrap_or(self.resource),
+            _ => {
+                let url = match name {
+                    "ucum" => "http://unitsofmeasure.org".to_string(),
+                    "sct" => "http://snomed.info/sct".to_string(),
+                    "loinc" => "http://loinc.org".to_string(),
+                    _ => {
+                        if let Some(value_set) = name.strip_prefix("vs-") {
+                            format!("http://hl7.org/fhir/ValueSet/{value_set}")
+                        } else if let Some(extension) = name.strip_prefix("ext-") {
+                            format!("http://hl7.org/fhir/StructureDefinition/{extension}")
+                        } else {
+                            return Err(Error::new(
+                                ErrorCode::UndefinedVariable,
+                                format!("Undefined variable: %{name}"),
+                            ));
+                        }
+                    }
+                };
+                return Ok(vec![Node::owned(Value::String(url))]);
+            }
+        };
+        Ok(vec![Node::new(Cow::Borrowed(value))])
+    }
+
+    /// The chain of values from the resource down to `target`, both included.
+    /// The resource is indexed on first use, so later calls don't search it.
+    pub(crate) fn ancestors(&self, target: &Value) -> Option<Vec<&'a Value>> {
+        self.parents
+            .get_or_init(|| Parents::new(self.resource))
+            .ancestors(self.resource, target)
+    }
+
+    // Helper: the chain of values from the resource down to `%context`
+    fn focus_path(&self) -> Vec<&'a Value> {
+        self.ancestors(self.focus)
+            .unwrap_or_else(|| vec![self.focus])
+    }
+}
+
+/// What a leading type name that doesn't match the resource type evaluates to.
+///
+/// A type name matches resources of that type and of the types derived from it,
+/// so `Resource.id` and `DomainResource.text` apply to any resource. Names may be
+/// qualified, as in `FHIR.Patient`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnmatchedType {
+    /// Fail with `ErrorCode::UnmatchedType`, catching typos like `Patien.name`.
+    Error,
+    /// An empty collection, so one expression can serve several resource types.
+    #[default]
+    Empty,
+    /// Look up a child element of that name instead.
+    MemberLookup,
+}
+
+/// A `FHIRPath` value for a user variable, converted to its FHIR JSON form.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TypedValue {
+    Boolean(bool),
+    String(String),
+    Integer(i64),
+    Decimal(f64),
+    Date(Date),
+    DateTime(OffsetDateTime),
+    Quantity { value: f64, unit: String },
+}
+
+impl From<TypedValue> for Value {
+    fn from(value: TypedValue) -> Self {
+        match value {
+            TypedValue::Boolean(boolean) => Self::Bool(boolean),
+            TypedValue::String(string) => Self::String(string),
+            TypedValue::Integer(integer) => Self::Number(Number::from(integer)),
+            TypedValue::Decimal(decimal) => {
+                Number::from_f64(decimal).map_or(Self::Null, Self::Number)
+            }
+            TypedValue::Date(date) => Self::String(date.to_string()),
+            TypedValue::DateTime(datetime) => Self::String(format_date_time(datetime)),
+            TypedValue::Quantity { value, unit } => {
+                let mut quantity = Map::new();
+                quantity.insert("value".to_string(), Self::from(TypedValue::Decimal(value)));
+                quantity.insert("unit".to_string(), Self::String(unit));
+                Self::Object(quantity)
+            }
+        }
+    }
+}
+
+// Helper: a dateTime as FHIR writes it, keeping sub-seconds and the offset,
+// e.g. `2024-02-29T08:05:00.25+01:00`
+fn format_date_time(datetime: OffsetDateTime) -> String {
+    let seconds = match datetime.nanosecond() {
+        0 => format!("{:02}", datetime.second()),
+        nanoseconds => {
+            let fraction = format!("{nanoseconds:09}");
+            format!(
+                "{:02}.{}",
+                datetime.second(),
+                fraction.trim_end_matches('0')
+            )
+        }
+    };
+    let offset = datetime.offset();
+    let zone = if offset.is_utc() {
+        "Z".to_string()
+    } else {
+        let sign = if offset.is_negative() { '-' } else { '+' };
+        let (hours, minutes, _) = offset.as_hms();
+        format!(
+            "{sign}{:02}:{:02}",
+            hours.unsigned_abs(),
+            minutes.unsigned_abs()
+        )
+    };
+    format!(
+        "{}T{:02}:{:02}:{seconds}{zone}",
+        datetime.date(),
+        datetime.hour(),
+        datetime.minute()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use time::{Month, PrimitiveDateTime, Time, UtcOffset};
+
+    #[test]
+    fn test_contained_resource_variables() {
+        let observation = json!({
+            "resourceType": "Observation",
+            "contained": [{"resourceType": "Patient", "id": "p1", "gender": "female"}]
+        });
+        let gender = &observation["contained"][0]["gender"];
+        let context = EvaluationContext::new(&observation).with_focus(gender);
+
+        let variable = |name| context.variable(name).unwrap()[0].value.clone();
+        assert_eq!(*variable("context"), json!("female"));
+        assert_eq!(variable("resource")["id"], json!("p1"));
+        assert_eq!(
+            variable("rootResource")["resourceType"],
+            json!("Observation")
+        );
+    }
+
+    #[test]
+    fn test_ancestors() {
+        let bundle = json!({
+            "resourceType": "Bundle",
+            "entry": [{"resource": {"resourceType": "Patient", "name": [{"given": ["Jim"]}]}}]
+        });
+        let patient = &bundle["entry"][0]["resource"];
+        let given = &patient["name"][0]["given"][0];
+        let context = EvaluationContext::new(&bundle);
+
+        let path = context.ancestors(given).unwrap();
+        assert_eq!(path.len(), 8);
+        assert!(std::ptr::eq(path[3], patient));
+        assert!(std::ptr::eq(path[7], given));
+        // The index built by the first call serves the next ones
+        assert_eq!(context.ancestors(patient).unwrap().len(), 4);
+        assert!(context.ancestors(&json!("Jim")).is_none());
+    }
+
+    #[test]
+    fn test_constants() {
+        let patient = json!({"resourceType": "Patient"});
+        let context = EvaluationContext::new(&patient);
+
+        assert_eq!(
+            context.variable("vs-administrative-gender").unwrap(),
+            vec![Node::owned(json!(
+                "http://hl7.org/fhir/ValueSet/administrative-gender"
+            ))]
+        );
+        assert!(matches!(
+            context.variable("unknown"),
+            Err(Error {
+                code: ErrorCode::UndefinedVariable,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_typed_values() {
+        let date = Date::from_calendar_date(2024, Month::February, 29).unwrap();
+        let time = Time::from_hms(8, 5, 0).unwrap();
+        assert_eq!(Value::from(TypedValue::Date(date)), json!("2024-02-29"));
+        let datetime = PrimitiveDateTime::new(date, time);
+        assert_eq!(
+            Value::from(TypedValue::DateTime(datetime.assume_utc())),
+            json!("2024-02-29T08:05:00Z")
+        );
+        let offset = UtcOffset::from_hms(-5, -30, 0).unwrap();
+        assert_eq!(
+            Value::from(TypedValue::DateTime(
+                datetime
+                    .replace_millisecond(250)
+                    .unwrap()
+                    .assume_offset(offset)
+            )),
+            json!("2024-02-29T08:05:00.25-05:30")
+        );
+        assert_eq!(
+            Value::from(TypedValue::Quantity {
+                value: 1.5,
+                unit: "mg".to_string()
+            }),
+            json!({"value": 1.5, "unit": "mg"})
+        );
+    }
+}
@@ -1,14 +1,36 @@
-use super::error::Error;
-use crate::evaluator::functions::array_functions::{count, empty, exists, last};
-use crate::evaluator::utils::{ComparableTypes, eval_index, get_from_array, get_from_object};
+use super::context::{EvaluationContext, UnmatchedType};
+use super::error::{Diagnostic, Error, ErrorCode};
+use super::model::{is_subtype, is_type_name};
+use super::node::{Collection, Node, to_value};
+use crate::evaluator::functions::array_functions::{count, empty, exists, first, last};
+use crate::evaluator::functions::fhir_functions::{
+    extension, get_value, has_value, html_checks, of_type, resolve, trace_value,
+};
+use crate::evaluator::utils::{ComparableTypes, eval_index};
 use crate::parser::ast::Ast;
+use crate::parser::functions;
 #[cfg(test)]
 use crate::parser::grammar::ExprPool;
 use crate::parser::grammar::{BinaryOperator, ExprRef, Expression};
 use serde_json::{Number, Value};
 use std::borrow::Cow;
 
-pub struct Evaluator;
+type Tracer = Box<dyn Fn(&str, &Value) + Send + Sync>;
+type Observer = Box<dyn Fn(ExprRef, &Value) + Send + Sync>;
+
+/// Evaluates parsed expressions. It holds no state of its own evaluations, so
+/// one evaluator can serve several threads, or a tracer that evaluates again.
+pub struct Evaluator {
+    tracer: Option<Tracer>,
+    observer: Option<Observer>,
+}
+
+/// The result of an evaluation together with the warnings raised on the way.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Evaluation {
+    pub value: Value,
+    pub diagnostics: Vec<Diagnostic>,
+}
 
 impl Default for Evaluator {
     fn default() -> Self {
@@ -19,146 +41,367 @@ impl Default for Evaluator {
 impl Evaluator {
     #[must_use]
     pub const fn new() -> Self {
-        Self
+        Self {
+            tracer: None,
+            observer: None,
+        }
+    }
+
+    /// Receive the output of `trace(name)` calls, which is discarded otherwise.
+    #[must_use]
+    pub fn with_tracer(mut self, tracer: impl Fn(&str, &Value) + Send + Sync + 'static) -> Self {
+        self.tracer = Some(Box::new(tracer));
+        self
+    }
+
+    /// Receive the value of each expression of the tree as it is evaluated, for
+    /// instance to annotate [`Ast::to_dot_with_values`]. Expressions evaluated for
+    /// each item, such as the projection of `trace()`, are reported once per item.
+    #[must_use]
+    pub fn with_observer(
+        mut self,
+        observer: impl Fn(ExprRef, &Value) + Send + Sync + 'static,
+    ) -> Self {
+        self.observer = Some(Box::new(observer));
+        self
     }
 
     /// # Errors
     ///
     /// Returns an error if expression evaluation fails due to invalid syntax or runtime issues.
     pub fn evaluate(&self, ast: &Ast, resource: &Value) -> Result<Value, Error> {
-        let start = ast.start;
-        match self.eval(ast, start, resource) {
-            Ok(value) => Ok(value.into_owned()),
-            Err(error) => match error {
-                Error::Parse(error) => {
-                    println!("{error}");
-                    Ok(Value::Array(vec![]))
-                }
-                _ => Err(error),
-            },
-        }
+        self.evaluate_with_context(ast, &EvaluationContext::new(resource))
+    }
+
+    /// # Errors
+    ///
+    /// Returns an error if expression evaluation fails due to invalid syntax or runtime issues.
+    pub fn evaluate_with_context<'a>(
+        &self,
+        ast: &'a Ast,
+        context: &EvaluationContext<'a>,
+    ) -> Result<Value, Error> {
+        self.evaluate_with_diagnostics(ast, context)
+            .map(|evaluation| evaluation.value)
     }
 
-    #[allow(clippy::only_used_in_recursion)]
+    /// Evaluate like [`Evaluator::evaluate_with_context`], also returning the
+    /// warnings raised, such as references `resolve()` couldn't find.
+    ///
+    /// An empty result is a success: it means the data has no match, not that the
+    /// expression is wrong.
+    ///
+    /// Function calls aren't checked again: [`crate::parse`] has done so, and a
+    /// tree built or read from JSON can be checked with `functions::check`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if expression evaluation fails due to invalid syntax or runtime issues.
+    pub fn evaluate_with_diagnostics<'a>(
+        &self,
+        ast: &'a Ast,
+        context: &EvaluationContext<'a>,
+    ) -> Result<Evaluation, Error> {
+        let focus = Node::new(Cow::Borrowed(context.focus));
+        let mut diagnostics = Vec::new();
+        let result = self.eval(ast, ast.start, &focus, context, &mut diagnostics)?;
+        Ok(Evaluation {
+            value: to_value(result),
+            diagnostics,
+        })
+    }
+
+    // Evaluate an expression whose leading identifier navigates from `focus`:
+    // the resource at the top level and each input item inside a lambda argument.
+    // Errors are located at the innermost expression that raised them, and so
+    // are the warnings added to `diagnostics`.
     fn eval<'a>(
         &self,
         ast: &'a Ast,
         expr_ref: ExprRef,
-        resource: &'a Value,
-    ) -> Result<Cow<'a, Value>, Error> {
+        focus: &Node<'a>,
+        context: &EvaluationContext<'a>,
+        diagnostics: &mut Vec<Diagnostic>,
+    ) -> Result<Collection<'a>, Error> {
+        let span = ast.expressions.span(expr_ref);
+        let raised = diagnostics.len();
+        let result = self.eval_expression(ast, expr_ref, focus, context, diagnostics);
+        for diagnostic in &mut diagnostics[raised..] {
+            diagnostic.span.get_or_insert(span);
+        }
+        if let (Some(observer), Ok(collection)) = (&self.observer, &result) {
+            observer(expr_ref, &to_value(collection.clone()));
+        }
+        result.map_err(|error| error.or_span(span))
+    }
+
+    #[allow(clippy::too_many_lines)]
+    fn eval_expression<'a>(
+        &self,
+        ast: &'a Ast,
+        expr_ref: ExprRef,
+        focus: &Node<'a>,
+        context: &EvaluationContext<'a>,
+        diagnostics: &mut Vec<Diagnostic>,
+    ) -> Result<Collection<'a>, Error> {
         let expression = ast.expressions.get(expr_ref);
         match expression {
             Expression::Identifier(name) => {
-                let resource_type = resource
-                    .get("resourceType")
-                    .unwrap_or_default()
-                    .as_str()
-                    .unwrap_or("");
-                if resource_type == name {
-                    return Ok(Cow::Borrowed(resource));
-                } else if let Some(value) = resource.get(name) {
-                    return Ok(Cow::Borrowed(value));
-                }
-                Err(Error::Parse(format!(
-                    "Could not find field or resource type: {name}"
-                )))
+                Self::eval_identifier(name, focus, context, diagnostics)
             }
             Expression::MemberAccess { object, member } => {
-                let member_object = self.eval(ast, *object, resource)?;
-                match member_object.as_ref() {
-                    Value::Array(array) => {
-                        let mut result = Vec::new();
-                        for item in array {
-                            if let Some(value) = item.get(member) {
-                                match value {
-                                    Value::Array(arr) => {
-                                        result.extend(arr.iter().cloned());
-                                    }
-                                    other => {
-                                        result.push(other.clone());
-                                    }
-                                }
-                            }
-                            // If member doesn't exist on this item, skip it (no error)
-                        }
-                        Ok(Cow::Owned(Value::Array(result)))
-                    }
-                    Value::Object(_) => get_from_object(member_object, member),
-                    _ => Err(Error::Parse("Unimplemented: MemberAccess".to_string())),
+                // `FHIR.Patient` is the type name `Patient` in the FHIR namespace
+                if is_fhir_namespace(ast.expressions.get(*object)) && is_type_name(member) {
+                    return Self::eval_identifier(member, focus, context, diagnostics);
                 }
+                Ok(self
+                    .eval(ast, *object, focus, context, diagnostics)?
+                    .into_iter()
+                    .flat_map(|node| node.into_children(member))
+                    .collect())
             }
             Expression::Index { object, index } => {
-                let index_object = self.eval(ast, *object, resource)?;
-                let index = eval_index(ast.expressions.get(index.to_owned()), resource)?;
-                get_from_array(index_object, index)
+                let index_object = self.eval(ast, *object, focus, context, diagnostics)?;
+                let index = eval_index(ast.expressions.get(index.to_owned()), &focus.value)?;
+                Ok(index_object.into_iter().nth(index).into_iter().collect())
             }
             Expression::FunctionCall {
                 object,
                 function,
-                arguments: _,
+                arguments,
             } => {
-                if let Some(context) = object {
-                    let function_object = self.eval(ast, *context, resource)?;
+                if let Some(object) = object {
+                    let function_object = self.eval(ast, *object, focus, context, diagnostics)?;
                     let function_expression = ast.expressions.get(*function);
                     if let Expression::Identifier(function_name) = function_expression {
-                        Ok(Self::eval_function(function_object, function_name)?)
+                        self.eval_function(
+                            ast,
+                            function_object,
+                            function_name,
+                            arguments,
+                            focus,
+                            context,
+                            diagnostics,
+                        )
                     } else {
-                        Err(Error::Parse(
-                            "Function name must be an identifier".to_string(),
+                        Err(Error::new(
+                            ErrorCode::Unsupported,
+                            "Function name must be an identifier",
                         ))
                     }
                 } else {
-                    Err(Error::Parse(
-                        "Standalone functions are not implemented".to_string(),
+                    Err(Error::new(
+                        ErrorCode::Unsupported,
+                        "Standalone functions are not implemented",
                     ))
                 }
             }
             Expression::BinaryOperation { operator, lhs, rhs } => {
-                let lhs =
-                    ComparableTypes::from_value(self.eval(ast, *lhs, resource)?.into_owned())?;
-                let rhs =
-                    ComparableTypes::from_value(self.eval(ast, *rhs, resource)?.into_owned())?;
-                let result = match operator {
-                    BinaryOperator::Equals => lhs == rhs,
-                    BinaryOperator::NotEquals => lhs != rhs,
-                    BinaryOperator::LessThan => lhs < rhs,
-                    BinaryOperator::LessThanOrEqual => lhs <= rhs,
-                    BinaryOperator::GreaterThan => lhs > rhs,
-                    BinaryOperator::GreaterThanOrEqual => lhs >= rhs,
+                let (Some(lhs), Some(rhs)) = (
+                    singleton(self.eval(ast, *lhs, focus, context, diagnostics)?)?,
+                    singleton(self.eval(ast, *rhs, focus, context, diagnostics)?)?,
+                ) else {
+                    // Comparisons with an empty operand are empty
+                    return Ok(Vec::new());
                 };
-                Ok(Cow::Owned(Value::Bool(result)))
+                let ordering = ComparableTypes::from_value(&lhs.value)
+                    .zip(ComparableTypes::from_value(&rhs.value))
+                    .and_then(|(lhs, rhs)| lhs.compare(&rhs));
+                let result = match (operator, ordering) {
+                    (BinaryOperator::Equals, Some(ordering)) => ordering.is_eq(),
+                    (BinaryOperator::NotEquals, Some(ordering)) => ordering.is_ne(),
+                    (BinaryOperator::LessThan, Some(ordering)) => ordering.is_lt(),
+                    (BinaryOperator::LessThanOrEqual, Some(ordering)) => ordering.is_le(),
+                    (BinaryOperator::GreaterThan, Some(ordering)) => ordering.is_gt(),
+                    (BinaryOperator::GreaterThanOrEqual, Some(ordering)) => ordering.is_ge(),
+                    (BinaryOperator::Equals | BinaryOperator::NotEquals, None) => {
+                        // Complex values are equal member by member, while values
+                        // of different types are never equal
+                        if !(lhs.value.is_object() && rhs.value.is_object()) {
+                            diagnostics.push(Diagnostic::warning(
+                                ErrorCode::IncomparableValues,
+                                format!(
+                                    "Comparing values of different types: {} and {}",
+                                    lhs.value, rhs.value
+                                ),
+                            ));
+                        }
+                        (lhs.value == rhs.value) == (*operator == BinaryOperator::Equals)
+                    }
+                    (_, None) => {
+                        return Err(Error::new(
+                            ErrorCode::IncomparableValues,
+                            format!("Can't order {} and {}", lhs.value, rhs.value),
+                        ));
+                    }
+                };
+                Ok(vec![Node::owned(Value::Bool(result))])
+            }
+            Expression::ExternalConstant(name) => context.variable(name),
+            Expression::String(literal) => Ok(vec![Node::owned(Value::String(literal.clone()))]),
+            Expression::Integer(integer) => {
+                Ok(vec![Node::owned(Value::Number(Number::from(*integer)))])
             }
-            Expression::String(literal) => Ok(Cow::Owned(Value::String(literal.to_string()))),
-            Expression::Integer(integer) => Ok(Cow::Owned(Value::Number(Number::from(*integer)))),
+            Expression::Number(number) => Ok(Number::from_f64(*number)
+                .map(|number| Node::owned(Value::Number(number)))
+                .into_iter()
+                .collect()),
+            Expression::Boolean(boolean) => Ok(vec![Node::owned(Value::Bool(*boolean))]),
             // TODO: Identify whether this causes issues/investigate a cleaner way to do this
-            Expression::ISODate(date) => Ok(Cow::Owned(Value::String(date.to_string()))),
-            Expression::ISODateTime(date) => Ok(Cow::Owned(Value::String(date.to_string()))),
-            expression => Err(Error::Parse(format!(
-                "Expression: {expression} not implemented",
-            ))),
+            Expression::ISODate(date) => Ok(vec![Node::owned(Value::String(date.to_string()))]),
+            Expression::ISODateTime(date) => Ok(vec![Node::owned(Value::String(date.to_string()))]),
+            Expression::Error => Err(Error::new(
+                ErrorCode::UnexpectedToken,
+                "Can't evaluate an expression that failed to parse",
+            )),
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn eval_function<'a>(
-        resource: Cow<'a, Value>,
+        &self,
+        ast: &'a Ast,
+        collection: Collection<'a>,
         function: &str,
-    ) -> Result<Cow<'a, Value>, Error> {
-        match function {
-            "first" => get_from_array(resource, 0),
-            "empty" => empty(resource),
-            "last" => last(resource),
-            "count" => count(resource),
-            "exists" => exists(resource),
-            function => Err(Error::Unrecoverable(format!(
-                "Couldn't evaluate function: {function}"
-            ))),
+        arguments: &[ExprRef],
+        focus: &Node<'a>,
+        context: &EvaluationContext<'a>,
+        diagnostics: &mut Vec<Diagnostic>,
+    ) -> Result<Collection<'a>, Error> {
+        match (function, arguments) {
+            ("first", []) => Ok(first(collection)),
+            ("empty", []) => Ok(empty(&collection)),
+            ("last", []) => Ok(last(collection)),
+            ("count", []) => Ok(count(&collection)),
+            ("exists", []) => Ok(exists(&collection)),
+            ("extension", [url]) => {
+                let url =
+                    string_argument(function, self.eval(ast, *url, focus, context, diagnostics)?)?;
+                Ok(extension(collection, &url))
+            }
+            ("hasValue", []) => Ok(has_value(&collection)),
+            ("getValue", []) => Ok(get_value(collection)),
+            ("htmlChecks", []) => Ok(html_checks(&collection)),
+            ("resolve", []) => Ok(resolve(collection, context, |reference| {
+                diagnostics.push(Diagnostic::warning(
+                    ErrorCode::UnresolvedReference,
+                    format!("Couldn't resolve reference {reference}"),
+                ));
+            })),
+            ("ofType", [type_specifier]) => type_name(ast, *type_specifier)
+                .map(|type_name| of_type(collection, type_name))
+                .ok_or_else(|| {
+                    Error::new(
+                        ErrorCode::InvalidArgument,
+                        "ofType() expects a type specifier",
+                    )
+                    .with_span(ast.expressions.span(*type_specifier))
+                }),
+            ("trace", [name, projection @ ..]) if projection.len() <= 1 => {
+                let name = string_argument(
+                    function,
+                    self.eval(ast, *name, focus, context, diagnostics)?,
+                )?;
+                let traced = match projection {
+                    // The projection is evaluated against each input item
+                    [projection] => collection
+                        .iter()
+                        .map(|item| self.eval(ast, *projection, item, context, diagnostics))
+                        .collect::<Result<Vec<_>, _>>()?
+                        .concat(),
+                    _ => collection.clone(),
+                };
+                if let Some(tracer) = &self.tracer {
+                    tracer(&name, &trace_value(&traced));
+                }
+                Ok(collection)
+            }
+            (function, _) if functions::lookup(function).is_some() => Err(Error::new(
+                ErrorCode::Unsupported,
+                format!("{function}() is not implemented yet"),
+            )),
+            (function, _) => Err(Error::new(
+                ErrorCode::UnknownFunction,
+                format!("Couldn't evaluate function: {function}"),
+            )),
         }
     }
+
+    // A leading identifier is either a type name matching the focus or an element of it
+    fn eval_identifier<'a>(
+        name: &str,
+        focus: &Node<'a>,
+        context: &EvaluationContext,
+        diagnostics: &mut Vec<Diagnostic>,
+    ) -> Result<Collection<'a>, Error> {
+        // Without a resource type the focus can't be checked against a type name
+        let resource_type = focus.value.get("resourceType").and_then(Value::as_str);
+        if let Some(resource_type) = resource_type.filter(|_| is_type_name(name)) {
+            if is_subtype(resource_type, name) {
+                return Ok(vec![focus.clone()]);
+            }
+            let message = format!("{name} does not match the resource type {resource_type}");
+            match context.unmatched_type {
+                UnmatchedType::Error => {
+                    return Err(Error::new(ErrorCode::UnmatchedType, message));
+                }
+                UnmatchedType::Empty => {
+                    diagnostics.push(Diagnostic::warning(ErrorCode::UnmatchedType, message));
+                    return Ok(Vec::new());
+                }
+                UnmatchedType::MemberLookup => {}
+            }
+        }
+        // A missing element is an empty collection, not an error
+        Ok(focus.clone().into_children(name))
+    }
+}
+
+// Helper: the type a type specifier such as `Patient` or `FHIR.Patient` names
+fn type_name(ast: &Ast, expr_ref: ExprRef) -> Option<&str> {
+    match ast.expressions.get(expr_ref) {
+        Expression::Identifier(name) => Some(name),
+        Expression::MemberAccess { object, member }
+            if is_fhir_namespace(ast.expressions.get(*object)) =>
+        {
+            Some(member)
+        }
+        _ => None,
+    }
+}
+
+fn is_fhir_namespace(expression: &Expression) -> bool {
+    matches!(expression, Expression::Identifier(namespace) if namespace == "FHIR")
+}
+
+// Helper: the single item of a collection, none if empty, error if several
+fn singleton(mut collection: Collection) -> Result<Option<Node>, Error> {
+    match collection.len() {
+        0 | 1 => Ok(collection.pop()),
+        length => Err(Error::new(
+            ErrorCode::NotSingleton,
+            format!("Expected a single item but found {length}"),
+        )),
+    }
+}
+
+// Helper: a function argument that must be a single string
+fn string_argument(function: &str, argument: Collection) -> Result<String, Error> {
+    match singleton(argument)? {
+        Some(Node { value, .. }) if value.is_string() => {
+            Ok(value.as_str().unwrap_or_default().to_string())
+        }
+        _ => Err(Error::new(
+            ErrorCode::InvalidArgument,
+            format!("{function}() expects a string argument"),
+        )),
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::evaluator::error::ErrorKind;
     use serde_json::json;
 
     fn get_test_patient() -> Value {
@@ -458,7 +701,37 @@ mod tests {
             start,
         };
 
-        let result = evaluator.evaluate(&ast, &patient).unwrap();
-        assert_eq!(result, Value::Array(vec![]));
+        // Reported rather than evaluating to an empty collection
+        let error = evaluator.evaluate(&ast, &patient).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn test_evaluator_is_shared_across_threads() {
+        const fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Evaluator>();
+
+        // Each evaluation keeps its own warnings
+        let evaluator = Evaluator::new().with_tracer(|_, _| {});
+        let patient = get_test_patient();
+        let warned = crate::parse("Encounter.status").unwrap();
+        let quiet = crate::parse("Patient.gender").unwrap();
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..50 {
+                        let context = EvaluationContext::new(&patient);
+                        let evaluation = evaluator
+                            .evaluate_with_diagnostics(&warned, &context)
+                            .unwrap();
+                        assert_eq!(evaluation.diagnostics.len(), 1);
+                        let evaluation = evaluator
+                            .evaluate_with_diagnostics(&quiet, &context)
+                            .unwrap();
+                        assert!(evaluation.diagnostics.is_empty());
+                    }
+                });
+            }
+        });
     }
 }
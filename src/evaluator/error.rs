@@ -1,18 +1,316 @@
-#[derive(Debug)]
-pub enum Error {
-    Parse(String),
-    Unrecoverable(String),
-    IntegerConversion(String),
+use crate::lexer::token::Span;
+use std::fmt;
+
+/// A stable identifier for each kind of error.
+///
+/// Codes are safe to match on and to cite in documentation. They are grouped by
+/// the stage that raises them: `E01xx` for the lexer, `E02xx` for the parser and
+/// `E03xx` for evaluation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorCode {
+    UnexpectedCharacter,
+    UnterminatedString,
+    InvalidNumber,
+    InvalidEscape,
+    UnterminatedComment,
+    UnexpectedToken,
+    InvalidLiteral,
+    TooManyExpressions,
+    NestingTooDeep,
+    UnbalancedDelimiter,
+    InvalidAst,
+    UndefinedVariable,
+    UnmatchedType,
+    UnknownFunction,
+    InvalidArgument,
+    NotSingleton,
+    InvalidIndex,
+    IncomparableValues,
+    Unsupported,
+    InvalidResource,
+    UnresolvedReference,
+    UnknownElement,
+}
+
+/// The broad category of an error, for deciding how to report or handle it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    /// The expression is not valid `FHIRPath` text.
+    Syntax,
+    /// The expression is well formed but its types don't fit, e.g. `1 < 'a'`.
+    StaticType,
+    /// Evaluation failed on the data, e.g. `=` with a collection of several items.
+    Runtime,
+    /// Valid `FHIRPath` this implementation doesn't support yet.
+    Unsupported,
+    /// The expression exceeds a size limit of the implementation.
+    ResourceLimit,
 }
 
-impl std::fmt::Display for Error {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl ErrorCode {
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
         match self {
-            Self::Parse(msg) => write!(f, "Parse error: {msg}"),
-            Self::Unrecoverable(msg) => write!(f, "Unrecoverable error: {msg}"),
-            Self::IntegerConversion(msg) => write!(f, "Unparseable index: {msg}"),
+            Self::UnexpectedCharacter => "E0101",
+            Self::UnterminatedString => "E0102",
+            Self::InvalidNumber => "E0103",
+            Self::InvalidEscape => "E0104",
+            Self::UnterminatedComment => "E0105",
+            Self::UnexpectedToken => "E0201",
+            Self::InvalidLiteral => "E0202",
+            Self::TooManyExpressions => "E0203",
+            Self::NestingTooDeep => "E0204",
+            Self::UnbalancedDelimiter => "E0205",
+            Self::InvalidAst => "E0206",
+            Self::UndefinedVariable => "E0301",
+            Self::UnmatchedType => "E0302",
+            Self::UnknownFunction => "E0303",
+            Self::InvalidArgument => "E0304",
+            Self::NotSingleton => "E0305",
+            Self::InvalidIndex => "E0306",
+            Self::IncomparableValues => "E0307",
+            Self::Unsupported => "E0308",
+            Self::InvalidResource => "E0309",
+            Self::UnresolvedReference => "E0310",
+            Self::UnknownElement => "E0311",
+        }
+    }
+
+    #[must_use]
+    pub const fn kind(self) -> ErrorKind {
+        match self {
+            Self::UnexpectedCharacter
+            | Self::UnterminatedString
+            | Self::InvalidNumber
+            | Self::InvalidEscape
+            | Self::UnterminatedComment
+            | Self::UnexpectedToken
+            | Self::InvalidLiteral
+            | Self::UnbalancedDelimiter
+            | Self::InvalidAst => ErrorKind::Syntax,
+            Self::TooManyExpressions | Self::NestingTooDeep => ErrorKind::ResourceLimit,
+            Self::UnmatchedType
+            | Self::UnknownFunction
+            | Self::InvalidArgument
+            | Self::IncomparableValues
+            | Self::UnknownElement => ErrorKind::StaticType,
+            Self::UndefinedVariable
+            | Self::NotSingleton
+            | Self::InvalidIndex
+            | Self::InvalidResource
+            | Self::UnresolvedReference => ErrorKind::Runtime,
+            Self::Unsupported => ErrorKind::Unsupported,
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// An error from tokenizing, parsing or evaluating an expression, located by the
+/// span of source it concerns when there is one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    pub code: ErrorCode,
+    pub message: String,
+    pub span: Option<Span>,
+}
+
+impl Error {
+    #[must_use]
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            span: None,
+        }
+    }
+
+    #[must_use]
+    pub const fn with_span(mut self, span: Span) -> Self {
+        self.span = Some(span);
+        self
+    }
+
+    // Locate an error raised without a span, keeping the innermost one
+    pub(crate) const fn or_span(mut self, span: Span) -> Self {
+        if self.span.is_none() {
+            self.span = Some(span);
+        }
+        self
+    }
+
+    #[must_use]
+    pub const fn kind(&self) -> ErrorKind {
+        self.code.kind()
+    }
+
+    /// Render the error with the offending line of `source` and a caret underline:
+    ///
+    /// ```text
+    /// error[E0205]: Unmatched ')'
+    ///   |
+    /// 1 | Patient.name )foo
+    ///   |              ^
+    /// ```
+    #[must_use]
+    pub fn render(&self, source: &str) -> String {
+        render(&self.to_string(), self.span, source)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Error => write!(f, "error"),
+            Self::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// A problem reported alongside a result rather than instead of one, such as a
+/// reference that couldn't be resolved.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: ErrorCode,
+    pub message: String,
+    pub span: Option<Span>,
+}
+
+impl Diagnostic {
+    #[must_use]
+    pub fn warning(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Warning,
+            code,
+            message: message.into(),
+            span: None,
+        }
+    }
+
+    #[must_use]
+    pub const fn with_span(mut self, span: Span) -> Self {
+        self.span = Some(span);
+        self
+    }
+
+    /// Render the diagnostic like [`Error::render`].
+    #[must_use]
+    pub fn render(&self, source: &str) -> String {
+        render(&self.to_string(), self.span, source)
+    }
+}
+
+impl From<Error> for Diagnostic {
+    fn from(error: Error) -> Self {
+        Self {
+            severity: Severity::Error,
+            code: error.code,
+            message: error.message,
+            span: error.span,
         }
     }
 }
 
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}[{}]: {}", self.severity, self.code, self.message)
+    }
+}
+
+// Helper: `header` followed by the line of `source` holding `span`, underlined
+pub(crate) fn render(header: &str, span: Option<Span>, source: &str) -> String {
+    let Some(span) = span else {
+        return header.to_string();
+    };
+
+    let start = floor_char_boundary(source, span.start);
+    let end = floor_char_boundary(source, span.end).max(start);
+    let line_start = source[..start].rfind('\n').map_or(0, |index| index + 1);
+    let line_end = source[start..]
+        .find('\n')
+        .map_or(source.len(), |index| start + index);
+    let line = source[line_start..line_end].trim_end_matches('\r');
+    let line_number = (source[..line_start].matches('\n').count() + 1).to_string();
+
+    // Keep tabs so the caret lines up with the source line
+    let indent: String = source[line_start..start]
+        .chars()
+        .map(|ch| if ch == '\t' { '\t' } else { ' ' })
+        .collect();
+    let width = source[start..end.min(line_end)].chars().count().max(1);
+    let gutter = " ".repeat(line_number.len());
+
+    format!(
+        "{header}\n{gutter} |\n{line_number} | {line}\n{gutter} | {indent}{}",
+        "^".repeat(width)
+    )
+}
+
+// Helper: clamp `index` into `source` and back off to a character boundary
+fn floor_char_boundary(source: &str, index: usize) -> usize {
+    let mut index = index.min(source.len());
+    while !source.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "error[{}]: {}", self.code, self.message)
+    }
+}
+
 impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_caret() {
+        let error = Error::new(ErrorCode::UnexpectedToken, "Unexpected token )")
+            .with_span(Span::new(13, 14));
+        assert_eq!(
+            error.render("Patient.name )foo"),
+            "error[E0201]: Unexpected token )\n  |\n1 | Patient.name )foo\n  |              ^"
+        );
+    }
+
+    #[test]
+    fn test_render_second_line() {
+        let error =
+            Error::new(ErrorCode::UnknownFunction, "Unknown function").with_span(Span::new(15, 20));
+        assert_eq!(
+            error.render("name\r\n  .given.frist()"),
+            "error[E0303]: Unknown function\n  |\n2 |   .given.frist()\n  |          ^^^^^"
+        );
+    }
+
+    #[test]
+    fn test_render_warning() {
+        let warning = Diagnostic::warning(ErrorCode::UnresolvedReference, "Couldn't resolve")
+            .with_span(Span::new(0, 4));
+        assert_eq!(
+            warning.render("name"),
+            "warning[E0310]: Couldn't resolve\n  |\n1 | name\n  | ^^^^"
+        );
+    }
+
+    #[test]
+    fn test_render_without_span() {
+        let error = Error::new(ErrorCode::InvalidResource, "Missing id");
+        assert_eq!(error.render("anything"), "error[E0309]: Missing id");
+    }
+}
@@ -0,0 +1,160 @@
+use super::super::context::EvaluationContext;
+use super::super::model::is_subtype;
+use super::super::node::{Collection, Node};
+use super::super::resolver::resolve_on_path;
+use super::super::xhtml::check_narrative;
+use serde_json::Value;
+use std::borrow::Cow;
+
+/// `extension(url)`: the extensions of every item whose `url` matches exactly.
+pub fn extension<'a>(collection: Collection<'a>, url: &str) -> Collection<'a> {
+    collection
+        .into_iter()
+        .flat_map(|node| node.into_children("extension"))
+        .filter(|extension| extension.value.get("url").and_then(|u| u.as_str()) == Some(url))
+        .collect()
+}
+
+/// `hasValue()`: true for a single primitive that has a value rather than only extensions.
+pub fn has_value(collection: &[Node]) -> Collection<'static> {
+    let result = matches!(collection, [node] if is_primitive(node) && node.has_value());
+    vec![Node::owned(Value::Bool(result))]
+}
+
+/// `getValue()`: the value of a single primitive without its id and extensions.
+pub fn get_value(mut collection: Collection) -> Collection {
+    match collection.pop() {
+        Some(node) if collection.is_empty() && is_primitive(&node) && node.has_value() => {
+            vec![Node::new(node.value)]
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// `htmlChecks()`: true if the single item is narrative XHTML following the FHIR rules.
+pub fn html_checks(collection: &[Node]) -> Collection<'static> {
+    let result = match collection {
+        [node] => node
+            .value
+            .as_str()
+            .is_some_and(|div| check_narrative(div).is_ok()),
+        _ => false,
+    };
+    vec![Node::owned(Value::Bool(result))]
+}
+
+/// `resolve()`: the resources that references (or reference strings) point to.
+/// Unresolvable references are skipped and passed to `on_unresolved`.
+pub fn resolve<'a>(
+    collection: Collection<'a>,
+    context: &EvaluationContext<'a>,
+    mut on_unresolved: impl FnMut(&str),
+) -> Collection<'a> {
+    collection
+        .into_iter()
+        .filter_map(|node| {
+            let reference = match node.value.as_ref() {
+                Value::String(reference) => reference.as_str(),
+                other => other.get("reference")?.as_str()?,
+            };
+            let local = match &node.value {
+                Cow::Borrowed(source) => context
+                    .ancestors(source)
+                    .and_then(|path| resolve_on_path(&path, reference)),
+                Cow::Owned(_) => None,
+            };
+            let resolved = local
+                .map(Cow::Borrowed)
+                .or_else(|| context.resolver?.resolve(reference));
+            if resolved.is_none() {
+                on_unresolved(reference);
+            }
+            resolved
+        })
+        .map(Node::new)
+        .collect()
+}
+
+/// `ofType(type)`: the items that are resources of the given type or a type
+/// derived from it.
+pub fn of_type<'a>(collection: Collection<'a>, type_name: &str) -> Collection<'a> {
+    collection
+        .into_iter()
+        .filter(|node| {
+            node.value
+                .get("resourceType")
+                .and_then(Value::as_str)
+                .is_some_and(|resource_type| is_subtype(resource_type, type_name))
+        })
+        .collect()
+}
+
+/// Render a collection for `trace()`. Primitives that carry an id or extensions
+/// are shown the way FHIR JSON would: the value alongside its element sibling.
+pub fn trace_value(collection: &[Node]) -> Value {
+    Value::Array(
+        collection
+            .iter()
+            .map(|node| match node.element.as_deref() {
+                Some(Value::Object(element)) => {
+                    let mut merged = element.clone();
+                    if node.has_value() {
+                        merged.insert("value".to_string(), node.value.clone().into_owned());
+                    }
+                    Value::Object(merged)
+                }
+                _ => node.value.clone().into_owned(),
+            })
+            .collect(),
+    )
+}
+
+fn is_primitive(node: &Node) -> bool {
+    !node.value.is_object() && !node.value.is_array()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn primitive_with_extension(value: Value) -> Node<'static> {
+        Node {
+            value: Cow::Owned(value),
+            element: Some(Cow::Owned(json!({"extension": [{"url": "a"}]}))),
+        }
+    }
+
+    #[test]
+    fn test_has_value() {
+        assert_eq!(
+            has_value(&[Node::owned(json!("x"))]),
+            vec![Node::owned(json!(true))]
+        );
+        assert_eq!(
+            has_value(&[primitive_with_extension(Value::Null)]),
+            vec![Node::owned(json!(false))]
+        );
+        assert_eq!(
+            has_value(&[Node::owned(json!({}))]),
+            vec![Node::owned(json!(false))]
+        );
+        assert_eq!(has_value(&[]), vec![Node::owned(json!(false))]);
+    }
+
+    #[test]
+    fn test_get_value_drops_element() {
+        let result = get_value(vec![primitive_with_extension(json!("x"))]);
+        assert_eq!(result, vec![Node::owned(json!("x"))]);
+        assert!(get_value(vec![primitive_with_extension(Value::Null)]).is_empty());
+    }
+
+    #[test]
+    fn test_trace_value_merges_element() {
+        let rendered = trace_value(&[primitive_with_extension(json!("x")), Node::owned(json!(1))]);
+        assert_eq!(
+            rendered,
+            json!([{"value": "x", "extension": [{"url": "a"}]}, 1])
+        );
+    }
+}
@@ -1 +1,2 @@
 pub mod array_functions;
+pub mod fhir_functions;
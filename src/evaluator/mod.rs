@@ -1,4 +1,10 @@
+pub mod context;
 pub mod engine;
 pub mod error;
 mod functions;
+mod model;
+mod node;
+pub mod resolver;
+pub mod types;
 mod utils;
+pub mod xhtml;
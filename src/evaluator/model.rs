@@ -0,0 +1,120 @@
+//! Knowledge of the FHIR data model that navigation depends on.
+
+// Type suffixes a choice element (`value[x]`) may use in FHIR JSON
+const CHOICE_TYPES: &[&str] = &[
+    // Primitive types
+    "Base64Binary",
+    "Boolean",
+    "Canonical",
+    "Code",
+    "Date",
+    "DateTime",
+    "Decimal",
+    "Id",
+    "Instant",
+    "Integer",
+    "Integer64",
+    "Markdown",
+    "Oid",
+    "PositiveInt",
+    "String",
+    "Time",
+    "UnsignedInt",
+    "Uri",
+    "Url",
+    "Uuid",
+    // Complex types
+    "Address",
+    "Age",
+    "Annotation",
+    "Attachment",
+    "Availability",
+    "CodeableConcept",
+    "CodeableReference",
+    "Coding",
+    "ContactDetail",
+    "ContactPoint",
+    "Contributor",
+    "Count",
+    "DataRequirement",
+    "Distance",
+    "Dosage",
+    "Duration",
+    "Expression",
+    "ExtendedContactDetail",
+    "HumanName",
+    "Identifier",
+    "Meta",
+    "Money",
+    "ParameterDefinition",
+    "Period",
+    "Quantity",
+    "Range",
+    "Ratio",
+    "RatioRange",
+    "Reference",
+    "RelatedArtifact",
+    "SampledData",
+    "Signature",
+    "Timing",
+    "TriggerDefinition",
+    "UsageContext",
+];
+
+// Resources that derive from `Resource` directly rather than from `DomainResource`
+const NON_DOMAIN_RESOURCES: &[&str] = &["Binary", "Bundle", "Parameters"];
+
+/// Whether `name` names a type rather than an element. FHIR type names start
+/// with an uppercase letter while element names start with a lowercase one.
+#[must_use]
+pub fn is_type_name(name: &str) -> bool {
+    name.starts_with(|ch: char| ch.is_ascii_uppercase())
+}
+
+/// Whether a resource of type `resource_type` is a `type_name`: the type itself
+/// or one of the base types `DomainResource` and `Resource` it derives from.
+#[must_use]
+pub fn is_subtype(resource_type: &str, type_name: &str) -> bool {
+    match type_name {
+        "Resource" => true,
+        "DomainResource" => {
+            resource_type != "Resource" && !NON_DOMAIN_RESOURCES.contains(&resource_type)
+        }
+        _ => resource_type == type_name,
+    }
+}
+
+/// Whether `key` is the JSON name of the choice element `name`, e.g. `valueString`
+/// for `value`. A leading underscore (the primitive's element sibling) is ignored.
+#[must_use]
+pub fn is_choice_of(key: &str, name: &str) -> bool {
+    key.strip_prefix('_')
+        .unwrap_or(key)
+        .strip_prefix(name)
+        .is_some_and(|suffix| CHOICE_TYPES.contains(&suffix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_choice_of() {
+        assert!(is_choice_of("valueString", "value"));
+        assert!(is_choice_of("_valueDateTime", "value"));
+        assert!(is_choice_of("deceasedBoolean", "deceased"));
+        assert!(!is_choice_of("value", "value"));
+        assert!(!is_choice_of("valueSet", "value"));
+        assert!(!is_choice_of("onsetString", "value"));
+    }
+
+    #[test]
+    fn test_is_subtype() {
+        assert!(is_subtype("Patient", "Patient"));
+        assert!(is_subtype("Patient", "DomainResource"));
+        assert!(is_subtype("Patient", "Resource"));
+        assert!(is_subtype("Bundle", "Resource"));
+        assert!(!is_subtype("Bundle", "DomainResource"));
+        assert!(!is_subtype("Patient", "Observation"));
+    }
+}
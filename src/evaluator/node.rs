@@ -0,0 +1,224 @@
+use super::model::is_choice_of;
+use serde_json::Value;
+use std::borrow::Cow;
+
+/// A single item of a `FHIRPath` collection.
+///
+/// FHIR JSON stores a primitive in two halves: the value lives under `name`
+/// while its `id` and `extension` live under the `_name` sibling. A node keeps
+/// both halves together so navigation into a primitive can reach its
+/// extensions. A primitive that only carries an extension has a null value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Node<'a> {
+    pub value: Cow<'a, Value>,
+    pub element: Option<Cow<'a, Value>>,
+}
+
+pub type Collection<'a> = Vec<Node<'a>>;
+
+impl<'a> Node<'a> {
+    #[must_use]
+    pub const fn new(value: Cow<'a, Value>) -> Self {
+        Self {
+            value,
+            element: None,
+        }
+    }
+
+    #[must_use]
+    pub const fn owned(value: Value) -> Self {
+        Self::new(Cow::Owned(value))
+    }
+
+    /// Whether the node holds an actual value rather than only an extension.
+    #[must_use]
+    pub fn has_value(&self) -> bool {
+        !self.value.is_null()
+    }
+
+    /// Navigate to the children called `name`, pairing every primitive with
+    /// its `_name` sibling. Repeating primitives use parallel arrays in which
+    /// `null` marks a missing value or a missing element. A choice element such
+    /// as `value` also matches its typed JSON name (`valueString`).
+    #[must_use]
+    pub fn into_children(self, name: &str) -> Collection<'a> {
+        // Members of a primitive (`id`, `extension`) live on its `_` sibling
+        let source = if self.value.is_object() {
+            self.value
+        } else if let Some(element) = self.element {
+            element
+        } else {
+            return Vec::new();
+        };
+
+        let (values, elements) = take_member(source, name);
+        let values = values.map(into_items).unwrap_or_default();
+        let elements = elements.map(into_items).unwrap_or_default();
+        let length = values.len().max(elements.len());
+
+        let mut values = values.into_iter();
+        let mut elements = elements.into_iter();
+        (0..length)
+            .filter_map(|_| {
+                let value = values.next().unwrap_or(Cow::Owned(Value::Null));
+                let element = elements.next().filter(|element| !element.is_null());
+                let node = Node { value, element };
+                (node.has_value() || node.element.is_some()).then_some(node)
+            })
+            .collect()
+    }
+}
+
+/// Convert a collection into the JSON returned to callers: an empty array for
+/// an empty collection, the bare value for a singleton and an array otherwise.
+#[must_use]
+pub fn to_value(collection: Collection) -> Value {
+    let mut values: Vec<Value> = collection
+        .into_iter()
+        .map(|node| node.value.into_owned())
+        .collect();
+    match values.pop() {
+        Some(value) if values.is_empty() => value,
+        Some(value) => {
+            values.push(value);
+            Value::Array(values)
+        }
+        None => Value::Array(values),
+    }
+}
+
+// Helper: take `name` and `_name` from an object, borrow if possible, move if owned
+fn take_member<'a>(
+    object: Cow<'a, Value>,
+    name: &str,
+) -> (Option<Cow<'a, Value>>, Option<Cow<'a, Value>>) {
+    let name = choice_name(&object, name).unwrap_or(name).to_string();
+    let element_name = format!("_{name}");
+    let name = name.as_str();
+    match object {
+        Cow::Borrowed(object) => (
+            object.get(name).map(Cow::Borrowed),
+            object.get(&element_name).map(Cow::Borrowed),
+        ),
+        Cow::Owned(Value::Object(mut map)) => (
+            map.remove(name).map(Cow::Owned),
+            map.remove(&element_name).map(Cow::Owned),
+        ),
+        Cow::Owned(_) => (None, None),
+    }
+}
+
+// Helper: the typed JSON name used for the choice element `name`, if any
+fn choice_name<'v>(object: &'v Value, name: &str) -> Option<&'v str> {
+    let object = object.as_object()?;
+    if object.contains_key(name) || object.contains_key(&format!("_{name}")) {
+        return None;
+    }
+    object
+        .keys()
+        .find(|key| is_choice_of(key, name))
+        .map(|key| key.strip_prefix('_').unwrap_or(key))
+}
+
+// Helper: flatten a JSON array into its items, a single value into itself
+fn into_items(value: Cow<Value>) -> Vec<Cow<Value>> {
+    match value {
+        Cow::Borrowed(Value::Array(items)) => items.iter().map(Cow::Borrowed).collect(),
+        Cow::Owned(Value::Array(items)) => items.into_iter().map(Cow::Owned).collect(),
+        other => vec![other],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_children_pairs_primitive_with_element() {
+        let patient = json!({
+            "birthDate": "1974-12-25",
+            "_birthDate": {"id": "bd", "extension": [{"url": "a"}]}
+        });
+        let children = Node::new(Cow::Borrowed(&patient)).into_children("birthDate");
+
+        assert_eq!(children.len(), 1);
+        assert_eq!(*children[0].value, json!("1974-12-25"));
+        assert_eq!(
+            children[0].element.as_deref(),
+            Some(&json!({"id": "bd", "extension": [{"url": "a"}]}))
+        );
+    }
+
+    #[test]
+    fn test_children_parallel_arrays() {
+        let name = json!({
+            "given": ["Peter", null, "Jim"],
+            "_given": [null, {"extension": [{"url": "b"}]}, null]
+        });
+        let children = Node::new(Cow::Borrowed(&name)).into_children("given");
+
+        assert_eq!(children.len(), 3);
+        assert_eq!(*children[0].value, json!("Peter"));
+        assert!(children[0].element.is_none());
+        assert!(!children[1].has_value());
+        assert!(children[1].element.is_some());
+        assert_eq!(*children[2].value, json!("Jim"));
+    }
+
+    #[test]
+    fn test_children_element_only() {
+        let patient = json!({"_birthDate": {"extension": [{"url": "a"}]}});
+        let children = Node::new(Cow::Borrowed(&patient)).into_children("birthDate");
+
+        assert_eq!(children.len(), 1);
+        assert!(!children[0].has_value());
+    }
+
+    #[test]
+    fn test_children_of_primitive_use_element() {
+        let patient = json!({
+            "birthDate": "1974-12-25",
+            "_birthDate": {"id": "bd", "extension": [{"url": "a"}, {"url": "b"}]}
+        });
+        let birth_date = Node::new(Cow::Borrowed(&patient))
+            .into_children("birthDate")
+            .remove(0);
+
+        assert_eq!(birth_date.clone().into_children("extension").len(), 2);
+        assert_eq!(*birth_date.into_children("id")[0].value, json!("bd"));
+    }
+
+    #[test]
+    fn test_children_of_owned_object() {
+        let node = Node::owned(json!({"code": "x", "_code": {"id": "c"}}));
+        let children = node.into_children("code");
+
+        assert_eq!(*children[0].value, json!("x"));
+        assert_eq!(children[0].element.as_deref(), Some(&json!({"id": "c"})));
+    }
+
+    #[test]
+    fn test_children_choice_element() {
+        let extension = json!({
+            "url": "a",
+            "valueString": "x",
+            "_valueString": {"id": "v"}
+        });
+        let children = Node::new(Cow::Borrowed(&extension)).into_children("value");
+
+        assert_eq!(children.len(), 1);
+        assert_eq!(*children[0].value, json!("x"));
+        assert_eq!(children[0].element.as_deref(), Some(&json!({"id": "v"})));
+    }
+
+    #[test]
+    fn test_to_value_shapes() {
+        assert_eq!(to_value(Vec::new()), json!([]));
+        assert_eq!(to_value(vec![Node::owned(json!(1))]), json!(1));
+        assert_eq!(
+            to_value(vec![Node::owned(json!(1)), Node::owned(json!(2))]),
+            json!([1, 2])
+        );
+    }
+}
@@ -0,0 +1,405 @@
+//! Reference resolution for `resolve()`.
+//!
+//! A reference is first looked up next to where it appears: `#id` among the
+//! contained resources of its container, anything else among the entries of
+//! the enclosing Bundle. Only when that fails is the [`ReferenceResolver`] of
+//! the evaluation context asked, e.g. a [`ResourceStore`].
+
+use super::error::{Error, ErrorCode};
+use super::utils::{Parents, root_resource};
+use serde_json::Value;
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+/// Locates the resource a reference string points to.
+pub trait ReferenceResolver {
+    /// Return the resource identified by `reference`, or `None` if it is unknown.
+    fn resolve(&self, reference: &str) -> Option<Cow<'_, Value>>;
+}
+
+/// What a reference string points at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReferenceTarget<'r> {
+    /// `#id` for a contained resource, or `#` (an empty id) for the container itself
+    Contained(&'r str),
+    /// A REST-style reference: `Type/id`, optionally absolute and/or versioned
+    Resource(ResourceReference<'r>),
+    /// Any other absolute URI, e.g. `urn:uuid:...` or a canonical URL
+    Url(&'r str),
+}
+
+/// The parts of a REST-style reference such as `http://server/fhir/Patient/1/_history/2`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResourceReference<'r> {
+    pub base: Option<&'r str>,
+    pub resource_type: &'r str,
+    pub id: &'r str,
+    pub version: Option<&'r str>,
+}
+
+impl<'r> ReferenceTarget<'r> {
+    #[must_use]
+    pub fn parse(reference: &'r str) -> Self {
+        if let Some(id) = reference.strip_prefix('#') {
+            return Self::Contained(id);
+        }
+        ResourceReference::parse(reference).map_or(Self::Url(reference), Self::Resource)
+    }
+}
+
+impl<'r> ResourceReference<'r> {
+    /// Parse a relative or absolute REST-style reference, `None` for anything else.
+    #[must_use]
+    pub fn parse(reference: &'r str) -> Option<Self> {
+        let (path, version) = match reference.rsplit_once("/_history/") {
+            Some((path, version)) if is_id(version) => (path, Some(version)),
+            Some(_) => return None,
+            None => (reference, None),
+        };
+        let (rest, id) = path.rsplit_once('/')?;
+        let (base, resource_type) = match rest.rsplit_once('/') {
+            Some((base, resource_type)) => (Some(base), resource_type),
+            None => (None, rest),
+        };
+        let is_restful_base =
+            base.is_none_or(|base| base.starts_with("http://") || base.starts_with("https://"));
+        (is_restful_base && is_resource_type(resource_type) && is_id(id)).then_some(Self {
+            base,
+            resource_type,
+            id,
+            version,
+        })
+    }
+
+    /// The reference without its base, e.g. `Patient/1/_history/2`.
+    #[must_use]
+    pub fn key(&self) -> String {
+        let (resource_type, id) = (self.resource_type, self.id);
+        self.version.map_or_else(
+            || format!("{resource_type}/{id}"),
+            |version| format!("{resource_type}/{id}/_history/{version}"),
+        )
+    }
+
+    fn matches(&self, resource: &Value) -> bool {
+        resource.get("resourceType").and_then(Value::as_str) == Some(self.resource_type)
+            && resource.get("id").and_then(Value::as_str) == Some(self.id)
+            && self.version.is_none_or(|version| {
+                resource.pointer("/meta/versionId").and_then(Value::as_str) == Some(version)
+            })
+    }
+}
+
+/// An in-memory [`ReferenceResolver`] keyed by `Type/id`, `Type/id/_history/version`
+/// and, for resources added with [`ResourceStore::insert_url`], their URL.
+#[derive(Debug, Clone, Default)]
+pub struct ResourceStore {
+    resources: HashMap<String, Value>,
+}
+
+impl ResourceStore {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a resource under `Type/id` and, if it has a `meta.versionId`, under
+    /// `Type/id/_history/version`. A later version replaces `Type/id`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the resource has no `resourceType` or `id`.
+    pub fn insert(&mut self, resource: Value) -> Result<(), Error> {
+        let (Some(resource_type), Some(id)) = (
+            resource.get("resourceType").and_then(Value::as_str),
+            resource.get("id").and_then(Value::as_str),
+        ) else {
+            return Err(Error::new(
+                ErrorCode::InvalidResource,
+                "A stored resource needs a resourceType and an id",
+            ));
+        };
+        let key = format!("{resource_type}/{id}");
+        if let Some(version) = resource.pointer("/meta/versionId").and_then(Value::as_str) {
+            self.resources
+                .insert(format!("{key}/_history/{version}"), resource.clone());
+        }
+        self.resources.insert(key, resource);
+        Ok(())
+    }
+
+    /// Add a resource under an absolute URL such as `urn:uuid:...`.
+    pub fn insert_url(&mut self, url: &str, resource: Value) {
+        self.resources.insert(url.to_string(), resource);
+    }
+}
+
+impl ReferenceResolver for ResourceStore {
+    fn resolve(&self, reference: &str) -> Option<Cow<'_, Value>> {
+        let found = match ReferenceTarget::parse(reference) {
+            ReferenceTarget::Resource(target) => self
+                .resources
+                .get(reference)
+                .or_else(|| self.resources.get(&target.key())),
+            ReferenceTarget::Url(url) => self.resources.get(url),
+            ReferenceTarget::Contained(_) => None,
+        };
+        found.map(Cow::Borrowed)
+    }
+}
+
+/// Resolve `reference`, found at `source` inside `root`, against the contained
+/// resources of its container or the entries of its enclosing Bundle.
+#[must_use]
+pub fn resolve_locally<'a>(root: &'a Value, source: &Value, reference: &str) -> Option<&'a Value> {
+    resolve_on_path(&Parents::new(root).ancestors(root, source)?, reference)
+}
+
+/// Resolve `reference` like [`resolve_locally`], given the chain of values from
+/// the root down to where it appears.
+pub(crate) fn resolve_on_path<'a>(path: &[&'a Value], reference: &str) -> Option<&'a Value> {
+    match ReferenceTarget::parse(reference) {
+        ReferenceTarget::Contained(id) => {
+            // Contained resources reference their siblings through their container
+            let container = root_resource(path)?;
+            if id.is_empty() {
+                return Some(container);
+            }
+            container
+                .get("contained")?
+                .as_array()?
+                .iter()
+                .find(|resource| resource.get("id").and_then(Value::as_str) == Some(id))
+        }
+        target => {
+            let (bundle_index, bundle) = path
+                .iter()
+                .enumerate()
+                .rev()
+                .find(|(_, value)| is_resource_of_type(value, "Bundle"))?;
+            // The entry holding the reference supplies the base for relative references
+            let source_url = path
+                .get(bundle_index + 2)
+                .and_then(|entry| entry.get("fullUrl"))
+                .and_then(Value::as_str);
+            find_entry(bundle, target, reference, source_url)
+        }
+    }
+}
+
+fn find_entry<'a>(
+    bundle: &'a Value,
+    target: ReferenceTarget,
+    reference: &str,
+    source_url: Option<&str>,
+) -> Option<&'a Value> {
+    let entries = bundle.get("entry")?.as_array()?;
+    let entry_with_url = |url: &str| {
+        entries
+            .iter()
+            .find(|entry| entry.get("fullUrl").and_then(Value::as_str) == Some(url))
+            .and_then(|entry| entry.get("resource"))
+    };
+
+    match target {
+        ReferenceTarget::Resource(resource) if resource.base.is_some() => {
+            entry_with_url(reference.split("/_history/").next().unwrap_or(reference))
+                .filter(|candidate| resource.matches(candidate))
+        }
+        ReferenceTarget::Resource(resource) => {
+            // Relative references resolve against the base of the referencing entry,
+            // falling back to any entry with the same type and id
+            let base = source_url
+                .and_then(ResourceReference::parse)
+                .and_then(|source| source.base);
+            base.and_then(|base| {
+                entry_with_url(&format!(
+                    "{base}/{}/{}",
+                    resource.resource_type, resource.id
+                ))
+            })
+            .or_else(|| {
+                entries
+                    .iter()
+                    .filter_map(|entry| entry.get("resource"))
+                    .find(|candidate| resource.matches(candidate))
+            })
+            .filter(|candidate| resource.matches(candidate))
+        }
+        ReferenceTarget::Url(url) => entry_with_url(url),
+        ReferenceTarget::Contained(_) => None,
+    }
+}
+
+fn is_resource_of_type(value: &Value, resource_type: &str) -> bool {
+    value.get("resourceType").and_then(Value::as_str) == Some(resource_type)
+}
+
+fn is_resource_type(name: &str) -> bool {
+    name.starts_with(|ch: char| ch.is_ascii_uppercase())
+        && name.chars().all(|ch| ch.is_ascii_alphanumeric())
+}
+
+fn is_id(id: &str) -> bool {
+    (1..=64).contains(&id.len())
+        && id
+            .chars()
+            .all(|ch| ch.is_ascii_alphanumeric() || ch == '-' || ch == '.')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_relative_reference() {
+        assert_eq!(
+            ReferenceTarget::parse("Patient/123"),
+            ReferenceTarget::Resource(ResourceReference {
+                base: None,
+                resource_type: "Patient",
+                id: "123",
+                version: None,
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_absolute_versioned_reference() {
+        assert_eq!(
+            ReferenceTarget::parse("http://example.org/fhir/Patient/123/_history/2"),
+            ReferenceTarget::Resource(ResourceReference {
+                base: Some("http://example.org/fhir"),
+                resource_type: "Patient",
+                id: "123",
+                version: Some("2"),
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_other_references() {
+        assert_eq!(
+            ReferenceTarget::parse("#p1"),
+            ReferenceTarget::Contained("p1")
+        );
+        assert_eq!(ReferenceTarget::parse("#"), ReferenceTarget::Contained(""));
+        assert_eq!(
+            ReferenceTarget::parse("urn:uuid:04121321-4af5-424c-a0e1-ed3aab1c349d"),
+            ReferenceTarget::Url("urn:uuid:04121321-4af5-424c-a0e1-ed3aab1c349d")
+        );
+        assert_eq!(
+            ReferenceTarget::parse("http://hl7.org/fhir/ValueSet/example|4.0.1"),
+            ReferenceTarget::Url("http://hl7.org/fhir/ValueSet/example|4.0.1")
+        );
+        assert_eq!(
+            ReferenceTarget::parse("patient/123"),
+            ReferenceTarget::Url("patient/123")
+        );
+    }
+
+    #[test]
+    fn test_store_versions() {
+        let mut store = ResourceStore::new();
+        store
+            .insert(json!({"resourceType": "Patient", "id": "1", "meta": {"versionId": "1"}}))
+            .unwrap();
+        store
+            .insert(json!({"resourceType": "Patient", "id": "1", "meta": {"versionId": "2"}}))
+            .unwrap();
+
+        let version = |reference: &str| {
+            store
+                .resolve(reference)
+                .and_then(|resource| resource.pointer("/meta/versionId").cloned())
+        };
+        assert_eq!(version("Patient/1"), Some(json!("2")));
+        assert_eq!(version("Patient/1/_history/1"), Some(json!("1")));
+        assert_eq!(
+            version("http://example.org/fhir/Patient/1"),
+            Some(json!("2"))
+        );
+        assert_eq!(version("Patient/2"), None);
+    }
+
+    #[test]
+    fn test_store_requires_type_and_id() {
+        let mut store = ResourceStore::new();
+        assert!(store.insert(json!({"resourceType": "Patient"})).is_err());
+    }
+
+    #[test]
+    fn test_resolve_contained() {
+        let observation = json!({
+            "resourceType": "Observation",
+            "contained": [{"resourceType": "Patient", "id": "p1"}],
+            "subject": {"reference": "#p1"}
+        });
+        let source = &observation["subject"];
+
+        let resolved = resolve_locally(&observation, source, "#p1").unwrap();
+        assert_eq!(resolved["id"], json!("p1"));
+        let container = resolve_locally(&observation, source, "#").unwrap();
+        assert_eq!(container["resourceType"], json!("Observation"));
+    }
+
+    #[test]
+    fn test_resolve_contained_from_sibling() {
+        let observation = json!({
+            "resourceType": "Observation",
+            "contained": [
+                {"resourceType": "Patient", "id": "p1"},
+                {"resourceType": "Group", "id": "g1", "member": [{"entity": {"reference": "#p1"}}]}
+            ]
+        });
+        let source = &observation["contained"][1]["member"][0]["entity"];
+
+        let resolved = resolve_locally(&observation, source, "#p1").unwrap();
+        assert_eq!(resolved["resourceType"], json!("Patient"));
+    }
+
+    #[test]
+    fn test_resolve_bundle_entries() {
+        let bundle = json!({
+            "resourceType": "Bundle",
+            "entry": [
+                {
+                    "fullUrl": "http://example.org/fhir/Observation/o1",
+                    "resource": {"resourceType": "Observation", "id": "o1", "subject": {"reference": "Patient/p1"}}
+                },
+                {
+                    "fullUrl": "http://example.org/fhir/Patient/p1",
+                    "resource": {"resourceType": "Patient", "id": "p1"}
+                },
+                {
+                    "fullUrl": "urn:uuid:61ebe359-bfdc-4613-8bf2-c5e300945f0a",
+                    "resource": {"resourceType": "Practitioner", "id": "pr1"}
+                }
+            ]
+        });
+        let source = &bundle["entry"][0]["resource"]["subject"];
+
+        let resolved = resolve_locally(&bundle, source, "Patient/p1").unwrap();
+        assert_eq!(resolved["id"], json!("p1"));
+        let resolved =
+            resolve_locally(&bundle, source, "http://example.org/fhir/Patient/p1").unwrap();
+        assert_eq!(resolved["id"], json!("p1"));
+        let resolved = resolve_locally(
+            &bundle,
+            source,
+            "urn:uuid:61ebe359-bfdc-4613-8bf2-c5e300945f0a",
+        )
+        .unwrap();
+        assert_eq!(resolved["id"], json!("pr1"));
+        assert!(resolve_locally(&bundle, source, "Patient/p2").is_none());
+        assert!(resolve_locally(&bundle, source, "Patient/p1/_history/3").is_none());
+    }
+
+    #[test]
+    fn test_resolve_outside_root() {
+        let observation = json!({"resourceType": "Observation"});
+        let elsewhere = json!({"reference": "#p1"});
+        assert!(resolve_locally(&observation, &elsewhere, "#p1").is_none());
+    }
+}
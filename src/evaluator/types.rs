@@ -0,0 +1,909 @@
+//! Static types and cardinalities of expressions, inferred without data.
+//!
+//! A [`TypeChecker`] walks a tree from the type of the resource it will be
+//! evaluated on, working out what each expression returns, such as `0..* string`
+//! for `Patient.name.given`. On the way it reports comparisons of values that
+//! can never be compared, such as a date with an integer, and, given a
+//! [`TypeModel`] such as [`StructureDefinitions`], elements that don't exist on
+//! the type they are looked up on.
+
+use super::error::{Error, ErrorCode};
+use super::model::{is_subtype, is_type_name};
+use crate::parser::ast::Ast;
+use crate::parser::functions::{self, ArgumentKind};
+use crate::parser::grammar::{BinaryOperator, ExprPool, ExprRef, Expression};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// The primitive types of `FHIRPath`, which FHIR primitives convert to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SystemType {
+    Boolean,
+    String,
+    Integer,
+    Decimal,
+    Date,
+    DateTime,
+    Time,
+    Quantity,
+}
+
+/// The type of the items an expression returns.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Type {
+    /// Not known statically, as for an element without a model.
+    Any,
+    /// A literal or function result such as `'text'` or `count()`.
+    System(SystemType),
+    /// A FHIR type such as `string` or `HumanName`, or a backbone element by its
+    /// path such as `Patient.contact`.
+    Fhir(String),
+}
+
+impl Type {
+    /// The `FHIRPath` primitive the type is or converts to, if any.
+    #[must_use]
+    pub fn system(&self) -> Option<SystemType> {
+        match self {
+            Self::Any => None,
+            Self::System(system) => Some(*system),
+            Self::Fhir(name) => name
+                .strip_prefix("http://hl7.org/fhirpath/System.")
+                .map_or_else(|| fhir_primitive(name), system_type),
+        }
+    }
+}
+
+/// How many items an expression returns, from `min` to `max` or without an
+/// upper bound when `max` is `None`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Cardinality {
+    pub min: usize,
+    pub max: Option<usize>,
+}
+
+impl Cardinality {
+    pub const ONE: Self = Self::new(1, Some(1));
+    pub const OPTIONAL: Self = Self::new(0, Some(1));
+    pub const MANY: Self = Self::new(0, None);
+
+    #[must_use]
+    pub const fn new(min: usize, max: Option<usize>) -> Self {
+        Self { min, max }
+    }
+
+    /// Whether there is at most one item.
+    #[must_use]
+    pub const fn is_singleton(self) -> bool {
+        matches!(self.max, Some(0 | 1))
+    }
+
+    // The items of `element` of each item of `self`
+    fn times(self, element: Self) -> Self {
+        Self {
+            min: self.min.saturating_mul(element.min),
+            max: self
+                .max
+                .zip(element.max)
+                .map(|(max, element)| max.saturating_mul(element)),
+        }
+    }
+
+    // At most as many items, but possibly none
+    const fn or_fewer(self) -> Self {
+        Self::new(0, self.max)
+    }
+}
+
+/// The type and cardinality inferred for an expression.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TypeInfo {
+    pub type_: Type,
+    pub cardinality: Cardinality,
+}
+
+impl TypeInfo {
+    #[must_use]
+    pub const fn new(type_: Type, cardinality: Cardinality) -> Self {
+        Self { type_, cardinality }
+    }
+
+    const fn system(system: SystemType, cardinality: Cardinality) -> Self {
+        Self::new(Type::System(system), cardinality)
+    }
+
+    const fn any() -> Self {
+        Self::new(Type::Any, Cardinality::MANY)
+    }
+
+    // The same type, with another cardinality
+    fn with(&self, cardinality: Cardinality) -> Self {
+        Self::new(self.type_.clone(), cardinality)
+    }
+}
+
+/// The elements of the types of a FHIR model.
+pub trait TypeModel {
+    /// Whether the model describes `type_name`, so that an element it doesn't
+    /// have doesn't exist.
+    fn has_type(&self, type_name: &str) -> bool;
+
+    /// The type and cardinality of the element `name` of `type_name`. A choice
+    /// element such as `value[x]` is found both as `value`, typed `Any` unless
+    /// it has a single type, and by its typed names such as `valueQuantity`.
+    fn element(&self, type_name: &str, name: &str) -> Option<TypeInfo>;
+}
+
+/// A [`TypeModel`] read from FHIR `StructureDefinition` resources.
+///
+/// Elements are taken from the snapshot of each definition, or else its
+/// differential. A differential lacks the elements its type inherits, such as
+/// `id` and `extension`, so the elements it lists are typed but its type stays
+/// unknown: no element is reported missing from it. Backbone elements are types
+/// named by their path, such as `Patient.contact`.
+#[derive(Debug, Clone, Default)]
+pub struct StructureDefinitions {
+    types: HashSet<String>,
+    // The types and cardinality of each element, by type and element name
+    elements: HashMap<(String, String), (Vec<String>, Cardinality)>,
+}
+
+impl StructureDefinitions {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the types and elements of a `StructureDefinition`, or of each one
+    /// in a `Bundle` of them.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ErrorCode::InvalidResource` if the resource is neither, or a
+    /// definition has no `type` or no elements.
+    pub fn insert(&mut self, resource: &Value) -> Result<(), Error> {
+        let invalid = |message: &str| Error::new(ErrorCode::InvalidResource, message);
+        match resource.get("resourceType").and_then(Value::as_str) {
+            Some("Bundle") => {
+                let entries = resource.get("entry").and_then(Value::as_array);
+                for entry in entries.into_iter().flatten() {
+                    if let Some(resource) = entry.get("resource") {
+                        self.insert(resource)?;
+                    }
+                }
+                return Ok(());
+            }
+            Some("StructureDefinition") => {}
+            _ => {
+                return Err(invalid(
+                    "Expected a StructureDefinition or a Bundle of them",
+                ));
+            }
+        }
+
+        let type_name = resource
+            .get("type")
+            .and_then(Value::as_str)
+            .ok_or_else(|| invalid("StructureDefinition has no type"))?;
+        let snapshot = resource
+            .get("snapshot")
+            .and_then(|snapshot| snapshot.get("element")?.as_array());
+        let elements = snapshot
+            .or_else(|| resource.get("differential")?.get("element")?.as_array())
+            .ok_or_else(|| invalid("StructureDefinition has no elements"))?;
+        // Only a snapshot has every element of its types
+        let complete = snapshot.is_some();
+        if complete {
+            self.types.insert(type_name.to_string());
+        }
+
+        for element in elements {
+            let Some(path) = element.get("path").and_then(Value::as_str) else {
+                continue;
+            };
+            let Some((parent, name)) = path.rsplit_once('.') else {
+                continue;
+            };
+            let codes: Vec<&str> = element
+                .get("type")
+                .and_then(Value::as_array)
+                .into_iter()
+                .flatten()
+                .filter_map(|type_| type_.get("code")?.as_str())
+                .collect();
+            // Elements with their own elements are types of their own
+            let types = if matches!(codes.as_slice(), ["BackboneElement" | "Element"]) {
+                if complete {
+                    self.types.insert(path.to_string());
+                }
+                vec![path.to_string()]
+            } else {
+                codes.iter().map(ToString::to_string).collect()
+            };
+            let min = element
+                .get("min")
+                .and_then(Value::as_u64)
+                .and_then(|min| usize::try_from(min).ok())
+                .unwrap_or(0);
+            let max = match element.get("max").and_then(Value::as_str) {
+                Some("*") | None => None,
+                Some(max) => max.parse().ok(),
+            };
+            let key = (parent.to_string(), name.to_string());
+            self.elements
+                .insert(key, (types, Cardinality::new(min, max)));
+        }
+        Ok(())
+    }
+}
+
+impl TypeModel for StructureDefinitions {
+    fn has_type(&self, type_name: &str) -> bool {
+        self.types.contains(type_name)
+    }
+
+    fn element(&self, type_name: &str, name: &str) -> Option<TypeInfo> {
+        let lookup = |name: &str| {
+            self.elements
+                .get(&(type_name.to_string(), name.to_string()))
+        };
+        if let Some((types, cardinality)) = lookup(name) {
+            let type_ = match types.as_slice() {
+                [type_] => Type::Fhir(type_.clone()),
+                _ => Type::Any,
+            };
+            return Some(TypeInfo::new(type_, *cardinality));
+        }
+        if let Some((types, cardinality)) = lookup(&format!("{name}[x]")) {
+            let type_ = match types.as_slice() {
+                [type_] => Type::Fhir(type_.clone()),
+                _ => Type::Any,
+            };
+            return Some(TypeInfo::new(type_, *cardinality));
+        }
+        // A typed name of a choice element, such as `valueQuantity`
+        (1..name.len()).find_map(|split| {
+            let (choice, suffix) = name.split_at_checked(split)?;
+            let (types, cardinality) = lookup(&format!("{choice}[x]"))?;
+            types
+                .iter()
+                .find(|type_| capitalized(type_) == suffix)
+                .map(|type_| TypeInfo::new(Type::Fhir(type_.clone()), *cardinality))
+        })
+    }
+}
+
+/// Infers the [`TypeInfo`] of each expression of a tree.
+///
+/// ```rust
+/// use fhirlighter::evaluator::types::TypeChecker;
+/// use fhirlighter::parse;
+///
+/// let ast = parse("Patient.name.count() > @2020-01-01")?;
+/// let typed = TypeChecker::new("Patient").check(&ast);
+/// assert_eq!(typed.result().unwrap().to_string(), "1..1 Boolean");
+/// assert_eq!(typed.errors[0].message, "Can't compare Integer with Date");
+/// # Ok::<(), fhirlighter::Error>(())
+/// ```
+pub struct TypeChecker<'m> {
+    root: String,
+    model: Option<&'m dyn TypeModel>,
+}
+
+/// The result of [`TypeChecker::check`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Typed {
+    // The inferred types, indexed by `ExprRef::index`
+    types: Vec<Option<TypeInfo>>,
+    start: ExprRef,
+    /// The problems found, in the order their expressions finish.
+    pub errors: Vec<Error>,
+}
+
+impl Typed {
+    /// What the expression at `expr_ref` returns, if it was inferred: the
+    /// names of functions and type specifiers aren't expressions of their own.
+    #[must_use]
+    pub fn get(&self, expr_ref: ExprRef) -> Option<&TypeInfo> {
+        self.types.get(expr_ref.index())?.as_ref()
+    }
+
+    /// What the whole expression returns, which is inferred for any tree the
+    /// checker was given.
+    #[must_use]
+    pub fn result(&self) -> Option<&TypeInfo> {
+        self.get(self.start)
+    }
+}
+
+impl<'m> TypeChecker<'m> {
+    /// A checker for expressions evaluated on resources of type `root`.
+    #[must_use]
+    pub fn new(root: impl Into<String>) -> Self {
+        Self {
+            root: root.into(),
+            model: None,
+        }
+    }
+
+    /// Look up elements in `model`, reporting those that don't exist.
+    #[must_use]
+    pub fn with_model(mut self, model: &'m dyn TypeModel) -> Self {
+        self.model = Some(model);
+        self
+    }
+
+    /// Infer the type of every expression of the tree.
+    #[must_use]
+    pub fn check(&self, ast: &Ast) -> Typed {
+        let mut inference = Inference {
+            checker: self,
+            pool: &ast.expressions,
+            types: vec![None; ast.expressions.len()],
+            errors: Vec::new(),
+        };
+        let root = TypeInfo::new(Type::Fhir(self.root.clone()), Cardinality::ONE);
+        inference.infer(ast.start, &root, true);
+        Typed {
+            types: inference.types,
+            start: ast.start,
+            errors: inference.errors,
+        }
+    }
+}
+
+struct Inference<'c, 'm> {
+    checker: &'c TypeChecker<'m>,
+    pool: &'c ExprPool,
+    types: Vec<Option<TypeInfo>>,
+    errors: Vec<Error>,
+}
+
+impl Inference<'_, '_> {
+    // The type of an expression whose identifiers navigate from an item of
+    // `focus`, which is the resource itself at the top level
+    fn infer(&mut self, expr_ref: ExprRef, focus: &TypeInfo, top: bool) -> TypeInfo {
+        let inferred = self.infer_expression(expr_ref, focus, top);
+        self.types[expr_ref.index()] = Some(inferred.clone());
+        inferred
+    }
+
+    fn infer_expression(&mut self, expr_ref: ExprRef, focus: &TypeInfo, top: bool) -> TypeInfo {
+        let literal = |system| TypeInfo::system(system, Cardinality::ONE);
+        match self.pool.get(expr_ref) {
+            Expression::Identifier(name) => self.identifier(expr_ref, name, focus, top),
+            Expression::MemberAccess { object, member } => {
+                // `FHIR.Patient` is the type name `Patient` in the FHIR namespace
+                if matches!(self.pool.get(*object), Expression::Identifier(namespace) if namespace == "FHIR")
+                    && is_type_name(member)
+                {
+                    return self.identifier(expr_ref, member, focus, top);
+                }
+                let object = self.infer(*object, focus, top);
+                self.member(expr_ref, &object, member)
+            }
+            Expression::FunctionCall {
+                object,
+                function,
+                arguments,
+            } => {
+                let input =
+                    object.map_or_else(|| focus.clone(), |object| self.infer(object, focus, top));
+                let name = match self.pool.get(*function) {
+                    Expression::Identifier(name) => name.as_str(),
+                    _ => "",
+                };
+                self.function(name, &input, arguments, focus, top)
+            }
+            Expression::BinaryOperation { operator, lhs, rhs } => {
+                let lhs = self.infer(*lhs, focus, top);
+                let rhs = self.infer(*rhs, focus, top);
+                self.comparison(expr_ref, *operator, &lhs, &rhs)
+            }
+            Expression::Index { object, index } => {
+                let object = self.infer(*object, focus, top);
+                self.infer(*index, focus, top);
+                object.with(Cardinality::OPTIONAL)
+            }
+            Expression::ExternalConstant(name) => match name.as_str() {
+                "context" | "resource" | "rootResource" => {
+                    TypeInfo::new(Type::Fhir(self.checker.root.clone()), Cardinality::ONE)
+                }
+                "ucum" | "sct" | "loinc" => literal(SystemType::String),
+                name if name.starts_with("vs-") || name.starts_with("ext-") => {
+                    literal(SystemType::String)
+                }
+                _ => TypeInfo::any(),
+            },
+            Expression::String(_) => literal(SystemType::String),
+            Expression::Integer(_) => literal(SystemType::Integer),
+            Expression::Number(_) => literal(SystemType::Decimal),
+            Expression::Boolean(_) => literal(SystemType::Boolean),
+            Expression::ISODate(_) => literal(SystemType::Date),
+            Expression::ISODateTime(_) => literal(SystemType::DateTime),
+            Expression::Error => TypeInfo::any(),
+        }
+    }
+
+    // A leading name: a type name matching the focus, or an element of it
+    fn identifier(
+        &mut self,
+        expr_ref: ExprRef,
+        name: &str,
+        focus: &TypeInfo,
+        top: bool,
+    ) -> TypeInfo {
+        if is_type_name(name)
+            && let Type::Fhir(focus_type) = &focus.type_
+        {
+            if is_subtype(focus_type, name) {
+                return focus.clone();
+            }
+            if top {
+                let message = format!("{name} does not match the resource type {focus_type}");
+                self.report(ErrorCode::UnmatchedType, message, expr_ref);
+                return TypeInfo::new(Type::Fhir(name.to_string()), Cardinality::new(0, Some(0)));
+            }
+        }
+        self.member(expr_ref, focus, name)
+    }
+
+    // The element `name` of each item of `object`
+    fn member(&mut self, expr_ref: ExprRef, object: &TypeInfo, name: &str) -> TypeInfo {
+        let Type::Fhir(type_name) = &object.type_ else {
+            return TypeInfo::any();
+        };
+        let Some(model) = self.checker.model else {
+            return TypeInfo::any();
+        };
+        match model.element(type_name, name) {
+            Some(element) => element.with(object.cardinality.times(element.cardinality)),
+            None if model.has_type(type_name) => {
+                let message = format!("{type_name} has no element '{name}'");
+                self.report(ErrorCode::UnknownElement, message, expr_ref);
+                TypeInfo::new(Type::Any, Cardinality::new(0, Some(0)))
+            }
+            None => TypeInfo::any(),
+        }
+    }
+
+    #[allow(clippy::too_many_lines)]
+    fn function(
+        &mut self,
+        name: &str,
+        input: &TypeInfo,
+        arguments: &[ExprRef],
+        focus: &TypeInfo,
+        top: bool,
+    ) -> TypeInfo {
+        use SystemType::{Boolean, Date, DateTime, Decimal, Integer, Quantity, String, Time};
+
+        // Lambdas apply to each item of the input, other arguments to the focus
+        let item = input.with(Cardinality::ONE);
+        let signature = functions::lookup(name);
+        let mut results = Vec::new();
+        for (index, argument) in arguments.iter().enumerate() {
+            results.push(
+                match signature.and_then(|signature| signature.parameter(index)) {
+                    Some(ArgumentKind::Lambda) => Some(self.infer(*argument, &item, false)),
+                    Some(ArgumentKind::TypeSpecifier) => None,
+                    _ => Some(self.infer(*argument, focus, top)),
+                },
+            );
+        }
+        let cardinality = input.cardinality;
+        let optional = |system| TypeInfo::system(system, Cardinality::OPTIONAL);
+        match name {
+            "empty" | "exists" | "all" | "allTrue" | "anyTrue" | "allFalse" | "anyFalse"
+            | "subsetOf" | "supersetOf" | "isDistinct" | "hasValue" | "htmlChecks" => {
+                TypeInfo::system(Boolean, Cardinality::ONE)
+            }
+            "count" => TypeInfo::system(Integer, Cardinality::ONE),
+            "now" => TypeInfo::system(DateTime, Cardinality::ONE),
+            "today" => TypeInfo::system(Date, Cardinality::ONE),
+            "timeOfDay" => TypeInfo::system(Time, Cardinality::ONE),
+            "first" | "last" | "single" => {
+                input.with(Cardinality::new(cardinality.min.min(1), Some(1)))
+            }
+            "where" | "tail" | "skip" | "take" | "slice" => input.with(cardinality.or_fewer()),
+            "distinct" => input.with(Cardinality::new(cardinality.min.min(1), cardinality.max)),
+            "trace" | "checkModifiers" => input.clone(),
+            "select" | "repeat" => match results.first() {
+                Some(Some(projection)) => projection.with(Cardinality::MANY),
+                _ => TypeInfo::any(),
+            },
+            "ofType" | "as" => {
+                let type_ = arguments
+                    .first()
+                    .and_then(|argument| self.type_specifier(*argument))
+                    .unwrap_or(Type::Any);
+                TypeInfo::new(type_, cardinality.or_fewer())
+            }
+            "union" | "combine" | "intersect" | "exclude" => match results.first() {
+                Some(Some(other)) if other.type_ == input.type_ => input.with(Cardinality::MANY),
+                _ => TypeInfo::any(),
+            },
+            "is" | "not" | "startsWith" | "endsWith" | "contains" | "matches" | "memberOf"
+            | "conformsTo" | "subsumes" | "subsumedBy" | "toBoolean" => optional(Boolean),
+            name if name.starts_with("convertsTo") => optional(Boolean),
+            "toString" | "upper" | "lower" | "replace" | "replaceMatches" | "substring" => {
+                optional(String)
+            }
+            "toChars" => TypeInfo::system(String, Cardinality::MANY),
+            "toInteger" | "length" | "indexOf" | "ceiling" | "floor" | "truncate" => {
+                optional(Integer)
+            }
+            "toDecimal" | "round" | "exp" | "ln" | "log" | "power" | "sqrt" => optional(Decimal),
+            "abs" => input.with(Cardinality::OPTIONAL),
+            "toDate" => optional(Date),
+            "toDateTime" => optional(DateTime),
+            "toTime" => optional(Time),
+            "toQuantity" => optional(Quantity),
+            "extension" => TypeInfo::new(Type::Fhir("Extension".to_string()), Cardinality::MANY),
+            "getValue" => {
+                let type_ = input.type_.system().map_or(Type::Any, Type::System);
+                TypeInfo::new(type_, cardinality.or_fewer())
+            }
+            "resolve" => TypeInfo::new(Type::Any, cardinality.or_fewer()),
+            "elementDefinition" => TypeInfo::new(
+                Type::Fhir("ElementDefinition".to_string()),
+                cardinality.or_fewer(),
+            ),
+            _ => TypeInfo::any(),
+        }
+    }
+
+    // The type a type specifier such as `Patient`, `FHIR.Patient` or
+    // `System.String` names
+    fn type_specifier(&self, expr_ref: ExprRef) -> Option<Type> {
+        let (namespace, name) = match self.pool.get(expr_ref) {
+            Expression::Identifier(name) => (None, name),
+            Expression::MemberAccess { object, member } => match self.pool.get(*object) {
+                Expression::Identifier(namespace) => (Some(namespace.as_str()), member),
+                _ => return None,
+            },
+            _ => return None,
+        };
+        match (namespace, system_type(name)) {
+            (Some("System") | None, Some(system)) => Some(Type::System(system)),
+            _ => Some(Type::Fhir(name.clone())),
+        }
+    }
+
+    fn comparison(
+        &mut self,
+        expr_ref: ExprRef,
+        operator: BinaryOperator,
+        lhs: &TypeInfo,
+        rhs: &TypeInfo,
+    ) -> TypeInfo {
+        if let (Some(left), Some(right)) = (lhs.type_.system(), rhs.type_.system()) {
+            let ordering = matches!(
+                operator,
+                BinaryOperator::LessThan
+                    | BinaryOperator::LessThanOrEqual
+                    | BinaryOperator::GreaterThan
+                    | BinaryOperator::GreaterThanOrEqual
+            );
+            if !comparable(left, right) {
+                let message = format!("Can't compare {left:?} with {right:?}");
+                self.report(ErrorCode::IncomparableValues, message, expr_ref);
+            } else if ordering && left == SystemType::Boolean {
+                let message = "Can't order Boolean values".to_string();
+                self.report(ErrorCode::IncomparableValues, message, expr_ref);
+            }
+        }
+        // Empty when either operand is
+        let min = usize::from(lhs.cardinality.min > 0 && rhs.cardinality.min > 0);
+        TypeInfo::system(SystemType::Boolean, Cardinality::new(min, Some(1)))
+    }
+
+    fn report(&mut self, code: ErrorCode, message: String, expr_ref: ExprRef) {
+        let span = self.pool.span(expr_ref);
+        self.errors.push(Error::new(code, message).with_span(span));
+    }
+}
+
+// Helper: whether values of two primitive types can be compared at all
+fn comparable(left: SystemType, right: SystemType) -> bool {
+    use SystemType::{Date, DateTime, Decimal, Integer};
+    left == right
+        || matches!(
+            (left, right),
+            (Integer | Decimal, Integer | Decimal) | (Date | DateTime, Date | DateTime)
+        )
+}
+
+// Helper: the primitive of a `System` type name, such as `String`
+fn system_type(name: &str) -> Option<SystemType> {
+    Some(match name {
+        "Boolean" => SystemType::Boolean,
+        "String" => SystemType::String,
+        "Integer" => SystemType::Integer,
+        "Decimal" => SystemType::Decimal,
+        "Date" => SystemType::Date,
+        "DateTime" => SystemType::DateTime,
+        "Time" => SystemType::Time,
+        "Quantity" => SystemType::Quantity,
+        _ => return None,
+    })
+}
+
+// Helper: the primitive a FHIR type converts to, such as `String` for `code`
+fn fhir_primitive(name: &str) -> Option<SystemType> {
+    Some(match name {
+        "boolean" => SystemType::Boolean,
+        "string" | "code" | "id" | "uri" | "url" | "canonical" | "markdown" | "oid" | "uuid"
+        | "base64Binary" | "xhtml" => SystemType::String,
+        "integer" | "integer64" | "positiveInt" | "unsignedInt" => SystemType::Integer,
+        "decimal" => SystemType::Decimal,
+        "date" => SystemType::Date,
+        "dateTime" | "instant" => SystemType::DateTime,
+        "time" => SystemType::Time,
+        "Quantity" | "Age" | "Count" | "Distance" | "Duration" | "SimpleQuantity" => {
+            SystemType::Quantity
+        }
+        _ => return None,
+    })
+}
+
+// Helper: a type name as the suffix of a choice element, `valueString` for `string`
+fn capitalized(name: &str) -> String {
+    let mut characters = name.chars();
+    characters.next().map_or_else(String::new, |first| {
+        first.to_uppercase().chain(characters).collect()
+    })
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Any => write!(f, "Any"),
+            Self::System(system) => write!(f, "{system:?}"),
+            Self::Fhir(name) => write!(f, "{name}"),
+        }
+    }
+}
+
+impl fmt::Display for Cardinality {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.max {
+            Some(max) => write!(f, "{}..{max}", self.min),
+            None => write!(f, "{}..*", self.min),
+        }
+    }
+}
+
+/// The cardinality then the type, as in `0..* string`.
+impl fmt::Display for TypeInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.cardinality, self.type_)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::token::Span;
+    use crate::parse_syntax as parse;
+    use crate::parser::visit::{Visitor, walk};
+    use serde_json::json;
+
+    fn element(path: &str, type_: &str, min: u64, max: &str) -> Value {
+        json!({"path": path, "type": [{"code": type_}], "min": min, "max": max})
+    }
+
+    // A small part of the Patient and HumanName definitions
+    fn model() -> StructureDefinitions {
+        let mut model = StructureDefinitions::new();
+        let patient = json!({
+            "resourceType": "StructureDefinition",
+            "type": "Patient",
+            "snapshot": {"element": [
+                {"path": "Patient", "min": 0, "max": "*"},
+                element("Patient.active", "boolean", 0, "1"),
+                element("Patient.name", "HumanName", 0, "*"),
+                element("Patient.gender", "code", 0, "1"),
+                element("Patient.birthDate", "date", 0, "1"),
+                {"path": "Patient.deceased[x]", "type": [{"code": "boolean"}, {"code": "dateTime"}], "min": 0, "max": "1"},
+                element("Patient.contact", "BackboneElement", 0, "*"),
+                element("Patient.contact.name", "HumanName", 0, "1"),
+            ]}
+        });
+        let human_name = json!({
+            "resourceType": "StructureDefinition",
+            "type": "HumanName",
+            "snapshot": {"element": [
+                element("HumanName.use", "code", 0, "1"),
+                element("HumanName.family", "string", 0, "1"),
+                element("HumanName.given", "string", 0, "*"),
+            ]}
+        });
+        let bundle = json!({
+            "resourceType": "Bundle",
+            "entry": [{"resource": patient}, {"resource": human_name}]
+        });
+        model.insert(&bundle).unwrap();
+        model
+    }
+
+    // Finds the last identifier with a name
+    struct Find(&'static str, Option<ExprRef>);
+
+    impl Visitor<'_> for Find {
+        fn visit_identifier(&mut self, expr_ref: ExprRef, name: &str) {
+            if name == self.0 {
+                self.1 = Some(expr_ref);
+            }
+        }
+    }
+
+    fn infer(input: &str, model: &StructureDefinitions) -> Typed {
+        TypeChecker::new("Patient")
+            .with_model(model)
+            .check(&parse(input).unwrap())
+    }
+
+    #[test]
+    fn test_navigation() {
+        let model = model();
+        for (input, expected) in [
+            ("Patient", "1..1 Patient"),
+            ("Patient.name", "0..* HumanName"),
+            ("name.given", "0..* string"),
+            ("Patient.name.family", "0..* string"),
+            ("Patient.name[0].family", "0..1 string"),
+            ("gender", "0..1 code"),
+            ("Patient.contact.name.use", "0..* code"),
+            ("deceased", "0..1 Any"),
+            ("deceasedDateTime", "0..1 dateTime"),
+            ("%resource.active", "0..1 boolean"),
+            ("FHIR.Patient.birthDate", "0..1 date"),
+        ] {
+            let typed = infer(input, &model);
+            assert!(typed.errors.is_empty(), "{input}: {:?}", typed.errors);
+            assert_eq!(typed.result().unwrap().to_string(), expected, "{input}");
+        }
+    }
+
+    #[test]
+    fn test_functions() {
+        let model = model();
+        for (input, expected) in [
+            ("name.count()", "1..1 Integer"),
+            ("name.exists()", "1..1 Boolean"),
+            ("name.first().given", "0..* string"),
+            ("name.where(use = 'official').family", "0..* string"),
+            ("name.select(given)", "0..* string"),
+            ("name.given.first().upper()", "0..1 String"),
+            ("birthDate.getValue()", "0..1 Date"),
+            ("extension('http://example.org')", "0..* Extension"),
+            ("name.ofType(HumanName)", "0..* HumanName"),
+            ("name.frobnicate()", "0..* Any"),
+        ] {
+            let typed = infer(input, &model);
+            assert!(typed.errors.is_empty(), "{input}: {:?}", typed.errors);
+            assert_eq!(typed.result().unwrap().to_string(), expected, "{input}");
+        }
+    }
+
+    #[test]
+    fn test_every_expression_is_typed() {
+        let ast = parse("name.where(use = 'official').given[0] = 'Peter'").unwrap();
+        let model = model();
+        let typed = TypeChecker::new("Patient").with_model(&model).check(&ast);
+        let Expression::BinaryOperation { lhs, rhs, .. } = ast.expressions.get(ast.start) else {
+            panic!("Expected a comparison");
+        };
+        assert_eq!(typed.get(*lhs).unwrap().to_string(), "0..1 string");
+        assert_eq!(typed.get(*rhs).unwrap().to_string(), "1..1 String");
+        assert_eq!(typed.result().unwrap().to_string(), "0..1 Boolean");
+        // Within the lambda, `use` is an element of each name
+        let mut find = Find("use", None);
+        walk(&ast, &mut find);
+        let use_ = find.1.unwrap();
+        assert_eq!(typed.get(use_).unwrap().to_string(), "0..1 code");
+    }
+
+    #[test]
+    fn test_mismatched_comparisons() {
+        let model = model();
+        for (input, message) in [
+            ("birthDate = 1", "Can't compare Date with Integer"),
+            (
+                "name.given.first() != %resource.active",
+                "Can't compare String with Boolean",
+            ),
+            ("active < true", "Can't order Boolean values"),
+        ] {
+            let typed = infer(input, &model);
+            assert_eq!(typed.errors.len(), 1, "{input}");
+            assert_eq!(typed.errors[0].message, message);
+        }
+        let typed = infer("(birthDate = 1) = 'x'", &model);
+        assert_eq!(typed.errors[0].code, ErrorCode::IncomparableValues);
+
+        for input in [
+            "birthDate < @2020-01-01T10:00:00",
+            "name.count() > 1.5",
+            "gender = 'male'",
+        ] {
+            assert!(infer(input, &model).errors.is_empty(), "{input}");
+        }
+    }
+
+    #[test]
+    fn test_unknown_elements() {
+        let model = model();
+        let typed = infer("Patient.nmae.given = name.famly", &model);
+        let messages: Vec<_> = typed
+            .errors
+            .iter()
+            .map(|error| error.message.as_str())
+            .collect();
+        assert_eq!(
+            messages,
+            [
+                "Patient has no element 'nmae'",
+                "HumanName has no element 'famly'"
+            ]
+        );
+        assert_eq!(typed.errors[0].code, ErrorCode::UnknownElement);
+        assert_eq!(typed.errors[0].span, Some(Span::new(0, 12)));
+
+        let typed = infer("Encounter.status", &model);
+        assert_eq!(typed.errors[0].code, ErrorCode::UnmatchedType);
+    }
+
+    #[test]
+    fn test_differentials_leave_types_unknown() {
+        let mut model = model();
+        let profile = json!({
+            "resourceType": "StructureDefinition",
+            "type": "Observation",
+            "differential": {"element": [
+                element("Observation.status", "code", 1, "1"),
+                element("Observation.component", "BackboneElement", 0, "*"),
+            ]}
+        });
+        model.insert(&profile).unwrap();
+        assert!(!model.has_type("Observation"));
+        assert!(!model.has_type("Observation.component"));
+
+        // `id` is inherited, so it isn't in the differential
+        let ast = parse("Observation.status = Observation.id").unwrap();
+        let typed = TypeChecker::new("Observation")
+            .with_model(&model)
+            .check(&ast);
+        assert!(typed.errors.is_empty(), "{:?}", typed.errors);
+        let Expression::BinaryOperation { lhs, rhs, .. } = ast.expressions.get(ast.start) else {
+            panic!("Expected a comparison");
+        };
+        assert_eq!(typed.get(*lhs).unwrap().to_string(), "1..1 code");
+        assert_eq!(typed.get(*rhs).unwrap().to_string(), "0..* Any");
+    }
+
+    #[test]
+    fn test_without_a_model() {
+        let ast = parse("Patient.nmae.given.count() = 'x'").unwrap();
+        let typed = TypeChecker::new("Patient").check(&ast);
+        let Expression::BinaryOperation { lhs, .. } = ast.expressions.get(ast.start) else {
+            panic!("Expected a comparison");
+        };
+        assert_eq!(typed.get(*lhs).unwrap().to_string(), "1..1 Integer");
+        assert_eq!(typed.errors.len(), 1);
+        assert_eq!(typed.errors[0].message, "Can't compare Integer with String");
+    }
+
+    #[test]
+    fn test_invalid_definitions() {
+        let mut model = StructureDefinitions::new();
+        let error = model
+            .insert(&json!({"resourceType": "Patient"}))
+            .unwrap_err();
+        assert_eq!(error.code, ErrorCode::InvalidResource);
+        let definition = json!({"resourceType": "StructureDefinition", "type": "Patient"});
+        assert!(model.insert(&definition).is_err());
+    }
+}
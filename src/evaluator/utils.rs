@@ -1,85 +1,152 @@
-use super::error::Error;
+use super::error::{Error, ErrorCode};
 use crate::parser::grammar::Expression;
-use serde_json::Value;
-use std::borrow::Cow;
+use serde_json::{Map, Value};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::mem::discriminant;
+use std::ptr;
 use time::{Date, PrimitiveDateTime, format_description::well_known::Iso8601};
 
-pub fn get_from_object<'a>(cow_obj: Cow<'a, Value>, key: &str) -> Result<Cow<'a, Value>, Error> {
-    match cow_obj {
-        Cow::Borrowed(Value::Object(obj)) => obj
-            .get(key)
-            .map(Cow::Borrowed)
-            .ok_or_else(|| Error::Parse(format!("Couldn't retrieve member: {key}"))),
-        Cow::Owned(Value::Object(mut map)) => map
-            .remove(key)
-            .map(Cow::Owned)
-            .ok_or_else(|| Error::Parse(format!("Couldn't retrieve member: {key}"))),
-        _ => Err(Error::Parse("Expected an object".to_string())),
-    }
+/// The parent of every value within a resource, found by identity.
+///
+/// Built with a single walk of the resource, after which the chain of values
+/// down to any of them takes one step per level rather than a search. Values
+/// are known by address only, so the index must be used with the resource it
+/// was built from.
+#[derive(Debug, Default)]
+pub struct Parents {
+    // Address of a value -> address of its parent
+    parents: HashMap<usize, usize>,
 }
 
-// Helper: get from array by index, borrow if possible, move if owned
-pub fn get_from_array(cow_arr: Cow<Value>, index: usize) -> Result<Cow<Value>, Error> {
-    match cow_arr {
-        Cow::Borrowed(Value::Array(obj)) => obj
-            .get(index)
-            .map(Cow::Borrowed)
-            .ok_or_else(|| Error::Parse(format!("Couldn't retrieve index: {index}"))),
-        Cow::Owned(Value::Array(mut arr)) => {
-            if index < arr.len() {
-                Ok(Cow::Owned(arr.swap_remove(index)))
-            } else {
-                Err(Error::Parse(format!("Couldn't retrieve index: {index}")))
+impl Parents {
+    pub fn new(root: &Value) -> Self {
+        let mut parents = HashMap::new();
+        let mut pending = vec![root];
+        while let Some(value) = pending.pop() {
+            for child in children(value) {
+                parents.insert(address(child), address(value));
+                pending.push(child);
             }
         }
-        _ => Err(Error::Parse("Expected an array".to_string())),
+        Self { parents }
     }
+
+    /// The chain of values from `root` down to `target`, both included, or
+    /// `None` when `target` isn't part of `root`.
+    pub fn ancestors<'a>(&self, root: &'a Value, target: &Value) -> Option<Vec<&'a Value>> {
+        let mut addresses = vec![address(target)];
+        while let Some(parent) = self.parents.get(addresses.last()?) {
+            addresses.push(*parent);
+        }
+        if addresses.pop() != Some(address(root)) {
+            return None;
+        }
+        let mut path = vec![root];
+        for next in addresses.into_iter().rev() {
+            path.push(children(path.last()?).find(|child| address(child) == next)?);
+        }
+        Some(path)
+    }
+}
+
+// Helper: the members of an object or the items of an array
+fn children(value: &Value) -> impl Iterator<Item = &Value> {
+    let members = value.as_object().into_iter().flat_map(Map::values);
+    members.chain(value.as_array().into_iter().flatten())
+}
+
+// Helper: the identity of a value within a resource
+fn address(value: &Value) -> usize {
+    ptr::from_ref(value).addr()
+}
+
+/// The innermost resource on `path`, e.g. the entry resource rather than its Bundle.
+pub fn nearest_resource<'a>(path: &[&'a Value]) -> Option<&'a Value> {
+    path.iter()
+        .rev()
+        .find(|value| value.get("resourceType").is_some())
+        .copied()
+}
+
+/// The innermost resource on `path`, or the resource containing it when it is
+/// a contained resource.
+pub fn root_resource<'a>(path: &[&'a Value]) -> Option<&'a Value> {
+    let (index, resource) = path
+        .iter()
+        .enumerate()
+        .rev()
+        .find(|(_, value)| value.get("resourceType").is_some())?;
+    let outer = index
+        .checked_sub(2)
+        .and_then(|outer| path.get(outer))
+        .filter(|outer| {
+            outer
+                .get("contained")
+                .is_some_and(|contained| ptr::eq(contained, path[index - 1]))
+        });
+    Some(outer.copied().unwrap_or(resource))
 }
 
 pub fn eval_index(index: &Expression, _: &Value) -> Result<usize, Error> {
     match index {
         Expression::Integer(i) => usize::try_from(*i).map_err(|e| {
-            Error::IntegerConversion(format!("Couldn't convert integer: {i} with error: {e}"))
+            Error::new(
+                ErrorCode::InvalidIndex,
+                format!("Couldn't convert integer: {i} with error: {e}"),
+            )
         }),
-        _other => Err(Error::Unrecoverable("Couldn't evaluate index".to_string())),
+        _other => Err(Error::new(
+            ErrorCode::InvalidIndex,
+            "Couldn't evaluate index",
+        )),
     }
 }
 
-#[derive(Eq, PartialEq, PartialOrd, Debug)]
+#[derive(PartialEq, PartialOrd, Debug)]
 pub enum ComparableTypes {
     String(String),
     Integer(i64),
+    Decimal(f64),
     Boolean(bool),
     ISODateTime(PrimitiveDateTime),
     ISODate(Date),
 }
 
 impl ComparableTypes {
-    pub fn from_value(value: Value) -> Result<Self, Error> {
+    /// None for values without an ordering: objects, arrays and null.
+    pub fn from_value(value: &Value) -> Option<Self> {
         match value {
             Value::String(string) => {
-                if let Ok(date) = Date::parse(&string, &Iso8601::DATE) {
-                    return Ok(Self::ISODate(date));
+                if let Ok(date) = Date::parse(string, &Iso8601::DATE) {
+                    return Some(Self::ISODate(date));
                 }
-                if let Ok(datetime) = PrimitiveDateTime::parse(&string, &Iso8601::DEFAULT) {
-                    return Ok(Self::ISODateTime(datetime));
+                if let Ok(datetime) = PrimitiveDateTime::parse(string, &Iso8601::DEFAULT) {
+                    return Some(Self::ISODateTime(datetime));
                 }
 
                 // If parsing fails, treat as regular string
-                Ok(Self::String(string))
+                Some(Self::String(string.clone()))
             }
-            Value::Number(number) => number.as_i64().map_or_else(
-                || {
-                    Err(Error::Parse(
-                        "Number cannot be represented as i64".to_string(),
-                    ))
-                },
-                |int| Ok(Self::Integer(int)),
-            ),
-            Value::Bool(b) => Ok(Self::Boolean(b)),
-            _ => Err(Error::Parse(
-                "Not implemented comparison for type.".to_string(),
-            )),
+            Value::Number(number) => number
+                .as_i64()
+                .map(Self::Integer)
+                .or_else(|| number.as_f64().map(Self::Decimal)),
+            Value::Bool(b) => Some(Self::Boolean(*b)),
+            _ => None,
+        }
+    }
+
+    /// None when the values are of different types. Integers and decimals are
+    /// both numbers and compare with each other.
+    pub fn compare(&self, other: &Self) -> Option<Ordering> {
+        // Precision beyond 2^53 doesn't matter when comparing against a decimal
+        #[allow(clippy::cast_precision_loss)]
+        match (self, other) {
+            (Self::Integer(lhs), Self::Decimal(rhs)) => (*lhs as f64).partial_cmp(rhs),
+            (Self::Decimal(lhs), Self::Integer(rhs)) => lhs.partial_cmp(&(*rhs as f64)),
+            _ if discriminant(self) == discriminant(other) => self.partial_cmp(other),
+            _ => None,
         }
     }
 }
@@ -0,0 +1,524 @@
+//! A small XHTML parser and the FHIR narrative rules checked by `htmlChecks()`.
+//!
+//! Narrative XHTML is well-formed XML, so the parser only understands elements,
+//! attributes, character data, comments and the predefined and numeric entity
+//! references. Anything else (doctype, processing instructions, CDATA) is
+//! rejected because it is not allowed in a FHIR narrative either.
+
+const XHTML_NAMESPACE: &str = "http://www.w3.org/1999/xhtml";
+
+// Elements allowed by the FHIR narrative rules (txt-1)
+const ALLOWED_ELEMENTS: &[&str] = &[
+    "p",
+    "br",
+    "div",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "a",
+    "span",
+    "b",
+    "em",
+    "i",
+    "strong",
+    "small",
+    "big",
+    "tt",
+    "dfn",
+    "q",
+    "var",
+    "abbr",
+    "acronym",
+    "cite",
+    "blockquote",
+    "hr",
+    "address",
+    "bdo",
+    "kbd",
+    "sub",
+    "sup",
+    "ul",
+    "ol",
+    "li",
+    "dl",
+    "dt",
+    "dd",
+    "pre",
+    "table",
+    "caption",
+    "colgroup",
+    "col",
+    "thead",
+    "tr",
+    "tfoot",
+    "tbody",
+    "th",
+    "td",
+    "code",
+    "samp",
+    "img",
+    "map",
+    "area",
+];
+
+// Attributes allowed on any element
+const ALLOWED_ATTRIBUTES: &[&str] = &[
+    "title",
+    "style",
+    "class",
+    "id",
+    "lang",
+    "xml:lang",
+    "dir",
+    "accesskey",
+    "tabindex",
+    "span",
+    "width",
+    "align",
+    "valign",
+    "char",
+    "charoff",
+    "abbr",
+    "axis",
+    "headers",
+    "scope",
+    "rowspan",
+    "colspan",
+];
+
+// Attributes allowed only on a specific element, as `element.attribute`
+const ALLOWED_ELEMENT_ATTRIBUTES: &[&str] = &[
+    "a.href",
+    "a.name",
+    "a.title",
+    "a.type",
+    "a.rel",
+    "a.rev",
+    "a.charset",
+    "a.hreflang",
+    "img.src",
+    "img.alt",
+    "img.border",
+    "img.width",
+    "img.height",
+    "img.usemap",
+    "img.ismap",
+    "img.longdesc",
+    "div.xmlns",
+    "blockquote.cite",
+    "q.cite",
+    "table.summary",
+    "table.width",
+    "table.border",
+    "table.frame",
+    "table.rules",
+    "table.cellspacing",
+    "table.cellpadding",
+    "pre.space",
+    "td.nowrap",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum XhtmlNode {
+    Element(XhtmlElement),
+    Text(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct XhtmlElement {
+    pub name: String,
+    pub attributes: Vec<(String, String)>,
+    pub children: Vec<XhtmlNode>,
+}
+
+impl XhtmlElement {
+    #[must_use]
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(attribute, _)| attribute == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Whether any text (other than whitespace) or image appears in the element.
+    #[must_use]
+    pub fn has_content(&self) -> bool {
+        self.children.iter().any(|child| match child {
+            XhtmlNode::Text(text) => !text.trim().is_empty(),
+            XhtmlNode::Element(element) => element.name == "img" || element.has_content(),
+        })
+    }
+}
+
+/// Check a narrative `div` against the FHIR rules: well-formed XHTML whose root
+/// is a `div` in the XHTML namespace, using only the allowed elements and
+/// attributes, and with some non-whitespace content.
+///
+/// # Errors
+///
+/// Returns a description of the first rule the narrative breaks.
+pub fn check_narrative(input: &str) -> Result<(), String> {
+    let root = parse(input)?;
+    if root.name != "div" {
+        return Err(format!(
+            "Narrative root must be a div, found <{}>",
+            root.name
+        ));
+    }
+    if root.attribute("xmlns") != Some(XHTML_NAMESPACE) {
+        return Err(format!(
+            "Narrative div must be in the {XHTML_NAMESPACE} namespace"
+        ));
+    }
+    check_element(&root)?;
+    if !root.has_content() {
+        return Err("Narrative must have some non-whitespace content".to_string());
+    }
+    Ok(())
+}
+
+fn check_element(element: &XhtmlElement) -> Result<(), String> {
+    if !ALLOWED_ELEMENTS.contains(&element.name.as_str()) {
+        return Err(format!("Element <{}> is not allowed", element.name));
+    }
+    for (attribute, _) in &element.attributes {
+        let qualified = format!("{}.{attribute}", element.name);
+        if !ALLOWED_ATTRIBUTES.contains(&attribute.as_str())
+            && !ALLOWED_ELEMENT_ATTRIBUTES.contains(&qualified.as_str())
+        {
+            return Err(format!(
+                "Attribute {attribute} is not allowed on <{}>",
+                element.name
+            ));
+        }
+    }
+    for child in &element.children {
+        if let XhtmlNode::Element(child) = child {
+            check_element(child)?;
+        }
+    }
+    Ok(())
+}
+
+/// Parse a single XHTML element, ignoring surrounding whitespace.
+///
+/// # Errors
+///
+/// Returns an error if the input is not a single well-formed element.
+pub fn parse(input: &str) -> Result<XhtmlElement, String> {
+    let mut parser = XhtmlParser {
+        input,
+        position: 0,
+        depth: 0,
+    };
+    parser.skip_misc()?;
+    let root = parser.parse_element()?;
+    parser.skip_misc()?;
+    if !parser.is_at_end() {
+        return Err(format!(
+            "Unexpected content after root element at position {}",
+            parser.position
+        ));
+    }
+    Ok(root)
+}
+
+// Elements may nest this deep, which bounds the recursion of the parser
+const MAX_DEPTH: usize = 256;
+
+struct XhtmlParser<'a> {
+    input: &'a str,
+    position: usize, // byte position
+    depth: usize,
+}
+
+impl XhtmlParser<'_> {
+    fn parse_element(&mut self) -> Result<XhtmlElement, String> {
+        self.expect("<")?;
+        let name = self.parse_name()?;
+        let mut attributes = Vec::new();
+
+        loop {
+            let had_whitespace = self.skip_whitespace();
+            if self.eat("/>") {
+                return Ok(XhtmlElement {
+                    name,
+                    attributes,
+                    children: Vec::new(),
+                });
+            }
+            if self.eat(">") {
+                break;
+            }
+            if !had_whitespace {
+                return Err(format!("Expected whitespace at position {}", self.position));
+            }
+            let attribute = self.parse_name()?;
+            if attributes
+                .iter()
+                .any(|(existing, _)| *existing == attribute)
+            {
+                return Err(format!("Duplicate attribute {attribute} on <{name}>"));
+            }
+            self.skip_whitespace();
+            self.expect("=")?;
+            self.skip_whitespace();
+            let value = self.parse_attribute_value()?;
+            attributes.push((attribute, value));
+        }
+
+        let mut children = Vec::new();
+        loop {
+            if self.is_at_end() {
+                return Err(format!("Unclosed element <{name}>"));
+            }
+            if self.eat("</") {
+                let closing = self.parse_name()?;
+                if closing != name {
+                    return Err(format!("Mismatched closing tag </{closing}> for <{name}>"));
+                }
+                self.skip_whitespace();
+                self.expect(">")?;
+                return Ok(XhtmlElement {
+                    name,
+                    attributes,
+                    children,
+                });
+            }
+            if self.rest().starts_with("<!--") {
+                self.skip_comment()?;
+            } else if self.rest().starts_with('<') {
+                if self.depth == MAX_DEPTH {
+                    return Err(format!("Elements nest more than {MAX_DEPTH} levels deep"));
+                }
+                self.depth += 1;
+                let child = self.parse_element();
+                self.depth -= 1;
+                children.push(XhtmlNode::Element(child?));
+            } else {
+                children.push(XhtmlNode::Text(self.parse_text()?));
+            }
+        }
+    }
+
+    fn parse_name(&mut self) -> Result<String, String> {
+        let start = self.position;
+        while let Some(ch) = self.current_char() {
+            if ch.is_alphanumeric() || matches!(ch, '_' | '-' | '.' | ':') {
+                self.position += ch.len_utf8();
+            } else {
+                break;
+            }
+        }
+        if start == self.position {
+            return Err(format!("Expected a name at position {start}"));
+        }
+        Ok(self.input[start..self.position].to_string())
+    }
+
+    fn parse_attribute_value(&mut self) -> Result<String, String> {
+        let Some(quote @ ('"' | '\'')) = self.current_char() else {
+            return Err(format!(
+                "Expected a quoted attribute value at position {}",
+                self.position
+            ));
+        };
+        self.position += 1;
+        let Some(length) = self.rest().find(quote) else {
+            return Err("Unterminated attribute value".to_string());
+        };
+        let raw = &self.input[self.position..self.position + length];
+        self.position += length + 1;
+        if raw.contains('<') {
+            return Err("Attribute values must not contain '<'".to_string());
+        }
+        decode_entities(raw)
+    }
+
+    fn parse_text(&mut self) -> Result<String, String> {
+        let length = self.rest().find('<').unwrap_or_else(|| self.rest().len());
+        let raw = &self.input[self.position..self.position + length];
+        self.position += length;
+        decode_entities(raw)
+    }
+
+    fn skip_comment(&mut self) -> Result<(), String> {
+        self.expect("<!--")?;
+        let Some(length) = self.rest().find("-->") else {
+            return Err("Unterminated comment".to_string());
+        };
+        self.position += length + 3;
+        Ok(())
+    }
+
+    // Skip whitespace and comments outside the root element
+    fn skip_misc(&mut self) -> Result<(), String> {
+        loop {
+            self.skip_whitespace();
+            if self.rest().starts_with("<!--") {
+                self.skip_comment()?;
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), String> {
+        if self.eat(expected) {
+            Ok(())
+        } else {
+            Err(format!(
+                "Expected '{expected}' at position {}",
+                self.position
+            ))
+        }
+    }
+
+    fn eat(&mut self, expected: &str) -> bool {
+        if self.rest().starts_with(expected) {
+            self.position += expected.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn skip_whitespace(&mut self) -> bool {
+        let start = self.position;
+        while let Some(ch) = self.current_char() {
+            if ch.is_whitespace() {
+                self.position += ch.len_utf8();
+            } else {
+                break;
+            }
+        }
+        self.position > start
+    }
+
+    fn rest(&self) -> &str {
+        &self.input[self.position..]
+    }
+
+    fn current_char(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    const fn is_at_end(&self) -> bool {
+        self.position >= self.input.len()
+    }
+}
+
+// Helper: replace the predefined XML entities and character references
+fn decode_entities(raw: &str) -> Result<String, String> {
+    let mut decoded = String::with_capacity(raw.len());
+    let mut rest = raw;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+        let Some(end) = rest.find(';') else {
+            return Err("Unterminated entity reference".to_string());
+        };
+        let entity = &rest[..end];
+        let ch = match entity {
+            "amp" => '&',
+            "lt" => '<',
+            "gt" => '>',
+            "quot" => '"',
+            "apos" => '\'',
+            _ => {
+                let code = match (entity.strip_prefix("#x"), entity.strip_prefix('#')) {
+                    (Some(hex), _) => u32::from_str_radix(hex, 16).ok(),
+                    (None, Some(decimal)) => decimal.parse().ok(),
+                    (None, None) => None,
+                };
+                code.and_then(char::from_u32)
+                    .ok_or_else(|| format!("Unknown entity reference &{entity};"))?
+            }
+        };
+        decoded.push(ch);
+        rest = &rest[end + 1..];
+    }
+    decoded.push_str(rest);
+    Ok(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn narrative(body: &str) -> String {
+        format!("<div xmlns=\"http://www.w3.org/1999/xhtml\">{body}</div>")
+    }
+
+    #[test]
+    fn test_parse_nested_elements() {
+        let root = parse("<div a='1'><p>Hi &amp; <b>bye</b></p><br/></div>").unwrap();
+
+        assert_eq!(root.name, "div");
+        assert_eq!(root.attribute("a"), Some("1"));
+        assert_eq!(root.children.len(), 2);
+        let XhtmlNode::Element(paragraph) = &root.children[0] else {
+            panic!("Expected an element");
+        };
+        assert_eq!(paragraph.children[0], XhtmlNode::Text("Hi & ".to_string()));
+    }
+
+    #[test]
+    fn test_parse_character_references() {
+        let root = parse("<p>&#233;&#x41;&quot;</p>").unwrap();
+        assert_eq!(root.children[0], XhtmlNode::Text("éA\"".to_string()));
+    }
+
+    #[test]
+    fn test_parse_rejects_malformed() {
+        assert!(parse("<div><p></div>").is_err());
+        assert!(parse("<div>").is_err());
+        assert!(parse("<div></div><div></div>").is_err());
+        assert!(parse("<div a=1></div>").is_err());
+        assert!(parse("<div>&nbsp;</div>").is_err());
+        assert!(parse("<div a='1' a='2'></div>").is_err());
+    }
+
+    #[test]
+    fn test_parse_nesting_limit() {
+        let nested = |depth: usize| format!("{}{}", "<b>".repeat(depth), "</b>".repeat(depth));
+        assert!(parse(&nested(MAX_DEPTH)).is_ok());
+        assert!(parse(&nested(100_000)).is_err());
+    }
+
+    #[test]
+    fn test_check_narrative_valid() {
+        let div = narrative("<p>Patient <a href=\"#x\">link</a></p><!-- note -->");
+        assert!(check_narrative(&div).is_ok());
+    }
+
+    #[test]
+    fn test_check_narrative_image_is_content() {
+        assert!(check_narrative(&narrative("<img src=\"#pic\" alt=\"\"/>")).is_ok());
+    }
+
+    #[test]
+    fn test_check_narrative_rejects_script() {
+        assert!(check_narrative(&narrative("<script>alert(1)</script>")).is_err());
+    }
+
+    #[test]
+    fn test_check_narrative_rejects_event_attributes() {
+        assert!(check_narrative(&narrative("<p onclick=\"x()\">Hi</p>")).is_err());
+        assert!(check_narrative(&narrative("<p href=\"#x\">Hi</p>")).is_err());
+    }
+
+    #[test]
+    fn test_check_narrative_requires_namespace_and_div() {
+        assert!(check_narrative("<div>Hi</div>").is_err());
+        assert!(check_narrative("<p xmlns=\"http://www.w3.org/1999/xhtml\">Hi</p>").is_err());
+    }
+
+    #[test]
+    fn test_check_narrative_requires_content() {
+        assert!(check_narrative(&narrative(" <p>\n\t</p> ")).is_err());
+    }
+}
@@ -4,6 +4,7 @@ use std::fmt;
 pub enum TokenKind {
     // Identifiers and literals
     Identifier,
+    DelimitedIdentifier, // `...`
     String,
     Number(f64),
     Integer(i64),
@@ -40,10 +41,9 @@ pub enum TokenKind {
     Pipe,         // |
 
     // Special
-    Dollar,   // $
-    Percent,  // %
-    At,       // @
-    BackTick, // `
+    Dollar,  // $
+    Percent, // %
+    At,      // @
 
     // Keywords
     Where,  // where
@@ -52,10 +52,85 @@ pub enum TokenKind {
     Any,    // any
     Exists, // exists
 
+    // Trivia, only kept by `Lexer::tokenize_lossless`
+    Whitespace,
+    LineComment,  // // ...
+    BlockComment, // /* ... */
+
     // End of input
     Eof,
 }
 
+impl TokenKind {
+    /// Whether the token is whitespace or a comment, which the parser ignores.
+    #[must_use]
+    pub const fn is_trivia(&self) -> bool {
+        matches!(
+            self,
+            Self::Whitespace | Self::LineComment | Self::BlockComment
+        )
+    }
+
+    /// Whether the token can be an identifier. Keywords are only keywords in
+    /// operator position, so `where`, `is` or `mod` name an element or function
+    /// when invoked, as in `Patient.where` or `name.exists()`.
+    #[must_use]
+    pub const fn is_identifier(&self) -> bool {
+        matches!(
+            self,
+            Self::Identifier
+                | Self::And
+                | Self::Or
+                | Self::Xor
+                | Self::Not
+                | Self::Is
+                | Self::As
+                | Self::Mod
+                | Self::Where
+                | Self::Select
+                | Self::All
+                | Self::Any
+                | Self::Exists
+        )
+    }
+}
+
+/// A byte range of the expression source, end exclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    #[must_use]
+    pub const fn new(start: usize, end: usize) -> Self {
+        Self { start, end }
+    }
+
+    /// The smallest span covering both `self` and `other`.
+    #[must_use]
+    pub const fn to(self, other: Self) -> Self {
+        let start = if self.start < other.start {
+            self.start
+        } else {
+            other.start
+        };
+        let end = if self.end > other.end {
+            self.end
+        } else {
+            other.end
+        };
+        Self { start, end }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}..{}", self.start, self.end)
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Token {
     pub kind: TokenKind,
@@ -69,16 +144,23 @@ impl Token {
         Self { kind, start, end }
     }
 
-    /// Get the text for this token from the original input
+    /// Get the text for this token from the original input, or an empty string
+    /// when the token doesn't fall on character boundaries of `input`
     #[must_use]
     pub fn text<'a>(&self, input: &'a str) -> &'a str {
-        &input[self.start..self.end]
+        input.get(self.start..self.end).unwrap_or_default()
+    }
+
+    /// Get the source range of this token
+    #[must_use]
+    pub const fn span(&self) -> Span {
+        Span::new(self.start, self.end)
     }
 
     /// Get the length of this token
     #[must_use]
     pub const fn length(&self) -> usize {
-        self.end - self.start
+        self.end.saturating_sub(self.start)
     }
 }
 
@@ -88,6 +170,9 @@ impl fmt::Display for Token {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self.kind {
             TokenKind::Identifier => write!(f, "identifier"),
+            TokenKind::DelimitedIdentifier => write!(f, "delimited identifier"),
+            TokenKind::Whitespace => write!(f, "whitespace"),
+            TokenKind::LineComment | TokenKind::BlockComment => write!(f, "comment"),
             TokenKind::String => write!(f, "string"),
             TokenKind::Number(n) => write!(f, "{n}"),
             TokenKind::Integer(i) => write!(f, "{i}"),
@@ -127,7 +212,6 @@ impl fmt::Display for Token {
             TokenKind::Any => write!(f, "any"),
             TokenKind::Exists => write!(f, "exists"),
             TokenKind::Eof => write!(f, "EOF"),
-            TokenKind::BackTick => write!(f, "`"),
         }
     }
 }
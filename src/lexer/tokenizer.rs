@@ -1,4 +1,5 @@
-use super::token::{Token, TokenKind};
+use super::token::{Span, Token, TokenKind};
+use crate::evaluator::error::{Error, ErrorCode};
 
 pub struct Lexer<'a> {
     input: &'a str,
@@ -11,19 +12,28 @@ impl<'a> Lexer<'a> {
         Self { input, position: 0 }
     }
 
+    /// The tokens of the input, without whitespace and comments.
+    ///
     /// # Errors
     ///
     /// Returns an error if the input contains invalid tokens or malformed syntax.
-    pub fn tokenize(mut self) -> Result<Vec<Token>, String> {
+    pub fn tokenize(self) -> Result<Vec<Token>, Error> {
+        let mut tokens = self.tokenize_lossless()?;
+        tokens.retain(|token| !token.kind.is_trivia());
+        Ok(tokens)
+    }
+
+    /// Every token of the input, including whitespace and comments as trivia
+    /// tokens, so that the tokens' text put together is the input.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the input contains invalid tokens or malformed syntax.
+    pub fn tokenize_lossless(mut self) -> Result<Vec<Token>, Error> {
         let mut tokens = Vec::new();
 
         while !self.is_at_end() {
-            self.skip_whitespace();
-            if self.is_at_end() {
-                break;
-            }
-
-            let token = self.next_token()?;
+            let token = self.next_trivia()?.map_or_else(|| self.next_token(), Ok)?;
             tokens.push(token);
         }
 
@@ -31,8 +41,36 @@ impl<'a> Lexer<'a> {
         Ok(tokens)
     }
 
+    // Whitespace, a `// line comment` or a `/* block comment */`
+    fn next_trivia(&mut self) -> Result<Option<Token>, Error> {
+        let start = self.position;
+        let rest = &self.input[self.position..];
+        let kind = if self.current_char().is_whitespace() {
+            self.skip_whitespace();
+            TokenKind::Whitespace
+        } else if rest.starts_with("//") {
+            // The line break ending the comment is whitespace
+            let length = rest.find(['\r', '\n']).unwrap_or(rest.len());
+            self.position += length;
+            TokenKind::LineComment
+        } else if let Some(body) = rest.strip_prefix("/*") {
+            let Some(length) = body.find("*/") else {
+                return Err(Error::new(
+                    ErrorCode::UnterminatedComment,
+                    "Unterminated block comment",
+                )
+                .with_span(Span::new(start, start + 2)));
+            };
+            self.position += length + 4;
+            TokenKind::BlockComment
+        } else {
+            return Ok(None);
+        };
+        Ok(Some(Token::new(kind, start, self.position)))
+    }
+
     #[allow(clippy::too_many_lines)]
-    fn next_token(&mut self) -> Result<Token, String> {
+    fn next_token(&mut self) -> Result<Token, Error> {
         let ch = self.current_char();
 
         match ch {
@@ -73,10 +111,11 @@ impl<'a> Lexer<'a> {
                     self.advance();
                     Ok(Token::new(TokenKind::NotEquals, start, self.position))
                 } else {
-                    Err(format!(
-                        "Unexpected character '!' at position {}",
-                        self.position
-                    ))
+                    Err(Error::new(
+                        ErrorCode::UnexpectedCharacter,
+                        "Unexpected character '!', expected '!='",
+                    )
+                    .with_span(Span::new(start, self.position)))
                 }
             }
             '<' => {
@@ -118,11 +157,7 @@ impl<'a> Lexer<'a> {
                 self.advance();
                 Ok(Token::new(TokenKind::LeftBracket, start, self.position))
             }
-            '`' => {
-                let start = self.position;
-                self.advance();
-                Ok(Token::new(TokenKind::BackTick, start, self.position))
-            }
+            '`' => self.parse_delimited_identifier(),
             ']' => {
                 let start = self.position;
                 self.advance();
@@ -152,34 +187,36 @@ impl<'a> Lexer<'a> {
             '\'' | '"' => self.parse_string(),
             _ if ch.is_ascii_digit() => self.parse_number(),
             _ if ch.is_ascii_alphabetic() || ch == '_' => Ok(self.parse_identifier_or_keyword()),
-            _ => Err(format!(
-                "Unexpected character '{ch}' at position {}",
-                self.position
-            )),
+            _ => Err(Error::new(
+                ErrorCode::UnexpectedCharacter,
+                format!("Unexpected character '{ch}'"),
+            )
+            .with_span(Span::new(self.position, self.position + ch.len_utf8()))),
         }
     }
 
     // TODO: Improve error checking.
     fn parse_date(&mut self) -> Token {
+        let start = self.position;
         // Consume @
         self.advance();
-        let start = self.position;
 
+        // Stop at anything that can't be part of a date or time, such as the dot of
+        // an invocation in `@2024-01-01.toString()`
         while !self.is_at_end()
-            && !self.current_char().is_whitespace()
-            && self.current_char() != ')'
-            && self.current_char() != ','
+            && (matches!(self.current_char(), '0'..='9' | '-' | ':' | 'T' | 'Z' | '+')
+                || (self.current_char() == '.' && self.peek_char().is_ascii_digit()))
         {
             self.advance();
         }
 
-        if self.position - start > 10 {
+        if self.position - start > 11 {
             return Token::new(TokenKind::ISODateTime, start, self.position);
         }
         Token::new(TokenKind::ISODate, start, self.position)
     }
 
-    fn parse_string(&mut self) -> Result<Token, String> {
+    fn parse_string(&mut self) -> Result<Token, Error> {
         let start = self.position;
         let quote_char = self.current_char();
         // Consume quote
@@ -189,23 +226,57 @@ impl<'a> Lexer<'a> {
             if self.current_char() == '\\' {
                 self.advance();
                 if self.is_at_end() {
-                    return Err("Unterminated string literal".to_string());
+                    return Err(unterminated_string(start, self.position));
                 }
             }
             self.advance();
         }
 
         if self.is_at_end() {
-            return Err("Unterminated string literal".to_string());
+            return Err(unterminated_string(start, self.position));
         }
 
         // Consume quote
         self.advance();
 
+        // Check the escapes now so that they are reported by the lexer
+        unescape(&self.input[start + 1..self.position - 1], start + 1)?;
         Ok(Token::new(TokenKind::String, start, self.position))
     }
 
-    fn parse_number(&mut self) -> Result<Token, String> {
+    // An identifier in backticks, which may contain any character and escapes
+    fn parse_delimited_identifier(&mut self) -> Result<Token, Error> {
+        let start = self.position;
+        // Consume backtick
+        self.advance();
+
+        while !self.is_at_end() && self.current_char() != '`' {
+            if self.current_char() == '\\' {
+                self.advance();
+            }
+            self.advance();
+        }
+
+        if self.is_at_end() {
+            return Err(Error::new(
+                ErrorCode::UnterminatedString,
+                "Unterminated delimited identifier",
+            )
+            .with_span(Span::new(start, self.position)));
+        }
+
+        // Consume backtick
+        self.advance();
+
+        unescape(&self.input[start + 1..self.position - 1], start + 1)?;
+        Ok(Token::new(
+            TokenKind::DelimitedIdentifier,
+            start,
+            self.position,
+        ))
+    }
+
+    fn parse_number(&mut self) -> Result<Token, Error> {
         let start = self.position;
         let mut value = String::new();
         let mut is_float = false;
@@ -214,7 +285,8 @@ impl<'a> Lexer<'a> {
             && (self.current_char().is_ascii_digit() || self.current_char() == '.')
         {
             if self.current_char() == '.' {
-                if is_float {
+                // A dot not followed by a digit starts an invocation, as in `1.toString()`
+                if is_float || !self.peek_char().is_ascii_digit() {
                     break;
                 }
                 is_float = true;
@@ -228,12 +300,12 @@ impl<'a> Lexer<'a> {
             value
                 .parse::<f64>()
                 .map(|n| Token::new(TokenKind::Number(n), start, end))
-                .map_err(|_| format!("Invalid number: {value}"))
+                .map_err(|_| invalid_number(&value, start, end))
         } else {
             value
                 .parse::<i64>()
                 .map(|i| Token::new(TokenKind::Integer(i), start, end))
-                .map_err(|_| format!("Invalid integer: {value}"))
+                .map_err(|_| invalid_number(&value, start, end))
         }
     }
 
@@ -273,6 +345,12 @@ impl<'a> Lexer<'a> {
         self.input[self.position..].chars().next().unwrap_or('\0')
     }
 
+    fn peek_char(&self) -> char {
+        let mut chars = self.input[self.position..].chars();
+        chars.next();
+        chars.next().unwrap_or('\0')
+    }
+
     fn advance(&mut self) -> Option<char> {
         if let Some(ch) = self.input[self.position..].chars().next() {
             self.position += ch.len_utf8();
@@ -292,6 +370,100 @@ impl<'a> Lexer<'a> {
         }
     }
 }
+/// Decode the escape sequences in a string literal or delimited identifier, given
+/// without its delimiters. `offset` is where `text` starts in the expression.
+///
+/// # Errors
+///
+/// Returns `ErrorCode::InvalidEscape` for an escape the specification doesn't
+/// define, a `\u` not followed by four hex digits and an unpaired surrogate.
+pub fn unescape(text: &str, offset: usize) -> Result<String, Error> {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    let mut position = offset;
+    while let Some(backslash) = rest.find('\\') {
+        decoded.push_str(&rest[..backslash]);
+        let start = position + backslash;
+        let sequence = &rest[backslash + 1..];
+        let (ch, length) = match sequence.chars().next() {
+            Some(quote @ ('\'' | '"' | '`' | '\\' | '/')) => (quote, 1),
+            Some('f') => ('\u{c}', 1),
+            Some('n') => ('\n', 1),
+            Some('r') => ('\r', 1),
+            Some('t') => ('\t', 1),
+            Some('u') => unescape_unicode(sequence, start)?,
+            Some(other) => {
+                return Err(invalid_escape(
+                    &format!("Invalid escape sequence '\\{other}'"),
+                    start,
+                    start + 1 + other.len_utf8(),
+                ));
+            }
+            None => {
+                return Err(invalid_escape(
+                    "Incomplete escape sequence",
+                    start,
+                    start + 1,
+                ));
+            }
+        };
+        decoded.push(ch);
+        rest = &sequence[length..];
+        position = start + 1 + length;
+    }
+    decoded.push_str(rest);
+    Ok(decoded)
+}
+
+// Helper: decode `uXXXX`, or a surrogate pair `uXXXX\uXXXX`, at the start of
+// `sequence`, returning the character and the length of the escape after `\`
+fn unescape_unicode(sequence: &str, start: usize) -> Result<(char, usize), Error> {
+    let unit = |at: usize| {
+        sequence
+            .get(at..at + 4)
+            .filter(|digits| digits.bytes().all(|byte| byte.is_ascii_hexdigit()))
+            .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+    };
+    let Some(code) = unit(1) else {
+        return Err(invalid_escape(
+            "Expected four hex digits after '\\u'",
+            start,
+            start + 2,
+        ));
+    };
+    if (0xD800..0xDC00).contains(&code)
+        && sequence.get(5..7) == Some("\\u")
+        && let Some(low) = unit(7)
+        && (0xDC00..0xE000).contains(&low)
+    {
+        let pair = 0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00);
+        if let Some(ch) = char::from_u32(pair) {
+            return Ok((ch, 11));
+        }
+    }
+    char::from_u32(code).map(|ch| (ch, 5)).ok_or_else(|| {
+        invalid_escape(
+            &format!("Unpaired surrogate '\\u{}'", &sequence[1..5]),
+            start,
+            start + 6,
+        )
+    })
+}
+
+fn invalid_escape(message: &str, start: usize, end: usize) -> Error {
+    Error::new(ErrorCode::InvalidEscape, message).with_span(Span::new(start, end))
+}
+
+fn unterminated_string(start: usize, end: usize) -> Error {
+    Error::new(ErrorCode::UnterminatedString, "Unterminated string literal")
+        .with_span(Span::new(start, end))
+}
+
+fn invalid_number(value: &str, start: usize, end: usize) -> Error {
+    Error::new(ErrorCode::InvalidNumber, format!("Invalid number: {value}"))
+        .with_span(Span::new(start, end))
+}
+
 // Usage example
 #[cfg(test)]
 mod tests {
@@ -317,6 +489,21 @@ mod tests {
         assert_eq!(tokens[4].length(), 6); // "family"
     }
 
+    #[test]
+    fn test_error_spans() {
+        let error = Lexer::new("name ! given").tokenize().unwrap_err();
+        assert_eq!(error.code, ErrorCode::UnexpectedCharacter);
+        assert_eq!(error.span, Some(Span::new(5, 6)));
+
+        let error = Lexer::new("name = 'Pet").tokenize().unwrap_err();
+        assert_eq!(error.code, ErrorCode::UnterminatedString);
+        assert_eq!(error.span, Some(Span::new(7, 11)));
+
+        // Spans are byte offsets, so they stay on character boundaries
+        let error = Lexer::new("'é' £").tokenize().unwrap_err();
+        assert_eq!(error.span, Some(Span::new(5, 7)));
+    }
+
     #[test]
     fn test_complex_expression() {
         let lexer = Lexer::new("Patient.name.where(use = 'official').family");
@@ -382,5 +569,105 @@ mod tests {
 
         assert_eq!(tokens[0].kind, TokenKind::Integer(123));
         assert_eq!(tokens[1].kind, TokenKind::Number(45.67));
+
+        let tokens = Lexer::new("1.toString()").tokenize().unwrap();
+        assert_eq!(tokens[0].kind, TokenKind::Integer(1));
+        assert_eq!(tokens[1].kind, TokenKind::Dot);
+    }
+
+    #[test]
+    fn test_unescape() {
+        assert_eq!(unescape(r"O\'Brien", 0).unwrap(), "O'Brien");
+        assert_eq!(
+            unescape(r#"\"\`\\\/\f\n\r\t"#, 0).unwrap(),
+            "\"`\\/\u{c}\n\r\t"
+        );
+        assert_eq!(unescape(r"caf\u00e9 \u00E9", 0).unwrap(), "café é");
+        assert_eq!(unescape(r"\uD83D\uDE00!", 0).unwrap(), "😀!");
+        assert_eq!(unescape("é\\n", 0).unwrap(), "é\n");
+    }
+
+    #[test]
+    fn test_invalid_escapes() {
+        for (text, span) in [
+            (r"a\qb", Span::new(11, 13)),
+            (r"\u12", Span::new(10, 12)),
+            (r"\u12G4", Span::new(10, 12)),
+            (r"x\uD83D", Span::new(11, 17)),
+            (r"\uDE00\uD83D", Span::new(10, 16)),
+            (r"\uD83Dx", Span::new(10, 16)),
+        ] {
+            let error = unescape(text, 10).unwrap_err();
+            assert_eq!(error.code, ErrorCode::InvalidEscape, "{text}");
+            assert_eq!(error.span, Some(span), "{text}");
+        }
+
+        let error = Lexer::new(r"name = 'a\é'").tokenize().unwrap_err();
+        assert_eq!(error.code, ErrorCode::InvalidEscape);
+        assert_eq!(error.span, Some(Span::new(9, 12)));
+    }
+
+    #[test]
+    fn test_delimited_identifiers() {
+        let tokens = Lexer::new(r"`given name`.`a\`b`").tokenize().unwrap();
+        assert_eq!(tokens[0].kind, TokenKind::DelimitedIdentifier);
+        assert_eq!(tokens[0].span(), Span::new(0, 12));
+        assert_eq!(tokens[1].kind, TokenKind::Dot);
+        assert_eq!(tokens[2].kind, TokenKind::DelimitedIdentifier);
+        assert_eq!(tokens[2].span(), Span::new(13, 19));
+
+        let error = Lexer::new("name.`given").tokenize().unwrap_err();
+        assert_eq!(error.code, ErrorCode::UnterminatedString);
+        assert_eq!(error.span, Some(Span::new(5, 11)));
+
+        let error = Lexer::new(r"`\x`").tokenize().unwrap_err();
+        assert_eq!(error.code, ErrorCode::InvalidEscape);
+    }
+
+    #[test]
+    fn test_comments() {
+        let input = "name // the name\n  /* given\n names */ .given / 2";
+        let kinds: Vec<_> = Lexer::new(input)
+            .tokenize()
+            .unwrap()
+            .into_iter()
+            .map(|token| token.kind)
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                TokenKind::Identifier,
+                TokenKind::Dot,
+                TokenKind::Identifier,
+                TokenKind::Divide,
+                TokenKind::Integer(2),
+                TokenKind::Eof
+            ]
+        );
+
+        let trivia: Vec<_> = Lexer::new(input)
+            .tokenize_lossless()
+            .unwrap()
+            .into_iter()
+            .filter(|token| token.kind.is_trivia() && token.kind != TokenKind::Whitespace)
+            .map(|token| token.text(input))
+            .collect();
+        assert_eq!(trivia, vec!["// the name", "/* given\n names */"]);
+    }
+
+    #[test]
+    fn test_comment_markers_in_strings() {
+        let input = "url = 'http://example.org/*x*/' // trailing";
+        let tokens = Lexer::new(input).tokenize().unwrap();
+        assert_eq!(tokens[2].kind, TokenKind::String);
+        assert_eq!(tokens[2].text(input), "'http://example.org/*x*/'");
+        assert_eq!(tokens[3].kind, TokenKind::Eof);
+    }
+
+    #[test]
+    fn test_unterminated_comment() {
+        let error = Lexer::new("name /* given").tokenize().unwrap_err();
+        assert_eq!(error.code, ErrorCode::UnterminatedComment);
+        assert_eq!(error.span, Some(Span::new(5, 7)));
     }
 }
@@ -30,12 +30,18 @@ pub mod evaluator;
 pub mod lexer;
 pub mod parser;
 
-use evaluator::engine::Evaluator;
+use evaluator::context::EvaluationContext;
+use evaluator::engine::{Evaluation, Evaluator};
 use lexer::tokenizer::Lexer;
-use parser::ast::FhirParser;
+use parser::ast::{FhirParser, Parsed};
+use parser::formatter::{self, FormatOptions};
+use parser::functions;
+use parser::grammar::ExprPool;
 
 // Re-export key types for public API
-pub use evaluator::error::Error;
+pub use evaluator::error::{Diagnostic, Error, ErrorCode, ErrorKind, Severity};
+pub use lexer::token::Span;
+pub use parser::builder::Path;
 pub use parser::grammar::Expression;
 pub use serde_json::Value;
 
@@ -77,15 +83,7 @@ use crate::parser::ast::Ast;
 /// Returns an error only for truly unrecoverable conditions like invalid syntax.
 /// Non-matching expressions return empty arrays as per `FHIRPath` specification.
 pub fn evaluate(expression: &str, resource: &Value) -> Result<Value, Error> {
-    // Tokenize the expression
-    let lexer = Lexer::new(expression);
-    let tokens = lexer
-        .tokenize()
-        .map_err(|e| Error::Parse(format!("Lexer error: {e}")))?;
-
-    // Parse tokens into AST
-    let parser = FhirParser::new(&tokens, expression);
-    let ast = parser.parse()?;
+    let ast = parse(expression)?;
 
     // Evaluate AST against resource
     let evaluator = Evaluator::new();
@@ -126,17 +124,92 @@ pub fn evaluate(expression: &str, resource: &Value) -> Result<Value, Error> {
 ///
 /// # Errors
 ///
-/// Returns an error if the expression contains invalid syntax or cannot be parsed.
+/// Returns an error if the expression contains invalid syntax or cannot be parsed,
+/// or for the first function call [`functions::check`] finds can't work, such
+/// as `name.frist()`. Evaluation relies on that check rather than repeating it.
 pub fn parse(expression: &str) -> Result<Ast, Error> {
+    let ast = parse_syntax(expression)?;
+    if let Some(error) = functions::check(&ast).into_iter().next() {
+        return Err(error);
+    }
+    Ok(ast)
+}
+
+// Helper: the tree of an expression, whichever functions it calls
+pub(crate) fn parse_syntax(expression: &str) -> Result<Ast, Error> {
     let lexer = Lexer::new(expression);
-    let tokens = lexer
-        .tokenize()
-        .map_err(|e| Error::Parse(format!("Lexer error: {e}")))?;
+    let tokens = lexer.tokenize()?;
 
     let parser = FhirParser::new(&tokens, expression);
     parser.parse()
 }
 
+/// Parse a `FHIRPath` expression, reporting every syntax error instead of only
+/// the first
+///
+/// Parts of the expression that couldn't be parsed are `Expression::Error` nodes
+/// of the returned AST. See [`FhirParser::parse_recovering`]. Every function call
+/// [`functions::check`] finds can't work is reported too.
+///
+/// # Examples
+///
+/// ```rust
+/// use fhirlighter::parse_with_diagnostics;
+///
+/// // A missing operand, an unclosed parenthesis, a missing argument and an
+/// // unknown function
+/// let parsed = parse_with_diagnostics("Patient.name.where(given = ).frist( = 'x'");
+/// assert_eq!(parsed.diagnostics.len(), 4);
+/// ```
+#[must_use]
+pub fn parse_with_diagnostics(expression: &str) -> Parsed {
+    match Lexer::new(expression).tokenize() {
+        Ok(tokens) => {
+            let mut parsed = FhirParser::new(&tokens, expression).parse_recovering();
+            let calls = functions::check(&parsed.ast);
+            parsed
+                .diagnostics
+                .extend(calls.into_iter().map(Diagnostic::from));
+            parsed
+        }
+        // Without tokens there is nothing to recover, so the whole input is in error
+        Err(error) => {
+            let mut expressions = ExprPool::new();
+            let span = Span::new(0, expression.len());
+            let start = expressions
+                .add_with_span(Expression::Error, span)
+                .unwrap_or_default();
+            Parsed {
+                ast: Ast { expressions, start },
+                diagnostics: vec![error.into()],
+            }
+        }
+    }
+}
+
+/// Format a `FHIRPath` expression in the canonical layout
+///
+/// Only whitespace changes, so the result parses to the same expression. Long
+/// expressions are broken over lines of at most 80 columns where possible; use
+/// [`formatter::format`] for other widths.
+///
+/// # Examples
+///
+/// ```rust
+/// use fhirlighter::format;
+///
+/// let formatted = format("Patient.name.where( use='official' )[0] // first")?;
+/// assert_eq!(formatted, "Patient.name.where(use = 'official')[0] // first");
+/// # Ok::<(), fhirlighter::Error>(())
+/// ```
+///
+/// # Errors
+///
+/// Returns an error if the expression contains invalid syntax.
+pub fn format(expression: &str) -> Result<String, Error> {
+    formatter::format(expression, &FormatOptions::default())
+}
+
 /// Evaluate a pre-parsed AST against a JSON resource
 ///
 /// This function takes a pre-parsed Expression AST and evaluates it against
@@ -159,6 +232,57 @@ pub fn evaluate_ast(ast: &Ast, resource: &Value) -> Result<Value, Error> {
     evaluator.evaluate(ast, resource)
 }
 
+/// Evaluate a `FHIRPath` expression within an [`EvaluationContext`]
+///
+/// Use this instead of [`evaluate`] when the expression needs more than the
+/// resource itself, such as a reference resolver for `resolve()`.
+///
+/// # Errors
+///
+/// Returns an error if the expression contains invalid syntax or evaluation fails.
+pub fn evaluate_with_context(
+    expression: &str,
+    context: &EvaluationContext,
+) -> Result<Value, Error> {
+    let ast = parse(expression)?;
+    let evaluator = Evaluator::new();
+    evaluator.evaluate_with_context(&ast, context)
+}
+
+/// Evaluate a `FHIRPath` expression within an [`EvaluationContext`], returning
+/// the warnings raised along with the result
+///
+/// # Examples
+///
+/// ```rust
+/// use fhirlighter::evaluator::context::EvaluationContext;
+/// use fhirlighter::{ErrorCode, evaluate_with_diagnostics};
+/// use serde_json::json;
+///
+/// let observation = json!({
+///     "resourceType": "Observation",
+///     "subject": {"reference": "Patient/unknown"}
+/// });
+/// let context = EvaluationContext::new(&observation);
+///
+/// let evaluation = evaluate_with_diagnostics("Observation.subject.resolve()", &context)?;
+/// assert_eq!(evaluation.value, json!([]));
+/// assert_eq!(evaluation.diagnostics[0].code, ErrorCode::UnresolvedReference);
+/// # Ok::<(), fhirlighter::Error>(())
+/// ```
+///
+/// # Errors
+///
+/// Returns an error if the expression contains invalid syntax or evaluation fails.
+pub fn evaluate_with_diagnostics(
+    expression: &str,
+    context: &EvaluationContext,
+) -> Result<Evaluation, Error> {
+    let ast = parse(expression)?;
+    let evaluator = Evaluator::new();
+    evaluator.evaluate_with_diagnostics(&ast, context)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
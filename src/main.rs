@@ -1,38 +1,343 @@
-mod evaluator;
-mod lexer;
-mod parser;
-
-use evaluator::engine::Evaluator;
-use lexer::token::Token;
-use lexer::tokenizer::Lexer;
-use parser::ast::FhirParser;
+use fhirlighter::Error;
+use fhirlighter::evaluator::engine::Evaluator;
+use fhirlighter::evaluator::types::StructureDefinitions;
+use fhirlighter::lexer::token::Token;
+use fhirlighter::lexer::tokenizer::Lexer;
+use fhirlighter::parser::ast::{Ast, FhirParser};
+use fhirlighter::parser::formatter::{FormatOptions, format};
+use fhirlighter::parser::functions;
+use fhirlighter::parser::grammar::ExprRef;
+use fhirlighter::parser::lint::{Linter, Rule, fix};
+use fhirlighter::parser::unparser::unparse;
 use serde_json::Value;
+use std::collections::HashMap;
 use std::env;
 use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
 use std::process;
+use std::sync::{Arc, Mutex};
 
 /// # Errors
 ///
 /// Returns an error if the expression contains invalid tokens or malformed syntax.
-pub fn parse_fhirpath_expression(expression: &str) -> Result<Vec<Token>, String> {
+pub fn parse_fhirpath_expression(expression: &str) -> Result<Vec<Token>, Error> {
     let lexer = Lexer::new(expression);
     lexer.tokenize()
 }
 
+// Print the error under the offending part of the expression and exit
+fn fail(error: &Error, expression: &str) -> ! {
+    eprintln!("{}", error.render(expression));
+    process::exit(1);
+}
+
+fn usage() -> ! {
+    println!("Require two args: ./fhirlighter <path> <file>");
+    println!("Or to format a path: ./fhirlighter fmt [--width <columns>] <path | ->");
+    println!("Or for the tree as JSON: ./fhirlighter ast <path | ->");
+    println!("Or for the path from JSON: ./fhirlighter ast --from-json <file | ->");
+    println!("Or for a Graphviz graph of the tree: ./fhirlighter ast --dot <path | -> [file]");
+    println!(
+        "Or to lint: ./fhirlighter lint [--rule <name>]... [--disable <name>]... [--model <file>]... [--type <name>] [--fix] <--expr <path> | file | directory | ->"
+    );
+    process::exit(1);
+}
+
+// Print the path in the canonical layout, reading it from stdin when it is `-`
+fn format_command(args: &[String]) {
+    let mut options = FormatOptions::default();
+    let mut path = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--width" {
+            options.width = args
+                .next()
+                .and_then(|width| width.parse().ok())
+                .unwrap_or_else(|| usage());
+        } else if path.replace(arg).is_some() {
+            usage();
+        }
+    }
+    let Some(path) = path else { usage() };
+
+    let source = read_stdin_for(path);
+    let formatted = format(&source, &options).unwrap_or_else(|error| fail(&error, &source));
+    println!("{formatted}");
+}
+
+// The argument itself, or what's on stdin when it is `-`
+fn read_stdin_for(arg: &str) -> String {
+    let mut source = arg.to_string();
+    if arg == "-" {
+        source.clear();
+        if let Err(error) = io::stdin().read_to_string(&mut source) {
+            eprintln!("Couldn't read stdin: {error}");
+            process::exit(1);
+        }
+    }
+    source
+}
+
+// Print the tree of the path as JSON, or with `--from-json` the path of a tree
+fn ast_command(args: &[String]) {
+    match args {
+        [flag, file] if flag == "--from-json" => {
+            let contents = if file == "-" {
+                read_stdin_for(file)
+            } else {
+                fs::read_to_string(file).unwrap_or_else(|error| {
+                    eprintln!("Couldn't read {file}: {error}");
+                    process::exit(1);
+                })
+            };
+            let json: Value = serde_json::from_str(&contents).unwrap_or_else(|error| {
+                eprintln!("Invalid JSON: {error}");
+                process::exit(1);
+            });
+            let ast = Ast::from_json(&json).unwrap_or_else(|error| {
+                eprintln!("{error}");
+                process::exit(1);
+            });
+            println!("{}", unparse(&ast));
+        }
+        [flag, path, file @ ..] if flag == "--dot" && file.len() <= 1 => {
+            let source = read_stdin_for(path);
+            let ast = fhirlighter::parse(&source).unwrap_or_else(|error| fail(&error, &source));
+            let Some(file) = file.first() else {
+                print!("{}", ast.to_dot());
+                return;
+            };
+            // Annotate each node with the values it evaluated to against the file
+            let contents = fs::read_to_string(file).unwrap_or_else(|error| {
+                eprintln!("Couldn't read {file}: {error}");
+                process::exit(1);
+            });
+            let data: Value = serde_json::from_str(&contents).unwrap_or_else(|error| {
+                eprintln!("Invalid JSON: {error}");
+                process::exit(1);
+            });
+            let values: Arc<Mutex<HashMap<ExprRef, Vec<Value>>>> = Arc::default();
+            let observed = Arc::clone(&values);
+            let evaluator = Evaluator::new().with_observer(move |expr_ref, value| {
+                observed
+                    .lock()
+                    .expect("the observer doesn't panic")
+                    .entry(expr_ref)
+                    .or_default()
+                    .push(value.clone());
+            });
+            // The graph shows the values up to a failure, so still print it
+            if let Err(error) = evaluator.evaluate(&ast, &data) {
+                eprintln!("{}", error.render(&source));
+            }
+            let dot = ast.to_dot_with_values(&values.lock().expect("the observer doesn't panic"));
+            print!("{dot}");
+        }
+        [path] if !path.starts_with("--") => {
+            let source = read_stdin_for(path);
+            let ast = fhirlighter::parse(&source).unwrap_or_else(|error| fail(&error, &source));
+            let json = serde_json::to_string_pretty(&ast.to_json()).expect("JSON values serialize");
+            println!("{json}");
+        }
+        _ => usage(),
+    }
+}
+
+// Report the lints of a path given with `--expr`, a file, each `.fhirpath` file
+// under a directory or stdin, and with `--fix` rewrite files with their fixes
+// applied
+fn lint_command(args: &[String]) {
+    let mut rules: Option<Vec<Rule>> = None;
+    let mut disabled = Vec::new();
+    let mut model = StructureDefinitions::new();
+    let mut root = None;
+    let mut apply = false;
+    let mut target = None;
+    let rule = |name: Option<&String>| -> Rule {
+        let name = name.unwrap_or_else(|| usage());
+        name.parse().unwrap_or_else(|error| {
+            eprintln!("{error}");
+            process::exit(1);
+        })
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--rule" => rules.get_or_insert_with(Vec::new).push(rule(args.next())),
+            "--disable" => disabled.push(rule(args.next())),
+            "--model" => {
+                let file = args.next().unwrap_or_else(|| usage());
+                let definitions = read_json(file);
+                if let Err(error) = model.insert(&definitions) {
+                    eprintln!("{file}: {error}");
+                    process::exit(1);
+                }
+            }
+            "--type" => root = Some(args.next().unwrap_or_else(|| usage())),
+            "--fix" => apply = true,
+            "--expr" => {
+                let expression = args.next().unwrap_or_else(|| usage());
+                if target.replace(Target::Expression(expression)).is_some() {
+                    usage();
+                }
+            }
+            _ if target.replace(Target::Path(arg)).is_none() => {}
+            _ => usage(),
+        }
+    }
+    let Some(target) = target else { usage() };
+
+    let mut linter = Linter::new().with_model(&model);
+    if let Some(rules) = rules {
+        linter = linter.with_rules(rules);
+    }
+    for rule in disabled {
+        linter = linter.disable(rule);
+    }
+    if let Some(root) = root {
+        linter = linter.with_root(root);
+    }
+
+    let mut problems = 0;
+    for (file, source) in lint_sources(target) {
+        let name = file.as_ref().map_or_else(
+            || "<expression>".to_string(),
+            |file| file.display().to_string(),
+        );
+        let lints = match linter.lint(&source) {
+            Ok(lints) => lints,
+            Err(error) => {
+                eprintln!("{name}\n{}\n", error.render(&source));
+                problems += 1;
+                continue;
+            }
+        };
+        if apply && lints.iter().any(|lint| !lint.fix.is_empty()) {
+            let fixed = fix(&source, &lints);
+            match &file {
+                Some(file) => fs::write(file, &fixed).unwrap_or_else(|error| {
+                    eprintln!("Couldn't write {name}: {error}");
+                    process::exit(1);
+                }),
+                None => println!("{fixed}"),
+            }
+            // Report what is left, such as fixes that overlapped
+            let remaining = linter.lint(&fixed).unwrap_or_default();
+            for lint in &remaining {
+                println!("{name}\n{}\n", lint.render(&fixed));
+            }
+            problems += remaining.len();
+            continue;
+        }
+        for lint in &lints {
+            println!("{name}\n{}\n", lint.render(&source));
+        }
+        problems += lints.len();
+    }
+    if problems > 0 {
+        process::exit(1);
+    }
+}
+
+// What to lint: an expression given inline, or a file, directory or `-`
+#[derive(Clone, Copy)]
+enum Target<'a> {
+    Expression(&'a str),
+    Path(&'a str),
+}
+
+// Helper: each source to lint, with the file it came from
+fn lint_sources(target: Target) -> Vec<(Option<PathBuf>, String)> {
+    let target = match target {
+        Target::Expression(expression) => return vec![(None, expression.to_string())],
+        Target::Path("-") => return vec![(None, read_stdin_for("-"))],
+        Target::Path(target) => target,
+    };
+    let path = Path::new(target);
+    let mut files = Vec::new();
+    if !path.exists() {
+        eprintln!("Couldn't read {target}: No such file or directory");
+        process::exit(1);
+    } else if path.is_dir() {
+        collect_expressions(path, &mut files);
+        files.sort();
+    } else {
+        files.push(path.to_path_buf());
+    }
+    files
+        .into_iter()
+        .map(|file| {
+            let source = fs::read_to_string(&file).unwrap_or_else(|error| {
+                eprintln!("Couldn't read {}: {error}", file.display());
+                process::exit(1);
+            });
+            (Some(file), source)
+        })
+        .collect()
+}
+
+// Helper: the `.fhirpath` files under a directory, recursively
+fn collect_expressions(directory: &Path, files: &mut Vec<PathBuf>) {
+    let entries = fs::read_dir(directory).unwrap_or_else(|error| {
+        eprintln!("Couldn't read {}: {error}", directory.display());
+        process::exit(1);
+    });
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            collect_expressions(&path, files);
+        } else if path
+            .extension()
+            .is_some_and(|extension| extension == "fhirpath")
+        {
+            files.push(path);
+        }
+    }
+}
+
+// Helper: the JSON in a file, exiting if it can't be read
+fn read_json(file: &str) -> Value {
+    let contents = fs::read_to_string(file).unwrap_or_else(|error| {
+        eprintln!("Couldn't read {file}: {error}");
+        process::exit(1);
+    });
+    serde_json::from_str(&contents).unwrap_or_else(|error| {
+        eprintln!("Invalid JSON in {file}: {error}");
+        process::exit(1);
+    })
+}
+
 // Example main function demonstrating usage
 fn main() {
     let args: Vec<String> = env::args().collect();
+    if args.get(1).is_some_and(|command| command == "fmt") {
+        format_command(&args[2..]);
+        return;
+    }
+    if args.get(1).is_some_and(|command| command == "ast") {
+        ast_command(&args[2..]);
+        return;
+    }
+    if args.get(1).is_some_and(|command| command == "lint") {
+        lint_command(&args[2..]);
+        return;
+    }
     if args.len() != 3 {
-        println!("Require two args: ./fhirlighter <path> <file>");
-        process::exit(1);
+        usage();
     }
     let test = &args[1];
-    let expression = parse_fhirpath_expression(test).unwrap();
+    let expression = parse_fhirpath_expression(test).unwrap_or_else(|error| fail(&error, test));
     let parser = FhirParser::new(&expression, test);
-    let compiled_expression = parser.parse().unwrap();
+    let compiled_expression = parser.parse().unwrap_or_else(|error| fail(&error, test));
+    if let Some(error) = functions::check(&compiled_expression).into_iter().next() {
+        fail(&error, test);
+    }
     let contents = fs::read_to_string(&args[2]).unwrap();
     let data: Value = serde_json::from_str(&contents).unwrap();
-    let evaluator = Evaluator::new();
-    let result = evaluator.evaluate(&compiled_expression, &data).unwrap();
+    let evaluator = Evaluator::new().with_tracer(|name, value| eprintln!("{name}: {value}"));
+    let result = evaluator
+        .evaluate(&compiled_expression, &data)
+        .unwrap_or_else(|error| fail(&error, test));
     println!("Result: {result}");
 }
@@ -1,22 +1,55 @@
 use super::grammar::{BinaryOperator, ExprPool, ExprRef, Expression};
-use crate::evaluator::error::Error;
-use crate::lexer::token::{Token, TokenKind};
+use crate::evaluator::error::{Diagnostic, Error, ErrorCode, ErrorKind};
+use crate::lexer::token::{Span, Token, TokenKind};
+use crate::lexer::tokenizer::unescape;
 use time::format_description::well_known::Iso8601;
 use time::{Date, PrimitiveDateTime};
 
+/// How deeply expressions may nest.
+///
+/// Each invocation, index or operator applied to an expression counts a level, as
+/// does each argument and operand. Evaluation recurses over the tree, so this
+/// bounds its stack use.
+pub const MAX_DEPTH: usize = 128;
+
 pub struct FhirParser<'a> {
     tokens: &'a Vec<Token>,
     input: &'a str,
     position: usize,
     str_position: usize,
+    depth: usize,
+    // Opening delimiters not yet closed, innermost last
+    delimiters: Vec<Token>,
+    // Set by `parse_recovering`, which collects errors in `diagnostics`
+    recovering: bool,
+    diagnostics: Vec<Diagnostic>,
     ast: ExprPool,
 }
 
+#[derive(Debug)]
 pub struct Ast {
     pub expressions: ExprPool,
     pub start: ExprRef,
 }
 
+impl Ast {
+    /// Whether both ASTs are the same tree, whatever their spans and the order
+    /// of their pools.
+    #[must_use]
+    pub fn equivalent(&self, other: &Self) -> bool {
+        self.expressions
+            .same_tree(self.start, &other.expressions, other.start)
+    }
+}
+
+/// The result of [`FhirParser::parse_recovering`]: an AST in which the parts
+/// that couldn't be parsed are `Expression::Error` nodes, and an error for each.
+#[derive(Debug)]
+pub struct Parsed {
+    pub ast: Ast,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
 impl<'a> FhirParser<'a> {
     #[must_use]
     pub const fn new(tokens: &'a Vec<Token>, input: &'a str) -> Self {
@@ -25,19 +58,61 @@ impl<'a> FhirParser<'a> {
             input,
             position: 0,
             str_position: 0, // end of current token
+            depth: 0,
+            delimiters: Vec::new(),
+            recovering: false,
+            diagnostics: Vec::new(),
             ast: ExprPool::new(),
         }
     }
 
+    /// Parse into `pool` rather than a new one, to reuse its memory across
+    /// parses. The pool is cleared first.
+    ///
+    /// ```rust
+    /// use fhirlighter::lexer::tokenizer::Lexer;
+    /// use fhirlighter::parser::ast::FhirParser;
+    /// use fhirlighter::parser::grammar::ExprPool;
+    ///
+    /// let mut pool = ExprPool::new();
+    /// for expression in ["Patient.name", "Patient.gender = 'male'"] {
+    ///     let tokens = Lexer::new(expression).tokenize()?;
+    ///     let ast = FhirParser::new(&tokens, expression).with_pool(pool).parse()?;
+    ///     // ... use the AST, then take back its pool for the next one
+    ///     pool = ast.expressions;
+    /// }
+    /// # Ok::<(), fhirlighter::Error>(())
+    /// ```
+    #[must_use]
+    pub fn with_pool(mut self, mut pool: ExprPool) -> Self {
+        pool.clear();
+        self.ast = pool;
+        self
+    }
+
+    // Make room for the tree up front, as there is about one expression per token
+    fn reserve(&mut self) {
+        self.ast.reserve(self.tokens.len());
+    }
+
     /// Get the text for a token from the original input
     fn token_text(&self, token: &Token) -> &str {
         token.text(self.input)
     }
 
+    /// Parse the tokens as a single expression, which must take up all of them.
+    ///
     /// # Errors
-    /// Parsing error.
+    /// Parsing error, including input left over after the expression and
+    /// parentheses, brackets or backticks that aren't balanced.
     pub fn parse(mut self) -> Result<Ast, Error> {
-        let start = self.parse_expression()?;
+        self.reserve();
+        let start = self
+            .parse_expression()
+            .map_err(|error| self.unclosed_delimiter().unwrap_or(error))?;
+        if !self.is_at_end() {
+            return Err(self.trailing_input());
+        }
 
         Ok(Ast {
             expressions: self.ast,
@@ -45,13 +120,53 @@ impl<'a> FhirParser<'a> {
         })
     }
 
+    /// Parse the tokens, carrying on past errors to report every one of them.
+    ///
+    /// A term that can't be parsed becomes an `Expression::Error` node and the
+    /// tokens up to the next `,`, closing delimiter or operator are skipped, as is
+    /// anything unexpected following an expression. Only exceeding a resource limit
+    /// stops parsing, leaving a single error node for the whole input.
+    #[must_use]
+    pub fn parse_recovering(mut self) -> Parsed {
+        self.recovering = true;
+        self.reserve();
+        let start = match self.parse_expression() {
+            Ok(start) => start,
+            Err(error) => {
+                self.report(error);
+                self.ast.clear();
+                let span = Span::new(0, self.input.len());
+                self.ast
+                    .add_with_span(Expression::Error, span)
+                    .unwrap_or_default()
+            }
+        };
+
+        Parsed {
+            ast: Ast {
+                expressions: self.ast,
+                start,
+            },
+            diagnostics: self.diagnostics,
+        }
+    }
+
     fn parse_expression(&mut self) -> Result<ExprRef, Error> {
-        let mut expression = self.parse_term()?;
+        let depth = self.depth;
+        let result = self.parse_nested_expression();
+        self.depth = depth;
+        result
+    }
+
+    fn parse_nested_expression(&mut self) -> Result<ExprRef, Error> {
+        self.descend()?;
+        let mut expression = self.recover(Self::parse_term)?;
         loop {
+            self.descend()?;
             // If we have expression/term . invocation/identifier/...
             if self.peek().kind == TokenKind::Dot {
                 self.advance();
-                let invocation = self.parse_invocation()?;
+                let invocation = self.recover(Self::parse_invocation)?;
                 let invocation_expr = self.ast.get(invocation).clone();
                 match invocation_expr {
                     Expression::FunctionCall {
@@ -62,38 +177,57 @@ impl<'a> FhirParser<'a> {
                         expression = self.ast.set_function_object(invocation, expression);
                     }
                     Expression::Identifier(member) => {
-                        expression = self.ast.add(Expression::MemberAccess {
-                            object: expression,
-                            member: member.to_string(),
-                        })?;
+                        let span = self.span(expression).to(self.span(invocation));
+                        expression = self.ast.add_with_span(
+                            Expression::MemberAccess {
+                                object: expression,
+                                member: member.clone(),
+                            },
+                            span,
+                        )?;
+                    }
+                    Expression::Error => {
+                        let span = self.span(expression).to(self.span(invocation));
+                        expression = self.ast.add_with_span(Expression::Error, span)?;
                     }
 
                     _ => {
-                        return Err(Error::Parse(
-                            "Couldn't parse invocation. Received".to_string(),
-                        ));
+                        return Err(Error::new(
+                            ErrorCode::UnexpectedToken,
+                            "Couldn't parse invocation",
+                        )
+                        .with_span(self.span(invocation)));
                     }
                 }
             // LeftBracket denotes index of e.g. we have name[0]
             } else if self.peek().kind == TokenKind::LeftBracket {
-                self.advance();
-                while !self.match_tokens(vec![TokenKind::RightBracket]) {
-                    let index = self.parse_term()?;
-                    expression = self.ast.add(Expression::Index {
+                self.open_delimiter();
+                let index = self.parse_expression()?;
+                let end = self.close_delimiter(TokenKind::RightBracket)?;
+                let span = self.span(expression).to(end.span());
+                expression = self.ast.add_with_span(
+                    Expression::Index {
                         object: expression,
                         index,
-                    })?;
-                }
+                    },
+                    span,
+                )?;
             } else if let Some(operator) = BinaryOperator::from_token(&self.peek().kind) {
                 self.advance();
                 let rhs = self.parse_expression()?;
-                expression = self.ast.add({
+                let span = self.span(expression).to(self.span(rhs));
+                expression = self.ast.add_with_span(
                     Expression::BinaryOperation {
                         operator,
                         lhs: expression,
                         rhs,
-                    }
-                })?;
+                    },
+                    span,
+                )?;
+            } else if self.recovering && !self.at_boundary() {
+                let error = self.trailing_input();
+                self.report(error);
+                self.synchronize();
             } else {
                 break;
             }
@@ -103,101 +237,341 @@ impl<'a> FhirParser<'a> {
     }
 
     fn parse_term(&mut self) -> Result<ExprRef, Error> {
-        match self.peek().kind {
+        let token = self.peek();
+        let expression = match token.kind {
             TokenKind::String => {
-                let token = self.advance();
+                self.advance();
                 let text = self.token_text(&token);
-                // Strip the surrounding quotes from the string literal
-                let text_without_quotes = &text[1..text.len() - 1];
-                Ok(self
-                    .ast
-                    .add(Expression::String(text_without_quotes.to_string())))?
+                Expression::String(unescape(strip_quotes(text), token.start + 1)?)
             }
             TokenKind::Integer(value) => {
                 self.advance();
-                Ok(self.ast.add(Expression::Integer(value)))?
+                Expression::Integer(value)
             }
             TokenKind::Number(value) => {
                 self.advance();
-                Ok(self.ast.add(Expression::Number(value)))?
+                Expression::Number(value)
             }
             TokenKind::Boolean(value) => {
                 self.advance();
-                Ok(self.ast.add(Expression::Boolean(value)))?
+                Expression::Boolean(value)
             }
             TokenKind::ISODateTime => {
-                let datetime_token = self.advance();
-                let iso_date =
-                    PrimitiveDateTime::parse(self.token_text(&datetime_token), &Iso8601::DEFAULT)
-                        .unwrap();
-                Ok(self.ast.add(Expression::ISODateTime(iso_date)))?
+                self.advance();
+                let text = self.token_text(&token);
+                let iso_date = PrimitiveDateTime::parse(strip_at(text), &Iso8601::DEFAULT)
+                    .map_err(|_| invalid_date(text, token))?;
+                Expression::ISODateTime(iso_date)
             }
             TokenKind::ISODate => {
-                let date_token = self.advance();
-                let iso_date = Date::parse(self.token_text(&date_token), &Iso8601::DATE).unwrap();
-                Ok(self.ast.add(Expression::ISODate(iso_date)))?
+                self.advance();
+                let text = self.token_text(&token);
+                let iso_date = Date::parse(strip_at(text), &Iso8601::DATE)
+                    .map_err(|_| invalid_date(text, token))?;
+                Expression::ISODate(iso_date)
+            }
+            kind if kind.is_identifier() || kind == TokenKind::DelimitedIdentifier => {
+                return self.parse_invocation();
             }
-            TokenKind::Identifier | TokenKind::BackTick => self.parse_invocation(),
+            TokenKind::Percent => return self.parse_external_constant(),
+            TokenKind::LeftParen => return self.parse_parenthesized(),
             _ => {
-                let token = self.peek();
-                Err(Error::Parse(format!(
-                    "Couldn't parse term. Received: {token}"
-                )))
+                return Err(Error::new(
+                    ErrorCode::UnexpectedToken,
+                    format!("Couldn't parse term. Received: {token}"),
+                )
+                .with_span(token.span()));
             }
-        }
+        };
+        self.ast.add_with_span(expression, token.span())
     }
 
     fn parse_invocation(&mut self) -> Result<ExprRef, Error> {
-        if self.peek().kind == TokenKind::BackTick {
-            self.advance();
-        }
-
+        let start = self.peek().span();
         let identifier = self.parse_identifier()?;
 
-        if self.peek().kind == TokenKind::BackTick {
-            self.advance();
-        }
         // If we have a function
         if self.peek().kind == TokenKind::LeftParen {
-            // Consume the left paren.
-            self.advance();
+            self.open_delimiter();
             let mut arguments = Vec::new();
-            // If the function parameters are non-empty.
-            while self.peek().kind != TokenKind::RightParen {
-                let expression = self.parse_expression()?;
-                arguments.push(expression);
-                // If we hit a comma, skip and loop for the next argument.
-                if self.peek().kind == TokenKind::Comma {
-                    self.advance();
+            // If the function parameters are non-empty, they are separated by commas
+            if !self.check(&TokenKind::RightParen) {
+                loop {
+                    arguments.push(self.parse_expression()?);
+                    if !self.match_tokens(vec![TokenKind::Comma]) {
+                        break;
+                    }
                 }
             }
 
-            // Consume the right paren.
-            self.advance();
-            let function = self.ast.add(Expression::FunctionCall {
-                object: None,
-                function: identifier,
-                arguments,
-            });
+            let end = self.close_delimiter(TokenKind::RightParen)?.span();
+            let function = self.ast.add_with_span(
+                Expression::FunctionCall {
+                    object: None,
+                    function: identifier,
+                    arguments,
+                },
+                start.to(end),
+            );
             return Ok(function)?;
         }
 
         Ok(identifier)
     }
 
+    // A parenthesized expression, whose span takes in the parentheses
+    fn parse_parenthesized(&mut self) -> Result<ExprRef, Error> {
+        let open = self.open_delimiter();
+        let expression = self.parse_expression()?;
+        let close = self.close_delimiter(TokenKind::RightParen)?;
+        self.ast.set_span(expression, open.span().to(close.span()));
+        Ok(expression)
+    }
+
+    fn parse_external_constant(&mut self) -> Result<ExprRef, Error> {
+        // Consume the percent sign.
+        let start = self.advance().span();
+        let name = match self.peek().kind {
+            kind if kind.is_identifier() => {
+                let token = self.advance();
+                self.token_text(&token).to_string()
+            }
+            // Quoted and delimited names such as `vs-name`
+            TokenKind::String | TokenKind::DelimitedIdentifier => {
+                let token = self.advance();
+                unescape(strip_quotes(self.token_text(&token)), token.start + 1)?
+            }
+            _ => {
+                let token = self.peek();
+                return Err(Error::new(
+                    ErrorCode::UnexpectedToken,
+                    format!("Couldn't parse external constant. Received: {token}"),
+                )
+                .with_span(token.span()));
+            }
+        };
+        let span = start.to(self.previous().span());
+        self.ast
+            .add_with_span(Expression::ExternalConstant(name), span)
+    }
+
     fn parse_identifier(&mut self) -> Result<ExprRef, Error> {
-        if self.peek().kind == TokenKind::Identifier {
-            let token = self.advance();
+        let token = self.peek();
+        if token.kind.is_identifier() {
+            self.advance();
             let text = self.token_text(&token);
-            Ok(self.ast.add(Expression::Identifier(text.to_string())))?
+            self.ast
+                .add_with_span(Expression::Identifier(text.to_string()), token.span())
+        } else if token.kind == TokenKind::DelimitedIdentifier {
+            self.advance();
+            let name = unescape(strip_quotes(self.token_text(&token)), token.start + 1)?;
+            self.ast
+                .add_with_span(Expression::Identifier(name), token.span())
         } else {
-            let token = self.peek();
-            let position = self.position;
-            let kind = self.peek().kind;
-            Err(Error::Parse(format!(
-                "Couldn't parse identifier. Received: {token}. Position: {position}. Kind: {kind:?}"
-            )))
+            Err(Error::new(
+                ErrorCode::UnexpectedToken,
+                format!("Couldn't parse identifier. Received: {token}"),
+            )
+            .with_span(token.span()))
+        }
+    }
+
+    // Helper: consume an opening delimiter, to be matched by `close_delimiter`
+    fn open_delimiter(&mut self) -> Token {
+        let open = self.advance();
+        self.delimiters.push(open);
+        open
+    }
+
+    // Helper: consume the delimiter closing the innermost open one. When
+    // recovering, skip ahead to it instead of failing.
+    fn close_delimiter(&mut self, closing: TokenKind) -> Result<Token, Error> {
+        if self.check(&closing) {
+            self.delimiters.pop();
+            return Ok(self.advance());
+        }
+        let token = self.peek();
+        let error = self
+            .unclosed_delimiter()
+            .unwrap_or_else(|| self.trailing_input());
+        if !self.recovering {
+            return Err(error);
+        }
+
+        self.report(error);
+        let open = self.delimiters.pop();
+        // Skip to the closing delimiter, unless one of an enclosing delimiter comes first
+        loop {
+            self.synchronize();
+            if self.check(&closing) {
+                return Ok(self.advance());
+            }
+            if self.is_at_end() || self.closes_open_delimiter() {
+                return Ok(open.unwrap_or(token));
+            }
+            self.advance();
+        }
+    }
+
+    // Helper: when recovering, turn a failure of `parse` into an error node
+    // covering the tokens skipped to get past it
+    fn recover(
+        &mut self,
+        parse: fn(&mut Self) -> Result<ExprRef, Error>,
+    ) -> Result<ExprRef, Error> {
+        let delimiters = self.delimiters.len();
+        let start = self.peek().span();
+        match parse(self) {
+            Err(error) if self.recovering && error.kind() != ErrorKind::ResourceLimit => {
+                let error = self.unclosed_delimiter().unwrap_or(error);
+                self.report(error);
+                self.delimiters.truncate(delimiters);
+                let skipped = self.synchronize();
+                let end = skipped.unwrap_or_else(|| Span::new(start.start, start.start));
+                self.ast.add_with_span(Expression::Error, start.to(end))
+            }
+            result => result,
+        }
+    }
+
+    // Helper: record an error when recovering, once per code and span
+    fn report(&mut self, error: Error) {
+        let diagnostic = Diagnostic::from(error);
+        if !self.diagnostics.contains(&diagnostic) {
+            self.diagnostics.push(diagnostic);
+        }
+    }
+
+    // Helper: skip tokens, and whatever they open, up to a point parsing can
+    // resume from. Returns the span of the tokens skipped, if any.
+    fn synchronize(&mut self) -> Option<Span> {
+        let mut skipped: Option<Span> = None;
+        let mut nesting = 0usize;
+        while !self.is_at_end() && (nesting > 0 || !self.at_sync_point()) {
+            let token = self.advance();
+            match token.kind {
+                TokenKind::LeftParen | TokenKind::LeftBracket => nesting += 1,
+                TokenKind::RightParen | TokenKind::RightBracket => {
+                    nesting = nesting.saturating_sub(1);
+                }
+                _ => {}
+            }
+            skipped = Some(skipped.map_or_else(|| token.span(), |span| span.to(token.span())));
         }
+        skipped
+    }
+
+    // Helper: whether the next token can follow a complete expression here
+    fn at_boundary(&self) -> bool {
+        self.is_at_end()
+            || (self.check(&TokenKind::Comma)
+                && self
+                    .delimiters
+                    .last()
+                    .is_some_and(|open| self.is_call(open)))
+            || self.closes_open_delimiter()
+    }
+
+    // Helper: whether parsing can resume at the next token
+    fn at_sync_point(&self) -> bool {
+        self.at_boundary() || BinaryOperator::from_token(&self.peek().kind).is_some()
+    }
+
+    // Helper: whether the next token closes a delimiter that is open
+    fn closes_open_delimiter(&self) -> bool {
+        let opening = match self.peek().kind {
+            TokenKind::RightParen => TokenKind::LeftParen,
+            TokenKind::RightBracket => TokenKind::LeftBracket,
+            _ => return false,
+        };
+        self.delimiters.iter().any(|open| open.kind == opening)
+    }
+
+    // Helper: whether an opening delimiter starts the arguments of a function
+    // call, which follow its name, rather than a parenthesized expression
+    fn is_call(&self, open: &Token) -> bool {
+        let index = self
+            .tokens
+            .partition_point(|token| token.start < open.start);
+        index
+            .checked_sub(1)
+            .and_then(|index| self.tokens.get(index))
+            .is_some_and(|name| {
+                open.kind == TokenKind::LeftParen
+                    && (name.kind.is_identifier() || name.kind == TokenKind::DelimitedIdentifier)
+            })
+    }
+
+    // Helper: at the end of input, the innermost delimiter left open
+    fn unclosed_delimiter(&self) -> Option<Error> {
+        if !self.is_at_end() {
+            return None;
+        }
+        let open = self.delimiters.last()?;
+        let closing = if open.kind == TokenKind::LeftParen {
+            ")"
+        } else {
+            "]"
+        };
+        Some(
+            Error::new(
+                ErrorCode::UnbalancedDelimiter,
+                format!("Unclosed '{open}', expected '{closing}' before the end of the expression"),
+            )
+            .with_span(open.span()),
+        )
+    }
+
+    // Helper: the error for a token following a complete expression
+    fn trailing_input(&self) -> Error {
+        let token = self.peek();
+        let expected = match self.delimiters.last() {
+            Some(open) if self.is_call(open) => "an operator, ',' or ')'",
+            Some(open) if open.kind == TokenKind::LeftParen => "an operator or ')'",
+            Some(_) => "an operator or ']'",
+            None => "an operator or the end of the expression",
+        };
+        let error = if matches!(token.kind, TokenKind::RightParen | TokenKind::RightBracket)
+            && !self.closes_open_delimiter()
+        {
+            Error::new(
+                ErrorCode::UnbalancedDelimiter,
+                format!("Unmatched '{token}'"),
+            )
+        } else {
+            Error::new(
+                ErrorCode::UnexpectedToken,
+                format!("Unexpected {}, expected {expected}", self.describe(token)),
+            )
+        };
+        error.with_span(token.span())
+    }
+
+    // Helper: a token as it appears in the input, for error messages
+    fn describe(&self, token: Token) -> String {
+        if token.kind == TokenKind::Eof {
+            "end of expression".to_string()
+        } else {
+            format!("'{}'", self.token_text(&token))
+        }
+    }
+
+    // Helper: count a level of nesting, failing past `MAX_DEPTH`
+    fn descend(&mut self) -> Result<(), Error> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(Error::new(
+                ErrorCode::NestingTooDeep,
+                format!("Expression nests more than {MAX_DEPTH} levels deep"),
+            )
+            .with_span(self.peek().span()));
+        }
+        Ok(())
+    }
+
+    // Helper: the source range of a parsed expression
+    fn span(&self, expr_ref: ExprRef) -> Span {
+        self.ast.span(expr_ref)
     }
 
     fn match_tokens(&mut self, tokens: Vec<TokenKind>) -> bool {
@@ -230,14 +604,41 @@ impl<'a> FhirParser<'a> {
     }
 
     fn previous(&self) -> Token {
-        self.tokens[self.position - 1]
+        self.position
+            .checked_sub(1)
+            .and_then(|position| self.tokens.get(position))
+            .copied()
+            .unwrap_or_else(|| self.peek())
     }
 
+    // Past the last token, as for token lists not ending in `Eof`, the input has ended
     fn peek(&self) -> Token {
-        self.tokens[self.position]
+        self.tokens.get(self.position).copied().unwrap_or_else(|| {
+            let end = self.tokens.last().map_or(0, |token| token.end);
+            Token::new(TokenKind::Eof, end, end)
+        })
     }
 }
 
+// Helper: the contents of a quoted literal or delimited identifier
+fn strip_quotes(text: &str) -> &str {
+    text.get(1..text.len().saturating_sub(1))
+        .unwrap_or_default()
+}
+
+// Helper: a date or time literal without its leading `@`
+fn strip_at(text: &str) -> &str {
+    text.strip_prefix('@').unwrap_or(text)
+}
+
+fn invalid_date(text: &str, token: Token) -> Error {
+    Error::new(
+        ErrorCode::InvalidLiteral,
+        format!("Invalid date or time literal: {text}"),
+    )
+    .with_span(token.span())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -300,6 +701,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::approx_constant)]
     fn test_parse_identifier_number() {
         let input = "3.14";
         let tokens = vec![create_number_token(3.14, 0, 4), create_eof_token(4)];
@@ -432,7 +834,7 @@ mod tests {
                 assert_eq!(*object_expr, Expression::Identifier("Patient".to_string()));
                 assert_eq!(member, "name");
             }
-            _ => panic!("Expected MemberAccess, got: {:?}", result),
+            _ => panic!("Expected MemberAccess, got: {result:?}"),
         }
     }
 
@@ -658,6 +1060,63 @@ mod tests {
         assert_eq!(*result, Expression::Identifier("Patient".to_string()));
     }
 
+    #[test]
+    fn test_parse_external_constants() {
+        use crate::lexer::tokenizer::Lexer;
+
+        for (input, name) in [
+            ("%resource", "resource"),
+            ("%'us-zip'", "us-zip"),
+            ("%`vs-administrative-gender`", "vs-administrative-gender"),
+        ] {
+            let tokens = Lexer::new(input).tokenize().unwrap();
+            let mut parser = create_parser(&tokens, input);
+
+            let expr_ref = parser.parse_expression().unwrap();
+            let result = parser.ast.get(expr_ref);
+            assert_eq!(*result, Expression::ExternalConstant(name.to_string()));
+        }
+
+        let error = Lexer::new("%`vs-open").tokenize().unwrap_err();
+        assert_eq!(error.code, ErrorCode::UnterminatedString);
+    }
+
+    #[test]
+    fn test_node_spans() {
+        use crate::lexer::tokenizer::Lexer;
+
+        let input = "Patient.name[0].given.first() = 'Peter'";
+        let tokens = Lexer::new(input).tokenize().unwrap();
+        let ast = create_parser(&tokens, input).parse().unwrap();
+
+        let Expression::BinaryOperation { lhs, rhs, .. } = ast.expressions.get(ast.start) else {
+            panic!("Expected a binary operation");
+        };
+        assert_eq!(ast.expressions.span(ast.start), Span::new(0, 39));
+        assert_eq!(ast.expressions.span(*lhs), Span::new(0, 29));
+        assert_eq!(ast.expressions.span(*rhs), Span::new(32, 39));
+
+        let Expression::FunctionCall {
+            object: Some(object),
+            ..
+        } = ast.expressions.get(*lhs)
+        else {
+            panic!("Expected a function call");
+        };
+        assert_eq!(ast.expressions.span(*object), Span::new(0, 21));
+    }
+
+    #[test]
+    fn test_error_span() {
+        let input = "name.(given)";
+        let tokens = crate::lexer::tokenizer::Lexer::new(input)
+            .tokenize()
+            .unwrap();
+        let error = create_parser(&tokens, input).parse().unwrap_err();
+        assert_eq!(error.code, ErrorCode::UnexpectedToken);
+        assert_eq!(error.span, Some(Span::new(5, 6)));
+    }
+
     #[test]
     fn test_empty_token_list() {
         let input = "";
@@ -667,4 +1126,299 @@ mod tests {
         // Should immediately be at end
         assert!(parser.is_at_end());
     }
+
+    #[test]
+    fn test_missing_eof_token() {
+        let input = "name";
+        let tokens = vec![Token::new(TokenKind::Identifier, 0, 4)];
+        let ast = create_parser(&tokens, input).parse().unwrap();
+        assert_eq!(
+            ast.expressions.get(ast.start),
+            &Expression::Identifier("name".to_string())
+        );
+
+        let tokens = Vec::new();
+        assert!(create_parser(&tokens, input).parse().is_err());
+    }
+
+    #[test]
+    fn test_invalid_date_literal() {
+        for input in ["@", "@2024-13-01", "@2024-01-01T25:00:00", "@T"] {
+            let tokens = crate::lexer::tokenizer::Lexer::new(input)
+                .tokenize()
+                .unwrap();
+            let error = create_parser(&tokens, input).parse().unwrap_err();
+            assert_eq!(error.code, ErrorCode::InvalidLiteral, "{input}");
+        }
+    }
+
+    #[test]
+    fn test_nesting_limit() {
+        let input = format!("{}1{}", "f(".repeat(MAX_DEPTH), ")".repeat(MAX_DEPTH));
+        let tokens = crate::lexer::tokenizer::Lexer::new(&input)
+            .tokenize()
+            .unwrap();
+        let error = create_parser(&tokens, &input).parse().unwrap_err();
+        assert_eq!(error.code, ErrorCode::NestingTooDeep);
+
+        let input = "a.".repeat(MAX_DEPTH) + "a";
+        let tokens = crate::lexer::tokenizer::Lexer::new(&input)
+            .tokenize()
+            .unwrap();
+        let error = create_parser(&tokens, &input).parse().unwrap_err();
+        assert_eq!(error.code, ErrorCode::NestingTooDeep);
+
+        let input = "a.".repeat(MAX_DEPTH / 2) + "a";
+        let tokens = crate::lexer::tokenizer::Lexer::new(&input)
+            .tokenize()
+            .unwrap();
+        assert!(create_parser(&tokens, &input).parse().is_ok());
+    }
+
+    // Helper: the error from parsing `input`
+    fn parse_error(input: &str) -> Error {
+        let tokens = crate::lexer::tokenizer::Lexer::new(input)
+            .tokenize()
+            .unwrap();
+        create_parser(&tokens, input).parse().unwrap_err()
+    }
+
+    #[test]
+    fn test_trailing_input() {
+        let error = parse_error("Patient.name foo");
+        assert_eq!(error.code, ErrorCode::UnexpectedToken);
+        assert_eq!(
+            error.message,
+            "Unexpected 'foo', expected an operator or the end of the expression"
+        );
+        assert_eq!(error.span, Some(Span::new(13, 16)));
+
+        let error = parse_error("Patient.name )foo");
+        assert_eq!(error.code, ErrorCode::UnbalancedDelimiter);
+        assert_eq!(error.message, "Unmatched ')'");
+        assert_eq!(error.span, Some(Span::new(13, 14)));
+    }
+
+    #[test]
+    fn test_unclosed_delimiters() {
+        for (input, opening) in [
+            ("Patient.name.given[0", Span::new(18, 19)),
+            ("name.where(given", Span::new(10, 11)),
+            ("name.where(given.exists(", Span::new(23, 24)),
+            ("name.where(", Span::new(10, 11)),
+        ] {
+            let error = parse_error(input);
+            assert_eq!(error.code, ErrorCode::UnbalancedDelimiter, "{input}");
+            assert_eq!(error.span, Some(opening), "{input}");
+        }
+        assert_eq!(
+            parse_error("name[0").message,
+            "Unclosed '[', expected ']' before the end of the expression"
+        );
+    }
+
+    #[test]
+    fn test_unexpected_token_in_delimiters() {
+        let error = parse_error("name[0 1]");
+        assert_eq!(error.code, ErrorCode::UnexpectedToken);
+        assert_eq!(error.message, "Unexpected '1', expected an operator or ']'");
+        assert_eq!(error.span, Some(Span::new(7, 8)));
+
+        let error = parse_error("substring(0 1)");
+        assert_eq!(
+            error.message,
+            "Unexpected '1', expected an operator, ',' or ')'"
+        );
+
+        let error = parse_error("substring(0,)");
+        assert_eq!(error.span, Some(Span::new(12, 13)));
+    }
+
+    // Helper: the recovering parse of `input`
+    fn parse_recovering(input: &str) -> Parsed {
+        let tokens = crate::lexer::tokenizer::Lexer::new(input)
+            .tokenize()
+            .unwrap();
+        create_parser(&tokens, input).parse_recovering()
+    }
+
+    #[test]
+    fn test_recovering_reports_every_error() {
+        let parsed = parse_recovering("name.where(given = ).first( = 'x'");
+        let spans: Vec<_> = parsed
+            .diagnostics
+            .iter()
+            .map(|diagnostic| (diagnostic.code, diagnostic.span))
+            .collect();
+        assert_eq!(
+            spans,
+            vec![
+                (ErrorCode::UnexpectedToken, Some(Span::new(19, 20))),
+                (ErrorCode::UnexpectedToken, Some(Span::new(28, 29))),
+                (ErrorCode::UnbalancedDelimiter, Some(Span::new(26, 27))),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_recovering_inserts_error_nodes() {
+        let parsed = parse_recovering("substring(1 2, , 3)");
+        assert_eq!(parsed.diagnostics.len(), 2);
+        let Expression::FunctionCall { arguments, .. } =
+            parsed.ast.expressions.get(parsed.ast.start)
+        else {
+            panic!("Expected FunctionCall");
+        };
+        let arguments: Vec<_> = arguments
+            .iter()
+            .map(|argument| parsed.ast.expressions.get(*argument))
+            .collect();
+        assert_eq!(
+            arguments,
+            vec![
+                &Expression::Integer(1),
+                &Expression::Error,
+                &Expression::Integer(3)
+            ]
+        );
+
+        let parsed = parse_recovering("name.(given) = 'x' 'y'");
+        assert_eq!(parsed.diagnostics.len(), 2);
+        let Expression::BinaryOperation { lhs, rhs, .. } =
+            parsed.ast.expressions.get(parsed.ast.start)
+        else {
+            panic!("Expected BinaryOperation");
+        };
+        assert_eq!(parsed.ast.expressions.get(*lhs), &Expression::Error);
+        assert_eq!(parsed.ast.expressions.span(*lhs), Span::new(0, 12));
+        assert_eq!(
+            parsed.ast.expressions.get(*rhs),
+            &Expression::String("x".to_string())
+        );
+    }
+
+    #[test]
+    fn test_recovering_valid_expression() {
+        let parsed = parse_recovering("name.given[0] = 'Peter'");
+        assert!(parsed.diagnostics.is_empty());
+        assert!(matches!(
+            parsed.ast.expressions.get(parsed.ast.start),
+            Expression::BinaryOperation { .. }
+        ));
+    }
+
+    #[test]
+    fn test_recovering_stops_at_limits() {
+        let input = "a.".repeat(MAX_DEPTH) + "a";
+        let parsed = parse_recovering(&input);
+        assert_eq!(parsed.diagnostics[0].code, ErrorCode::NestingTooDeep);
+        assert_eq!(
+            parsed.ast.expressions.get(parsed.ast.start),
+            &Expression::Error
+        );
+        assert_eq!(
+            parsed.ast.expressions.span(parsed.ast.start),
+            Span::new(0, input.len())
+        );
+    }
+
+    #[test]
+    fn test_parse_delimited_identifiers() {
+        use crate::lexer::tokenizer::Lexer;
+
+        let input = r"`given name`.`div`.`where`(`\u0061`)";
+        let tokens = Lexer::new(input).tokenize().unwrap();
+        let ast = create_parser(&tokens, input).parse().unwrap();
+        let Expression::FunctionCall {
+            object: Some(object),
+            function,
+            arguments,
+        } = ast.expressions.get(ast.start)
+        else {
+            panic!("Expected FunctionCall");
+        };
+        assert_eq!(
+            ast.expressions.get(*object),
+            &Expression::MemberAccess {
+                object: ExprRef::default(),
+                member: "div".to_string()
+            }
+        );
+        assert_eq!(
+            ast.expressions.get(ExprRef::default()),
+            &Expression::Identifier("given name".to_string())
+        );
+        assert_eq!(
+            ast.expressions.get(*function),
+            &Expression::Identifier("where".to_string())
+        );
+        assert_eq!(
+            ast.expressions.get(arguments[0]),
+            &Expression::Identifier("a".to_string())
+        );
+        assert_eq!(ast.expressions.span(ExprRef::default()), Span::new(0, 12));
+    }
+    #[test]
+    fn test_parse_parenthesized_expressions() {
+        let input = "(a = b) = c";
+        let tokens = crate::lexer::tokenizer::Lexer::new(input)
+            .tokenize()
+            .unwrap();
+        let ast = create_parser(&tokens, input).parse().unwrap();
+        let Expression::BinaryOperation { lhs, rhs, .. } = ast.expressions.get(ast.start) else {
+            panic!("Expected an operation");
+        };
+        assert!(matches!(
+            ast.expressions.get(*lhs),
+            Expression::BinaryOperation { .. }
+        ));
+        assert_eq!(ast.expressions.span(*lhs), Span::new(0, 7));
+        assert_eq!(
+            ast.expressions.get(*rhs),
+            &Expression::Identifier("c".to_string())
+        );
+
+        let error = parse_error("(a b)");
+        assert_eq!(error.message, "Unexpected 'b', expected an operator or ')'");
+        let error = parse_error("(a, b)");
+        assert_eq!(error.message, "Unexpected ',', expected an operator or ')'");
+        let error = parse_error("((a)");
+        assert_eq!(error.code, ErrorCode::UnbalancedDelimiter);
+        assert_eq!(error.span, Some(Span::new(0, 1)));
+    }
+
+    #[test]
+    fn test_parse_more_expressions_than_fit_a_u16() {
+        // A code list as long as those of generated expressions
+        let codes = (0..70_000).map(|code| format!("'{code}'"));
+        let input = format!("code.memberOf({})", codes.collect::<Vec<_>>().join(", "));
+        let tokens = crate::lexer::tokenizer::Lexer::new(&input)
+            .tokenize()
+            .unwrap();
+        let result = create_parser(&tokens, &input).parse();
+        if cfg!(feature = "compact-refs") {
+            assert!(result.is_err_and(|error| error.code == ErrorCode::TooManyExpressions));
+        } else {
+            let ast = result.unwrap();
+            assert_eq!(ast.expressions.len(), 70_003);
+        }
+    }
+
+    #[test]
+    fn test_parse_into_reused_pool() {
+        let mut pool = ExprPool::new();
+        pool.add(Expression::Integer(1)).unwrap();
+        for (input, expressions) in [("name.given", 3), ("a = b", 3), ("exists()", 2)] {
+            let tokens = crate::lexer::tokenizer::Lexer::new(input)
+                .tokenize()
+                .unwrap();
+            let ast = create_parser(&tokens, input)
+                .with_pool(pool)
+                .parse()
+                .unwrap();
+            assert_eq!(ast.expressions.len(), expressions, "{input}");
+            assert_eq!(ast.expressions.span(ast.start), Span::new(0, input.len()));
+            pool = ast.expressions;
+        }
+    }
 }
@@ -0,0 +1,352 @@
+use super::ast::Ast;
+use super::grammar::{BinaryOperator, ExprPool, ExprRef, Expression};
+use super::unparser::unparse_expression;
+use crate::evaluator::error::{Error, ErrorCode};
+use std::fmt;
+use time::{Date, PrimitiveDateTime};
+
+/// An expression built in code rather than parsed, such as from the fields of a
+/// search form.
+///
+/// Each step is a node of the tree, so there is no text to get wrong: names
+/// that aren't plain identifiers, such as keywords, are delimited and strings
+/// are escaped when the expression is written out with `Display`, and
+/// operations are grouped as they were built.
+///
+/// ```rust
+/// use fhirlighter::Path;
+///
+/// let family = "O'Brien";
+/// let path = Path::root("Patient")
+///     .member("name")
+///     .call("where", [Path::root("family").equals(family)])
+///     .member("given")
+///     .index(0);
+/// assert_eq!(
+///     path.to_string(),
+///     r"Patient.name.where(family = 'O\'Brien').given[0]"
+/// );
+///
+/// let ast = path.build()?;
+/// assert!(ast.equivalent(&fhirlighter::parse(&path.to_string())?));
+/// # Ok::<(), fhirlighter::Error>(())
+/// ```
+///
+/// Strings, integers and booleans convert into literals, so they can be passed
+/// where a `Path` is expected; element names are always `Path::root` or
+/// `Path::member`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Path(Box<Step>);
+
+#[derive(Debug, Clone, PartialEq)]
+enum Step {
+    Identifier(String),
+    Member(Path, String),
+    Call(Option<Path>, String, Vec<Path>),
+    Operation(BinaryOperator, Path, Path),
+    Index(Path, Path),
+    Constant(String),
+    Literal(Expression),
+}
+
+impl Path {
+    fn new(step: Step) -> Self {
+        Self(Box::new(step))
+    }
+
+    /// A name resolved against the input, a type such as `Patient` or an
+    /// element such as `name`.
+    #[must_use]
+    pub fn root(name: impl Into<String>) -> Self {
+        Self::new(Step::Identifier(name.into()))
+    }
+
+    /// An environment variable such as `%resource`, by its name without `%`.
+    #[must_use]
+    pub fn constant(name: impl Into<String>) -> Self {
+        Self::new(Step::Constant(name.into()))
+    }
+
+    /// A call of a function on the input, such as `exists()`.
+    #[must_use]
+    pub fn function(name: impl Into<String>, arguments: impl IntoIterator<Item = Self>) -> Self {
+        Self::new(Step::Call(
+            None,
+            name.into(),
+            arguments.into_iter().collect(),
+        ))
+    }
+
+    #[must_use]
+    pub fn string(string: impl Into<String>) -> Self {
+        Self::literal(Expression::String(string.into()))
+    }
+
+    /// An integer, which is unsigned as `FHIRPath` has no negative literals.
+    #[must_use]
+    pub fn integer(integer: u32) -> Self {
+        Self::literal(Expression::Integer(i64::from(integer)))
+    }
+
+    /// A decimal.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ErrorCode::InvalidLiteral` for a negative decimal or one that
+    /// isn't finite, which `FHIRPath` has no literals for.
+    pub fn decimal(number: f64) -> Result<Self, Error> {
+        if !number.is_finite() || number.is_sign_negative() {
+            return Err(Error::new(
+                ErrorCode::InvalidLiteral,
+                format!("{number} can't be written as a FHIRPath decimal"),
+            ));
+        }
+        Ok(Self::literal(Expression::Number(number)))
+    }
+
+    #[must_use]
+    pub fn boolean(boolean: bool) -> Self {
+        Self::literal(Expression::Boolean(boolean))
+    }
+
+    #[must_use]
+    pub fn date(date: Date) -> Self {
+        Self::literal(Expression::ISODate(date))
+    }
+
+    #[must_use]
+    pub fn datetime(datetime: PrimitiveDateTime) -> Self {
+        Self::literal(Expression::ISODateTime(datetime))
+    }
+
+    fn literal(literal: Expression) -> Self {
+        Self::new(Step::Literal(literal))
+    }
+
+    /// The element `name` of each item, as in `.name`.
+    #[must_use]
+    pub fn member(self, name: impl Into<String>) -> Self {
+        Self::new(Step::Member(self, name.into()))
+    }
+
+    /// A call of a function on this, as in `.where(...)`.
+    #[must_use]
+    pub fn call(self, name: impl Into<String>, arguments: impl IntoIterator<Item = Self>) -> Self {
+        Self::new(Step::Call(
+            Some(self),
+            name.into(),
+            arguments.into_iter().collect(),
+        ))
+    }
+
+    /// The item at `index`, as in `[0]`.
+    #[must_use]
+    pub fn index(self, index: impl Into<Self>) -> Self {
+        Self::new(Step::Index(self, index.into()))
+    }
+
+    /// A comparison of this with `rhs`.
+    #[must_use]
+    pub fn compare(self, operator: BinaryOperator, rhs: impl Into<Self>) -> Self {
+        Self::new(Step::Operation(operator, self, rhs.into()))
+    }
+
+    #[must_use]
+    pub fn equals(self, rhs: impl Into<Self>) -> Self {
+        self.compare(BinaryOperator::Equals, rhs)
+    }
+
+    #[must_use]
+    pub fn not_equals(self, rhs: impl Into<Self>) -> Self {
+        self.compare(BinaryOperator::NotEquals, rhs)
+    }
+
+    #[must_use]
+    pub fn less_than(self, rhs: impl Into<Self>) -> Self {
+        self.compare(BinaryOperator::LessThan, rhs)
+    }
+
+    #[must_use]
+    pub fn less_than_or_equal(self, rhs: impl Into<Self>) -> Self {
+        self.compare(BinaryOperator::LessThanOrEqual, rhs)
+    }
+
+    #[must_use]
+    pub fn greater_than(self, rhs: impl Into<Self>) -> Self {
+        self.compare(BinaryOperator::GreaterThan, rhs)
+    }
+
+    #[must_use]
+    pub fn greater_than_or_equal(self, rhs: impl Into<Self>) -> Self {
+        self.compare(BinaryOperator::GreaterThanOrEqual, rhs)
+    }
+
+    /// The expression as an AST, as if parsed from `self.to_string()` but
+    /// with empty spans.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ErrorCode::TooManyExpressions` if the expression doesn't fit a
+    /// pool.
+    pub fn build(&self) -> Result<Ast, Error> {
+        let mut expressions = ExprPool::new();
+        let start = self.add_to(&mut expressions)?;
+        Ok(Ast { expressions, start })
+    }
+
+    /// Add the expression to an existing pool, for instance as part of a larger
+    /// tree, returning its root.
+    ///
+    /// # Errors
+    ///
+    /// As for [`Path::build`].
+    pub fn add_to(&self, pool: &mut ExprPool) -> Result<ExprRef, Error> {
+        let expression = match &*self.0 {
+            Step::Identifier(name) => Expression::Identifier(name.clone()),
+            Step::Member(object, member) => Expression::MemberAccess {
+                object: object.add_to(pool)?,
+                member: member.clone(),
+            },
+            Step::Call(object, name, arguments) => Expression::FunctionCall {
+                object: object
+                    .as_ref()
+                    .map(|object| object.add_to(pool))
+                    .transpose()?,
+                function: pool.add(Expression::Identifier(name.clone()))?,
+                arguments: arguments
+                    .iter()
+                    .map(|argument| argument.add_to(pool))
+                    .collect::<Result<_, _>>()?,
+            },
+            Step::Operation(operator, lhs, rhs) => Expression::BinaryOperation {
+                operator: *operator,
+                lhs: lhs.add_to(pool)?,
+                rhs: rhs.add_to(pool)?,
+            },
+            Step::Index(object, index) => Expression::Index {
+                object: object.add_to(pool)?,
+                index: index.add_to(pool)?,
+            },
+            Step::Constant(name) => Expression::ExternalConstant(name.clone()),
+            Step::Literal(literal) => literal.clone(),
+        };
+        pool.add(expression)
+    }
+}
+
+/// A string literal.
+impl From<&str> for Path {
+    fn from(string: &str) -> Self {
+        Self::string(string)
+    }
+}
+
+/// A string literal.
+impl From<String> for Path {
+    fn from(string: String) -> Self {
+        Self::string(string)
+    }
+}
+
+impl From<u32> for Path {
+    fn from(integer: u32) -> Self {
+        Self::integer(integer)
+    }
+}
+
+impl From<bool> for Path {
+    fn from(boolean: bool) -> Self {
+        Self::boolean(boolean)
+    }
+}
+
+/// The expression as `FHIRPath` source, which parses back to the same tree.
+///
+/// Fails, and `to_string()` panics, only if [`Path::build`] does, for a path of
+/// more than [`MAX_EXPRESSIONS`](super::grammar::MAX_EXPRESSIONS) steps.
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ast = self.build().map_err(|_| fmt::Error)?;
+        write!(f, "{}", unparse_expression(&ast.expressions, ast.start))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_syntax as parse;
+    use time::Month;
+
+    #[test]
+    fn test_names_and_strings_are_escaped() {
+        for (path, source) in [
+            (
+                Path::root("true").member("where").member("given name"),
+                "`true`.where.`given name`",
+            ),
+            (
+                Path::constant("vs-name").equals("it's a `test`\n"),
+                r"%`vs-name` = 'it\'s a `test`\n'",
+            ),
+            (
+                Path::function("exists", []).not_equals(true),
+                "exists() != true",
+            ),
+            (
+                Path::root("name").call("`where`", [Path::root("use").equals("usual")]),
+                r"name.`\`where\``(use = 'usual')",
+            ),
+        ] {
+            assert_eq!(path.to_string(), source);
+            let ast = path.build().unwrap();
+            assert!(parse(source).unwrap().equivalent(&ast), "{source}");
+        }
+    }
+
+    #[test]
+    fn test_operations_are_grouped_as_built() {
+        let path = Path::root("a")
+            .equals(Path::root("b"))
+            .equals(Path::root("c"))
+            .call("exists", []);
+        assert_eq!(path.to_string(), "((a = b) = c).exists()");
+
+        let path = Path::root("a").equals(Path::root("b").equals(Path::root("c")));
+        assert_eq!(path.to_string(), "a = b = c");
+    }
+
+    #[test]
+    fn test_literals() {
+        let date = Date::from_calendar_date(2020, Month::January, 31).unwrap();
+        let path = Path::root("birthDate")
+            .greater_than_or_equal(Path::date(date))
+            .equals(Path::decimal(1.0).unwrap().less_than(Path::integer(2)));
+        assert_eq!(path.to_string(), "(birthDate >= @2020-01-31) = 1.0 < 2");
+        assert!(
+            parse(&path.to_string())
+                .unwrap()
+                .equivalent(&path.build().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_literals_parse_back() {
+        let path = Path::root("name")
+            .member("given")
+            .index(u32::MAX)
+            .equals(Path::decimal(0.25).unwrap());
+        let source = path.to_string();
+        assert_eq!(source, "name.given[4294967295] = 0.25");
+        assert!(
+            crate::parse(&source)
+                .unwrap()
+                .equivalent(&path.build().unwrap())
+        );
+
+        // The grammar has no minus sign, nor infinities
+        for number in [-1.5, -0.0, f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            let error = Path::decimal(number).unwrap_err();
+            assert_eq!(error.code, ErrorCode::InvalidLiteral, "{number}");
+        }
+    }
+}
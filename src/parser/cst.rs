@@ -0,0 +1,432 @@
+use super::ast::MAX_DEPTH;
+use super::grammar::BinaryOperator;
+use crate::evaluator::error::{Error, ErrorCode};
+use crate::lexer::token::{Span, Token, TokenKind};
+use crate::lexer::tokenizer::Lexer;
+use std::fmt;
+
+/// The kind of a [`SyntaxNode`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum NodeKind {
+    // The expression and the trivia around it
+    Root,
+    // 'text', 1, 1.5, true or @2020-01-01
+    Literal,
+    // name or `name`
+    Identifier,
+    // %name, %'name' or %`name`
+    ExternalConstant,
+    // Identifier ( expression, ... )
+    Function,
+    // expression . Identifier or expression . Function
+    Invocation,
+    // expression [ expression ]
+    Index,
+    // expression operator expression
+    BinaryOperation,
+    // ( expression )
+    Parenthesized,
+    // Tokens that don't fit the grammar, or none where an expression, a name or
+    // a closing delimiter is missing
+    Error,
+}
+
+/// A child of a [`SyntaxNode`]: a nested node or a token, trivia included.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SyntaxElement {
+    Node(SyntaxNode),
+    Token(Token),
+}
+
+/// A node of a [`Cst`], holding every token it was parsed from in source order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntaxNode {
+    pub kind: NodeKind,
+    pub children: Vec<SyntaxElement>,
+}
+
+impl SyntaxNode {
+    const fn new(kind: NodeKind, children: Vec<SyntaxElement>) -> Self {
+        Self { kind, children }
+    }
+
+    /// The nodes directly below this one.
+    pub fn nodes(&self) -> impl Iterator<Item = &Self> {
+        self.children.iter().filter_map(|child| match child {
+            SyntaxElement::Node(node) => Some(node),
+            SyntaxElement::Token(_) => None,
+        })
+    }
+
+    /// Every token of the node in source order, trivia included.
+    #[must_use]
+    pub fn tokens(&self) -> Vec<Token> {
+        let mut tokens = Vec::new();
+        self.collect_tokens(&mut tokens);
+        tokens
+    }
+
+    fn collect_tokens(&self, tokens: &mut Vec<Token>) {
+        for child in &self.children {
+            match child {
+                SyntaxElement::Node(node) => node.collect_tokens(tokens),
+                SyntaxElement::Token(token) => tokens.push(*token),
+            }
+        }
+    }
+
+    /// The source range of the node's tokens, or `None` when it has none.
+    #[must_use]
+    pub fn span(&self) -> Option<Span> {
+        let tokens = self.tokens();
+        Some(tokens.first()?.span().to(tokens.last()?.span()))
+    }
+
+    /// Whether the node or any below it is an error.
+    #[must_use]
+    pub fn has_errors(&self) -> bool {
+        self.kind == NodeKind::Error || self.nodes().any(Self::has_errors)
+    }
+}
+
+/// A concrete syntax tree: unlike [`Ast`](super::ast::Ast) it keeps every token
+/// of the source, whitespace and comments included, so printing it gives back
+/// the source byte for byte.
+///
+/// Input that doesn't fit the grammar is kept in `NodeKind::Error` nodes rather
+/// than rejected, so any input that can be tokenized has a tree.
+///
+/// ```rust
+/// use fhirlighter::parser::cst::Cst;
+///
+/// let source = "Patient.name /* all of them */\n  .given";
+/// let cst = Cst::parse(source)?;
+/// assert_eq!(cst.to_string(), source);
+/// # Ok::<(), fhirlighter::Error>(())
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cst<'a> {
+    input: &'a str,
+    pub root: SyntaxNode,
+}
+
+impl<'a> Cst<'a> {
+    /// # Errors
+    ///
+    /// Returns an error if the input can't be tokenized or nests more than
+    /// `MAX_DEPTH` levels deep.
+    pub fn parse(input: &'a str) -> Result<Self, Error> {
+        let tokens = Lexer::new(input).tokenize_lossless()?;
+        let mut builder = CstBuilder {
+            tokens: &tokens,
+            position: 0,
+            depth: 0,
+        };
+        let root = builder.root()?;
+        Ok(Self { input, root })
+    }
+
+    /// The source the tree was parsed from.
+    #[must_use]
+    pub const fn input(&self) -> &'a str {
+        self.input
+    }
+
+    /// The text of a token of the tree.
+    #[must_use]
+    pub fn text(&self, token: &Token) -> &'a str {
+        token.text(self.input)
+    }
+}
+
+impl fmt::Display for Cst<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for token in self.root.tokens() {
+            write!(f, "{}", self.text(&token))?;
+        }
+        Ok(())
+    }
+}
+
+// Builds the tree following the same grammar as `FhirParser`, attaching the
+// trivia before each token to the node that token belongs to. A property test
+// checks the two accept the same input, so a change to one needs the other.
+struct CstBuilder<'a> {
+    tokens: &'a [Token],
+    position: usize,
+    depth: usize,
+}
+
+impl CstBuilder<'_> {
+    fn root(&mut self) -> Result<SyntaxNode, Error> {
+        let mut children = Vec::new();
+        if self.peek().kind != TokenKind::Eof {
+            children.push(SyntaxElement::Node(self.expression()?));
+        }
+        if self.peek().kind != TokenKind::Eof {
+            let mut rest = Vec::new();
+            while self.peek().kind != TokenKind::Eof {
+                self.bump(&mut rest);
+            }
+            children.push(SyntaxElement::Node(SyntaxNode::new(NodeKind::Error, rest)));
+        }
+        self.trivia(&mut children);
+        Ok(SyntaxNode::new(NodeKind::Root, children))
+    }
+
+    fn expression(&mut self) -> Result<SyntaxNode, Error> {
+        let depth = self.depth;
+        let result = self.nested_expression();
+        self.depth = depth;
+        result
+    }
+
+    fn nested_expression(&mut self) -> Result<SyntaxNode, Error> {
+        self.descend()?;
+        let mut expression = self.term()?;
+        loop {
+            self.descend()?;
+            let kind = self.peek().kind;
+            let node_kind = if kind == TokenKind::Dot {
+                NodeKind::Invocation
+            } else if kind == TokenKind::LeftBracket {
+                NodeKind::Index
+            } else if BinaryOperator::from_token(&kind).is_some() {
+                NodeKind::BinaryOperation
+            } else {
+                return Ok(expression);
+            };
+
+            let mut children = vec![SyntaxElement::Node(expression)];
+            self.bump(&mut children);
+            let next = self.peek().kind;
+            let operand = if node_kind != NodeKind::Invocation {
+                self.expression()?
+            } else if next.is_identifier() || next == TokenKind::DelimitedIdentifier {
+                self.invocation()?
+            } else {
+                self.error()
+            };
+            children.push(SyntaxElement::Node(operand));
+            if node_kind == NodeKind::Index {
+                self.expect(TokenKind::RightBracket, &mut children);
+            }
+            expression = SyntaxNode::new(node_kind, children);
+        }
+    }
+
+    fn term(&mut self) -> Result<SyntaxNode, Error> {
+        let mut children = Vec::new();
+        let kind = match self.peek().kind {
+            TokenKind::String
+            | TokenKind::Integer(_)
+            | TokenKind::Number(_)
+            | TokenKind::Boolean(_)
+            | TokenKind::ISODate
+            | TokenKind::ISODateTime => NodeKind::Literal,
+            kind if kind.is_identifier() || kind == TokenKind::DelimitedIdentifier => {
+                return self.invocation();
+            }
+            TokenKind::Percent => {
+                self.bump(&mut children);
+                let kind = self.peek().kind;
+                if kind.is_identifier()
+                    || matches!(kind, TokenKind::String | TokenKind::DelimitedIdentifier)
+                {
+                    self.bump(&mut children);
+                } else {
+                    // The name is missing, and what follows is left to the caller
+                    let error = SyntaxNode::new(NodeKind::Error, Vec::new());
+                    children.push(SyntaxElement::Node(error));
+                }
+                return Ok(SyntaxNode::new(NodeKind::ExternalConstant, children));
+            }
+            TokenKind::LeftParen => {
+                self.bump(&mut children);
+                children.push(SyntaxElement::Node(self.expression()?));
+                self.expect(TokenKind::RightParen, &mut children);
+                return Ok(SyntaxNode::new(NodeKind::Parenthesized, children));
+            }
+            _ => return Ok(self.error()),
+        };
+        self.bump(&mut children);
+        Ok(SyntaxNode::new(kind, children))
+    }
+
+    fn invocation(&mut self) -> Result<SyntaxNode, Error> {
+        let mut identifier = Vec::new();
+        self.bump(&mut identifier);
+        let identifier = SyntaxNode::new(NodeKind::Identifier, identifier);
+        if self.peek().kind != TokenKind::LeftParen {
+            return Ok(identifier);
+        }
+
+        let mut children = vec![SyntaxElement::Node(identifier)];
+        self.bump(&mut children);
+        if self.peek().kind != TokenKind::RightParen {
+            loop {
+                children.push(SyntaxElement::Node(self.expression()?));
+                if self.peek().kind != TokenKind::Comma {
+                    break;
+                }
+                self.bump(&mut children);
+            }
+        }
+        self.expect(TokenKind::RightParen, &mut children);
+        Ok(SyntaxNode::new(NodeKind::Function, children))
+    }
+
+    // Helper: an error node in place of a missing term. It takes the next token,
+    // unless that can follow an expression and so is left to the caller.
+    fn error(&mut self) -> SyntaxNode {
+        let mut children = Vec::new();
+        if !matches!(
+            self.peek().kind,
+            TokenKind::Eof | TokenKind::Comma | TokenKind::RightParen | TokenKind::RightBracket
+        ) {
+            self.bump(&mut children);
+        }
+        SyntaxNode::new(NodeKind::Error, children)
+    }
+
+    // Helper: take the next token if it is the closing delimiter expected, or
+    // else mark it missing with an empty error node
+    fn expect(&mut self, kind: TokenKind, children: &mut Vec<SyntaxElement>) {
+        if self.peek().kind == kind {
+            self.bump(children);
+        } else {
+            let error = SyntaxNode::new(NodeKind::Error, Vec::new());
+            children.push(SyntaxElement::Node(error));
+        }
+    }
+
+    // Helper: move the trivia at the current position into `children`
+    fn trivia(&mut self, children: &mut Vec<SyntaxElement>) {
+        while let Some(token) = self.tokens.get(self.position)
+            && token.kind.is_trivia()
+        {
+            children.push(SyntaxElement::Token(*token));
+            self.position += 1;
+        }
+    }
+
+    // Helper: move the next token into `children`, with the trivia before it
+    fn bump(&mut self, children: &mut Vec<SyntaxElement>) {
+        self.trivia(children);
+        if let Some(token) = self.tokens.get(self.position)
+            && token.kind != TokenKind::Eof
+        {
+            children.push(SyntaxElement::Token(*token));
+            self.position += 1;
+        }
+    }
+
+    // Helper: the next token that isn't trivia
+    fn peek(&self) -> Token {
+        self.tokens[self.position..]
+            .iter()
+            .find(|token| !token.kind.is_trivia())
+            .copied()
+            .unwrap_or_else(|| {
+                let end = self.tokens.last().map_or(0, |token| token.end);
+                Token::new(TokenKind::Eof, end, end)
+            })
+    }
+
+    // Helper: count a level of nesting, failing past `MAX_DEPTH` as the parser does
+    fn descend(&mut self) -> Result<(), Error> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(Error::new(
+                ErrorCode::NestingTooDeep,
+                format!("Expression nests more than {MAX_DEPTH} levels deep"),
+            )
+            .with_span(self.peek().span()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Helper: the kinds of a node and those below it, depth first
+    fn kinds(node: &SyntaxNode) -> Vec<NodeKind> {
+        let mut result = vec![node.kind];
+        for child in node.nodes() {
+            result.extend(kinds(child));
+        }
+        result
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for source in [
+            "",
+            "  ",
+            "Patient.name.given",
+            "  Patient . name [ 0 ] // first\n",
+            "/* lead */ name.where(use = 'official' /* inner */, 1)\r\n\t.first()",
+            "%`vs-name` != @2020-01-01",
+            "( a = b ) = c",
+            "name.where(",
+            "1 2 ) ]",
+        ] {
+            let cst = Cst::parse(source).unwrap();
+            assert_eq!(cst.to_string(), source);
+        }
+    }
+
+    #[test]
+    fn test_structure() {
+        let cst = Cst::parse("Patient.name[0].where(given = 'Peter')").unwrap();
+        assert_eq!(
+            kinds(&cst.root),
+            vec![
+                NodeKind::Root,
+                NodeKind::Invocation,
+                NodeKind::Index,
+                NodeKind::Invocation,
+                NodeKind::Identifier,
+                NodeKind::Identifier,
+                NodeKind::Literal,
+                NodeKind::Function,
+                NodeKind::Identifier,
+                NodeKind::BinaryOperation,
+                NodeKind::Identifier,
+                NodeKind::Literal,
+            ]
+        );
+        assert!(!cst.root.has_errors());
+    }
+
+    #[test]
+    fn test_trivia_belongs_to_the_following_token() {
+        let cst = Cst::parse("a = /* c */ b").unwrap();
+        let operation = cst.root.nodes().next().unwrap();
+        let rhs = operation.nodes().nth(1).unwrap();
+        let text: Vec<&str> = rhs.tokens().iter().map(|token| cst.text(token)).collect();
+        assert_eq!(text, vec![" ", "/* c */", " ", "b"]);
+        assert_eq!(rhs.span(), Some(Span::new(3, 13)));
+    }
+
+    #[test]
+    fn test_errors_are_kept() {
+        let cst = Cst::parse("name.where(, 1) )").unwrap();
+        assert!(cst.root.has_errors());
+        assert_eq!(cst.to_string(), "name.where(, 1) )");
+
+        let too_deep = format!("Patient{}", ".name".repeat(MAX_DEPTH));
+        let error = Cst::parse(&too_deep).unwrap_err();
+        assert_eq!(error.code, ErrorCode::NestingTooDeep);
+        assert!(Cst::parse("'unterminated").is_err());
+
+        // Missing names and closing delimiters are errors too, as for the parser
+        for source in ["%", "% = a", "(a", "name[0", "name.where(a"] {
+            let cst = Cst::parse(source).unwrap();
+            assert!(cst.root.has_errors(), "{source}");
+            assert_eq!(cst.to_string(), source);
+        }
+    }
+}
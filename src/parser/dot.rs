@@ -0,0 +1,275 @@
+use super::ast::Ast;
+use super::grammar::{ExprPool, ExprRef, Expression};
+use super::unparser::unparse_expression;
+use serde_json::Value;
+use std::collections::HashMap;
+
+// Longest value shown in full, and most evaluations shown, per node
+const VALUE_WIDTH: usize = 40;
+const VALUES_SHOWN: usize = 3;
+
+impl Ast {
+    /// The tree as a Graphviz DOT graph, to render with for instance
+    /// `dot -Tsvg`.
+    ///
+    /// Operators, invocations and names label their nodes, literals show their
+    /// type, and edges are labelled with the part of the parent they are:
+    /// `lhs`/`rhs` of an operation, the `focus` of an invocation or index, a
+    /// function's arguments in order and the `index`.
+    ///
+    /// ```rust
+    /// let ast = fhirlighter::parse("name.exists()")?;
+    /// let dot = ast.to_dot();
+    /// assert!(dot.starts_with("digraph fhirpath {"));
+    /// assert!(dot.contains(r#"n0 [label="exists()", shape=box];"#));
+    /// assert!(dot.contains(r#"n0 -> n1 [label="focus"];"#));
+    /// # Ok::<(), fhirlighter::Error>(())
+    /// ```
+    #[must_use]
+    pub fn to_dot(&self) -> String {
+        self.to_dot_with_values(&HashMap::new())
+    }
+
+    /// Like [`Ast::to_dot`], with each node also showing the values it
+    /// evaluated to, as collected by [`Evaluator::with_observer`]. Nodes
+    /// evaluated once for each item, such as the projection of `trace()`, show
+    /// a line for each of the first few values.
+    ///
+    /// [`Evaluator::with_observer`]: crate::evaluator::engine::Evaluator::with_observer
+    #[must_use]
+    pub fn to_dot_with_values<S: std::hash::BuildHasher>(
+        &self,
+        values: &HashMap<ExprRef, Vec<Value>, S>,
+    ) -> String {
+        let mut graph = Graph {
+            pool: &self.expressions,
+            values: &|expr_ref| values.get(&expr_ref).map(Vec::as_slice),
+            lines: Vec::new(),
+            nodes: 0,
+        };
+        graph.node(self.start);
+        let mut dot = String::from("digraph fhirpath {\n    node [fontname=\"Helvetica\"];\n");
+        for line in graph.lines {
+            dot.push_str("    ");
+            dot.push_str(&line);
+            dot.push('\n');
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+struct Graph<'a> {
+    pool: &'a ExprPool,
+    values: &'a dyn Fn(ExprRef) -> Option<&'a [Value]>,
+    lines: Vec<String>,
+    // Nodes are numbered in the order they are visited, from the root
+    nodes: usize,
+}
+
+impl Graph<'_> {
+    // Add the node for an expression and those below it, returning its id
+    fn node(&mut self, expr_ref: ExprRef) -> String {
+        let id = format!("n{}", self.nodes);
+        self.nodes += 1;
+        let (label, shape, children) = self.describe(expr_ref);
+
+        let mut label = escape(&label);
+        if let Some(values) = (self.values)(expr_ref) {
+            for value in values.iter().take(VALUES_SHOWN) {
+                label.push_str("\\n= ");
+                label.push_str(&escape(&abbreviate(&value.to_string())));
+            }
+            if values.len() > VALUES_SHOWN {
+                label.push_str("\\n(");
+                label.push_str(&(values.len() - VALUES_SHOWN).to_string());
+                label.push_str(" more)");
+            }
+        }
+        let attributes = shape.map_or_else(String::new, |shape| format!(", {shape}"));
+        self.lines
+            .push(format!("{id} [label=\"{label}\"{attributes}];"));
+
+        for (edge, child) in children {
+            let child = self.node(child);
+            self.lines
+                .push(format!("{id} -> {child} [label=\"{edge}\"];"));
+        }
+        id
+    }
+
+    // The label, any shape and the labelled children of a node
+    fn describe(
+        &self,
+        expr_ref: ExprRef,
+    ) -> (String, Option<&'static str>, Vec<(String, ExprRef)>) {
+        let literal = |type_name: &str| {
+            let text = unparse_expression(self.pool, expr_ref);
+            (
+                format!("{text} : {type_name}"),
+                Some("shape=plaintext"),
+                Vec::new(),
+            )
+        };
+        match self.pool.get(expr_ref) {
+            Expression::Identifier(name) => (name.clone(), None, Vec::new()),
+            Expression::MemberAccess { object, member } => (
+                format!(".{member}"),
+                None,
+                vec![("focus".to_string(), *object)],
+            ),
+            Expression::FunctionCall {
+                object,
+                function,
+                arguments,
+            } => {
+                let name = match self.pool.get(*function) {
+                    Expression::Identifier(name) => name.clone(),
+                    _ => unparse_expression(self.pool, *function),
+                };
+                let mut children: Vec<_> = object
+                    .iter()
+                    .map(|object| ("focus".to_string(), *object))
+                    .collect();
+                children.extend(
+                    arguments
+                        .iter()
+                        .enumerate()
+                        .map(|(index, argument)| (format!("argument {}", index + 1), *argument)),
+                );
+                (format!("{name}()"), Some("shape=box"), children)
+            }
+            Expression::BinaryOperation { operator, lhs, rhs } => (
+                operator.to_string(),
+                Some("shape=circle"),
+                vec![("lhs".to_string(), *lhs), ("rhs".to_string(), *rhs)],
+            ),
+            Expression::Index { object, index } => (
+                "[]".to_string(),
+                Some("shape=box"),
+                vec![
+                    ("focus".to_string(), *object),
+                    ("index".to_string(), *index),
+                ],
+            ),
+            Expression::ExternalConstant(name) => (format!("%{name}"), None, Vec::new()),
+            Expression::String(_) => literal("String"),
+            Expression::Number(_) => literal("Decimal"),
+            Expression::Integer(_) => literal("Integer"),
+            Expression::Boolean(_) => literal("Boolean"),
+            Expression::ISODate(_) => literal("Date"),
+            Expression::ISODateTime(_) => literal("DateTime"),
+            Expression::Error => (
+                "<error>".to_string(),
+                Some("color=red, fontcolor=red"),
+                Vec::new(),
+            ),
+        }
+    }
+}
+
+// Helper: shorten a value past `VALUE_WIDTH` characters
+fn abbreviate(text: &str) -> String {
+    match text.char_indices().nth(VALUE_WIDTH) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text.to_string(),
+    }
+}
+
+// Helper: text as the inside of a DOT string, on one line
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for character in text.chars() {
+        match character {
+            '\\' => escaped.push_str(r"\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str(r"\n"),
+            '\r' => {}
+            character => escaped.push(character),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::evaluator::engine::Evaluator;
+    use crate::parse;
+    use serde_json::{Value, json};
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_graph() {
+        let ast = parse("name.where(use = 'official')[0]").unwrap();
+        assert_eq!(
+            ast.to_dot(),
+            r#"digraph fhirpath {
+    node [fontname="Helvetica"];
+    n0 [label="[]", shape=box];
+    n1 [label="where()", shape=box];
+    n2 [label="name"];
+    n1 -> n2 [label="focus"];
+    n3 [label="=", shape=circle];
+    n4 [label="use"];
+    n3 -> n4 [label="lhs"];
+    n5 [label="'official' : String", shape=plaintext];
+    n3 -> n5 [label="rhs"];
+    n1 -> n3 [label="argument 1"];
+    n0 -> n1 [label="focus"];
+    n6 [label="0 : Integer", shape=plaintext];
+    n0 -> n6 [label="index"];
+}
+"#
+        );
+    }
+
+    #[test]
+    fn test_labels_are_escaped() {
+        let ast = parse(r#"`say "hi"` = 'back\\slash'"#).unwrap();
+        let dot = ast.to_dot();
+        assert!(dot.contains(r#"n1 [label="say \"hi\""];"#), "{dot}");
+        assert!(
+            dot.contains(r#"n2 [label="'back\\\\slash' : String""#),
+            "{dot}"
+        );
+    }
+
+    #[test]
+    fn test_graph_with_values() {
+        let ast = parse("name.trace('names', use = 'official').family").unwrap();
+        let values: Arc<Mutex<HashMap<_, Vec<Value>>>> = Arc::default();
+        let observed = Arc::clone(&values);
+        let evaluator =
+            Evaluator::new()
+                .with_tracer(|_, _| {})
+                .with_observer(move |expr_ref, value| {
+                    observed
+                        .lock()
+                        .unwrap()
+                        .entry(expr_ref)
+                        .or_default()
+                        .push(value.clone());
+                });
+        let patient = json!({
+            "resourceType": "Patient",
+            "name": [
+                {"use": "official", "family": "Chalmers"},
+                {"use": "usual"},
+                {"use": "maiden"},
+                {"use": "old"},
+            ]
+        });
+        evaluator.evaluate(&ast, &patient).unwrap();
+
+        let dot = ast.to_dot_with_values(&values.lock().unwrap());
+        assert!(
+            dot.contains(r#"n0 [label=".family\n= \"Chalmers\""];"#),
+            "{dot}"
+        );
+        assert!(
+            dot.contains(r#"n4 [label="=\n= true\n= false\n= false\n(1 more)", shape=circle];"#),
+            "{dot}"
+        );
+    }
+}
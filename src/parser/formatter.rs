@@ -0,0 +1,636 @@
+use super::ast::FhirParser;
+use super::cst::{Cst, NodeKind, SyntaxElement, SyntaxNode};
+use crate::evaluator::error::{Error, ErrorCode};
+use crate::lexer::token::{Token, TokenKind};
+use crate::lexer::tokenizer::Lexer;
+use std::collections::HashMap;
+
+/// Layout settings for [`format`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FormatOptions {
+    /// The column lines are kept within where the expression can be broken
+    pub width: usize,
+    /// Spaces added for each level of indentation
+    pub indent: usize,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        Self {
+            width: 80,
+            indent: 4,
+        }
+    }
+}
+
+/// Print an expression in the canonical layout.
+///
+/// Operators are surrounded by single spaces and arguments separated by `, `.
+/// When an expression doesn't fit in `options.width`, chains of several function
+/// calls are broken before each `.`, operations before each operator and
+/// argument lists after `(`, each indented a level. Comments are kept next to
+/// the same tokens, and a line is always broken after a `//` comment.
+///
+/// Only whitespace changes: the result is checked to hold the same tokens and
+/// comments as the input, so it always parses to the same expression.
+///
+/// # Errors
+///
+/// Returns an error if the input isn't a valid expression, or in the unexpected
+/// case that formatting would change its tokens.
+pub fn format(input: &str, options: &FormatOptions) -> Result<String, Error> {
+    let tokens = Lexer::new(input).tokenize()?;
+    FhirParser::new(&tokens, input).parse()?;
+
+    let cst = Cst::parse(input)?;
+    let printer = Printer::new(&cst);
+    let output = Renderer::new(options).render(&printer.node(&cst.root));
+
+    if content(&output).ok() != Some(content(input)?) {
+        return Err(Error::new(
+            ErrorCode::Unsupported,
+            "Formatting would change the expression",
+        ));
+    }
+    Ok(output)
+}
+
+// Helper: the tokens of the source other than whitespace, followed by its
+// comments in the order of their text, which is what formatting must preserve
+fn content(source: &str) -> Result<Vec<(TokenKind, &str)>, Error> {
+    let mut tokens = Vec::new();
+    let mut comments = Vec::new();
+    for token in Lexer::new(source).tokenize_lossless()? {
+        let text = token.text(source);
+        match token.kind {
+            TokenKind::Whitespace => {}
+            TokenKind::LineComment | TokenKind::BlockComment => comments.push(text.trim_end()),
+            kind => tokens.push((kind, text)),
+        }
+    }
+    comments.sort_unstable();
+    let comments = comments
+        .into_iter()
+        .map(|text| (TokenKind::BlockComment, text));
+    tokens.extend(comments);
+    Ok(tokens)
+}
+
+// A layout to render, after Wadler's "A prettier printer"
+enum Doc<'a> {
+    Text(&'a str),
+    // A space, or a line break when the enclosing group is broken
+    Line,
+    // Nothing, or a line break when the enclosing group is broken
+    SoftLine,
+    HardLine,
+    // A space, unless at the start of a line or just inside `(` or `[`
+    Gap,
+    // A `//` comment on a line of its own
+    LineComment(&'a str),
+    // A `//` comment ending the current line
+    TrailingComment(&'a str),
+    Concat(Vec<Self>),
+    Indent(Vec<Self>),
+    // Laid out on one line if it fits, the flag forcing it to break
+    Group(Vec<Self>, bool),
+}
+
+impl Doc<'_> {
+    fn group(docs: Vec<Self>) -> Self {
+        let mut started = false;
+        let breaks = docs.iter().any(|doc| doc.breaks(&mut started));
+        Self::Group(docs, breaks)
+    }
+
+    // Whether the layout holds a line break whatever the width. A `//` comment
+    // before any text doesn't count, as it goes on the lines before the group.
+    fn breaks(&self, started: &mut bool) -> bool {
+        match self {
+            Self::Text(_) => {
+                *started = true;
+                false
+            }
+            Self::LineComment(_) => *started,
+            Self::HardLine | Self::TrailingComment(_) => true,
+            Self::Concat(docs) | Self::Indent(docs) | Self::Group(docs, _) => {
+                docs.iter().any(|doc| doc.breaks(started))
+            }
+            Self::Line | Self::SoftLine | Self::Gap => false,
+        }
+    }
+}
+
+// Turns the tree into a layout, keeping each comment with a neighbouring token
+struct Printer<'a> {
+    cst: &'a Cst<'a>,
+    // Comments before each token, by the token's start
+    leading: HashMap<usize, Vec<Token>>,
+    // A `//` comment on the same line as a token, by the token's start
+    trailing: HashMap<usize, Token>,
+    // Comments after the last token, and whether a line break precedes each
+    dangling: Vec<(Token, bool)>,
+}
+
+impl<'a> Printer<'a> {
+    fn new(cst: &'a Cst<'a>) -> Self {
+        let mut printer = Self {
+            cst,
+            leading: HashMap::new(),
+            trailing: HashMap::new(),
+            dangling: Vec::new(),
+        };
+        let mut previous: Option<usize> = None;
+        let mut line_break = false;
+        for token in cst.root.tokens() {
+            match token.kind {
+                TokenKind::Whitespace => {
+                    line_break |= cst.text(&token).contains(['\n', '\r']);
+                }
+                // Only when it directly follows the token, with no other comment between
+                TokenKind::LineComment
+                    if !line_break && previous.is_some() && printer.dangling.is_empty() =>
+                {
+                    printer.trailing.insert(previous.unwrap_or_default(), token);
+                }
+                TokenKind::LineComment | TokenKind::BlockComment => {
+                    printer.dangling.push((token, line_break));
+                }
+                _ => {
+                    let comments = printer.dangling.drain(..).map(|(comment, _)| comment);
+                    printer.leading.insert(token.start, comments.collect());
+                    previous = Some(token.start);
+                    line_break = false;
+                }
+            }
+        }
+        printer
+    }
+
+    fn node(&self, node: &SyntaxNode) -> Doc<'a> {
+        match node.kind {
+            NodeKind::Root => {
+                let mut docs: Vec<Doc> = node.nodes().map(|node| self.node(node)).collect();
+                for (comment, line_break) in &self.dangling {
+                    let text = self.cst.text(comment).trim_end();
+                    if comment.kind == TokenKind::LineComment {
+                        docs.push(if *line_break {
+                            Doc::LineComment(text)
+                        } else {
+                            Doc::TrailingComment(text)
+                        });
+                    } else {
+                        docs.push(if *line_break {
+                            Doc::HardLine
+                        } else {
+                            Doc::Text(" ")
+                        });
+                        docs.push(Doc::Text(text));
+                    }
+                }
+                Doc::Concat(docs)
+            }
+            NodeKind::Invocation | NodeKind::Index => self.chain(node),
+            NodeKind::BinaryOperation => self.operation(node),
+            NodeKind::Function => self.function(node),
+            NodeKind::Parenthesized => self.parenthesized(node),
+            NodeKind::Literal
+            | NodeKind::Identifier
+            | NodeKind::ExternalConstant
+            | NodeKind::Error => Doc::Concat(self.elements(significant(node))),
+        }
+    }
+
+    // A chain of invocations and indexes, broken before each `.` from the first
+    // function call on when it holds several calls
+    fn chain(&self, node: &SyntaxNode) -> Doc<'a> {
+        let mut suffixes = Vec::new();
+        let mut head = node;
+        while matches!(head.kind, NodeKind::Invocation | NodeKind::Index)
+            && let Some(object) = head.nodes().next()
+        {
+            suffixes.push(head);
+            head = object;
+        }
+        suffixes.reverse();
+
+        let is_call = |suffix: &SyntaxNode| {
+            suffix.kind == NodeKind::Invocation
+                && suffix.nodes().nth(1).map(|member| member.kind) == Some(NodeKind::Function)
+        };
+        let calls = suffixes.iter().filter(|suffix| is_call(suffix)).count();
+        let first_call = suffixes.iter().position(|suffix| is_call(suffix));
+
+        let mut docs = vec![self.node(head)];
+        let mut broken = Vec::new();
+        for (index, suffix) in suffixes.iter().enumerate() {
+            // Everything but the object the suffix applies to
+            let doc = Doc::Concat(self.elements(significant(suffix).skip(1)));
+            if calls < 2 || first_call.is_none_or(|first| index < first) {
+                docs.push(doc);
+            } else {
+                if suffix.kind == NodeKind::Invocation {
+                    broken.push(Doc::SoftLine);
+                }
+                broken.push(doc);
+            }
+        }
+        if broken.is_empty() {
+            return Doc::Concat(docs);
+        }
+        docs.push(Doc::Indent(broken));
+        Doc::group(docs)
+    }
+
+    // Operations, which associate to the right, laid out as one list of
+    // operands broken before each operator
+    fn operation(&self, node: &SyntaxNode) -> Doc<'a> {
+        let mut docs = Vec::new();
+        let mut rest = Vec::new();
+        let mut current = node;
+        loop {
+            let mut elements = significant(current);
+            let (Some(SyntaxElement::Node(lhs)), Some(SyntaxElement::Token(operator))) =
+                (elements.next(), elements.next())
+            else {
+                return Doc::Concat(self.elements(significant(node)));
+            };
+            let lhs = self.node(lhs);
+            if docs.is_empty() {
+                docs.push(lhs);
+            } else {
+                rest.push(lhs);
+            }
+            rest.extend([Doc::Line, self.token(operator), Doc::Text(" ")]);
+            match elements.next() {
+                Some(SyntaxElement::Node(rhs)) if rhs.kind == NodeKind::BinaryOperation => {
+                    current = rhs;
+                }
+                Some(SyntaxElement::Node(rhs)) => {
+                    rest.push(self.node(rhs));
+                    break;
+                }
+                _ => break,
+            }
+        }
+        docs.push(Doc::Indent(rest));
+        Doc::group(docs)
+    }
+
+    // A function call, with its arguments broken onto lines of their own when
+    // they don't fit
+    fn function(&self, node: &SyntaxNode) -> Doc<'a> {
+        let mut name = Vec::new();
+        let mut arguments = Vec::new();
+        let mut close = Vec::new();
+        for element in significant(node) {
+            match element {
+                SyntaxElement::Token(token) if token.kind == TokenKind::LeftParen => {
+                    name.push(self.token(token));
+                }
+                SyntaxElement::Token(token) if token.kind == TokenKind::RightParen => {
+                    close.push(self.token(token));
+                }
+                SyntaxElement::Token(token) => {
+                    arguments.extend([self.token(token), Doc::Line]);
+                }
+                SyntaxElement::Node(node) if name.is_empty() => name.push(self.node(node)),
+                SyntaxElement::Node(node) => arguments.push(self.node(node)),
+            }
+        }
+        if arguments.is_empty() {
+            name.extend(close);
+            return Doc::Concat(name);
+        }
+
+        arguments.insert(0, Doc::SoftLine);
+        name.push(Doc::Indent(arguments));
+        name.push(Doc::SoftLine);
+        name.extend(close);
+        Doc::group(name)
+    }
+
+    // A parenthesized expression, broken inside the parentheses when it
+    // doesn't fit
+    fn parenthesized(&self, node: &SyntaxNode) -> Doc<'a> {
+        let mut elements = self.elements(significant(node)).into_iter();
+        let (Some(open), Some(expression)) = (elements.next(), elements.next()) else {
+            return Doc::Concat(self.elements(significant(node)));
+        };
+        let mut docs = vec![
+            open,
+            Doc::Indent(vec![Doc::SoftLine, expression]),
+            Doc::SoftLine,
+        ];
+        // The closing parenthesis
+        docs.extend(elements);
+        Doc::group(docs)
+    }
+
+    fn elements<'n>(&self, elements: impl Iterator<Item = &'n SyntaxElement>) -> Vec<Doc<'a>> {
+        elements
+            .map(|element| match element {
+                SyntaxElement::Node(node) => self.node(node),
+                SyntaxElement::Token(token) => self.token(token),
+            })
+            .collect()
+    }
+
+    // A token with the comments kept with it
+    fn token(&self, token: &Token) -> Doc<'a> {
+        let mut docs = Vec::new();
+        for comment in self.leading.get(&token.start).into_iter().flatten() {
+            let text = self.cst.text(comment);
+            if comment.kind == TokenKind::LineComment {
+                docs.push(Doc::LineComment(text.trim_end()));
+            } else {
+                docs.extend([Doc::Gap, Doc::Text(text), Doc::Text(" ")]);
+            }
+        }
+        docs.push(Doc::Text(self.cst.text(token)));
+        if let Some(comment) = self.trailing.get(&token.start) {
+            docs.push(Doc::TrailingComment(self.cst.text(comment).trim_end()));
+        }
+        Doc::Concat(docs)
+    }
+}
+
+// Helper: the children of a node other than whitespace and comments
+fn significant(node: &SyntaxNode) -> impl Iterator<Item = &SyntaxElement> {
+    node.children
+        .iter()
+        .filter(|element| !matches!(element, SyntaxElement::Token(token) if token.kind.is_trivia()))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Flat,
+    Break,
+}
+
+struct Renderer<'a> {
+    options: &'a FormatOptions,
+    output: String,
+    column: usize,
+    // Whether a `//` comment ended the line, so nothing more can go on it
+    line_ended: bool,
+}
+
+impl<'a> Renderer<'a> {
+    const fn new(options: &'a FormatOptions) -> Self {
+        Self {
+            options,
+            output: String::new(),
+            column: 0,
+            line_ended: false,
+        }
+    }
+
+    fn render(mut self, doc: &'a Doc<'a>) -> String {
+        let mut stack = vec![(0, Mode::Break, doc)];
+        while let Some((indent, mode, doc)) = stack.pop() {
+            match doc {
+                Doc::Text(text) => {
+                    if self.line_ended {
+                        self.line_break(indent);
+                    }
+                    self.text(text);
+                }
+                Doc::Line if mode == Mode::Flat && !self.line_ended => self.text(" "),
+                Doc::SoftLine if mode == Mode::Flat && !self.line_ended => {}
+                Doc::Line | Doc::SoftLine | Doc::HardLine => self.line_break(indent),
+                Doc::LineComment(comment) => {
+                    if !self.at_line_start() {
+                        self.line_break(indent);
+                    }
+                    self.text(comment);
+                    self.line_break(indent);
+                }
+                Doc::Gap => {
+                    if !self.line_ended
+                        && !self.at_line_start()
+                        && !self.output.ends_with([' ', '(', '['])
+                    {
+                        self.text(" ");
+                    }
+                }
+                Doc::TrailingComment(comment) => {
+                    self.text(" ");
+                    self.text(comment);
+                    self.line_ended = true;
+                }
+                Doc::Concat(docs) => stack.extend(docs.iter().rev().map(|doc| (indent, mode, doc))),
+                Doc::Indent(docs) => {
+                    let indent = indent + self.options.indent;
+                    stack.extend(docs.iter().rev().map(|doc| (indent, mode, doc)));
+                }
+                Doc::Group(docs, breaks) => {
+                    let width = self.options.width.saturating_sub(self.column);
+                    let mode = if mode == Mode::Flat || (!breaks && fits(docs, &stack, width)) {
+                        Mode::Flat
+                    } else {
+                        Mode::Break
+                    };
+                    stack.extend(docs.iter().rev().map(|doc| (indent, mode, doc)));
+                }
+            }
+        }
+        self.output.truncate(self.output.trim_end().len());
+        self.output
+    }
+
+    fn text(&mut self, text: &str) {
+        self.output.push_str(text);
+        self.column = match text.rfind('\n') {
+            Some(line_start) => text[line_start + 1..].chars().count(),
+            None => self.column + text.chars().count(),
+        };
+    }
+
+    fn line_break(&mut self, indent: usize) {
+        self.line_ended = false;
+        self.output
+            .truncate(self.output.trim_end_matches(' ').len());
+        self.output.push('\n');
+        self.output.push_str(&" ".repeat(indent));
+        self.column = indent;
+    }
+
+    fn at_line_start(&self) -> bool {
+        let line_start = self.output.rfind('\n').map_or(0, |index| index + 1);
+        self.output[line_start..].trim().is_empty()
+    }
+}
+
+// Helper: whether `docs` laid out flat, and what follows them up to the next
+// line break, take no more than `width` columns
+fn fits(docs: &[Doc], rest: &[(usize, Mode, &Doc)], width: usize) -> bool {
+    let mut remaining = width;
+    let mut stack: Vec<(Mode, &Doc)> = docs.iter().rev().map(|doc| (Mode::Flat, doc)).collect();
+    let mut rest = rest.iter().rev();
+    loop {
+        let Some((mode, doc)) = stack
+            .pop()
+            .or_else(|| rest.next().map(|(_, mode, doc)| (*mode, *doc)))
+        else {
+            return true;
+        };
+        match doc {
+            Doc::Text(text) => {
+                let line = text.split('\n').next().unwrap_or_default();
+                let Some(left) = remaining.checked_sub(line.chars().count()) else {
+                    return false;
+                };
+                if line.len() < text.len() {
+                    return true;
+                }
+                remaining = left;
+            }
+            Doc::Line | Doc::Gap if mode == Mode::Flat => {
+                let Some(left) = remaining.checked_sub(1) else {
+                    return false;
+                };
+                remaining = left;
+            }
+            Doc::SoftLine if mode == Mode::Flat => {}
+            Doc::Line
+            | Doc::SoftLine
+            | Doc::HardLine
+            | Doc::LineComment(_)
+            | Doc::TrailingComment(_) => return true,
+            Doc::Gap => {}
+            Doc::Concat(docs) | Doc::Indent(docs) => {
+                stack.extend(docs.iter().rev().map(|doc| (mode, doc)));
+            }
+            Doc::Group(docs, breaks) => {
+                let mode = if *breaks { Mode::Break } else { mode };
+                stack.extend(docs.iter().rev().map(|doc| (mode, doc)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format_default(input: &str) -> String {
+        format(input, &FormatOptions::default()).unwrap()
+    }
+
+    fn format_width(input: &str, width: usize) -> String {
+        let options = FormatOptions {
+            width,
+            ..FormatOptions::default()
+        };
+        format(input, &options).unwrap()
+    }
+
+    #[test]
+    fn test_canonical_spacing() {
+        assert_eq!(
+            format_default("  Patient . name [ 0 ].given.first( )  "),
+            "Patient.name[0].given.first()"
+        );
+        assert_eq!(
+            format_default("name.where(use='official'  ,true)=%`vs-name`"),
+            "name.where(use = 'official', true) = %`vs-name`"
+        );
+        assert_eq!(
+            format_default("Patient\r\n  .birthDate\t>=@1970-01-01"),
+            "Patient.birthDate >= @1970-01-01"
+        );
+    }
+
+    #[test]
+    fn test_tokens_are_kept_verbatim() {
+        assert_eq!(
+            format_default(r"'O\'Brien' != `given name`.`exists`()"),
+            r"'O\'Brien' != `given name`.`exists`()"
+        );
+        assert_eq!(format_default("1.50 = 1.5"), "1.50 = 1.5");
+    }
+
+    #[test]
+    fn test_long_chains_break_before_calls() {
+        let input = "Patient.name.where(use = 'official').given.where(length() > 3).first()";
+        assert_eq!(format_width(input, 80), input);
+        assert_eq!(
+            format_width(input, 40),
+            "Patient.name\n    .where(use = 'official')\n    .given\n    .where(length() > 3)\n    .first()"
+        );
+    }
+
+    #[test]
+    fn test_long_operations_break_before_operators() {
+        assert_eq!(
+            format_width("Patient.gender = 'male' = Patient.active", 30),
+            "Patient.gender\n    = 'male'\n    = Patient.active"
+        );
+    }
+
+    #[test]
+    fn test_long_arguments_break_after_parenthesis() {
+        assert_eq!(
+            format_width("name.where(given = 'Peter', family = 'Chalmers')", 30),
+            "name.where(\n    given = 'Peter',\n    family = 'Chalmers'\n)"
+        );
+    }
+
+    #[test]
+    fn test_parentheses_are_kept() {
+        assert_eq!(
+            format_default("( a = b ) = c.where( ( d ) )"),
+            "(a = b) = c.where((d))"
+        );
+        assert_eq!(
+            format_width("(Patient.gender = 'male') = Patient.active", 20),
+            "(\n    Patient.gender\n        = 'male'\n)\n    = Patient.active"
+        );
+    }
+
+    #[test]
+    fn test_comments_are_kept() {
+        assert_eq!(
+            format_default("Patient /* the resource */ .name"),
+            "Patient /* the resource */ .name"
+        );
+        assert_eq!(
+            format_default("// Official names\nPatient.name // every one\n  .given"),
+            "// Official names\nPatient.name // every one\n.given"
+        );
+        assert_eq!(
+            format_default("name.where(\n  // official only\n  use = 'official'\n).given // end"),
+            "name.where(\n    // official only\n    use = 'official'\n).given // end"
+        );
+        assert_eq!(
+            format_default("Patient.name\n/* trailing */"),
+            "Patient.name\n/* trailing */"
+        );
+    }
+
+    #[test]
+    fn test_format_is_idempotent() {
+        for input in [
+            "Patient.name.where(use = 'official').given.where(length() > 3).first()",
+            "a = // note\n b",
+            "a = \n// note\nb",
+            "name.where(\n  // official only\n  use = 'official'\n).given // end",
+            "f(/* c */ x, y)",
+            "a /* c */ = /* d */ b.c /* e */",
+            "a /* c */ // d\n = b /* e */ // f",
+        ] {
+            for width in [20, 80] {
+                let formatted = format_width(input, width);
+                assert_eq!(format_width(&formatted, width), formatted, "{input}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_invalid_input_is_rejected() {
+        let error = format("Patient.name.where(", &FormatOptions::default()).unwrap_err();
+        assert_eq!(error.code, ErrorCode::UnbalancedDelimiter);
+        assert!(format("", &FormatOptions::default()).is_err());
+    }
+}
@@ -0,0 +1,485 @@
+//! The functions of `FHIRPath` and FHIR, for checking calls before evaluation.
+//!
+//! [`check`] finds calls of functions that don't exist, such as
+//! `name.frist()`, with the function probably meant, and calls with the wrong
+//! number or kind of arguments, such as `name.first(1)`. The parser runs it
+//! over every tree it builds, so these are found once, whatever the data, and
+//! evaluation doesn't check them again. Trees made otherwise, with
+//! [`Path`](crate::Path) or [`Ast::from_json`], are checked by calling it.
+
+use super::ast::Ast;
+use super::grammar::{ExprPool, ExprRef, Expression};
+use super::visit::{Visitor, walk};
+use crate::evaluator::error::{Error, ErrorCode};
+
+/// How a function uses an argument.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArgumentKind {
+    /// Evaluated once, against the input of the whole expression, as the
+    /// `'url'` of `extension('url')`.
+    Value,
+    /// Evaluated for each item of the collection the function is called on, as
+    /// the criteria of `where(use = 'official')`.
+    Lambda,
+    /// A type name rather than an expression, as the `Patient` of
+    /// `ofType(Patient)`.
+    TypeSpecifier,
+}
+
+/// The name and parameters of a function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Signature {
+    pub name: &'static str,
+    pub parameters: &'static [ArgumentKind],
+    /// How many of the parameters are required, the rest being optional.
+    pub required: usize,
+    /// Whether the last parameter may be repeated, as the sort keys of
+    /// `sort()`.
+    pub variadic: bool,
+}
+
+impl Signature {
+    const fn new(name: &'static str, parameters: &'static [ArgumentKind]) -> Self {
+        Self {
+            name,
+            parameters,
+            required: parameters.len(),
+            variadic: false,
+        }
+    }
+
+    // The last `optional` parameters may be left out
+    const fn optional(mut self, optional: usize) -> Self {
+        self.required -= optional;
+        self
+    }
+
+    // The last parameter may be given any number of times
+    const fn variadic(mut self) -> Self {
+        self.variadic = true;
+        self
+    }
+
+    /// Whether the function takes `count` arguments.
+    #[must_use]
+    pub const fn accepts(&self, count: usize) -> bool {
+        self.required <= count && (self.variadic || count <= self.parameters.len())
+    }
+
+    /// How the function uses its argument at `index`.
+    #[must_use]
+    pub fn parameter(&self, index: usize) -> Option<ArgumentKind> {
+        match self.parameters.get(index) {
+            None if self.variadic => self.parameters.last().copied(),
+            parameter => parameter.copied(),
+        }
+    }
+
+    // "takes no arguments", "takes 1 argument", "takes 1 or 2 arguments"
+    fn arity(&self) -> String {
+        let (required, total) = (self.required, self.parameters.len());
+        match (required, total) {
+            (0, _) if self.variadic => "takes any number of arguments".to_string(),
+            (required, _) if self.variadic => format!("takes at least {required} arguments"),
+            (_, 0) => "takes no arguments".to_string(),
+            (1, 1) => "takes 1 argument".to_string(),
+            (required, total) if required == total => format!("takes {total} arguments"),
+            (0, 1) => "takes at most 1 argument".to_string(),
+            (required, total) if required + 1 == total => {
+                format!("takes {required} or {total} arguments")
+            }
+            (required, total) => format!("takes {required} to {total} arguments"),
+        }
+    }
+}
+
+const NONE: &[ArgumentKind] = &[];
+const VALUE: &[ArgumentKind] = &[ArgumentKind::Value];
+const VALUES: &[ArgumentKind] = &[ArgumentKind::Value, ArgumentKind::Value];
+const VALUES_3: &[ArgumentKind] = &[
+    ArgumentKind::Value,
+    ArgumentKind::Value,
+    ArgumentKind::Value,
+];
+const LAMBDA: &[ArgumentKind] = &[ArgumentKind::Lambda];
+const TYPE: &[ArgumentKind] = &[ArgumentKind::TypeSpecifier];
+
+/// Every function of `FHIRPath`, including those still in trial use, and of its
+/// FHIR profile, whether this implementation evaluates it yet or not.
+pub const FUNCTIONS: &[Signature] = &[
+    // Existence
+    Signature::new("empty", NONE),
+    Signature::new("exists", LAMBDA).optional(1),
+    Signature::new("all", LAMBDA),
+    Signature::new("allTrue", NONE),
+    Signature::new("anyTrue", NONE),
+    Signature::new("allFalse", NONE),
+    Signature::new("anyFalse", NONE),
+    Signature::new("subsetOf", VALUE),
+    Signature::new("supersetOf", VALUE),
+    Signature::new("count", NONE),
+    Signature::new("distinct", NONE),
+    Signature::new("isDistinct", NONE),
+    // Filtering and projection
+    Signature::new("where", LAMBDA),
+    Signature::new("select", LAMBDA),
+    Signature::new("repeat", LAMBDA),
+    Signature::new("ofType", TYPE),
+    // Subsetting
+    Signature::new("single", NONE),
+    Signature::new("first", NONE),
+    Signature::new("last", NONE),
+    Signature::new("tail", NONE),
+    Signature::new("skip", VALUE),
+    Signature::new("take", VALUE),
+    Signature::new("intersect", VALUE),
+    Signature::new("exclude", VALUE),
+    // Combining
+    Signature::new("union", VALUE),
+    Signature::new("combine", VALUE),
+    // Conversion
+    Signature::new("iif", VALUES_3).optional(1),
+    Signature::new("toBoolean", NONE),
+    Signature::new("convertsToBoolean", NONE),
+    Signature::new("toInteger", NONE),
+    Signature::new("convertsToInteger", NONE),
+    Signature::new("toDate", NONE),
+    Signature::new("convertsToDate", NONE),
+    Signature::new("toDateTime", NONE),
+    Signature::new("convertsToDateTime", NONE),
+    Signature::new("toDecimal", NONE),
+    Signature::new("convertsToDecimal", NONE),
+    Signature::new("toQuantity", VALUE).optional(1),
+    Signature::new("convertsToQuantity", VALUE).optional(1),
+    Signature::new("toString", NONE),
+    Signature::new("convertsToString", NONE),
+    Signature::new("toTime", NONE),
+    Signature::new("convertsToTime", NONE),
+    // Strings
+    Signature::new("indexOf", VALUE),
+    Signature::new("substring", VALUES).optional(1),
+    Signature::new("startsWith", VALUE),
+    Signature::new("endsWith", VALUE),
+    Signature::new("contains", VALUE),
+    Signature::new("upper", NONE),
+    Signature::new("lower", NONE),
+    Signature::new("replace", VALUES),
+    Signature::new("matches", VALUE),
+    Signature::new("replaceMatches", VALUES),
+    Signature::new("length", NONE),
+    Signature::new("toChars", NONE),
+    Signature::new("lastIndexOf", VALUE),
+    Signature::new("matchesFull", VALUE),
+    Signature::new("trim", NONE),
+    Signature::new("split", VALUE),
+    Signature::new("join", VALUE).optional(1),
+    Signature::new("encode", VALUE),
+    Signature::new("decode", VALUE),
+    Signature::new("escape", VALUE),
+    Signature::new("unescape", VALUE),
+    // Math
+    Signature::new("abs", NONE),
+    Signature::new("ceiling", NONE),
+    Signature::new("exp", NONE),
+    Signature::new("floor", NONE),
+    Signature::new("ln", NONE),
+    Signature::new("log", VALUE),
+    Signature::new("power", VALUE),
+    Signature::new("round", VALUE).optional(1),
+    Signature::new("sqrt", NONE),
+    Signature::new("truncate", NONE),
+    // Dates, times and precision
+    Signature::new("lowBoundary", VALUE).optional(1),
+    Signature::new("highBoundary", VALUE).optional(1),
+    Signature::new("precision", NONE),
+    Signature::new("yearOf", NONE),
+    Signature::new("monthOf", NONE),
+    Signature::new("dayOf", NONE),
+    Signature::new("hourOf", NONE),
+    Signature::new("minuteOf", NONE),
+    Signature::new("secondOf", NONE),
+    Signature::new("millisecondOf", NONE),
+    Signature::new("timezoneOffsetOf", NONE),
+    Signature::new("dateOf", NONE),
+    Signature::new("timeOf", NONE),
+    // Tree navigation
+    Signature::new("children", NONE),
+    Signature::new("descendants", NONE),
+    // Utility
+    Signature::new("trace", &[ArgumentKind::Value, ArgumentKind::Lambda]).optional(1),
+    Signature::new("now", NONE),
+    Signature::new("timeOfDay", NONE),
+    Signature::new("today", NONE),
+    Signature::new("not", NONE),
+    Signature::new("is", TYPE),
+    Signature::new("as", TYPE),
+    Signature::new("aggregate", &[ArgumentKind::Lambda, ArgumentKind::Value]).optional(1),
+    Signature::new(
+        "defineVariable",
+        &[ArgumentKind::Value, ArgumentKind::Lambda],
+    )
+    .optional(1),
+    Signature::new("sort", LAMBDA).optional(1).variadic(),
+    // Types
+    Signature::new("type", NONE),
+    // FHIR
+    Signature::new("extension", VALUE),
+    Signature::new("hasValue", NONE),
+    Signature::new("getValue", NONE),
+    Signature::new("resolve", NONE),
+    Signature::new("elementDefinition", NONE),
+    Signature::new("slice", VALUES),
+    Signature::new("checkModifiers", VALUE),
+    Signature::new("conformsTo", VALUE),
+    Signature::new("memberOf", VALUE),
+    Signature::new("subsumes", VALUE),
+    Signature::new("subsumedBy", VALUE),
+    Signature::new("htmlChecks", NONE),
+    Signature::new("comparable", VALUE),
+];
+
+/// The signature of the function `name`, if there is one.
+#[must_use]
+pub fn lookup(name: &str) -> Option<&'static Signature> {
+    FUNCTIONS.iter().find(|signature| signature.name == name)
+}
+
+/// The function whose name is closest to `name`, if any is close enough to be
+/// a likely misspelling.
+#[must_use]
+pub fn suggest(name: &str) -> Option<&'static str> {
+    let limit = (name.chars().count() / 4).max(1);
+    let name = name.to_lowercase();
+    FUNCTIONS
+        .iter()
+        .map(|signature| {
+            (
+                distance(&name, &signature.name.to_lowercase()),
+                signature.name,
+            )
+        })
+        .filter(|(distance, _)| *distance <= limit)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, name)| name)
+}
+
+/// Every call of the tree that doesn't fit a function of [`FUNCTIONS`], in
+/// source order.
+///
+/// Reports `ErrorCode::UnknownFunction` for a name that isn't a function,
+/// suggesting a close one, and `ErrorCode::InvalidArgument` for a call with
+/// the wrong number of arguments or with a type specifier that isn't a type
+/// name.
+///
+/// ```rust
+/// use fhirlighter::Path;
+/// use fhirlighter::parser::functions::check;
+///
+/// let ast = Path::root("name")
+///     .call("frist", [])
+///     .member("given")
+///     .call("first", [Path::integer(1)])
+///     .build()?;
+/// let errors = check(&ast);
+/// assert_eq!(errors[0].message, "Unknown function 'frist', did you mean 'first'?");
+/// assert_eq!(errors[1].message, "first() takes no arguments, but was given 1");
+/// # Ok::<(), fhirlighter::Error>(())
+/// ```
+#[must_use]
+pub fn check(ast: &Ast) -> Vec<Error> {
+    let mut checker = Checker {
+        pool: &ast.expressions,
+        errors: Vec::new(),
+    };
+    walk(ast, &mut checker);
+    checker.errors
+}
+
+struct Checker<'a> {
+    pool: &'a ExprPool,
+    errors: Vec<Error>,
+}
+
+impl<'a> Visitor<'a> for Checker<'a> {
+    fn leave_function_call(&mut self, expr_ref: ExprRef, name: &'a str, arguments: &'a [ExprRef]) {
+        let span = self.pool.span(expr_ref);
+        let Some(signature) = lookup(name) else {
+            let mut message = format!("Unknown function '{name}'");
+            if let Some(suggestion) = suggest(name) {
+                message.push_str(", did you mean '");
+                message.push_str(suggestion);
+                message.push_str("'?");
+            }
+            let Expression::FunctionCall { function, .. } = self.pool.get(expr_ref) else {
+                return;
+            };
+            let span = self.pool.span(*function);
+            self.errors
+                .push(Error::new(ErrorCode::UnknownFunction, message).with_span(span));
+            return;
+        };
+
+        if !signature.accepts(arguments.len()) {
+            let message = format!(
+                "{name}() {}, but was given {}",
+                signature.arity(),
+                arguments.len()
+            );
+            self.errors
+                .push(Error::new(ErrorCode::InvalidArgument, message).with_span(span));
+            return;
+        }
+        for (index, argument) in arguments.iter().enumerate() {
+            if signature.parameter(index) == Some(ArgumentKind::TypeSpecifier)
+                && !self.is_type_specifier(*argument)
+            {
+                let message = format!("{name}() expects a type specifier");
+                let span = self.pool.span(*argument);
+                self.errors
+                    .push(Error::new(ErrorCode::InvalidArgument, message).with_span(span));
+            }
+        }
+    }
+}
+
+impl Checker<'_> {
+    // A type name, possibly qualified by its namespace as in `FHIR.Patient`
+    fn is_type_specifier(&self, expr_ref: ExprRef) -> bool {
+        match self.pool.get(expr_ref) {
+            Expression::Identifier(_) => true,
+            Expression::MemberAccess { object, .. } => {
+                matches!(self.pool.get(*object), Expression::Identifier(_))
+            }
+            _ => false,
+        }
+    }
+}
+
+// Helper: the edit distance between two names, counting swapped neighbours as
+// one edit
+fn distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    // Rows of the table for the prefixes of `a` of length i - 2, i - 1 and i
+    let mut previous2 = vec![0; b.len() + 1];
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    let mut current = vec![0; b.len() + 1];
+    for i in 1..=a.len() {
+        current[0] = i;
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            current[j] = (previous[j] + 1)
+                .min(current[j - 1] + 1)
+                .min(previous[j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                current[j] = current[j].min(previous2[j - 2] + 1);
+            }
+        }
+        std::mem::swap(&mut previous2, &mut previous);
+        std::mem::swap(&mut previous, &mut current);
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::token::Span;
+    use crate::parse_with_diagnostics;
+
+    // Helper: the tree of `input`, which `parse` would reject for its calls
+    fn unchecked(input: &str) -> Ast {
+        parse_with_diagnostics(input).ast
+    }
+
+    fn messages(input: &str) -> Vec<String> {
+        check(&unchecked(input))
+            .into_iter()
+            .map(|error| error.message)
+            .collect::<Vec<_>>()
+    }
+
+    #[test]
+    fn test_known_calls() {
+        for input in [
+            "name.where(use = 'official').given.first()",
+            "name.exists() = name.exists(given = 'Peter')",
+            "name.substring(1) = name.substring(1, 2)",
+            "entry.resource.ofType(FHIR.Patient).is(Patient)",
+            "trace('name', given).iif(a, b, c)",
+            "name.type() = name.given.join(',')",
+            "name.sort() = name.sort(family, given.first())",
+            "defineVariable('n', name).select(%n)",
+            "exists()",
+        ] {
+            assert!(messages(input).is_empty(), "{input}");
+        }
+    }
+
+    #[test]
+    fn test_specification_functions() {
+        // The functions of FHIRPath, including those still in trial use, and of
+        // its FHIR profile
+        let names = "\
+            empty exists all allTrue anyTrue allFalse anyFalse subsetOf supersetOf \
+            count distinct isDistinct where select repeat ofType single first last \
+            tail skip take intersect exclude union combine iif toBoolean \
+            convertsToBoolean toInteger convertsToInteger toDate convertsToDate \
+            toDateTime convertsToDateTime toDecimal convertsToDecimal toQuantity \
+            convertsToQuantity toString convertsToString toTime convertsToTime \
+            indexOf lastIndexOf substring startsWith endsWith contains upper lower \
+            replace matches matchesFull replaceMatches length toChars trim split \
+            join encode decode escape unescape abs ceiling exp floor ln log power \
+            round sqrt truncate lowBoundary highBoundary precision yearOf monthOf \
+            dayOf hourOf minuteOf secondOf millisecondOf timezoneOffsetOf dateOf \
+            timeOf children descendants trace now timeOfDay today defineVariable \
+            sort not is as type aggregate extension hasValue getValue resolve \
+            elementDefinition slice checkModifiers conformsTo memberOf subsumes \
+            subsumedBy htmlChecks comparable";
+        for name in names.split_whitespace() {
+            assert!(lookup(name).is_some(), "{name}");
+        }
+    }
+
+    #[test]
+    fn test_unknown_functions() {
+        assert_eq!(
+            messages("name.frist() = name.Count() = name.frobnicate()"),
+            [
+                "Unknown function 'frist', did you mean 'first'?",
+                "Unknown function 'Count', did you mean 'count'?",
+                "Unknown function 'frobnicate'",
+            ]
+        );
+        let errors = check(&unchecked("name.frist()"));
+        assert_eq!(errors[0].code, ErrorCode::UnknownFunction);
+        assert_eq!(errors[0].span, Some(Span::new(5, 10)));
+    }
+
+    #[test]
+    fn test_wrong_arguments() {
+        assert_eq!(
+            messages("name.first(1) = name.where() = substring(1, 2, 3) = iif(1)"),
+            [
+                "first() takes no arguments, but was given 1",
+                "where() takes 1 argument, but was given 0",
+                "substring() takes 1 or 2 arguments, but was given 3",
+                "iif() takes 2 or 3 arguments, but was given 1",
+            ]
+        );
+        let errors = check(&unchecked("value.ofType('Quantity')"));
+        assert_eq!(errors[0].message, "ofType() expects a type specifier");
+        assert_eq!(errors[0].code, ErrorCode::InvalidArgument);
+        assert_eq!(errors[0].span, Some(Span::new(13, 23)));
+    }
+
+    #[test]
+    fn test_distance() {
+        assert_eq!(distance("frist", "first"), 1);
+        assert_eq!(distance("exits", "exists"), 1);
+        assert_eq!(distance("where", "where"), 0);
+        assert_eq!(distance("", "abc"), 3);
+        assert_eq!(suggest("lenght"), Some("length"));
+        assert_eq!(suggest("x"), None);
+    }
+}
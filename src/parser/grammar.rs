@@ -1,6 +1,7 @@
 use time::{Date, PrimitiveDateTime};
 
-use crate::evaluator::error::Error;
+use crate::evaluator::error::{Error, ErrorCode};
+use crate::lexer::token::Span;
 use std::fmt;
 /*
 
@@ -53,7 +54,11 @@ function_call = identifier "(" [param_list] ")" ;
 param_list = expression {"," expression} ;
 */
 #[derive(Debug, Clone, PartialEq)]
-pub struct ExprPool(Vec<Expression>);
+pub struct ExprPool {
+    expressions: Vec<Expression>,
+    // The source range of each expression, parallel to `expressions`
+    spans: Vec<Span>,
+}
 
 impl Default for ExprPool {
     fn default() -> Self {
@@ -64,49 +69,194 @@ impl Default for ExprPool {
 impl ExprPool {
     #[must_use]
     pub const fn new() -> Self {
-        // TODO: Reduce re-allocations by estimating capacity
-        Self(Vec::new())
+        Self {
+            expressions: Vec::new(),
+            spans: Vec::new(),
+        }
+    }
+
+    /// A pool with room for `capacity` expressions before it reallocates.
+    #[must_use]
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            expressions: Vec::with_capacity(capacity),
+            spans: Vec::with_capacity(capacity),
+        }
     }
 
+    /// Make room for at least `additional` more expressions.
+    pub fn reserve(&mut self, additional: usize) {
+        self.expressions.reserve(additional);
+        self.spans.reserve(additional);
+    }
+
+    /// Remove every expression, keeping the memory for the next tree. Any
+    /// `ExprRef` into the pool is left dangling.
+    pub fn clear(&mut self) {
+        self.expressions.clear();
+        self.spans.clear();
+    }
+
+    /// Add an expression that doesn't come from source text, such as one built
+    /// programmatically. Its span is empty.
+    ///
     /// # Errors
     ///
-    /// Returns `Error::Parse` if the number of expressions exceeds the maximum size
-    /// that can be represented by a u16 (65,535 expressions).
+    /// Returns `ErrorCode::TooManyExpressions` if the pool already holds
+    /// `MAX_EXPRESSIONS` expressions.
     pub fn add(&mut self, expr: Expression) -> Result<ExprRef, Error> {
-        self.0.push(expr);
-        let index = (self.0.len() - 1)
-            .try_into()
-            .map_err(|_| Error::Parse("Number of expressions exceeded pool size".to_string()))?;
+        self.add_with_span(expr, Span::default())
+    }
+
+    /// # Errors
+    ///
+    /// Returns `ErrorCode::TooManyExpressions` if the pool already holds
+    /// `MAX_EXPRESSIONS` expressions.
+    pub fn add_with_span(&mut self, expr: Expression, span: Span) -> Result<ExprRef, Error> {
+        let index = ExprIndex::try_from(self.expressions.len())
+            .ok()
+            .filter(|index| *index < ExprIndex::MAX)
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorCode::TooManyExpressions,
+                    format!("Number of expressions exceeded pool size of {MAX_EXPRESSIONS}"),
+                )
+                .with_span(span)
+            })?;
+        self.expressions.push(expr);
+        self.spans.push(span);
         Ok(ExprRef(index))
     }
 
     #[must_use]
     pub fn get(&self, expr_ref: ExprRef) -> &Expression {
-        &self.0[expr_ref.0 as usize]
+        &self.expressions[expr_ref.0 as usize]
+    }
+
+    /// Change an expression in place. Its references must stay in this pool.
+    pub fn get_mut(&mut self, expr_ref: ExprRef) -> &mut Expression {
+        &mut self.expressions[expr_ref.0 as usize]
+    }
+
+    /// The number of expressions in the pool, reachable from the root or not.
+    #[must_use]
+    pub const fn len(&self) -> usize {
+        self.expressions.len()
+    }
+
+    #[must_use]
+    pub const fn is_empty(&self) -> bool {
+        self.expressions.is_empty()
+    }
+
+    /// The source range the expression was parsed from.
+    #[must_use]
+    pub fn span(&self, expr_ref: ExprRef) -> Span {
+        self.spans[expr_ref.0 as usize]
+    }
+
+    /// Whether the expression at `expr_ref` and the one at `other_ref` of `other`
+    /// are the same tree, whatever their spans and places in the pools.
+    #[must_use]
+    pub fn same_tree(&self, expr_ref: ExprRef, other: &Self, other_ref: ExprRef) -> bool {
+        let same = |lhs: &ExprRef, rhs: &ExprRef| self.same_tree(*lhs, other, *rhs);
+        match (self.get(expr_ref), other.get(other_ref)) {
+            (
+                Expression::MemberAccess { object, member },
+                Expression::MemberAccess {
+                    object: other_object,
+                    member: other_member,
+                },
+            ) => member == other_member && same(object, other_object),
+            (
+                Expression::FunctionCall {
+                    object,
+                    function,
+                    arguments,
+                },
+                Expression::FunctionCall {
+                    object: other_object,
+                    function: other_function,
+                    arguments: other_arguments,
+                },
+            ) => {
+                let objects = match (object, other_object) {
+                    (Some(object), Some(other_object)) => same(object, other_object),
+                    (object, other_object) => object.is_none() && other_object.is_none(),
+                };
+                objects
+                    && same(function, other_function)
+                    && arguments.len() == other_arguments.len()
+                    && arguments
+                        .iter()
+                        .zip(other_arguments)
+                        .all(|(argument, other_argument)| same(argument, other_argument))
+            }
+            (
+                Expression::BinaryOperation { operator, lhs, rhs },
+                Expression::BinaryOperation {
+                    operator: other_operator,
+                    lhs: other_lhs,
+                    rhs: other_rhs,
+                },
+            ) => operator == other_operator && same(lhs, other_lhs) && same(rhs, other_rhs),
+            (
+                Expression::Index { object, index },
+                Expression::Index {
+                    object: other_object,
+                    index: other_index,
+                },
+            ) => same(object, other_object) && same(index, other_index),
+            // Anything else holds no references, so is compared directly
+            (expression, other_expression) => expression == other_expression,
+        }
+    }
+
+    // Widen the span of an expression, as for one in parentheses
+    pub(crate) fn set_span(&mut self, expr_ref: ExprRef, span: Span) {
+        self.spans[expr_ref.0 as usize] = span;
     }
 
     // TODO: Avoid this
     pub fn set_function_object(&mut self, expr_ref: ExprRef, object: ExprRef) -> ExprRef {
-        let expression = &self.0[expr_ref.0 as usize];
+        let expression = &self.expressions[expr_ref.0 as usize];
         if let Expression::FunctionCall {
             object: _,
             function,
             arguments,
         } = expression
         {
-            self.0[expr_ref.0 as usize] = Expression::FunctionCall {
+            self.expressions[expr_ref.0 as usize] = Expression::FunctionCall {
                 object: Some(object),
                 function: function.to_owned(),
                 arguments: arguments.to_owned(),
             };
+            self.spans[expr_ref.0 as usize] = self.span(object).to(self.span(expr_ref));
         }
         expr_ref
     }
 }
 
+/// The integer behind an `ExprRef`, which bounds the size of a pool.
+///
+/// It is a `u32` unless the `compact-refs` feature selects a `u16`, which halves
+/// the size of references for pools of fewer than 65,535 expressions.
+///
+/// The feature isn't additive: it changes this type and lowers
+/// [`MAX_EXPRESSIONS`] for every crate of a build, so a dependency that names
+/// `ExprIndex` as a `u32` stops compiling and one that parses larger trees gets
+/// `TooManyExpressions`. Only enable it in the final application.
+#[cfg(not(feature = "compact-refs"))]
+pub type ExprIndex = u32;
+#[cfg(feature = "compact-refs")]
+pub type ExprIndex = u16;
+
+/// The most expressions a pool can hold.
+pub const MAX_EXPRESSIONS: usize = ExprIndex::MAX as usize;
+
 // TODO: Remove Copy due to function update in Arena
-#[derive(Debug, Clone, PartialEq, Eq, Copy)]
-pub struct ExprRef(u16);
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Copy, Default)]
+pub struct ExprRef(ExprIndex);
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum BinaryOperator {
@@ -147,6 +297,9 @@ pub enum Expression {
         index: ExprRef,
     },
 
+    // Environment variables like "%resource" or "%`vs-administrative-gender`"
+    ExternalConstant(String),
+
     // Literals
     String(String),
     Number(f64),
@@ -154,6 +307,8 @@ pub enum Expression {
     Boolean(bool),
     ISODateTime(PrimitiveDateTime),
     ISODate(Date),
+    // Part of the source that couldn't be parsed, see `FhirParser::parse_recovering`
+    Error,
 }
 
 impl fmt::Display for BinaryOperator {
@@ -185,6 +340,8 @@ impl BinaryOperator {
     }
 }
 
+// A single node, showing the expressions it refers to as their `ExprRef`s. See
+// `unparser::unparse` for the source of a whole tree.
 impl fmt::Display for Expression {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -219,16 +376,32 @@ impl fmt::Display for Expression {
             Self::Index { object, index } => {
                 write!(f, "{object}[{index}]")
             }
+            Self::ExternalConstant(name) => write!(f, "%{name}"),
             Self::String(s) => write!(f, "'{s}'"),
             Self::Number(n) => write!(f, "{n}"),
             Self::Integer(i) => write!(f, "{i}"),
             Self::Boolean(b) => write!(f, "{b}"),
             Self::ISODateTime(date) => write!(f, "{date}"),
             Self::ISODate(date) => write!(f, "{date}"),
+            Self::Error => write!(f, "<error>"),
         }
     }
 }
 
+impl ExprRef {
+    /// The place of the expression in its pool, from 0 to `ExprPool::len`.
+    ///
+    /// Expressions are only appended to a pool, so while the pool holds its
+    /// tree this identifies the node: use it to key side tables such as a `Vec`
+    /// indexed by node, and to refer to nodes across a serialization. Once the
+    /// pool is cleared, as `FhirParser::with_pool` does to reuse it, the same
+    /// index names a node of the next tree instead.
+    #[must_use]
+    pub const fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
 impl fmt::Display for ExprRef {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "ExprRef({})", self.0)
@@ -0,0 +1,437 @@
+use super::ast::{Ast, MAX_DEPTH};
+use super::grammar::{BinaryOperator, ExprPool, ExprRef, Expression};
+use super::unparser::{datetime_text, number_text, unparse_expression};
+use crate::evaluator::error::{Error, ErrorCode};
+use crate::lexer::token::Span;
+use serde_json::{Map, Value, json};
+use time::format_description::well_known::Iso8601;
+use time::{Date, PrimitiveDateTime};
+
+// The implicit focus of an invocation with no object, such as `exists()`
+const THAT: &str = "builtin.that";
+
+impl Ast {
+    /// The tree as JSON, in the layout the `FHIRPath` Lab shows for each engine.
+    ///
+    /// Each expression is an object with an `ExpressionType`, mostly a `Name`,
+    /// and its operands in `Arguments`:
+    ///
+    /// | Expression | `ExpressionType` | `Name` | `Arguments` |
+    /// |------------|------------------|--------|-------------|
+    /// | `name`, `a.name` | `ChildExpression` | `name` | the focus |
+    /// | `f(x)`, `a.f(x)` | `FunctionCallExpression` | `f` | the focus, then `x` |
+    /// | `a = b` | `BinaryExpression` | `=` | `a`, `b` |
+    /// | `a[0]` | `IndexerExpression` | `[]` | `a`, `0` |
+    /// | `%name` | `VariableRefExpression` | `name` | |
+    /// | `'text'`, `1.5`, ... | `ConstantExpression` | the value | |
+    ///
+    /// A name or function with no object has the focus
+    /// `{"ExpressionType": "AxisExpression", "Name": "builtin.that"}`. Constants
+    /// have a `ReturnType` of `System.String`, `System.Decimal`,
+    /// `System.Integer`, `System.Boolean`, `System.Date` or `System.DateTime`,
+    /// with the value in `Name` as text. Every expression from source has the
+    /// `Position` and `Length` in bytes of its span, and the parts that couldn't
+    /// be parsed are `ErrorExpression`s.
+    ///
+    /// ```rust
+    /// use fhirlighter::parse;
+    /// use serde_json::json;
+    ///
+    /// let ast = parse("name[0]")?;
+    /// assert_eq!(
+    ///     ast.to_json(),
+    ///     json!({
+    ///         "ExpressionType": "IndexerExpression",
+    ///         "Name": "[]",
+    ///         "Arguments": [
+    ///             {
+    ///                 "ExpressionType": "ChildExpression",
+    ///                 "Name": "name",
+    ///                 "Arguments": [{"ExpressionType": "AxisExpression", "Name": "builtin.that"}],
+    ///                 "Position": 0,
+    ///                 "Length": 4
+    ///             },
+    ///             {
+    ///                 "ExpressionType": "ConstantExpression",
+    ///                 "Name": "0",
+    ///                 "ReturnType": "System.Integer",
+    ///                 "Position": 5,
+    ///                 "Length": 1
+    ///             }
+    ///         ],
+    ///         "Position": 0,
+    ///         "Length": 7
+    ///     })
+    /// );
+    /// # Ok::<(), fhirlighter::Error>(())
+    /// ```
+    #[must_use]
+    pub fn to_json(&self) -> Value {
+        node_json(&self.expressions, self.start)
+    }
+
+    /// Read back a tree written by [`Ast::to_json`]. `Position` and `Length` are
+    /// optional, and spans are empty without them.
+    ///
+    /// Trees may nest at most `MAX_DEPTH` levels deep, as for parsing. Note that
+    /// `serde_json` reads JSON text nested at most 128 levels deep by default,
+    /// which with an `Arguments` array for each level is about 64 of them.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ErrorCode::InvalidAst` if the JSON isn't such a tree, naming the
+    /// offending value by its JSON pointer.
+    pub fn from_json(value: &Value) -> Result<Self, Error> {
+        let mut reader = Reader {
+            expressions: ExprPool::new(),
+            depth: 0,
+        };
+        let start = reader.node(value, "")?;
+        Ok(Self {
+            expressions: reader.expressions,
+            start,
+        })
+    }
+}
+
+fn node_json(pool: &ExprPool, expr_ref: ExprRef) -> Value {
+    let node = |expr_ref: &ExprRef| node_json(pool, *expr_ref);
+    let (kind, name, arguments, return_type) = match pool.get(expr_ref) {
+        Expression::Identifier(name) => ("ChildExpression", name.clone(), vec![that()], None),
+        Expression::MemberAccess { object, member } => {
+            ("ChildExpression", member.clone(), vec![node(object)], None)
+        }
+        Expression::FunctionCall {
+            object,
+            function,
+            arguments,
+        } => {
+            let name = match pool.get(*function) {
+                Expression::Identifier(name) => name.clone(),
+                _ => unparse_expression(pool, *function),
+            };
+            let mut focus = vec![object.as_ref().map_or_else(that, node)];
+            focus.extend(arguments.iter().map(node));
+            ("FunctionCallExpression", name, focus, None)
+        }
+        Expression::BinaryOperation { operator, lhs, rhs } => (
+            "BinaryExpression",
+            operator.to_string(),
+            vec![node(lhs), node(rhs)],
+            None,
+        ),
+        Expression::Index { object, index } => (
+            "IndexerExpression",
+            "[]".to_string(),
+            vec![node(object), node(index)],
+            None,
+        ),
+        Expression::ExternalConstant(name) => ("VariableRefExpression", name.clone(), vec![], None),
+        Expression::String(string) => constant(string.clone(), "System.String"),
+        Expression::Number(number) => constant(number_text(*number), "System.Decimal"),
+        Expression::Integer(integer) => constant(integer.to_string(), "System.Integer"),
+        Expression::Boolean(boolean) => constant(boolean.to_string(), "System.Boolean"),
+        Expression::ISODate(date) => constant(date.to_string(), "System.Date"),
+        Expression::ISODateTime(datetime) => constant(datetime_text(datetime), "System.DateTime"),
+        Expression::Error => ("ErrorExpression", String::new(), vec![], None),
+    };
+
+    let mut object = Map::new();
+    object.insert("ExpressionType".to_string(), json!(kind));
+    if kind != "ErrorExpression" {
+        object.insert("Name".to_string(), json!(name));
+    }
+    if let Some(return_type) = return_type {
+        object.insert("ReturnType".to_string(), json!(return_type));
+    }
+    if !arguments.is_empty() {
+        object.insert("Arguments".to_string(), Value::Array(arguments));
+    }
+    let span = pool.span(expr_ref);
+    object.insert("Position".to_string(), json!(span.start));
+    object.insert("Length".to_string(), json!(span.end - span.start));
+    Value::Object(object)
+}
+
+fn that() -> Value {
+    json!({"ExpressionType": "AxisExpression", "Name": THAT})
+}
+
+const fn constant(
+    name: String,
+    return_type: &'static str,
+) -> (&'static str, String, Vec<Value>, Option<&'static str>) {
+    ("ConstantExpression", name, vec![], Some(return_type))
+}
+
+struct Reader {
+    expressions: ExprPool,
+    depth: usize,
+}
+
+impl Reader {
+    fn node(&mut self, value: &Value, path: &str) -> Result<ExprRef, Error> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(Error::new(
+                ErrorCode::NestingTooDeep,
+                format!("Expression nests more than {MAX_DEPTH} levels deep"),
+            ));
+        }
+        let result = self.read(value, path);
+        self.depth -= 1;
+        result
+    }
+
+    fn read(&mut self, value: &Value, path: &str) -> Result<ExprRef, Error> {
+        let object = value
+            .as_object()
+            .ok_or_else(|| invalid("Expected an expression object", path))?;
+        let arguments = match object.get("Arguments") {
+            None => &Vec::new(),
+            Some(Value::Array(arguments)) => arguments,
+            Some(_) => return Err(invalid("Expected \"Arguments\" to be an array", path)),
+        };
+        let argument = |index: usize| format!("{path}/Arguments/{index}");
+        let name = || text(object, "Name", path);
+        let span = span(object, path)?;
+
+        let expression = match text(object, "ExpressionType", path)? {
+            "ChildExpression" => {
+                let [focus] = arguments.as_slice() else {
+                    return Err(invalid("Expected 1 argument, the focus", path));
+                };
+                let name = name()?.to_string();
+                if is_that(focus) {
+                    Expression::Identifier(name)
+                } else {
+                    Expression::MemberAccess {
+                        object: self.node(focus, &argument(0))?,
+                        member: name,
+                    }
+                }
+            }
+            "FunctionCallExpression" => {
+                let Some((focus, rest)) = arguments.split_first() else {
+                    return Err(invalid("Expected the focus as the first argument", path));
+                };
+                let object = if is_that(focus) {
+                    None
+                } else {
+                    Some(self.node(focus, &argument(0))?)
+                };
+                let arguments = rest
+                    .iter()
+                    .enumerate()
+                    .map(|(index, value)| self.node(value, &argument(index + 1)))
+                    .collect::<Result<_, _>>()?;
+                let function = Expression::Identifier(name()?.to_string());
+                Expression::FunctionCall {
+                    object,
+                    function: self.expressions.add_with_span(function, span)?,
+                    arguments,
+                }
+            }
+            "BinaryExpression" => {
+                let [lhs, rhs] = arguments.as_slice() else {
+                    return Err(invalid("Expected 2 arguments, the operands", path));
+                };
+                let name = name()?;
+                let operator = operator(name)
+                    .ok_or_else(|| invalid(&format!("Unknown operator '{name}'"), path))?;
+                Expression::BinaryOperation {
+                    operator,
+                    lhs: self.node(lhs, &argument(0))?,
+                    rhs: self.node(rhs, &argument(1))?,
+                }
+            }
+            "IndexerExpression" => {
+                let [object, index] = arguments.as_slice() else {
+                    return Err(invalid("Expected 2 arguments, the focus and index", path));
+                };
+                Expression::Index {
+                    object: self.node(object, &argument(0))?,
+                    index: self.node(index, &argument(1))?,
+                }
+            }
+            "VariableRefExpression" => Expression::ExternalConstant(name()?.to_string()),
+            "ConstantExpression" => read_constant(name()?, text(object, "ReturnType", path)?)
+                .ok_or_else(|| invalid("Expected a constant of its \"ReturnType\"", path))?,
+            "ErrorExpression" => Expression::Error,
+            kind => {
+                return Err(invalid(&format!("Unknown expression type '{kind}'"), path));
+            }
+        };
+        self.expressions.add_with_span(expression, span)
+    }
+}
+
+// Helper: whether a focus is the implicit one, of an invocation with no object
+fn is_that(value: &Value) -> bool {
+    value.get("ExpressionType") == Some(&json!("AxisExpression"))
+        && value.get("Name") == Some(&json!(THAT))
+}
+
+// Numbers are read only if they are literals the grammar can write, so not
+// negative, as it has no minus sign
+fn read_constant(name: &str, return_type: &str) -> Option<Expression> {
+    match return_type {
+        "System.String" => Some(Expression::String(name.to_string())),
+        "System.Decimal" => name
+            .parse()
+            .ok()
+            .filter(|number: &f64| number.is_finite() && number.is_sign_positive())
+            .map(Expression::Number),
+        "System.Integer" => name
+            .parse()
+            .ok()
+            .filter(|integer: &i64| *integer >= 0)
+            .map(Expression::Integer),
+        "System.Boolean" => name.parse().ok().map(Expression::Boolean),
+        "System.Date" => Date::parse(name, &Iso8601::DATE)
+            .ok()
+            .map(Expression::ISODate),
+        "System.DateTime" => PrimitiveDateTime::parse(name, &Iso8601::DEFAULT)
+            .ok()
+            .map(Expression::ISODateTime),
+        _ => None,
+    }
+}
+
+fn operator(symbol: &str) -> Option<BinaryOperator> {
+    [
+        BinaryOperator::Equals,
+        BinaryOperator::NotEquals,
+        BinaryOperator::LessThan,
+        BinaryOperator::LessThanOrEqual,
+        BinaryOperator::GreaterThan,
+        BinaryOperator::GreaterThanOrEqual,
+    ]
+    .into_iter()
+    .find(|operator| operator.to_string() == symbol)
+}
+
+// Helper: a string member of an expression object
+fn text<'a>(object: &'a Map<String, Value>, key: &str, path: &str) -> Result<&'a str, Error> {
+    object
+        .get(key)
+        .and_then(Value::as_str)
+        .ok_or_else(|| invalid(&format!("Expected \"{key}\" to be a string"), path))
+}
+
+// Helper: the span from `Position` and `Length`, if there are both
+fn span(object: &Map<String, Value>, path: &str) -> Result<Span, Error> {
+    let (Some(position), Some(length)) = (object.get("Position"), object.get("Length")) else {
+        return Ok(Span::default());
+    };
+    let as_usize = |value: &Value| value.as_u64().and_then(|value| usize::try_from(value).ok());
+    match (as_usize(position), as_usize(length)) {
+        (Some(position), Some(length)) => Ok(Span::new(position, position.saturating_add(length))),
+        _ => Err(invalid(
+            "Expected \"Position\" and \"Length\" to be non-negative integers",
+            path,
+        )),
+    }
+}
+
+fn invalid(message: &str, path: &str) -> Error {
+    let location = if path.is_empty() { "the root" } else { path };
+    Error::new(ErrorCode::InvalidAst, format!("{message} at {location}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse;
+
+    #[test]
+    fn test_round_trip() {
+        for expression in [
+            "Patient.name.where(use = 'official').given.first()",
+            "exists()",
+            "(a = b) != %`vs-name`",
+            "@2020-01-01 < @2020-01-01T10:30:00.25",
+            "1.0 >= 1 = true",
+            "'\\'quoted\\''[0]",
+        ] {
+            let ast = parse(expression).unwrap();
+            let json = ast.to_json();
+            let read = Ast::from_json(&json).unwrap();
+            assert!(read.equivalent(&ast), "{expression}");
+            assert_eq!(read.to_json(), json, "{expression}");
+        }
+    }
+
+    #[test]
+    fn test_function_call_layout() {
+        let ast = parse("name.substring(0, 1)").unwrap();
+        let json = ast.to_json();
+        assert_eq!(json["ExpressionType"], "FunctionCallExpression");
+        assert_eq!(json["Name"], "substring");
+        let arguments = json["Arguments"].as_array().unwrap();
+        assert_eq!(arguments.len(), 3);
+        assert_eq!(arguments[0]["Name"], "name");
+        assert_eq!(arguments[0]["Arguments"][0]["Name"], THAT);
+        assert_eq!(arguments[2]["ReturnType"], "System.Integer");
+    }
+
+    #[test]
+    fn test_without_spans() {
+        let json = json!({
+            "ExpressionType": "BinaryExpression",
+            "Name": "=",
+            "Arguments": [
+                {"ExpressionType": "VariableRefExpression", "Name": "resource"},
+                {"ExpressionType": "ConstantExpression", "Name": "1.5", "ReturnType": "System.Decimal"}
+            ]
+        });
+        let ast = Ast::from_json(&json).unwrap();
+        assert!(ast.equivalent(&parse("%resource = 1.5").unwrap()));
+        assert_eq!(ast.expressions.span(ast.start), Span::default());
+    }
+
+    #[test]
+    fn test_invalid_trees() {
+        for (json, message) in [
+            (json!([]), "Expected an expression object at the root"),
+            (
+                json!({"ExpressionType": "ChildExpression", "Name": "a"}),
+                "Expected 1 argument, the focus at the root",
+            ),
+            (
+                json!({"ExpressionType": "BinaryExpression", "Name": "=", "Arguments": [
+                    {"ExpressionType": "VariableRefExpression", "Name": "a"},
+                    {"ExpressionType": "ConstantExpression", "Name": "x", "ReturnType": "System.Integer"}
+                ]}),
+                "Expected a constant of its \"ReturnType\" at /Arguments/1",
+            ),
+            (
+                json!({"ExpressionType": "ConstantExpression", "Name": "-1", "ReturnType": "System.Integer"}),
+                "Expected a constant of its \"ReturnType\" at the root",
+            ),
+            (
+                json!({"ExpressionType": "ConstantExpression", "Name": "-1.5", "ReturnType": "System.Decimal"}),
+                "Expected a constant of its \"ReturnType\" at the root",
+            ),
+            (
+                json!({"ExpressionType": "BinaryExpression", "Name": "~", "Arguments": [{}, {}]}),
+                "Unknown operator '~' at the root",
+            ),
+            (
+                json!({"ExpressionType": "UnaryExpression", "Name": "-"}),
+                "Unknown expression type 'UnaryExpression' at the root",
+            ),
+        ] {
+            let error = Ast::from_json(&json).unwrap_err();
+            assert_eq!(error.code, ErrorCode::InvalidAst);
+            assert_eq!(error.message, message);
+        }
+
+        let mut json = json!({"ExpressionType": "VariableRefExpression", "Name": "a"});
+        for _ in 0..MAX_DEPTH {
+            json = json!({"ExpressionType": "ChildExpression", "Name": "a", "Arguments": [json]});
+        }
+        let error = Ast::from_json(&json).unwrap_err();
+        assert_eq!(error.code, ErrorCode::NestingTooDeep);
+    }
+}
@@ -1,2 +1,12 @@
 pub mod ast;
+pub mod builder;
+pub mod cst;
+pub mod dot;
+pub mod formatter;
+pub mod functions;
 pub mod grammar;
+pub mod json;
+pub mod lint;
+pub mod optimize;
+pub mod unparser;
+pub mod visit;
@@ -0,0 +1,321 @@
+//! Rewriting trees into simpler ones that evaluate to the same result, such as
+//! generated expressions full of constant comparisons.
+//!
+//! [`optimize`] rewrites bottom-up, so each rule sees children already
+//! simplified:
+//!
+//! - Comparisons of literals, including dates, are folded into their result,
+//!   `1 < 2` into `true`. Those that evaluate to an error or a warning, such as
+//!   `1 = 'a'`, are kept so that evaluation still reports them.
+//! - `exists()`, `empty()` and `count()` of a literal are folded, and `first()`
+//!   and `last()` of one are the literal itself.
+//! - Comparisons with `true` and `false` of an expression that returns at most
+//!   one Boolean, such as a comparison or `exists()`, are simplified:
+//!   `x.exists() = true` into `x.exists()` and `(a < b) = false` into `a >= b`.
+//!   An empty operand still gives an empty result, so three-valued logic is
+//!   respected, while operands of other types are left alone, as `'a' = true`
+//!   is `false` where `'a'` isn't.
+//!
+//! Only calls this crate evaluates are rewritten, so `where(true)` is kept for
+//! evaluation to report as unsupported. Evaluating the result gives the same
+//! value, errors and warnings as evaluating `ast`, though warnings may point at
+//! more of the source.
+//!
+//! Repeated sub-expressions aren't shared and sub-expressions aren't hoisted
+//! out of `where()` and `select()`: without `defineVariable()` a tree has
+//! nowhere to keep their values.
+//!
+//! ```rust
+//! use fhirlighter::parser::optimize::optimize;
+//! use fhirlighter::parser::unparser::unparse;
+//!
+//! let ast = fhirlighter::parse("(name.exists() = (1 < 2)) != false")?;
+//! assert_eq!(unparse(&optimize(&ast)?), "name.exists()");
+//! # Ok::<(), fhirlighter::Error>(())
+//! ```
+
+use super::ast::Ast;
+use super::grammar::{BinaryOperator, ExprPool, ExprRef, Expression};
+use super::visit::{Folder, fold};
+use crate::evaluator::context::EvaluationContext;
+use crate::evaluator::engine::Evaluator;
+use crate::evaluator::error::Error;
+use crate::lexer::token::Span;
+use serde_json::Value;
+
+/// A simplified copy of `ast`.
+///
+/// See the [module documentation](self) for the rewrites. Expressions they add take the span of those they replace,
+/// so warnings may point at more of the source than before.
+///
+/// # Errors
+///
+/// Returns `ErrorCode::TooManyExpressions` if the copy doesn't fit a pool,
+/// which can only happen to a tree near the limit.
+pub fn optimize(ast: &Ast) -> Result<Ast, Error> {
+    fold(ast, &mut Optimizer)
+}
+
+struct Optimizer;
+
+impl Folder for Optimizer {
+    fn fold_function_call(
+        &mut self,
+        pool: &mut ExprPool,
+        span: Span,
+        object: Option<ExprRef>,
+        function: ExprRef,
+        arguments: Vec<ExprRef>,
+    ) -> Result<ExprRef, Error> {
+        let name = match pool.get(function) {
+            Expression::Identifier(name) => name.clone(),
+            _ => String::new(),
+        };
+        if let Some(object) = object {
+            let literal = is_literal(pool.get(object));
+            match (name.as_str(), arguments.as_slice()) {
+                ("first" | "last", []) if literal => return Ok(object),
+                ("exists" | "empty", []) if literal => {
+                    return pool.add_with_span(Expression::Boolean(name == "exists"), span);
+                }
+                ("count", []) if literal => {
+                    return pool.add_with_span(Expression::Integer(1), span);
+                }
+                _ => {}
+            }
+        }
+        let expression = Expression::FunctionCall {
+            object,
+            function,
+            arguments,
+        };
+        pool.add_with_span(expression, span)
+    }
+
+    fn fold_binary_operation(
+        &mut self,
+        pool: &mut ExprPool,
+        span: Span,
+        operator: BinaryOperator,
+        lhs: ExprRef,
+        rhs: ExprRef,
+    ) -> Result<ExprRef, Error> {
+        if is_literal(pool.get(lhs))
+            && is_literal(pool.get(rhs))
+            && let Some(result) = compare_literals(pool, operator, lhs, rhs)
+        {
+            return pool.add_with_span(Expression::Boolean(result), span);
+        }
+        let operation = Expression::BinaryOperation { operator, lhs, rhs };
+
+        // An operand compared with `true` or `false`
+        let (operand, boolean) = match (pool.get(lhs), pool.get(rhs)) {
+            (_, Expression::Boolean(boolean)) => (lhs, *boolean),
+            (Expression::Boolean(boolean), _) => (rhs, *boolean),
+            _ => return pool.add_with_span(operation, span),
+        };
+        let negate = match operator {
+            BinaryOperator::Equals => !boolean,
+            BinaryOperator::NotEquals => boolean,
+            _ => return pool.add_with_span(operation, span),
+        };
+        if !negate && is_boolean(pool, operand) {
+            return Ok(operand);
+        }
+        if negate && let Some(negated) = negation(pool, operand) {
+            return pool.add_with_span(negated, span);
+        }
+        pool.add_with_span(operation, span)
+    }
+}
+
+// Helper: the result of a comparison of two literals, unless it fails or warns
+fn compare_literals(
+    pool: &ExprPool,
+    operator: BinaryOperator,
+    lhs: ExprRef,
+    rhs: ExprRef,
+) -> Option<bool> {
+    let mut expressions = ExprPool::with_capacity(3);
+    let lhs = expressions.add(pool.get(lhs).clone()).ok()?;
+    let rhs = expressions.add(pool.get(rhs).clone()).ok()?;
+    let start = expressions
+        .add(Expression::BinaryOperation { operator, lhs, rhs })
+        .ok()?;
+    let ast = Ast { expressions, start };
+    let evaluation = Evaluator::new()
+        .evaluate_with_diagnostics(&ast, &EvaluationContext::new(&Value::Null))
+        .ok()?;
+    match evaluation.value {
+        Value::Bool(result) if evaluation.diagnostics.is_empty() => Some(result),
+        _ => None,
+    }
+}
+
+// Helper: whether an expression returns at most one Boolean, whatever the data
+fn is_boolean(pool: &ExprPool, expr_ref: ExprRef) -> bool {
+    match pool.get(expr_ref) {
+        Expression::Boolean(_) | Expression::BinaryOperation { .. } => true,
+        Expression::FunctionCall {
+            object: Some(_),
+            function,
+            arguments,
+        } => {
+            arguments.is_empty()
+                && matches!(pool.get(*function), Expression::Identifier(name) if name == "exists" || name == "empty")
+        }
+        _ => false,
+    }
+}
+
+// Helper: an expression for the opposite of a Boolean one, empty where it is
+fn negation(pool: &mut ExprPool, expr_ref: ExprRef) -> Option<Expression> {
+    use BinaryOperator::{
+        Equals, GreaterThan, GreaterThanOrEqual, LessThan, LessThanOrEqual, NotEquals,
+    };
+    match pool.get(expr_ref).clone() {
+        Expression::Boolean(boolean) => Some(Expression::Boolean(!boolean)),
+        Expression::BinaryOperation { operator, lhs, rhs } => {
+            let operator = match operator {
+                Equals => NotEquals,
+                NotEquals => Equals,
+                LessThan => GreaterThanOrEqual,
+                LessThanOrEqual => GreaterThan,
+                GreaterThan => LessThanOrEqual,
+                GreaterThanOrEqual => LessThan,
+            };
+            Some(Expression::BinaryOperation { operator, lhs, rhs })
+        }
+        Expression::FunctionCall {
+            object: Some(object),
+            function,
+            arguments,
+        } if is_boolean(pool, expr_ref) => {
+            let Expression::Identifier(name) = pool.get(function) else {
+                return None;
+            };
+            let opposite = if name == "exists" { "empty" } else { "exists" };
+            let span = pool.span(function);
+            let function = pool
+                .add_with_span(Expression::Identifier(opposite.to_string()), span)
+                .ok()?;
+            Some(Expression::FunctionCall {
+                object: Some(object),
+                function,
+                arguments,
+            })
+        }
+        _ => None,
+    }
+}
+
+// Helper: whether an expression is a single value known without data
+const fn is_literal(expression: &Expression) -> bool {
+    matches!(
+        expression,
+        Expression::String(_)
+            | Expression::Integer(_)
+            | Expression::Number(_)
+            | Expression::Boolean(_)
+            | Expression::ISODate(_)
+            | Expression::ISODateTime(_)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evaluate;
+    use crate::parse;
+    use crate::parser::unparser::unparse;
+    use serde_json::json;
+
+    fn optimized(source: &str) -> String {
+        unparse(&optimize(&parse(source).unwrap()).unwrap())
+    }
+
+    #[test]
+    fn test_literal_comparisons_are_folded() {
+        for (source, expected) in [
+            ("1 < 2", "true"),
+            ("1.5 >= 2", "false"),
+            ("'a' != 'b'", "true"),
+            ("@2020-01-01 < @2021-06-30", "true"),
+            ("@2020-01-01T10:00:00 = @2020-01-01T10:00:00", "true"),
+            ("name.exists() = (1 = 1)", "name.exists()"),
+            ("'x'.exists()", "true"),
+            ("@2020-01-01.first()", "@2020-01-01"),
+            ("'x'.count() > 0", "true"),
+        ] {
+            assert_eq!(optimized(source), expected, "{source}");
+        }
+    }
+
+    #[test]
+    fn test_failing_comparisons_are_kept() {
+        // Errors and warnings are still raised when the expression is evaluated
+        for source in ["1 = 'a'", "1 < 'a'", "@2020-01-01 = @2020-01-01T10:00:00"] {
+            assert_eq!(optimized(source), source);
+        }
+    }
+
+    #[test]
+    fn test_boolean_identities() {
+        for (source, expected) in [
+            ("name.exists() = true", "name.exists()"),
+            ("true = name.empty()", "name.empty()"),
+            ("name.exists() != false", "name.exists()"),
+            ("name.exists() = false", "name.empty()"),
+            ("name.empty() != true", "name.exists()"),
+            ("(gender = 'male') = false", "gender != 'male'"),
+            (
+                "(birthDate < @2000-01-01) != true",
+                "birthDate >= @2000-01-01",
+            ),
+            // Not known to be Boolean, so `= true` may change the result
+            ("active = true", "active = true"),
+            ("gender = false", "gender = false"),
+            ("name.first() = true", "name.first() = true"),
+        ] {
+            assert_eq!(optimized(source), expected, "{source}");
+        }
+    }
+
+    #[test]
+    fn test_unsupported_calls_are_kept() {
+        // `where()` isn't evaluated yet, so removing it would hide the error
+        assert_eq!(
+            optimized("name.where(true).given"),
+            "name.where(true).given"
+        );
+        assert_eq!(optimized("name.where(1 < 2)"), "name.where(true)");
+    }
+
+    #[test]
+    fn test_results_are_unchanged() {
+        let patient = json!({
+            "resourceType": "Patient",
+            "active": true,
+            "gender": "male",
+            "birthDate": "1974-12-25",
+            "name": [{"family": "Chalmers"}]
+        });
+        for source in [
+            "(name.exists() = true) = (1 < 2)",
+            "(gender = 'male') = false",
+            "(gender = 'female') != true",
+            "(birthDate < @2000-01-01) = false",
+            "(address.exists() = false) = true",
+            "(telecom = 'x') = false",
+            "name.empty() != true",
+        ] {
+            let ast = parse(source).unwrap();
+            assert_ne!(unparse(&optimize(&ast).unwrap()), source);
+            assert_eq!(
+                evaluate(&unparse(&optimize(&ast).unwrap()), &patient).ok(),
+                evaluate(source, &patient).ok(),
+                "{source}"
+            );
+        }
+    }
+}
@@ -0,0 +1,282 @@
+use super::ast::Ast;
+use super::grammar::{ExprPool, ExprRef, Expression};
+use crate::lexer::tokenizer::Lexer;
+use std::fmt;
+use time::PrimitiveDateTime;
+
+/// Write an AST back out as `FHIRPath` source.
+///
+/// Parentheses are added only where the parser would otherwise read another
+/// tree: around an operation that is the object of an invocation or index, or
+/// the left operand of another operation, as operations associate to the
+/// right. Names that aren't plain identifiers are delimited and strings are
+/// escaped, so for an AST without `Expression::Error` nodes
+/// `parse(&unparse(&ast))` gives an equivalent tree.
+///
+/// The grammar has no minus sign, so a negative or non-finite number, which
+/// neither the parser, [`Ast::from_json`] nor [`Path`](crate::Path) create, is
+/// written out as text that doesn't parse.
+///
+/// ```rust
+/// use fhirlighter::parse;
+/// use fhirlighter::parser::unparser::unparse;
+///
+/// let ast = parse("Patient.name.where( given = 'Peter' )[0]")?;
+/// assert_eq!(unparse(&ast), "Patient.name.where(given = 'Peter')[0]");
+/// # Ok::<(), fhirlighter::Error>(())
+/// ```
+#[must_use]
+pub fn unparse(ast: &Ast) -> String {
+    unparse_expression(&ast.expressions, ast.start)
+}
+
+/// Write the expression at `expr_ref` of `pool` back out as `FHIRPath` source.
+/// See [`unparse`].
+#[must_use]
+pub fn unparse_expression(pool: &ExprPool, expr_ref: ExprRef) -> String {
+    let mut unparser = Unparser {
+        pool,
+        source: String::new(),
+    };
+    unparser.expression(expr_ref);
+    unparser.source
+}
+
+impl fmt::Display for Ast {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", unparse(self))
+    }
+}
+
+struct Unparser<'a> {
+    pool: &'a ExprPool,
+    source: String,
+}
+
+impl Unparser<'_> {
+    fn expression(&mut self, expr_ref: ExprRef) {
+        match self.pool.get(expr_ref) {
+            Expression::Identifier(name) => self.name(name),
+            Expression::MemberAccess { object, member } => {
+                self.object(*object);
+                self.source.push('.');
+                self.name(member);
+            }
+            Expression::FunctionCall {
+                object,
+                function,
+                arguments,
+            } => {
+                if let Some(object) = object {
+                    self.object(*object);
+                    self.source.push('.');
+                }
+                match self.pool.get(*function) {
+                    Expression::Identifier(name) => self.name(name),
+                    _ => self.expression(*function),
+                }
+                self.source.push('(');
+                for (index, argument) in arguments.iter().enumerate() {
+                    if index > 0 {
+                        self.source.push_str(", ");
+                    }
+                    self.expression(*argument);
+                }
+                self.source.push(')');
+            }
+            Expression::BinaryOperation { operator, lhs, rhs } => {
+                self.object(*lhs);
+                self.source.push(' ');
+                self.source.push_str(&operator.to_string());
+                self.source.push(' ');
+                self.expression(*rhs);
+            }
+            Expression::Index { object, index } => {
+                self.object(*object);
+                self.source.push('[');
+                self.expression(*index);
+                self.source.push(']');
+            }
+            Expression::ExternalConstant(name) => {
+                self.source.push('%');
+                self.name(name);
+            }
+            Expression::String(string) => self.quoted(string, '\''),
+            Expression::Number(number) => self.source.push_str(&number_text(*number)),
+            Expression::Integer(integer) => self.source.push_str(&integer.to_string()),
+            Expression::Boolean(boolean) => self.source.push_str(&boolean.to_string()),
+            Expression::ISODateTime(datetime) => {
+                self.source.push('@');
+                self.source.push_str(&datetime_text(datetime));
+            }
+            Expression::ISODate(date) => {
+                self.source.push('@');
+                self.source.push_str(&date.to_string());
+            }
+            Expression::Error => self.source.push_str("<error>"),
+        }
+    }
+
+    // An expression an invocation, index or operator applies to, in parentheses
+    // when it is itself an operation
+    fn object(&mut self, expr_ref: ExprRef) {
+        if matches!(self.pool.get(expr_ref), Expression::BinaryOperation { .. }) {
+            self.source.push('(');
+            self.expression(expr_ref);
+            self.source.push(')');
+        } else {
+            self.expression(expr_ref);
+        }
+    }
+
+    // A name as an identifier when it reads back as one, or else delimited
+    fn name(&mut self, name: &str) {
+        let identifier = Lexer::new(name).tokenize().is_ok_and(|tokens| {
+            matches!(tokens.as_slice(), [token, _] if token.kind.is_identifier() && token.length() == name.len())
+        });
+        if identifier {
+            self.source.push_str(name);
+        } else {
+            self.quoted(name, '`');
+        }
+    }
+
+    fn quoted(&mut self, text: &str, quote: char) {
+        self.source.push(quote);
+        for character in text.chars() {
+            match character {
+                '\\' => self.source.push_str(r"\\"),
+                '\n' => self.source.push_str(r"\n"),
+                '\r' => self.source.push_str(r"\r"),
+                '\t' => self.source.push_str(r"\t"),
+                '\u{c}' => self.source.push_str(r"\f"),
+                character if character == quote => {
+                    self.source.push('\\');
+                    self.source.push(character);
+                }
+                character => self.source.push(character),
+            }
+        }
+        self.source.push(quote);
+    }
+}
+
+// Helper: a decimal as the lexer reads it back
+pub(crate) fn number_text(number: f64) -> String {
+    let mut text = number.to_string();
+    // Without a decimal point it would read back as an integer
+    if !text.contains('.') {
+        text.push_str(".0");
+    }
+    text
+}
+
+// Helper: a date and time as the lexer reads it back, without the `@`
+pub(crate) fn datetime_text(datetime: &PrimitiveDateTime) -> String {
+    let (hour, minute, second, nanosecond) = datetime.as_hms_nano();
+    let mut text = format!("{}T{hour:02}:{minute:02}:{second:02}", datetime.date());
+    if nanosecond > 0 {
+        let fraction = format!("{nanosecond:09}");
+        text.push('.');
+        text.push_str(fraction.trim_end_matches('0'));
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_syntax as parse;
+    use crate::parser::grammar::BinaryOperator;
+
+    fn round_trip(input: &str) -> String {
+        let ast = parse(input).unwrap();
+        let source = unparse(&ast);
+        let reparsed = parse(&source).unwrap();
+        assert!(ast.equivalent(&reparsed), "{input} => {source}");
+        source
+    }
+
+    #[test]
+    fn test_unparse_parsed_expressions() {
+        for (input, expected) in [
+            ("Patient.name[0].given", "Patient.name[0].given"),
+            (
+                "name.where( use='official' ,true)",
+                "name.where(use = 'official', true)",
+            ),
+            ("exists()", "exists()"),
+            ("a = b = c", "a = b = c"),
+            ("(a = b) = c", "(a = b) = c"),
+            ("((a)).b", "a.b"),
+            ("(a = b).exists()", "(a = b).exists()"),
+            ("%`vs-name` != %resource", "%`vs-name` != %resource"),
+            ("%'vs-name'", "%`vs-name`"),
+            (r"'O\'Brien\n'", r"'O\'Brien\n'"),
+            ("`given name`.`where`", "`given name`.where"),
+            ("1.50 > 1", "1.5 > 1"),
+            (
+                "@2020-01-01 <= @2020-01-01T10:30:00.250",
+                "@2020-01-01 <= @2020-01-01T10:30:00.25",
+            ),
+        ] {
+            assert_eq!(round_trip(input), expected);
+        }
+    }
+
+    #[test]
+    fn test_negative_literals_are_not_built() {
+        // `-1` would be written out as is, which doesn't parse
+        assert!(parse("name[-1]").is_err());
+        assert!(parse("x = -1.5").is_err());
+        for (name, return_type) in [("-1", "System.Integer"), ("-1.5", "System.Decimal")] {
+            let json = serde_json::json!({
+                "ExpressionType": "ConstantExpression",
+                "Name": name,
+                "ReturnType": return_type
+            });
+            assert!(Ast::from_json(&json).is_err(), "{name}");
+        }
+        assert!(crate::Path::decimal(-1.5).is_err());
+        assert_eq!(round_trip("name[0] = 0.0"), "name[0] = 0.0");
+    }
+
+    #[test]
+    fn test_unparse_built_expressions() {
+        let mut pool = ExprPool::new();
+        let name = pool
+            .add(Expression::Identifier("true".to_string()))
+            .unwrap();
+        let number = pool.add(Expression::Number(2.0)).unwrap();
+        let lhs = pool
+            .add(Expression::BinaryOperation {
+                operator: BinaryOperator::Equals,
+                lhs: name,
+                rhs: number,
+            })
+            .unwrap();
+        let string = pool.add(Expression::String("`".to_string())).unwrap();
+        let operation = pool
+            .add(Expression::BinaryOperation {
+                operator: BinaryOperator::LessThan,
+                lhs,
+                rhs: string,
+            })
+            .unwrap();
+        let start = pool
+            .add(Expression::MemberAccess {
+                object: operation,
+                member: "a b".to_string(),
+            })
+            .unwrap();
+        let ast = Ast {
+            expressions: pool,
+            start,
+        };
+
+        let source = unparse(&ast);
+        assert_eq!(source, "((`true` = 2.0) < '`').`a b`");
+        assert!(parse(&source).unwrap().equivalent(&ast));
+        assert_eq!(ast.to_string(), source);
+    }
+}
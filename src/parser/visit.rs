@@ -0,0 +1,631 @@
+//! Walking and rewriting trees without matching on each `Expression` variant.
+//!
+//! - A [`Visitor`] reads a tree: implement the hooks for the expressions of
+//!   interest and call [`walk`].
+//! - A [`VisitorMut`] changes expressions in place with [`walk_mut`], such as
+//!   renaming elements.
+//! - A [`Folder`] rebuilds a tree bottom-up into a new pool with [`fold`], and
+//!   can replace any expression with a new subtree or one of its children.
+//!
+//! Expressions with children have `enter_` hooks, called before their children
+//! in pre-order, and `leave_` hooks, called after them in post-order. Leaves have
+//! a single `visit_` hook. Children are walked in source order: the object of an
+//! invocation or index before the rest, and a function's arguments in order. The
+//! identifier naming a function isn't walked as a child, so `visit_identifier`
+//! sees only the names used as elements or types.
+//!
+//! ```rust
+//! use fhirlighter::parse;
+//! use fhirlighter::parser::grammar::ExprRef;
+//! use fhirlighter::parser::visit::{Visitor, walk};
+//!
+//! // Which functions does an expression use?
+//! #[derive(Default)]
+//! struct Functions<'a>(Vec<&'a str>);
+//!
+//! impl<'a> Visitor<'a> for Functions<'a> {
+//!     fn enter_function_call(&mut self, _: ExprRef, name: &'a str, _: &'a [ExprRef]) {
+//!         self.0.push(name);
+//!     }
+//! }
+//!
+//! let ast = parse("name.where(given.exists()).first()")?;
+//! let mut functions = Functions::default();
+//! walk(&ast, &mut functions);
+//! assert_eq!(functions.0, ["first", "where", "exists"]);
+//! # Ok::<(), fhirlighter::Error>(())
+//! ```
+
+use super::ast::Ast;
+use super::grammar::{BinaryOperator, ExprPool, ExprRef, Expression};
+use crate::evaluator::error::Error;
+use crate::lexer::token::Span;
+
+/// Whether to go on into the children of an expression.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Walk {
+    #[default]
+    Children,
+    /// Go on with the next sibling. The expression's own hooks and `leave` are
+    /// still called.
+    SkipChildren,
+}
+
+/// Read-only hooks over a tree, see [`walk`]. Each does nothing by default.
+#[allow(unused_variables)]
+pub trait Visitor<'a> {
+    /// Before any other hook of an expression.
+    fn enter(&mut self, pool: &'a ExprPool, expr_ref: ExprRef) -> Walk {
+        Walk::Children
+    }
+    /// After every other hook of an expression.
+    fn leave(&mut self, pool: &'a ExprPool, expr_ref: ExprRef) {}
+
+    fn visit_identifier(&mut self, expr_ref: ExprRef, name: &'a str) {}
+    fn enter_member_access(&mut self, expr_ref: ExprRef, object: ExprRef, member: &'a str) {}
+    fn leave_member_access(&mut self, expr_ref: ExprRef, object: ExprRef, member: &'a str) {}
+    /// A call, by the name of the function. The object, if any, is its first
+    /// child.
+    fn enter_function_call(&mut self, expr_ref: ExprRef, name: &'a str, arguments: &'a [ExprRef]) {}
+    fn leave_function_call(&mut self, expr_ref: ExprRef, name: &'a str, arguments: &'a [ExprRef]) {}
+    fn enter_binary_operation(
+        &mut self,
+        expr_ref: ExprRef,
+        operator: BinaryOperator,
+        lhs: ExprRef,
+        rhs: ExprRef,
+    ) {
+    }
+    fn leave_binary_operation(
+        &mut self,
+        expr_ref: ExprRef,
+        operator: BinaryOperator,
+        lhs: ExprRef,
+        rhs: ExprRef,
+    ) {
+    }
+    fn enter_index(&mut self, expr_ref: ExprRef, object: ExprRef, index: ExprRef) {}
+    fn leave_index(&mut self, expr_ref: ExprRef, object: ExprRef, index: ExprRef) {}
+    fn visit_external_constant(&mut self, expr_ref: ExprRef, name: &'a str) {}
+    /// A string, number, integer, boolean, date or date and time.
+    fn visit_literal(&mut self, expr_ref: ExprRef, literal: &'a Expression) {}
+    fn visit_error(&mut self, expr_ref: ExprRef) {}
+}
+
+/// Call the hooks of `visitor` for each expression of the tree, depth first.
+pub fn walk<'a>(ast: &'a Ast, visitor: &mut impl Visitor<'a>) {
+    walk_expression(&ast.expressions, ast.start, visitor);
+}
+
+/// Call the hooks of `visitor` for each expression below `expr_ref`, itself
+/// included.
+pub fn walk_expression<'a>(pool: &'a ExprPool, expr_ref: ExprRef, visitor: &mut impl Visitor<'a>) {
+    let walk = visitor.enter(pool, expr_ref);
+    let children = walk == Walk::Children;
+    match pool.get(expr_ref) {
+        Expression::Identifier(name) => visitor.visit_identifier(expr_ref, name),
+        Expression::MemberAccess { object, member } => {
+            visitor.enter_member_access(expr_ref, *object, member);
+            if children {
+                walk_expression(pool, *object, visitor);
+            }
+            visitor.leave_member_access(expr_ref, *object, member);
+        }
+        Expression::FunctionCall {
+            object,
+            function,
+            arguments,
+        } => {
+            let name = function_name(pool, *function);
+            visitor.enter_function_call(expr_ref, name, arguments);
+            if children {
+                for child in object.iter().chain(arguments) {
+                    walk_expression(pool, *child, visitor);
+                }
+            }
+            visitor.leave_function_call(expr_ref, name, arguments);
+        }
+        Expression::BinaryOperation { operator, lhs, rhs } => {
+            visitor.enter_binary_operation(expr_ref, *operator, *lhs, *rhs);
+            if children {
+                walk_expression(pool, *lhs, visitor);
+                walk_expression(pool, *rhs, visitor);
+            }
+            visitor.leave_binary_operation(expr_ref, *operator, *lhs, *rhs);
+        }
+        Expression::Index { object, index } => {
+            visitor.enter_index(expr_ref, *object, *index);
+            if children {
+                walk_expression(pool, *object, visitor);
+                walk_expression(pool, *index, visitor);
+            }
+            visitor.leave_index(expr_ref, *object, *index);
+        }
+        Expression::ExternalConstant(name) => visitor.visit_external_constant(expr_ref, name),
+        Expression::Error => visitor.visit_error(expr_ref),
+        literal => visitor.visit_literal(expr_ref, literal),
+    }
+    visitor.leave(pool, expr_ref);
+}
+
+/// Hooks that may change each expression of a tree in place, see [`walk_mut`].
+/// Each does nothing by default.
+///
+/// References may be pointed at other expressions of the same pool, but hooks
+/// can't add expressions: use a [`Folder`] for that. Children are walked as
+/// they are after the `enter_` hook.
+#[allow(unused_variables)]
+pub trait VisitorMut {
+    /// Before any other hook of an expression.
+    fn enter(&mut self, expr_ref: ExprRef, expression: &mut Expression) -> Walk {
+        Walk::Children
+    }
+    /// After every other hook of an expression.
+    fn leave(&mut self, expr_ref: ExprRef, expression: &mut Expression) {}
+
+    fn visit_identifier(&mut self, expr_ref: ExprRef, name: &mut String) {}
+    fn enter_member_access(
+        &mut self,
+        expr_ref: ExprRef,
+        object: &mut ExprRef,
+        member: &mut String,
+    ) {
+    }
+    fn leave_member_access(
+        &mut self,
+        expr_ref: ExprRef,
+        object: &mut ExprRef,
+        member: &mut String,
+    ) {
+    }
+    /// A call. The identifier naming the function isn't walked, but can be
+    /// renamed from `enter`.
+    fn enter_function_call(
+        &mut self,
+        expr_ref: ExprRef,
+        object: &mut Option<ExprRef>,
+        arguments: &mut Vec<ExprRef>,
+    ) {
+    }
+    fn leave_function_call(
+        &mut self,
+        expr_ref: ExprRef,
+        object: &mut Option<ExprRef>,
+        arguments: &mut Vec<ExprRef>,
+    ) {
+    }
+    fn enter_binary_operation(
+        &mut self,
+        expr_ref: ExprRef,
+        operator: &mut BinaryOperator,
+        lhs: &mut ExprRef,
+        rhs: &mut ExprRef,
+    ) {
+    }
+    fn leave_binary_operation(
+        &mut self,
+        expr_ref: ExprRef,
+        operator: &mut BinaryOperator,
+        lhs: &mut ExprRef,
+        rhs: &mut ExprRef,
+    ) {
+    }
+    fn enter_index(&mut self, expr_ref: ExprRef, object: &mut ExprRef, index: &mut ExprRef) {}
+    fn leave_index(&mut self, expr_ref: ExprRef, object: &mut ExprRef, index: &mut ExprRef) {}
+    fn visit_external_constant(&mut self, expr_ref: ExprRef, name: &mut String) {}
+    /// A string, number, integer, boolean, date or date and time, which may be
+    /// replaced with another literal.
+    fn visit_literal(&mut self, expr_ref: ExprRef, literal: &mut Expression) {}
+    fn visit_error(&mut self, expr_ref: ExprRef) {}
+}
+
+/// Call the hooks of `visitor` for each expression of the tree, depth first,
+/// letting them change it.
+pub fn walk_mut(ast: &mut Ast, visitor: &mut impl VisitorMut) {
+    walk_expression_mut(&mut ast.expressions, ast.start, visitor);
+}
+
+/// Call the hooks of `visitor` for each expression below `expr_ref`, itself
+/// included, letting them change it.
+pub fn walk_expression_mut(pool: &mut ExprPool, expr_ref: ExprRef, visitor: &mut impl VisitorMut) {
+    let walk = visitor.enter(expr_ref, pool.get_mut(expr_ref));
+    let children = match pool.get_mut(expr_ref) {
+        Expression::Identifier(name) => {
+            visitor.visit_identifier(expr_ref, name);
+            Vec::new()
+        }
+        Expression::MemberAccess { object, member } => {
+            visitor.enter_member_access(expr_ref, object, member);
+            vec![*object]
+        }
+        Expression::FunctionCall {
+            object, arguments, ..
+        } => {
+            visitor.enter_function_call(expr_ref, object, arguments);
+            object.iter().chain(arguments.iter()).copied().collect()
+        }
+        Expression::BinaryOperation { operator, lhs, rhs } => {
+            visitor.enter_binary_operation(expr_ref, operator, lhs, rhs);
+            vec![*lhs, *rhs]
+        }
+        Expression::Index { object, index } => {
+            visitor.enter_index(expr_ref, object, index);
+            vec![*object, *index]
+        }
+        Expression::ExternalConstant(name) => {
+            visitor.visit_external_constant(expr_ref, name);
+            Vec::new()
+        }
+        Expression::Error => {
+            visitor.visit_error(expr_ref);
+            Vec::new()
+        }
+        literal => {
+            visitor.visit_literal(expr_ref, literal);
+            Vec::new()
+        }
+    };
+
+    if walk == Walk::Children {
+        for child in children {
+            walk_expression_mut(pool, child, visitor);
+        }
+    }
+
+    match pool.get_mut(expr_ref) {
+        Expression::MemberAccess { object, member } => {
+            visitor.leave_member_access(expr_ref, object, member);
+        }
+        Expression::FunctionCall {
+            object, arguments, ..
+        } => visitor.leave_function_call(expr_ref, object, arguments),
+        Expression::BinaryOperation { operator, lhs, rhs } => {
+            visitor.leave_binary_operation(expr_ref, operator, lhs, rhs);
+        }
+        Expression::Index { object, index } => visitor.leave_index(expr_ref, object, index),
+        _ => {}
+    }
+    visitor.leave(expr_ref, pool.get_mut(expr_ref));
+}
+
+/// Hooks that rebuild a tree bottom-up, see [`fold`].
+///
+/// Each `fold_` hook gets the parts of an expression, with its children
+/// already folded into `pool`, the new pool, and returns the expression to use
+/// in its place: by default one like the original, added with
+/// `pool.add_with_span(.., span)`. A hook may instead add other expressions, or
+/// return one of the children to drop the expression altogether.
+#[allow(unused_variables)]
+pub trait Folder {
+    /// Before the children of an expression are folded, with the original
+    /// tree. [`Walk::SkipChildren`] copies the expression and those below it
+    /// unchanged, without calling their hooks.
+    fn enter(&mut self, source: &ExprPool, expr_ref: ExprRef) -> Walk {
+        Walk::Children
+    }
+
+    /// # Errors
+    ///
+    /// Returns an error if the pool is full.
+    fn fold_identifier(
+        &mut self,
+        pool: &mut ExprPool,
+        span: Span,
+        name: String,
+    ) -> Result<ExprRef, Error> {
+        pool.add_with_span(Expression::Identifier(name), span)
+    }
+
+    /// # Errors
+    ///
+    /// Returns an error if the pool is full.
+    fn fold_member_access(
+        &mut self,
+        pool: &mut ExprPool,
+        span: Span,
+        object: ExprRef,
+        member: String,
+    ) -> Result<ExprRef, Error> {
+        pool.add_with_span(Expression::MemberAccess { object, member }, span)
+    }
+
+    /// A call, with the identifier naming the function folded like any other.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the pool is full.
+    fn fold_function_call(
+        &mut self,
+        pool: &mut ExprPool,
+        span: Span,
+        object: Option<ExprRef>,
+        function: ExprRef,
+        arguments: Vec<ExprRef>,
+    ) -> Result<ExprRef, Error> {
+        let expression = Expression::FunctionCall {
+            object,
+            function,
+            arguments,
+        };
+        pool.add_with_span(expression, span)
+    }
+
+    /// # Errors
+    ///
+    /// Returns an error if the pool is full.
+    fn fold_binary_operation(
+        &mut self,
+        pool: &mut ExprPool,
+        span: Span,
+        operator: BinaryOperator,
+        lhs: ExprRef,
+        rhs: ExprRef,
+    ) -> Result<ExprRef, Error> {
+        pool.add_with_span(Expression::BinaryOperation { operator, lhs, rhs }, span)
+    }
+
+    /// # Errors
+    ///
+    /// Returns an error if the pool is full.
+    fn fold_index(
+        &mut self,
+        pool: &mut ExprPool,
+        span: Span,
+        object: ExprRef,
+        index: ExprRef,
+    ) -> Result<ExprRef, Error> {
+        pool.add_with_span(Expression::Index { object, index }, span)
+    }
+
+    /// # Errors
+    ///
+    /// Returns an error if the pool is full.
+    fn fold_external_constant(
+        &mut self,
+        pool: &mut ExprPool,
+        span: Span,
+        name: String,
+    ) -> Result<ExprRef, Error> {
+        pool.add_with_span(Expression::ExternalConstant(name), span)
+    }
+
+    /// A string, number, integer, boolean, date or date and time.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the pool is full.
+    fn fold_literal(
+        &mut self,
+        pool: &mut ExprPool,
+        span: Span,
+        literal: Expression,
+    ) -> Result<ExprRef, Error> {
+        pool.add_with_span(literal, span)
+    }
+
+    /// # Errors
+    ///
+    /// Returns an error if the pool is full.
+    fn fold_error(&mut self, pool: &mut ExprPool, span: Span) -> Result<ExprRef, Error> {
+        pool.add_with_span(Expression::Error, span)
+    }
+}
+
+/// Rebuild the tree through the hooks of `folder` into a new AST, leaving the
+/// original as it was. Expressions the root doesn't reach aren't copied.
+///
+/// # Errors
+///
+/// Returns the first error of a hook, such as `ErrorCode::TooManyExpressions`.
+pub fn fold(ast: &Ast, folder: &mut impl Folder) -> Result<Ast, Error> {
+    let mut expressions = ExprPool::new();
+    let start = fold_expression(&ast.expressions, ast.start, &mut expressions, folder)?;
+    Ok(Ast { expressions, start })
+}
+
+/// Fold the expression at `expr_ref` of `source` and those below it into
+/// `pool`, returning where it ended up. See [`fold`].
+///
+/// # Errors
+///
+/// Returns the first error of a hook, such as `ErrorCode::TooManyExpressions`.
+pub fn fold_expression(
+    source: &ExprPool,
+    expr_ref: ExprRef,
+    pool: &mut ExprPool,
+    folder: &mut impl Folder,
+) -> Result<ExprRef, Error> {
+    if folder.enter(source, expr_ref) == Walk::SkipChildren {
+        return copy(source, expr_ref, pool);
+    }
+    let mut child =
+        |expr_ref: ExprRef, pool: &mut ExprPool| fold_expression(source, expr_ref, pool, folder);
+    let span = source.span(expr_ref);
+    match source.get(expr_ref).clone() {
+        Expression::Identifier(name) => folder.fold_identifier(pool, span, name),
+        Expression::MemberAccess { object, member } => {
+            let object = child(object, pool)?;
+            folder.fold_member_access(pool, span, object, member)
+        }
+        Expression::FunctionCall {
+            object,
+            function,
+            arguments,
+        } => {
+            let object = object.map(|object| child(object, pool)).transpose()?;
+            let function = child(function, pool)?;
+            let arguments = arguments
+                .into_iter()
+                .map(|argument| child(argument, pool))
+                .collect::<Result<_, _>>()?;
+            folder.fold_function_call(pool, span, object, function, arguments)
+        }
+        Expression::BinaryOperation { operator, lhs, rhs } => {
+            let lhs = child(lhs, pool)?;
+            let rhs = child(rhs, pool)?;
+            folder.fold_binary_operation(pool, span, operator, lhs, rhs)
+        }
+        Expression::Index { object, index } => {
+            let object = child(object, pool)?;
+            let index = child(index, pool)?;
+            folder.fold_index(pool, span, object, index)
+        }
+        Expression::ExternalConstant(name) => folder.fold_external_constant(pool, span, name),
+        Expression::Error => folder.fold_error(pool, span),
+        literal => folder.fold_literal(pool, span, literal),
+    }
+}
+
+/// Copy the expression at `expr_ref` of `source` and those below it into
+/// `pool` unchanged, returning where it ended up.
+///
+/// # Errors
+///
+/// Returns `ErrorCode::TooManyExpressions` if the pool is full.
+pub fn copy(source: &ExprPool, expr_ref: ExprRef, pool: &mut ExprPool) -> Result<ExprRef, Error> {
+    struct Copy;
+    impl Folder for Copy {}
+    fold_expression(source, expr_ref, pool, &mut Copy)
+}
+
+// Helper: the name of a called function, or "" when it isn't an identifier
+fn function_name(pool: &ExprPool, function: ExprRef) -> &str {
+    match pool.get(function) {
+        Expression::Identifier(name) => name,
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_syntax as parse;
+    use crate::parser::unparser::unparse;
+
+    // Records the hooks called, to check their order
+    #[derive(Default)]
+    struct Trace(Vec<String>);
+
+    impl<'a> Visitor<'a> for Trace {
+        fn enter(&mut self, pool: &'a ExprPool, expr_ref: ExprRef) -> Walk {
+            // Don't look inside index operations
+            if matches!(pool.get(expr_ref), Expression::Index { .. }) {
+                Walk::SkipChildren
+            } else {
+                Walk::Children
+            }
+        }
+        fn visit_identifier(&mut self, _: ExprRef, name: &'a str) {
+            self.0.push(name.to_string());
+        }
+        fn enter_function_call(&mut self, _: ExprRef, name: &'a str, _: &'a [ExprRef]) {
+            self.0.push(format!("{name}("));
+        }
+        fn leave_function_call(&mut self, _: ExprRef, name: &'a str, _: &'a [ExprRef]) {
+            self.0.push(format!("){name}"));
+        }
+        fn leave_binary_operation(
+            &mut self,
+            _: ExprRef,
+            operator: BinaryOperator,
+            _: ExprRef,
+            _: ExprRef,
+        ) {
+            self.0.push(operator.to_string());
+        }
+        fn enter_index(&mut self, _: ExprRef, _: ExprRef, _: ExprRef) {
+            self.0.push("[]".to_string());
+        }
+        fn visit_literal(&mut self, _: ExprRef, literal: &'a Expression) {
+            self.0.push(literal.to_string());
+        }
+    }
+
+    #[test]
+    fn test_walk_order() {
+        let ast = parse("a.f(b = 1, c[0]).g()").unwrap();
+        let mut trace = Trace::default();
+        walk(&ast, &mut trace);
+        assert_eq!(trace.0, ["g(", "f(", "a", "b", "1", "=", "[]", ")f", ")g"]);
+    }
+
+    // Renames elements and turns `!=` into `=`
+    struct Rename;
+
+    impl VisitorMut for Rename {
+        fn visit_identifier(&mut self, _: ExprRef, name: &mut String) {
+            *name = name.to_uppercase();
+        }
+        fn enter_member_access(&mut self, _: ExprRef, _: &mut ExprRef, member: &mut String) {
+            *member = member.to_uppercase();
+        }
+        fn enter_binary_operation(
+            &mut self,
+            _: ExprRef,
+            operator: &mut BinaryOperator,
+            lhs: &mut ExprRef,
+            rhs: &mut ExprRef,
+        ) {
+            if *operator == BinaryOperator::NotEquals {
+                *operator = BinaryOperator::Equals;
+                std::mem::swap(lhs, rhs);
+            }
+        }
+    }
+
+    #[test]
+    fn test_walk_mut() {
+        let mut ast = parse("name.given != family.exists()").unwrap();
+        walk_mut(&mut ast, &mut Rename);
+        assert_eq!(unparse(&ast), "FAMILY.exists() = NAME.GIVEN");
+    }
+
+    // Drops `first()` after an index, which already gives a single item
+    struct DropFirst;
+
+    impl Folder for DropFirst {
+        fn fold_function_call(
+            &mut self,
+            pool: &mut ExprPool,
+            span: Span,
+            object: Option<ExprRef>,
+            function: ExprRef,
+            arguments: Vec<ExprRef>,
+        ) -> Result<ExprRef, Error> {
+            if let Some(object) = object
+                && matches!(pool.get(object), Expression::Index { .. })
+                && matches!(pool.get(function), Expression::Identifier(name) if name == "first")
+            {
+                return Ok(object);
+            }
+            let expression = Expression::FunctionCall {
+                object,
+                function,
+                arguments,
+            };
+            pool.add_with_span(expression, span)
+        }
+    }
+
+    #[test]
+    fn test_fold() {
+        let ast = parse("name[0].first().given.first() = 'Peter'").unwrap();
+        let folded = fold(&ast, &mut DropFirst).unwrap();
+        assert_eq!(unparse(&folded), "name[0].given.first() = 'Peter'");
+        // The original is untouched
+        assert_eq!(unparse(&ast), "name[0].first().given.first() = 'Peter'");
+    }
+
+    #[test]
+    fn test_copy_keeps_spans_and_drops_unreachable_expressions() {
+        let mut ast = parse("name.given").unwrap();
+        ast.expressions.add(Expression::Integer(1)).unwrap();
+        let copied = fold(&ast, &mut DropFirst).unwrap();
+        assert!(copied.equivalent(&ast));
+        assert_eq!(copied.expressions.len(), 2);
+        assert_eq!(
+            copied.expressions.span(copied.start),
+            ast.expressions.span(ast.start)
+        );
+    }
+}
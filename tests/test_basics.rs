@@ -3,7 +3,14 @@
 //! These tests are based on the official FHIRPath test suite:
 //! tests/specification/tests-fhir-r4.xml (testBasics group)
 
-use fhirlighter::{Error, evaluate};
+#![allow(
+    clippy::doc_markdown,
+    clippy::uninlined_format_args,
+    clippy::single_match_else
+)]
+
+use fhirlighter::evaluator::context::{EvaluationContext, UnmatchedType};
+use fhirlighter::{Error, ErrorCode, evaluate, evaluate_with_context};
 use serde_json::Value;
 use std::fs;
 
@@ -170,3 +177,12 @@ fn test_simple_with_wrong_context() {
         }
     }
 }
+
+// Not part of the specification suite: testSimpleWithWrongContext in strict mode
+#[test]
+fn test_simple_with_wrong_context_strict() {
+    let patient = load_patient_example();
+    let context = EvaluationContext::new(&patient).with_unmatched_type(UnmatchedType::Error);
+    let result = evaluate_with_context("Encounter.name.given", &context);
+    assert_eq!(result.unwrap_err().code, ErrorCode::UnmatchedType);
+}
@@ -0,0 +1,172 @@
+//! Tests for error categories and warnings
+//!
+//! Errors are grouped by kind, empty results are successes and non-fatal
+//! problems are returned as diagnostics alongside the result.
+
+use fhirlighter::evaluator::context::EvaluationContext;
+use fhirlighter::{
+    ErrorCode, ErrorKind, Severity, Span, evaluate, evaluate_with_diagnostics, parse,
+    parse_with_diagnostics,
+};
+use serde_json::{Value, json};
+use std::fs;
+
+/// Load an example JSON file
+fn load_example(name: &str) -> Value {
+    let contents =
+        fs::read_to_string(format!("tests/examples/{name}")).expect("Failed to read example");
+    serde_json::from_str(&contents).expect("Failed to parse example")
+}
+
+#[test]
+fn test_empty_result_is_success() {
+    let patient = load_example("patient-example.json");
+    let context = EvaluationContext::new(&patient);
+    let evaluation = evaluate_with_diagnostics("Patient.photo", &context).unwrap();
+    assert_eq!(evaluation.value, json!([]));
+    assert!(evaluation.diagnostics.is_empty());
+}
+
+#[test]
+fn test_error_kinds() {
+    let patient = load_example("patient-example.json");
+
+    let error = parse("Patient.name.'given'").err().unwrap();
+    assert_eq!(error.kind(), ErrorKind::Syntax);
+
+    let error = evaluate("Patient.gender < 1", &patient).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::StaticType);
+    assert_eq!(error.span, Some(Span::new(0, 18)));
+
+    let error = evaluate("Patient.name.given = 'Peter'", &patient).unwrap_err();
+    assert_eq!(error.code, ErrorCode::NotSingleton);
+    assert_eq!(error.kind(), ErrorKind::Runtime);
+
+    let error = evaluate("Patient.name.frobnicate()", &patient).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::StaticType);
+
+    for expression in ["Patient.name.select(given)", "Patient.name.given.join(',')"] {
+        let error = evaluate(expression, &patient).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::Unsupported, "{expression}");
+    }
+}
+
+#[test]
+fn test_incomplete_expressions_are_rejected() {
+    let patient = load_example("patient-example.json");
+    for expression in [
+        "Patient.name )foo",
+        "Patient.name.given[0",
+        "Patient.name[0 1]",
+        "Patient.name.given.first(",
+        "Patient.gender 'male'",
+    ] {
+        let error = evaluate(expression, &patient).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::Syntax, "{expression}");
+    }
+    assert_eq!(
+        parse("Patient.name.given[0")
+            .err()
+            .unwrap()
+            .render("Patient.name.given[0"),
+        "error[E0205]: Unclosed '[', expected ']' before the end of the expression\n  |\n1 | Patient.name.given[0\n  |                   ^"
+    );
+}
+
+#[test]
+fn test_numbers_compare_across_integer_and_decimal() {
+    let observation = load_example("observation-example.json");
+    assert_eq!(
+        evaluate("Observation.value.value > 184.5", &observation).unwrap(),
+        json!(true)
+    );
+    assert_eq!(
+        evaluate("Observation.value.value = 185.0", &observation).unwrap(),
+        json!(true)
+    );
+}
+
+#[test]
+fn test_complex_values_compare_by_members() {
+    let patient = load_example("patient-example.json");
+    assert_eq!(
+        evaluate("Patient.name[0] = Patient.name[0]", &patient).unwrap(),
+        json!(true)
+    );
+    assert_eq!(
+        evaluate("Patient.name[0] != Patient.name[1]", &patient).unwrap(),
+        json!(true)
+    );
+}
+
+#[test]
+fn test_different_types_warn() {
+    let patient = load_example("patient-example.json");
+    let context = EvaluationContext::new(&patient);
+    let evaluation = evaluate_with_diagnostics("Patient.active = 'true'", &context).unwrap();
+    assert_eq!(evaluation.value, json!(false));
+
+    let [warning] = evaluation.diagnostics.as_slice() else {
+        panic!("Expected one warning");
+    };
+    assert_eq!(warning.severity, Severity::Warning);
+    assert_eq!(warning.code, ErrorCode::IncomparableValues);
+    assert_eq!(warning.span, Some(Span::new(0, 23)));
+}
+
+#[test]
+fn test_unresolved_reference_warns() {
+    let observation = load_example("observation-example.json");
+    let context = EvaluationContext::new(&observation);
+    let evaluation =
+        evaluate_with_diagnostics("Observation.subject.resolve().exists()", &context).unwrap();
+    assert_eq!(evaluation.value, json!(false));
+    assert_eq!(evaluation.diagnostics.len(), 1);
+    assert_eq!(
+        evaluation.diagnostics[0].code,
+        ErrorCode::UnresolvedReference
+    );
+    assert_eq!(evaluation.diagnostics[0].span, Some(Span::new(0, 29)));
+}
+
+#[test]
+fn test_unmatched_type_warns() {
+    let patient = load_example("patient-example.json");
+    let context = EvaluationContext::new(&patient);
+    let evaluation = evaluate_with_diagnostics("Encounter.status", &context).unwrap();
+    assert_eq!(evaluation.value, json!([]));
+    assert_eq!(evaluation.diagnostics[0].code, ErrorCode::UnmatchedType);
+    assert_eq!(evaluation.diagnostics[0].span, Some(Span::new(0, 9)));
+}
+
+#[test]
+fn test_function_calls_are_checked_when_parsing() {
+    let error = parse("Patient.photo.frist()").unwrap_err();
+    assert_eq!(error.code, ErrorCode::UnknownFunction);
+    let parsed = parse_with_diagnostics("name.frist() = name.first(1)");
+    let codes: Vec<_> = parsed
+        .diagnostics
+        .iter()
+        .map(|diagnostic| diagnostic.code)
+        .collect();
+    assert_eq!(
+        codes,
+        [ErrorCode::UnknownFunction, ErrorCode::InvalidArgument]
+    );
+
+    let patient = load_example("patient-example.json");
+
+    // `photo` is empty, so evaluation would never reach the calls
+    let error = evaluate("Patient.photo.frist()", &patient).unwrap_err();
+    assert_eq!(error.code, ErrorCode::UnknownFunction);
+    assert_eq!(
+        error.message,
+        "Unknown function 'frist', did you mean 'first'?"
+    );
+    assert_eq!(error.span, Some(Span::new(14, 19)));
+
+    let error = evaluate("Patient.photo.first(1)", &patient).unwrap_err();
+    assert_eq!(error.code, ErrorCode::InvalidArgument);
+    assert_eq!(error.message, "first() takes no arguments, but was given 1");
+    assert_eq!(error.span, Some(Span::new(0, 22)));
+}
@@ -0,0 +1,117 @@
+//! Tests ported from the `FHIRPath` specification `testExtension` group
+//!
+//! These tests are based on the official `FHIRPath` test suite:
+//! tests/specification/tests-fhir-r4.xml (testExtension group)
+
+use fhirlighter::{Error, evaluate};
+use serde_json::{Value, json};
+use std::fs;
+
+/// Load the patient example JSON file
+fn load_patient_example() -> Value {
+    let contents = fs::read_to_string("tests/examples/patient-example.json")
+        .expect("Failed to read patient-example.json");
+    serde_json::from_str(&contents).expect("Failed to parse patient-example.json")
+}
+
+/// Helper function to evaluate an expression against patient example
+fn eval_patient(expression: &str) -> Result<Value, Error> {
+    let patient = load_patient_example();
+    evaluate(expression, &patient)
+}
+
+// Not part of the specification suite: birthDate carries the birthTime extension in its _birthDate sibling
+#[test]
+fn test_extension_count() {
+    let result = eval_patient(
+        "Patient.birthDate.extension('http://hl7.org/fhir/StructureDefinition/patient-birthTime').count()",
+    )
+    .unwrap();
+    assert_eq!(result, json!(1));
+}
+
+// Test: extension url given as an environment variable
+// XML: <test name="testExtension2" inputfile="patient-example.xml">
+//        <expression>Patient.birthDate.extension(%`ext-patient-birthTime`).exists()</expression>
+//        <output type="boolean">true</output>
+//      </test>
+#[test]
+fn test_extension2() {
+    let result =
+        eval_patient("Patient.birthDate.extension(%`ext-patient-birthTime`).exists()").unwrap();
+    assert_eq!(result, json!(true));
+}
+
+// Test: no extension with a different url
+// XML: <test name="testExtension3" inputfile="patient-example.xml">
+//        <expression>Patient.birthDate.extension('http://hl7.org/fhir/StructureDefinition/patient-birthTime1').empty()</expression>
+//        <output type="boolean">true</output>
+//      </test>
+#[test]
+fn test_extension3() {
+    let result = eval_patient(
+        "Patient.birthDate.extension('http://hl7.org/fhir/StructureDefinition/patient-birthTime1').empty()",
+    )
+    .unwrap();
+    assert_eq!(result, json!(true));
+}
+
+// Not part of the specification suite: the extension value itself is reachable
+#[test]
+fn test_extension_value() {
+    let result = eval_patient(
+        "Patient.birthDate.extension('http://hl7.org/fhir/StructureDefinition/patient-birthTime').valueDateTime",
+    )
+    .unwrap();
+    assert_eq!(result, json!("1974-12-25T14:35:45-05:00"));
+}
+
+// Not part of the specification suite: the primitive value is unaffected by its extension
+#[test]
+fn test_extended_primitive_value() {
+    let result = eval_patient("Patient.birthDate").unwrap();
+    assert_eq!(result, json!("1974-12-25"));
+}
+
+// Not part of the specification suite: repeating primitives use parallel arrays
+#[test]
+fn test_repeating_primitive_extension() {
+    let patient = json!({
+        "resourceType": "Patient",
+        "name": [{
+            "given": ["Peter", null],
+            "_given": [null, {
+                "extension": [{
+                    "url": "http://hl7.org/fhir/StructureDefinition/iso21090-EN-qualifier",
+                    "valueCode": "CL"
+                }]
+            }]
+        }]
+    });
+
+    let result = evaluate("Patient.name.given.count()", &patient).unwrap();
+    assert_eq!(result, json!(2));
+
+    let result = evaluate("Patient.name.given.extension.valueCode", &patient).unwrap();
+    assert_eq!(result, json!("CL"));
+}
+
+// Not part of the specification suite: a primitive with only an extension exists without a value
+#[test]
+fn test_extension_only_primitive() {
+    let patient = json!({
+        "resourceType": "Patient",
+        "_birthDate": {
+            "extension": [{
+                "url": "http://hl7.org/fhir/StructureDefinition/data-absent-reason",
+                "valueCode": "unknown"
+            }]
+        }
+    });
+
+    let result = evaluate("Patient.birthDate.empty()", &patient).unwrap();
+    assert_eq!(result, json!(false));
+
+    let result = evaluate("Patient.birthDate.extension.valueCode", &patient).unwrap();
+    assert_eq!(result, json!("unknown"));
+}
@@ -0,0 +1,149 @@
+//! Tests for the FHIR-specific additions to `FHIRPath`
+//!
+//! These cover the functions defined in the FHIR specification (section 2.1.9.1
+//! "Additional functions") and the core invariants built on them.
+
+use fhirlighter::evaluator::engine::Evaluator;
+use fhirlighter::{Error, evaluate, parse};
+use serde_json::{Value, json};
+use std::fs;
+use std::sync::{Arc, Mutex};
+
+/// Load an example JSON file
+fn load_example(name: &str) -> Value {
+    let contents =
+        fs::read_to_string(format!("tests/examples/{name}")).expect("Failed to read example");
+    serde_json::from_str(&contents).expect("Failed to parse example")
+}
+
+/// Helper function to evaluate an expression against patient example
+fn eval_patient(expression: &str) -> Result<Value, Error> {
+    let patient = load_example("patient-example.json");
+    evaluate(expression, &patient)
+}
+
+// txt-1 and txt-2: the narrative is valid XHTML with some content
+#[test]
+fn test_html_checks_example_narratives() {
+    assert_eq!(eval_patient("text.div.htmlChecks()").unwrap(), json!(true));
+
+    let observation = load_example("observation-example.json");
+    let result = evaluate("Observation.text.div.htmlChecks()", &observation).unwrap();
+    assert_eq!(result, json!(true));
+}
+
+#[test]
+fn test_html_checks_rejects_active_content() {
+    let patient = json!({
+        "resourceType": "Patient",
+        "text": {
+            "status": "generated",
+            "div": "<div xmlns=\"http://www.w3.org/1999/xhtml\"><script>alert(1)</script></div>"
+        }
+    });
+    let result = evaluate("Patient.text.div.htmlChecks()", &patient).unwrap();
+    assert_eq!(result, json!(false));
+}
+
+#[test]
+fn test_html_checks_rejects_empty_narrative() {
+    let patient = json!({
+        "resourceType": "Patient",
+        "text": {
+            "status": "generated",
+            "div": "<div xmlns=\"http://www.w3.org/1999/xhtml\">  </div>"
+        }
+    });
+    let result = evaluate("Patient.text.div.htmlChecks()", &patient).unwrap();
+    assert_eq!(result, json!(false));
+}
+
+// ext-1: Must have either extensions or value[x], not both
+#[test]
+fn test_ext_1() {
+    let invariant = "extension.exists() != value.exists()";
+
+    let with_value = json!({"url": "http://example.org/a", "valueString": "x"});
+    assert_eq!(evaluate(invariant, &with_value).unwrap(), json!(true));
+
+    let with_extension = json!({
+        "url": "http://example.org/a",
+        "extension": [{"url": "b", "valueCode": "y"}]
+    });
+    assert_eq!(evaluate(invariant, &with_extension).unwrap(), json!(true));
+
+    let with_both = json!({
+        "url": "http://example.org/a",
+        "valueString": "x",
+        "extension": [{"url": "b", "valueCode": "y"}]
+    });
+    assert_eq!(evaluate(invariant, &with_both).unwrap(), json!(false));
+}
+
+#[test]
+fn test_choice_element() {
+    let observation = load_example("observation-example.json");
+    let result = evaluate("Observation.value.unit", &observation).unwrap();
+    assert_eq!(result, json!("lbs"));
+}
+
+#[test]
+fn test_has_value() {
+    assert_eq!(
+        eval_patient("Patient.birthDate.hasValue()").unwrap(),
+        json!(true)
+    );
+    assert_eq!(
+        eval_patient("Patient.name.hasValue()").unwrap(),
+        json!(false)
+    );
+
+    let patient = json!({
+        "resourceType": "Patient",
+        "_birthDate": {"extension": [{"url": "a", "valueCode": "unknown"}]}
+    });
+    let result = evaluate("Patient.birthDate.hasValue()", &patient).unwrap();
+    assert_eq!(result, json!(false));
+}
+
+#[test]
+fn test_get_value() {
+    assert_eq!(
+        eval_patient("Patient.birthDate.getValue()").unwrap(),
+        json!("1974-12-25")
+    );
+    assert_eq!(eval_patient("Patient.name.getValue()").unwrap(), json!([]));
+}
+
+#[test]
+fn test_trace_returns_input_and_logs() {
+    let patient = load_example("patient-example.json");
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let sink = Arc::clone(&log);
+    let evaluator = Evaluator::new().with_tracer(move |name, value| {
+        sink.lock().unwrap().push((name.to_string(), value.clone()));
+    });
+
+    let ast = parse("Patient.birthDate.trace('birth').hasValue()").unwrap();
+    assert_eq!(evaluator.evaluate(&ast, &patient).unwrap(), json!(true));
+
+    let ast = parse("Patient.name.trace('families', family).count()").unwrap();
+    assert_eq!(evaluator.evaluate(&ast, &patient).unwrap(), json!(3));
+
+    let log = std::mem::take(&mut *log.lock().unwrap());
+    assert_eq!(log[0].0, "birth");
+    assert_eq!(
+        log[0].1,
+        json!([{
+            "value": "1974-12-25",
+            "extension": [{
+                "url": "http://hl7.org/fhir/StructureDefinition/patient-birthTime",
+                "valueDateTime": "1974-12-25T14:35:45-05:00"
+            }]
+        }])
+    );
+    assert_eq!(
+        log[1],
+        ("families".to_string(), json!(["Chalmers", "Windsor"]))
+    );
+}
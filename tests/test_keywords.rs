@@ -0,0 +1,66 @@
+//! Tests for keywords used as element and function names
+//!
+//! Not part of the specification suite: words such as `where`, `is` and `mod`
+//! are keywords only in operator position, so each must still work as a member
+//! name, a leading identifier and a function name.
+
+use fhirlighter::{ErrorKind, evaluate, parse, parse_with_diagnostics};
+use serde_json::json;
+
+const WORDS: [&str; 16] = [
+    "where", "select", "all", "any", "exists", "is", "as", "not", "mod", "and", "or", "xor",
+    "implies", "div", "contains", "in",
+];
+
+#[test]
+fn test_keywords_as_member_names() {
+    for word in WORDS {
+        let resource = json!({"resourceType": "Basic", word: "value"});
+        assert_eq!(
+            evaluate(&format!("Basic.{word}"), &resource).unwrap(),
+            json!("value"),
+            "Basic.{word}"
+        );
+        assert_eq!(evaluate(word, &resource).unwrap(), json!("value"), "{word}");
+        assert_eq!(
+            evaluate(&format!("Basic.{word}.exists()"), &resource).unwrap(),
+            json!(true),
+            "Basic.{word}.exists()"
+        );
+    }
+}
+
+#[test]
+fn test_keywords_as_function_names() {
+    for word in WORDS {
+        for expression in [
+            format!("name.{word}()"),
+            format!("name.{word}(given = 'Peter')"),
+            format!("{word}()"),
+        ] {
+            // Calls such as `name.is()` parse, though they don't fit the function
+            let parsed = parse_with_diagnostics(&expression);
+            assert!(
+                parsed
+                    .diagnostics
+                    .iter()
+                    .all(|diagnostic| diagnostic.code.kind() != ErrorKind::Syntax),
+                "{expression}"
+            );
+        }
+    }
+}
+
+#[test]
+fn test_keywords_as_external_constants() {
+    let patient = json!({"resourceType": "Patient"});
+    let error = evaluate("%as", &patient).unwrap_err();
+    assert_eq!(error.message, "Undefined variable: %as");
+}
+
+#[test]
+fn test_keywords_in_operator_position() {
+    // `and` after a complete expression is the operator, which isn't supported yet
+    assert!(parse("Basic.where and Basic.select").is_err());
+    assert!(parse("Basic.where = Basic.select").is_ok());
+}
@@ -0,0 +1,89 @@
+//! Tests for escapes in string literals, quoted names and delimited identifiers
+//!
+//! Not part of the specification suite: these check that every escape the
+//! specification defines is decoded and that any other is rejected.
+
+use fhirlighter::{ErrorCode, Span, evaluate, parse};
+use serde_json::{Value, json};
+use std::fs;
+
+/// Load an example JSON file
+fn load_example(name: &str) -> Value {
+    let contents =
+        fs::read_to_string(format!("tests/examples/{name}")).expect("Failed to read example");
+    serde_json::from_str(&contents).expect("Failed to parse example")
+}
+
+#[test]
+fn test_string_escapes() {
+    let patient = load_example("patient-example.json");
+    for (expression, expected) in [
+        (r"'O\'Brien'", "O'Brien"),
+        (r"'line\nbreak'", "line\nbreak"),
+        (r"'caf\u00e9'", "café"),
+        (r"'\uD83D\uDE00'", "😀"),
+        (r#"'\"\`\\\/\f\r\t'"#, "\"`\\/\u{c}\r\t"),
+    ] {
+        assert_eq!(
+            evaluate(expression, &patient).unwrap(),
+            json!(expected),
+            "{expression}"
+        );
+    }
+}
+
+#[test]
+fn test_quoted_external_constant_escapes() {
+    let patient = load_example("patient-example.json");
+    assert_eq!(
+        evaluate(r"%'vs-administrative\u002Dgender'", &patient).unwrap(),
+        json!("http://hl7.org/fhir/ValueSet/administrative-gender")
+    );
+}
+
+#[test]
+fn test_invalid_escapes() {
+    let error = parse(r"Patient.name.family = 'O\Brien'").err().unwrap();
+    assert_eq!(error.code, ErrorCode::InvalidEscape);
+    assert_eq!(error.span, Some(Span::new(24, 26)));
+
+    let error = parse(r"'\u00'").err().unwrap();
+    assert_eq!(error.code, ErrorCode::InvalidEscape);
+
+    let error = parse(r"'\uDE00'").err().unwrap();
+    assert_eq!(error.message, r"Unpaired surrogate '\uDE00'");
+}
+
+#[test]
+fn test_delimited_identifiers() {
+    let resource = json!({
+        "resourceType": "Basic",
+        "given name": "Peter",
+        "div": "keyword",
+        "it's": true
+    });
+    assert_eq!(evaluate("`given name`", &resource).unwrap(), json!("Peter"));
+    assert_eq!(
+        evaluate("Basic.`div`", &resource).unwrap(),
+        json!("keyword")
+    );
+    assert_eq!(evaluate(r"`it\'s`", &resource).unwrap(), json!(true));
+    assert_eq!(
+        evaluate("`given name`.`exists`()", &resource).unwrap(),
+        json!(true)
+    );
+
+    let patient = load_example("patient-example.json");
+    assert_eq!(
+        evaluate("Patient.`ofType`(`Patient`).id", &patient).unwrap(),
+        json!("example")
+    );
+    assert_eq!(
+        evaluate("%`ext-patient-birthTime`", &patient).unwrap(),
+        json!("http://hl7.org/fhir/StructureDefinition/patient-birthTime")
+    );
+
+    let error = parse("Patient.`name").err().unwrap();
+    assert_eq!(error.code, ErrorCode::UnterminatedString);
+    assert_eq!(error.span, Some(Span::new(8, 13)));
+}
@@ -3,6 +3,8 @@
 //! These tests are based on the official FHIRPath test suite:
 //! tests/specification/tests-fhir-r4.xml (testMiscellaneousAccessorTests group)
 
+#![allow(clippy::doc_markdown, clippy::uninlined_format_args)]
+
 use fhirlighter::{Error, evaluate};
 use serde_json::Value;
 use std::fs;
@@ -0,0 +1,55 @@
+//! Tests for expressions spanning several lines
+//!
+//! Not part of the specification suite: these check that comments and line
+//! breaks, in any mix of CRLF and LF, don't change what an expression means.
+
+use fhirlighter::{ErrorCode, evaluate, parse};
+use serde_json::{Value, json};
+use std::fs;
+
+/// Load an example JSON file
+fn load_example(name: &str) -> Value {
+    let contents =
+        fs::read_to_string(format!("tests/examples/{name}")).expect("Failed to read example");
+    serde_json::from_str(&contents).expect("Failed to parse example")
+}
+
+#[test]
+fn test_multiline_with_comments() {
+    let patient = load_example("patient-example.json");
+    let expression = "// The official name\r\n\
+                      Patient\r\n\
+                      \t.name[0] /* first listed */\n\
+                      \t.given\r\n\
+                      \t.first() // Peter\n";
+    assert_eq!(evaluate(expression, &patient).unwrap(), json!("Peter"));
+}
+
+#[test]
+fn test_line_endings_dont_matter() {
+    let patient = load_example("patient-example.json");
+    let lines = ["Patient", ".name[0]", ".family", "= 'Chalmers'"];
+    let expected = evaluate(&lines.concat(), &patient);
+    assert_eq!(expected, Ok(json!(true)));
+    for separator in ["\n", "\r\n", "\n\n", "\r\n\t// comment\r\n", "/*\r\n*/"] {
+        let expression = lines.join(separator);
+        assert_eq!(evaluate(&expression, &patient), expected, "{expression:?}");
+    }
+}
+
+#[test]
+fn test_division_is_not_a_comment() {
+    let error = parse("Patient.name / 2").err().unwrap();
+    assert_ne!(error.code, ErrorCode::UnterminatedComment);
+    assert!(parse("Patient.name // 2").is_ok());
+}
+
+#[test]
+fn test_error_on_later_line() {
+    let expression = "Patient.name\r\n  // which name\n  .given[0 1]";
+    let error = parse(expression).err().unwrap();
+    assert_eq!(
+        error.render(expression),
+        "error[E0201]: Unexpected '1', expected an operator or ']'\n  |\n3 |   .given[0 1]\n  |            ^"
+    );
+}
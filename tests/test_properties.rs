@@ -0,0 +1,428 @@
+//! Property tests for the lexer, parser and evaluator
+//!
+//! Not part of the specification suite: these check that arbitrary input is
+//! rejected with an error rather than a panic, that tokens, syntax trees and
+//! parsed expressions reproduce the source they came from, and that formatting
+//! and optimizing don't change what an expression means.
+
+use fhirlighter::evaluator::context::EvaluationContext;
+use fhirlighter::evaluator::engine::Evaluator;
+use fhirlighter::lexer::token::TokenKind;
+use fhirlighter::lexer::tokenizer::Lexer;
+use fhirlighter::parser::ast::{Ast, FhirParser, MAX_DEPTH};
+use fhirlighter::parser::cst::Cst;
+use fhirlighter::parser::formatter::{FormatOptions, format};
+use fhirlighter::parser::grammar::{BinaryOperator, ExprPool, ExprRef, Expression};
+use fhirlighter::parser::optimize::optimize;
+use fhirlighter::parser::unparser::unparse;
+use fhirlighter::{
+    Error, ErrorCode, ErrorKind, evaluate, evaluate_with_diagnostics, parse, parse_with_diagnostics,
+};
+use proptest::prelude::*;
+use serde_json::Value;
+use std::fs;
+use time::{Date, Month, PrimitiveDateTime, Time};
+
+/// Load an example JSON file
+fn load_example(name: &str) -> Value {
+    let contents =
+        fs::read_to_string(format!("tests/examples/{name}")).expect("Failed to read example");
+    serde_json::from_str(&contents).expect("Failed to parse example")
+}
+
+/// Parse without checking function calls, as generated expressions call
+/// functions with made-up names
+fn parse_syntax(source: &str) -> Result<Ast, Error> {
+    let tokens = Lexer::new(source).tokenize()?;
+    FhirParser::new(&tokens, source).parse()
+}
+
+// Text made mostly of characters that mean something in FHIRPath, so generated
+// input gets past the lexer more often than arbitrary text does
+const FHIRPATH_TEXT: &str = "[a-zA-Z0-9_ .,()\\[\\]'\"`%@=<>!|$+*/-]{0,40}";
+
+const KEYWORDS: [&str; 14] = [
+    "and", "or", "xor", "not", "is", "as", "mod", "where", "select", "all", "any", "exists",
+    "true", "false",
+];
+
+// Pieces of FHIRPath that often form an expression when strung together, to
+// compare the parsers on input the generators below don't write
+const PIECES: [&str; 22] = [
+    "a",
+    "b",
+    "`c d`",
+    "%e",
+    "%'f'",
+    "1",
+    "2.5",
+    "'s'",
+    "@2020-01-01",
+    "true",
+    "where",
+    ".",
+    "(",
+    ")",
+    "[",
+    "]",
+    ", ",
+    " = ",
+    " != ",
+    "<",
+    " >= ",
+    " /* c */ ",
+];
+
+const TRIVIA: [&str; 6] = [" ", "\n", "\r\n  ", " /* c */ ", "// c\n", "\n// c\n"];
+
+fn identifier() -> impl Strategy<Value = String> {
+    "[a-zA-Z_][a-zA-Z0-9_]{0,6}".prop_filter("keywords aren't identifiers", |name| {
+        !KEYWORDS.contains(&name.as_str())
+    })
+}
+
+// Mostly made-up names, but also the functions the optimizer rewrites and
+// `where()`, which it must leave for evaluation to report as unsupported
+fn function_name() -> impl Strategy<Value = String> {
+    prop_oneof![
+        3 => identifier(),
+        1 => prop::sample::select(&["first", "last", "exists", "empty", "count", "where"][..])
+            .prop_map(str::to_string),
+    ]
+}
+
+fn term() -> impl Strategy<Value = String> {
+    prop_oneof![
+        identifier(),
+        (0..1000i64).prop_map(|integer| integer.to_string()),
+        "[a-z ]{0,8}".prop_map(|string| format!("'{string}'")),
+        any::<bool>().prop_map(|boolean| boolean.to_string()),
+        identifier().prop_map(|name| format!("%{name}")),
+        (1900..2100i32, 1..=12u8, 1..=28u8)
+            .prop_map(|(year, month, day)| format!("@{year}-{month:02}-{day:02}")),
+    ]
+}
+
+// Expressions in the form the parser reads back unchanged: operators associate
+// to the right, so the left operand of an operator is never itself an operation
+fn expression() -> impl Strategy<Value = String> {
+    let leaf = term();
+    leaf.prop_recursive(4, 32, 4, |inner| {
+        let postfix = prop_oneof![
+            identifier().prop_map(|member| format!(".{member}")),
+            (function_name(), prop::collection::vec(inner.clone(), 0..3))
+                .prop_map(|(function, arguments)| format!(".{function}({})", arguments.join(", "))),
+            (0..10i64).prop_map(|index| format!("[{index}]")),
+        ];
+        let operand = (term(), prop::collection::vec(postfix, 0..3))
+            .prop_map(|(term, postfix)| term + &postfix.concat())
+            .boxed();
+        let operator = prop_oneof![
+            Just("="),
+            Just("!="),
+            Just("<"),
+            Just("<="),
+            Just(">"),
+            Just(">="),
+        ];
+        prop_oneof![
+            operand.clone(),
+            (operand, operator, inner)
+                .prop_map(|(lhs, operator, rhs)| format!("{lhs} {operator} {rhs}")),
+        ]
+    })
+}
+
+// Trees the parser can't produce from source without parentheses, such as
+// operations nested to the left, with names and literals that need escaping
+#[derive(Debug, Clone)]
+enum Tree {
+    Leaf(Expression),
+    Member(Box<Self>, String),
+    Call(Option<Box<Self>>, String, Vec<Self>),
+    Index(Box<Self>, Box<Self>),
+    Operation(BinaryOperator, Box<Self>, Box<Self>),
+}
+
+fn name() -> impl Strategy<Value = String> {
+    prop_oneof![
+        identifier(),
+        Just("true".to_string()),
+        "\\PC{1,6}",
+        "[ `'\\\\\n]{1,3}"
+    ]
+}
+
+fn leaf() -> impl Strategy<Value = Expression> {
+    prop_oneof![
+        name().prop_map(Expression::Identifier),
+        name().prop_map(Expression::ExternalConstant),
+        "\\PC{0,6}|[ `'\\\\\n\r\t]{0,3}".prop_map(Expression::String),
+        (0.0..1e20f64).prop_map(Expression::Number),
+        (0..=i64::MAX).prop_map(Expression::Integer),
+        any::<bool>().prop_map(Expression::Boolean),
+        (1..=9999i32, 1..=12u8, 1..=28u8).prop_map(|(year, month, day)| {
+            let month = Month::try_from(month).unwrap();
+            Expression::ISODate(Date::from_calendar_date(year, month, day).unwrap())
+        }),
+        (0..24u8, 0..60u8, 0..60u8, 0..1000u16).prop_map(|(hour, minute, second, milli)| {
+            let time = Time::from_hms_milli(hour, minute, second, milli).unwrap();
+            let date = Date::from_calendar_date(2024, Month::February, 29).unwrap();
+            Expression::ISODateTime(PrimitiveDateTime::new(date, time))
+        }),
+    ]
+}
+
+fn tree() -> impl Strategy<Value = Tree> {
+    leaf()
+        .prop_map(Tree::Leaf)
+        .prop_recursive(5, 48, 3, |inner| {
+            let operator = prop_oneof![
+                Just(BinaryOperator::Equals),
+                Just(BinaryOperator::NotEquals),
+                Just(BinaryOperator::LessThan),
+                Just(BinaryOperator::GreaterThanOrEqual),
+            ];
+            prop_oneof![
+                (inner.clone(), name())
+                    .prop_map(|(object, member)| Tree::Member(Box::new(object), member)),
+                (
+                    prop::option::of(inner.clone()),
+                    name(),
+                    prop::collection::vec(inner.clone(), 0..3)
+                )
+                    .prop_map(|(object, function, arguments)| {
+                        Tree::Call(object.map(Box::new), function, arguments)
+                    }),
+                (inner.clone(), inner.clone())
+                    .prop_map(|(object, index)| Tree::Index(Box::new(object), Box::new(index))),
+                (operator, inner.clone(), inner).prop_map(|(operator, lhs, rhs)| {
+                    Tree::Operation(operator, Box::new(lhs), Box::new(rhs))
+                }),
+            ]
+        })
+}
+
+// Helper: add a tree to the pool, returning its root
+fn build(tree: &Tree, pool: &mut ExprPool) -> ExprRef {
+    let expression = match tree {
+        Tree::Leaf(expression) => expression.clone(),
+        Tree::Member(object, member) => Expression::MemberAccess {
+            object: build(object, pool),
+            member: member.clone(),
+        },
+        Tree::Call(object, function, arguments) => Expression::FunctionCall {
+            object: object.as_ref().map(|object| build(object, pool)),
+            function: pool.add(Expression::Identifier(function.clone())).unwrap(),
+            arguments: arguments
+                .iter()
+                .map(|argument| build(argument, pool))
+                .collect(),
+        },
+        Tree::Index(object, index) => Expression::Index {
+            object: build(object, pool),
+            index: build(index, pool),
+        },
+        Tree::Operation(operator, lhs, rhs) => Expression::BinaryOperation {
+            operator: *operator,
+            lhs: build(lhs, pool),
+            rhs: build(rhs, pool),
+        },
+    };
+    pool.add(expression).unwrap()
+}
+
+proptest! {
+    #[test]
+    fn test_parse_never_panics(input in any::<String>()) {
+        let _ = parse(&input);
+    }
+
+    #[test]
+    fn test_parse_fhirpath_text_never_panics(input in FHIRPATH_TEXT) {
+        let _ = parse(&input);
+    }
+
+    #[test]
+    fn test_recovering_parse_agrees_with_parse(input in FHIRPATH_TEXT) {
+        let parsed = parse_with_diagnostics(&input);
+        match parse(&input) {
+            Ok(ast) => {
+                prop_assert!(parsed.diagnostics.is_empty());
+                prop_assert_eq!(parsed.ast.expressions, ast.expressions);
+            }
+            Err(error) => prop_assert!(parsed.diagnostics.contains(&error.into())),
+        }
+    }
+
+    #[test]
+    fn test_evaluate_never_panics(input in FHIRPATH_TEXT) {
+        let patient = load_example("patient-example.json");
+        let context = EvaluationContext::new(&patient);
+        let _ = evaluate_with_diagnostics(&input, &context);
+    }
+
+    #[test]
+    fn test_tokens_cover_input(input in FHIRPATH_TEXT) {
+        let Ok(tokens) = Lexer::new(&input).tokenize_lossless() else {
+            return Ok(());
+        };
+        let mut position = 0;
+        for token in &tokens {
+            prop_assert_eq!(token.start, position);
+            prop_assert!(token.start <= token.end && input.is_char_boundary(token.end));
+            position = token.end;
+        }
+        let text: String = tokens.iter().map(|token| token.text(&input)).collect();
+        prop_assert_eq!(text, input.as_str());
+        prop_assert_eq!(tokens.last().map(|token| token.kind), Some(TokenKind::Eof));
+
+        let significant: Vec<_> = tokens.into_iter().filter(|token| !token.kind.is_trivia()).collect();
+        prop_assert_eq!(Lexer::new(&input).tokenize().ok(), Some(significant));
+    }
+
+    #[test]
+    fn test_cst_round_trip(input in FHIRPATH_TEXT) {
+        if let Ok(cst) = Cst::parse(&input) {
+            prop_assert_eq!(cst.to_string(), input);
+        }
+    }
+
+    #[test]
+    fn test_cst_agrees_with_parser(
+        input in prop_oneof![
+            FHIRPATH_TEXT.boxed(),
+            prop::collection::vec(prop::sample::select(&PIECES[..]), 1..12)
+                .prop_map(|pieces| pieces.concat())
+                .boxed(),
+        ],
+    ) {
+        // The CST follows the grammar of the parser with a copy of its own, so
+        // what one accepts the other must too, and formatting must succeed.
+        // Blank input has an empty tree but no expression to parse.
+        let Ok(cst) = Cst::parse(&input) else {
+            return Ok(());
+        };
+        if cst.root.nodes().next().is_none() {
+            return Ok(());
+        }
+        // Only the parser reads the values of literals, such as dates, and it
+        // stops at one that is invalid
+        let codes: Vec<_> = parse_with_diagnostics(&input)
+            .diagnostics
+            .iter()
+            .map(|diagnostic| diagnostic.code)
+            .collect();
+        if codes.contains(&ErrorCode::InvalidLiteral) {
+            return Ok(());
+        }
+        let misplaced = codes.iter().any(|code| code.kind() == ErrorKind::Syntax);
+        prop_assert_eq!(cst.root.has_errors(), misplaced, "{}", input);
+        if let Ok(ast) = parse_syntax(&input) {
+            let formatted = fhirlighter::format(&input).map_err(|error| TestCaseError::fail(error.render(&input)))?;
+            let reparsed = parse_syntax(&formatted).map_err(|error| TestCaseError::fail(error.render(&formatted)))?;
+            prop_assert!(reparsed.equivalent(&ast));
+        }
+    }
+
+    #[test]
+    fn test_format_preserves_expression(source in expression(), width in 10..100usize) {
+        let options = FormatOptions { width, ..FormatOptions::default() };
+        let formatted = format(&source, &options).map_err(|error| TestCaseError::fail(error.render(&source)))?;
+        let ast = parse_syntax(&source).unwrap();
+        let reparsed = parse_syntax(&formatted).map_err(|error| TestCaseError::fail(error.render(&formatted)))?;
+        prop_assert!(reparsed.equivalent(&ast));
+        prop_assert_eq!(format(&formatted, &options).ok(), Some(formatted));
+    }
+
+    #[test]
+    fn test_format_keeps_comments(
+        source in expression(),
+        separators in prop::collection::vec(prop::sample::select(&TRIVIA[..]), 64),
+    ) {
+        // The expression's tokens with whitespace and comments between them
+        let tokens = Lexer::new(&source).tokenize().unwrap();
+        let mut input = String::new();
+        for (token, separator) in tokens.iter().zip(separators.iter().cycle()) {
+            input.push_str(token.text(&source));
+            input.push_str(separator);
+        }
+
+        let formatted = fhirlighter::format(&input).map_err(|error| TestCaseError::fail(error.render(&input)))?;
+        let ast = parse_syntax(&source).unwrap();
+        let reparsed = parse_syntax(&formatted).unwrap();
+        prop_assert!(reparsed.equivalent(&ast));
+        prop_assert_eq!(fhirlighter::format(&formatted).ok(), Some(formatted));
+    }
+
+    #[test]
+    fn test_optimize_preserves_results(source in expression()) {
+        let patient = load_example("patient-example.json");
+        let context = EvaluationContext::new(&patient);
+        let ast = parse_syntax(&source).unwrap();
+        let optimized = optimize(&ast).map_err(|error| TestCaseError::fail(error.to_string()))?;
+        let evaluator = Evaluator::new();
+        let expected = evaluator.evaluate_with_diagnostics(&ast, &context);
+        let actual = evaluator.evaluate_with_diagnostics(&optimized, &context);
+        match (expected, actual) {
+            (Ok(expected), Ok(actual)) => {
+                // Rewritten expressions have the spans of those they replace
+                let messages = |diagnostics: Vec<fhirlighter::Diagnostic>| {
+                    diagnostics.into_iter().map(|diagnostic| diagnostic.message).collect::<Vec<_>>()
+                };
+                prop_assert_eq!(&expected.value, &actual.value, "{}", unparse(&optimized));
+                prop_assert_eq!(messages(expected.diagnostics), messages(actual.diagnostics));
+            }
+            (Err(expected), Err(actual)) => prop_assert_eq!(expected.code, actual.code),
+            (expected, actual) => prop_assert!(false, "{:?} became {:?} as {}", expected, actual, unparse(&optimized)),
+        }
+    }
+
+    #[test]
+    fn test_parse_round_trip(source in expression()) {
+        let ast = parse_syntax(&source).map_err(|error| TestCaseError::fail(error.render(&source)))?;
+        prop_assert_eq!(unparse(&ast), source.as_str());
+        prop_assert_eq!(ast.expressions.span(ast.start).end, source.len());
+    }
+}
+
+proptest! {
+    #[test]
+    fn test_unparse_round_trip(tree in tree()) {
+        let mut expressions = ExprPool::new();
+        let start = build(&tree, &mut expressions);
+        let ast = Ast { expressions, start };
+        let source = unparse(&ast);
+        let reparsed = parse_syntax(&source).map_err(|error| TestCaseError::fail(error.render(&source)))?;
+        prop_assert!(reparsed.equivalent(&ast), "{}", source);
+        prop_assert_eq!(unparse(&reparsed), source);
+    }
+
+    #[test]
+    fn test_json_round_trip(tree in tree()) {
+        let mut expressions = ExprPool::new();
+        let start = build(&tree, &mut expressions);
+        let ast = Ast { expressions, start };
+        let json = ast.to_json();
+        let read = Ast::from_json(&json).map_err(|error| TestCaseError::fail(error.to_string()))?;
+        prop_assert!(read.equivalent(&ast), "{}", json);
+        prop_assert_eq!(read.to_json(), json);
+    }
+}
+
+#[test]
+fn test_deepest_expressions_evaluate() {
+    let patient = load_example("patient-example.json");
+
+    let members = format!("Patient{}", ".name".repeat(MAX_DEPTH - 2));
+    assert_eq!(
+        evaluate(&members, &patient).unwrap(),
+        Value::Array(Vec::new())
+    );
+
+    let comparisons = "1 = ".repeat(MAX_DEPTH / 2 - 1) + "1";
+    assert!(evaluate(&comparisons, &patient).is_ok());
+
+    let too_deep = format!("Patient{}", ".name".repeat(MAX_DEPTH));
+    assert!(parse(&too_deep).is_err());
+}
@@ -0,0 +1,155 @@
+//! Tests for reference resolution with `resolve()`
+//!
+//! References resolve against contained resources, the entries of an enclosing
+//! Bundle and finally the resolver supplied in the evaluation context.
+
+use fhirlighter::evaluate_with_context;
+use fhirlighter::evaluator::context::EvaluationContext;
+use fhirlighter::evaluator::resolver::{ReferenceResolver, ResourceStore};
+use serde_json::{Value, json};
+use std::borrow::Cow;
+use std::fs;
+
+/// Load an example JSON file
+fn load_example(name: &str) -> Value {
+    let contents =
+        fs::read_to_string(format!("tests/examples/{name}")).expect("Failed to read example");
+    serde_json::from_str(&contents).expect("Failed to parse example")
+}
+
+fn patient_store() -> ResourceStore {
+    let mut store = ResourceStore::new();
+    let mut patient = load_example("patient-example.json");
+    patient["meta"] = json!({"versionId": "1"});
+    store.insert(patient).unwrap();
+    store
+}
+
+#[test]
+fn test_resolve_from_store() {
+    let observation = load_example("observation-example.json");
+    let store = patient_store();
+    let context = EvaluationContext::new(&observation).with_resolver(&store);
+
+    let result = evaluate_with_context(
+        "Observation.subject.resolve().ofType(Patient).name[0].family",
+        &context,
+    )
+    .unwrap();
+    assert_eq!(result, json!("Chalmers"));
+
+    // Encounter/example is not in the store
+    let result = evaluate_with_context("Observation.encounter.resolve()", &context).unwrap();
+    assert_eq!(result, json!([]));
+}
+
+#[test]
+fn test_resolve_reference_string() {
+    let observation = load_example("observation-example.json");
+    let store = patient_store();
+    let context = EvaluationContext::new(&observation).with_resolver(&store);
+
+    let result =
+        evaluate_with_context("Observation.subject.reference.resolve().gender", &context).unwrap();
+    assert_eq!(result, json!("male"));
+}
+
+#[test]
+fn test_resolve_versioned_and_absolute() {
+    let observation = json!({
+        "resourceType": "Observation",
+        "subject": {"reference": "Patient/example/_history/1"},
+        "performer": [{"reference": "http://example.org/fhir/Patient/example/_history/2"}],
+        "focus": [{"reference": "https://example.org/fhir/Patient/example"}]
+    });
+    let store = patient_store();
+    let context = EvaluationContext::new(&observation).with_resolver(&store);
+
+    let result = evaluate_with_context("Observation.subject.resolve().id", &context).unwrap();
+    assert_eq!(result, json!("example"));
+    let result = evaluate_with_context("Observation.performer.resolve()", &context).unwrap();
+    assert_eq!(result, json!([]));
+    let result = evaluate_with_context("Observation.focus.resolve().id", &context).unwrap();
+    assert_eq!(result, json!("example"));
+}
+
+#[test]
+fn test_resolve_contained() {
+    let observation = json!({
+        "resourceType": "Observation",
+        "contained": [{"resourceType": "Patient", "id": "p1", "gender": "female"}],
+        "subject": {"reference": "#p1"}
+    });
+    let context = EvaluationContext::new(&observation);
+
+    let result = evaluate_with_context(
+        "Observation.subject.resolve().ofType(Patient).gender",
+        &context,
+    )
+    .unwrap();
+    assert_eq!(result, json!("female"));
+    let result =
+        evaluate_with_context("Observation.subject.resolve().ofType(Group)", &context).unwrap();
+    assert_eq!(result, json!([]));
+}
+
+#[test]
+fn test_resolve_bundle_entry() {
+    let bundle = json!({
+        "resourceType": "Bundle",
+        "type": "collection",
+        "entry": [
+            {
+                "fullUrl": "urn:uuid:a6c1b4b5-4d7c-4d5c-9c7b-62b1d5c1f0a1",
+                "resource": {
+                    "resourceType": "Observation",
+                    "id": "o1",
+                    "subject": {"reference": "urn:uuid:2b1a8c8e-7f6e-4a5c-8d1e-3f9a1b2c4d5e"},
+                    "performer": [{"reference": "Practitioner/pr1"}]
+                }
+            },
+            {
+                "fullUrl": "urn:uuid:2b1a8c8e-7f6e-4a5c-8d1e-3f9a1b2c4d5e",
+                "resource": {"resourceType": "Patient", "id": "p1", "gender": "other"}
+            },
+            {
+                "fullUrl": "http://example.org/fhir/Practitioner/pr1",
+                "resource": {"resourceType": "Practitioner", "id": "pr1", "active": true}
+            }
+        ]
+    });
+    let context = EvaluationContext::new(&bundle);
+
+    let result = evaluate_with_context(
+        "Bundle.entry.resource.subject.resolve().ofType(Patient).gender",
+        &context,
+    )
+    .unwrap();
+    assert_eq!(result, json!("other"));
+    let result =
+        evaluate_with_context("Bundle.entry.resource.performer.resolve().active", &context)
+            .unwrap();
+    assert_eq!(result, json!(true));
+}
+
+// A custom resolver, e.g. backed by a server or database
+struct FixedResolver(Value);
+
+impl ReferenceResolver for FixedResolver {
+    fn resolve(&self, reference: &str) -> Option<Cow<'_, Value>> {
+        (reference == "Patient/remote").then_some(Cow::Borrowed(&self.0))
+    }
+}
+
+#[test]
+fn test_custom_resolver() {
+    let observation = json!({
+        "resourceType": "Observation",
+        "subject": {"reference": "Patient/remote"}
+    });
+    let resolver = FixedResolver(json!({"resourceType": "Patient", "id": "remote"}));
+    let context = EvaluationContext::new(&observation).with_resolver(&resolver);
+
+    let result = evaluate_with_context("Observation.subject.resolve().id", &context).unwrap();
+    assert_eq!(result, json!("remote"));
+}
@@ -0,0 +1,147 @@
+//! Tests for static type and cardinality inference
+//!
+//! Not part of the specification suite: expressions are checked against the
+//! Patient definitions in `structuredefinitions-patient.json`, a trimmed copy
+//! of the FHIR snapshots, and what they are inferred to return is compared with
+//! what they do return on the Patient example.
+
+use fhirlighter::evaluator::types::{StructureDefinitions, TypeChecker, TypeModel, Typed};
+use fhirlighter::{ErrorCode, ErrorKind, Span, evaluate, parse};
+use serde_json::{Value, json};
+use std::fs;
+
+/// Load an example JSON file
+fn load_example(name: &str) -> Value {
+    let contents =
+        fs::read_to_string(format!("tests/examples/{name}")).expect("Failed to read example");
+    serde_json::from_str(&contents).expect("Failed to parse example")
+}
+
+fn patient_model() -> StructureDefinitions {
+    let mut model = StructureDefinitions::new();
+    model
+        .insert(&load_example("structuredefinitions-patient.json"))
+        .unwrap();
+    model
+}
+
+fn check(expression: &str, model: &StructureDefinitions) -> Typed {
+    TypeChecker::new("Patient")
+        .with_model(model)
+        .check(&parse(expression).unwrap())
+}
+
+#[test]
+fn test_inferred_types_fit_evaluation() {
+    let model = patient_model();
+    let patient = load_example("patient-example.json");
+    for (expression, expected) in [
+        ("Patient.id", "0..1 id"),
+        ("Patient.name", "0..* HumanName"),
+        ("Patient.name.given", "0..* string"),
+        ("Patient.name.first().family", "0..1 string"),
+        ("Patient.contact.name.family", "0..* string"),
+        ("Patient.deceased", "0..1 Any"),
+        ("Patient.deceasedBoolean", "0..1 boolean"),
+        ("Patient.birthDate < @2000-01-01", "0..1 Boolean"),
+        ("Patient.telecom.count()", "1..1 Integer"),
+        ("Patient.name.exists()", "1..1 Boolean"),
+    ] {
+        let typed = check(expression, &model);
+        assert!(typed.errors.is_empty(), "{expression}: {:?}", typed.errors);
+        let inferred = typed.result().unwrap();
+        assert_eq!(inferred.to_string(), expected, "{expression}");
+
+        // The example returns as many items as the cardinality allows
+        let items = match evaluate(expression, &patient).unwrap() {
+            Value::Array(items) => items.len(),
+            _ => 1,
+        };
+        let cardinality = inferred.cardinality;
+        assert!(cardinality.min <= items, "{expression}");
+        assert!(
+            cardinality.max.is_none_or(|max| items <= max),
+            "{expression}"
+        );
+    }
+}
+
+#[test]
+fn test_unknown_elements_are_reported() {
+    let model = patient_model();
+    let typed = check("Patient.nmae.given = Patient.contact.name.famly", &model);
+    let messages: Vec<_> = typed
+        .errors
+        .iter()
+        .map(|error| error.message.as_str())
+        .collect();
+    assert_eq!(
+        messages,
+        [
+            "Patient has no element 'nmae'",
+            "HumanName has no element 'famly'"
+        ]
+    );
+    assert_eq!(typed.errors[0].code, ErrorCode::UnknownElement);
+    assert_eq!(typed.errors[0].kind(), ErrorKind::StaticType);
+    assert_eq!(typed.errors[0].span, Some(Span::new(0, 12)));
+
+    // Backbone elements are types of their own
+    let typed = check(
+        "Patient.contact.relationship.text = Patient.contact.telecom",
+        &model,
+    );
+    assert_eq!(typed.errors.len(), 1);
+    assert_eq!(
+        typed.errors[0].message,
+        "Patient.contact has no element 'telecom'"
+    );
+}
+
+#[test]
+fn test_differentials_leave_inherited_elements_open() {
+    let mut model = patient_model();
+    model
+        .insert(&json!({
+            "resourceType": "StructureDefinition",
+            "type": "Observation",
+            "differential": {"element": [
+                {"path": "Observation.status", "type": [{"code": "code"}], "min": 1, "max": "1"}
+            ]}
+        }))
+        .unwrap();
+    assert!(model.has_type("Patient"));
+    assert!(!model.has_type("Observation"));
+
+    // `id` and `extension` come from the base definitions, not the differential,
+    // so they aren't reported
+    for expression in [
+        "Observation.status = Observation.id",
+        "Observation.extension.exists()",
+    ] {
+        let typed = TypeChecker::new("Observation")
+            .with_model(&model)
+            .check(&parse(expression).unwrap());
+        assert!(typed.errors.is_empty(), "{expression}: {:?}", typed.errors);
+    }
+}
+
+#[test]
+fn test_comparisons_without_a_model() {
+    for (expression, message) in [
+        (
+            "Patient.birthDate.count() = 'x'",
+            "Can't compare Integer with String",
+        ),
+        ("Patient.name.exists() < true", "Can't order Boolean values"),
+    ] {
+        let typed = TypeChecker::new("Patient").check(&parse(expression).unwrap());
+        assert_eq!(typed.errors.len(), 1, "{expression}");
+        assert_eq!(typed.errors[0].kind(), ErrorKind::StaticType);
+        assert_eq!(typed.errors[0].message, message);
+    }
+    // Without a model, elements are of any type and none is unknown
+    let typed = TypeChecker::new("Patient").check(&parse("Patient.nmae").unwrap());
+    assert!(typed.errors.is_empty());
+    assert_eq!(typed.result().unwrap().to_string(), "0..* Any");
+}
@@ -0,0 +1,95 @@
+//! Tests for type names at the start of a path
+//!
+//! A leading type name matches the resource it is evaluated against when it is
+//! the resource type or one of its base types, optionally qualified as `FHIR.`.
+
+use fhirlighter::evaluator::context::{EvaluationContext, UnmatchedType};
+use fhirlighter::{ErrorCode, Span, evaluate, evaluate_with_context};
+use serde_json::{Value, json};
+use std::fs;
+
+/// Load an example JSON file
+fn load_example(name: &str) -> Value {
+    let contents =
+        fs::read_to_string(format!("tests/examples/{name}")).expect("Failed to read example");
+    serde_json::from_str(&contents).expect("Failed to parse example")
+}
+
+#[test]
+fn test_base_types() {
+    let patient = load_example("patient-example.json");
+    assert_eq!(evaluate("Resource.id", &patient).unwrap(), json!("example"));
+    assert_eq!(
+        evaluate("DomainResource.text.status", &patient).unwrap(),
+        json!("generated")
+    );
+
+    let bundle = json!({"resourceType": "Bundle", "id": "b1", "type": "collection"});
+    assert_eq!(evaluate("Resource.id", &bundle).unwrap(), json!("b1"));
+    assert_eq!(evaluate("DomainResource.id", &bundle).unwrap(), json!([]));
+}
+
+#[test]
+fn test_fhir_qualified_names() {
+    let patient = load_example("patient-example.json");
+    assert_eq!(
+        evaluate("FHIR.Patient.gender", &patient).unwrap(),
+        json!("male")
+    );
+    assert_eq!(
+        evaluate("FHIR.Resource.id", &patient).unwrap(),
+        json!("example")
+    );
+
+    let bundle = json!({
+        "resourceType": "Bundle",
+        "type": "collection",
+        "entry": [
+            {"resource": {"resourceType": "Patient", "id": "p1"}},
+            {"resource": {"resourceType": "Observation", "id": "o1"}},
+            {"resource": {"resourceType": "Binary", "id": "b1"}}
+        ]
+    });
+    assert_eq!(
+        evaluate("Bundle.entry.resource.ofType(FHIR.Patient).id", &bundle).unwrap(),
+        json!("p1")
+    );
+    assert_eq!(
+        evaluate("Bundle.entry.resource.ofType(DomainResource).id", &bundle).unwrap(),
+        json!(["p1", "o1"])
+    );
+}
+
+#[test]
+fn test_unmatched_type() {
+    let patient = load_example("patient-example.json");
+
+    let context = EvaluationContext::new(&patient);
+    assert_eq!(
+        evaluate_with_context("Patien.name", &context).unwrap(),
+        json!([])
+    );
+
+    let context = EvaluationContext::new(&patient).with_unmatched_type(UnmatchedType::Error);
+    let result = evaluate_with_context("Patien.name", &context);
+    let error = result.unwrap_err();
+    assert_eq!(error.code, ErrorCode::UnmatchedType);
+    assert_eq!(error.span, Some(Span::new(0, 6)));
+    assert!(error.message.contains("Patien"));
+    // Element names are not type names
+    assert_eq!(
+        evaluate_with_context("gender", &context).unwrap(),
+        json!("male")
+    );
+
+    let parameters = json!({
+        "resourceType": "Parameters",
+        "Patient": {"resourceType": "Patient", "id": "p1"}
+    });
+    let context =
+        EvaluationContext::new(&parameters).with_unmatched_type(UnmatchedType::MemberLookup);
+    assert_eq!(
+        evaluate_with_context("Patient.id", &context).unwrap(),
+        json!("p1")
+    );
+}
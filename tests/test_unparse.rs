@@ -0,0 +1,63 @@
+//! Tests for writing parsed expressions back out as source
+//!
+//! Not part of the specification suite: these check that each expression of a
+//! corpus unparses to source that parses to the same tree and evaluates to the
+//! same result.
+
+use fhirlighter::parser::unparser::unparse;
+use fhirlighter::{evaluate, evaluate_ast, parse};
+use serde_json::Value;
+use std::fs;
+
+/// Load an example JSON file
+fn load_example(name: &str) -> Value {
+    let contents =
+        fs::read_to_string(format!("tests/examples/{name}")).expect("Failed to read example");
+    serde_json::from_str(&contents).expect("Failed to parse example")
+}
+
+const CORPUS: [&str; 20] = [
+    "Patient",
+    "Patient.name[0].given.first()",
+    "Patient.name.where(use = 'official').family",
+    "Patient.name.given = 'Peter'",
+    "Patient.name.given.count()",
+    "Patient.name[0] != Patient.name[1]",
+    "Patient.birthDate.hasValue()",
+    "Patient.birthDate.extension('http://hl7.org/fhir/StructureDefinition/patient-birthTime')",
+    "Patient.birthDate.extension.valueCode",
+    "Patient.birthDate >= @1970-01-01",
+    "Patient.`ofType`(`Patient`).id",
+    "Patient.text.div.htmlChecks()",
+    "FHIR.Patient.gender",
+    "DomainResource.text.status",
+    "%resource.extension(%`ext-patient-birthTime`)",
+    "%'vs-administrative-gender'",
+    "%context.gender",
+    "(Patient.gender = 'male') = true",
+    "(Patient.name[0] = Patient.name[0]).exists()",
+    "'O\\'Brien\\n' != Patient.name.family",
+];
+
+#[test]
+fn test_corpus_round_trips() {
+    let patient = load_example("patient-example.json");
+    for expression in CORPUS {
+        let ast = parse(expression).unwrap();
+        let source = unparse(&ast);
+        let reparsed = parse(&source).unwrap();
+        assert!(reparsed.equivalent(&ast), "{expression} => {source}");
+        assert_eq!(
+            evaluate_ast(&reparsed, &patient),
+            evaluate(expression, &patient),
+            "{expression} => {source}"
+        );
+    }
+}
+
+#[test]
+fn test_redundant_parentheses_are_dropped() {
+    let ast = parse("((Patient.name)).where((use) = ('official'))").unwrap();
+    assert_eq!(unparse(&ast), "Patient.name.where(use = 'official')");
+    assert_eq!(ast.to_string(), unparse(&ast));
+}
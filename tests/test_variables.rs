@@ -0,0 +1,145 @@
+//! Tests for environment variables and user variables
+//!
+//! `%context`, `%resource` and `%rootResource` follow the node evaluation starts
+//! from, the remaining constants are fixed and user variables come from the
+//! evaluation context.
+
+use fhirlighter::evaluator::context::{EvaluationContext, TypedValue};
+use fhirlighter::{ErrorCode, evaluate, evaluate_with_context};
+use serde_json::{Value, json};
+use std::fs;
+
+/// Load an example JSON file
+fn load_example(name: &str) -> Value {
+    let contents =
+        fs::read_to_string(format!("tests/examples/{name}")).expect("Failed to read example");
+    serde_json::from_str(&contents).expect("Failed to parse example")
+}
+
+#[test]
+fn test_resource_variables_default_to_input() {
+    let patient = load_example("patient-example.json");
+    assert_eq!(
+        evaluate("%resource.id", &patient).unwrap(),
+        json!("example")
+    );
+    assert_eq!(
+        evaluate("%rootResource.id", &patient).unwrap(),
+        json!("example")
+    );
+    assert_eq!(
+        evaluate("%context.gender", &patient).unwrap(),
+        json!("male")
+    );
+}
+
+#[test]
+fn test_resource_variables_in_bundle_entry() {
+    let bundle = json!({
+        "resourceType": "Bundle",
+        "type": "collection",
+        "entry": [{
+            "resource": {
+                "resourceType": "Observation",
+                "id": "o1",
+                "contained": [{"resourceType": "Patient", "id": "p1", "gender": "other"}],
+                "status": "final"
+            }
+        }]
+    });
+
+    let status = &bundle["entry"][0]["resource"]["status"];
+    let context = EvaluationContext::new(&bundle).with_focus(status);
+    assert_eq!(
+        evaluate_with_context("%context", &context).unwrap(),
+        json!("final")
+    );
+    assert_eq!(
+        evaluate_with_context("%resource.id", &context).unwrap(),
+        json!("o1")
+    );
+    assert_eq!(
+        evaluate_with_context("%rootResource.id", &context).unwrap(),
+        json!("o1")
+    );
+
+    let gender = &bundle["entry"][0]["resource"]["contained"][0]["gender"];
+    let context = EvaluationContext::new(&bundle).with_focus(gender);
+    assert_eq!(
+        evaluate_with_context("%resource.id", &context).unwrap(),
+        json!("p1")
+    );
+    assert_eq!(
+        evaluate_with_context("%rootResource.id", &context).unwrap(),
+        json!("o1")
+    );
+}
+
+#[test]
+fn test_constants() {
+    let patient = load_example("patient-example.json");
+    assert_eq!(
+        evaluate("%ucum", &patient).unwrap(),
+        json!("http://unitsofmeasure.org")
+    );
+    assert_eq!(
+        evaluate("%sct", &patient).unwrap(),
+        json!("http://snomed.info/sct")
+    );
+    assert_eq!(
+        evaluate("%loinc", &patient).unwrap(),
+        json!("http://loinc.org")
+    );
+    assert_eq!(
+        evaluate("%`vs-administrative-gender`", &patient).unwrap(),
+        json!("http://hl7.org/fhir/ValueSet/administrative-gender")
+    );
+    assert_eq!(
+        evaluate("%'ext-patient-birthTime'", &patient).unwrap(),
+        json!("http://hl7.org/fhir/StructureDefinition/patient-birthTime")
+    );
+}
+
+#[test]
+fn test_user_variables() {
+    let patient = load_example("patient-example.json");
+    let context = EvaluationContext::new(&patient)
+        .with_variable("gender", "male")
+        .with_variable("families", json!(["Chalmers", "Windsor"]))
+        .with_variable("nothing", Value::Null)
+        .with_variable(
+            "born",
+            TypedValue::Date(
+                time::Date::from_calendar_date(1974, time::Month::December, 25).unwrap(),
+            ),
+        );
+
+    assert_eq!(
+        evaluate_with_context("Patient.gender = %gender", &context).unwrap(),
+        json!(true)
+    );
+    assert_eq!(
+        evaluate_with_context("%families.count()", &context).unwrap(),
+        json!(2)
+    );
+    assert_eq!(
+        evaluate_with_context("%nothing.empty()", &context).unwrap(),
+        json!(true)
+    );
+    assert_eq!(
+        evaluate_with_context("Patient.birthDate = %born", &context).unwrap(),
+        json!(true)
+    );
+}
+
+#[test]
+fn test_undefined_variable() {
+    let patient = load_example("patient-example.json");
+    let result = evaluate("Patient.gender = %unknown", &patient);
+    let error = result.unwrap_err();
+    assert_eq!(error.code, ErrorCode::UndefinedVariable);
+    assert_eq!(
+        error.render("Patient.gender = %unknown"),
+        "error[E0301]: Undefined variable: %unknown\n  |\n1 | Patient.gender = %unknown\n  |                  ^^^^^^^^"
+    );
+}